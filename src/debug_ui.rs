@@ -5,7 +5,142 @@
 // and a single `show()` at the end to inspect everything.
 //
 // When the `debug_ui` feature is disabled (or `cli_only` is enabled), all APIs
-// in this module become no-ops.
+// in this module become no-ops, except for the headless renderers below.
+// `dump_all()` writes the collected items to PNGs instead of opening a window.
+
+use crate::im::{Lum16Im, MaskIm, RGBAIm};
+use crate::toolpath::ToolPath;
+
+// Headless rendering (shared with the toolpath movie viewer)
+// -----------------------------------------------------------------------------
+
+const OVERLAY_PATH_RGB: [u8; 3] = [255, 40, 40];
+const OVERLAY_TRAVERSE_RGB: [u8; 3] = [240, 200, 40];
+const OVERLAY_START_RGB: [u8; 3] = [40, 255, 40];
+const OVERLAY_END_RGB: [u8; 3] = [40, 160, 255];
+
+/// Gray render of a sim heightmap scaled so its max is white (times `mul`).
+fn render_sim_gray(sim: &Lum16Im, mul: f32, out_rgba: &mut RGBAIm) {
+    debug_assert_eq!(out_rgba.w, sim.w);
+    debug_assert_eq!(out_rgba.h, sim.h);
+
+    let maxv = sim.arr.iter().copied().max().unwrap_or(0);
+    let gray: MaskIm = sim.map_values(|v| gray_auto_max(v as f32, maxv as f32, mul));
+    gray_to_rgba(&gray.to_packed().arr, out_rgba);
+}
+
+/// A sample scaled so `max` is white (times `mul`).
+fn gray_auto_max(v: f32, max: f32, mul: f32) -> u8 {
+    ((v / max.max(1.0)) * 255.0 * mul.max(0.0)).clamp(0.0, 255.0) as u8
+}
+
+/// Expand packed gray samples into an opaque RGBA image of the same size.
+fn gray_to_rgba(gray: &[u8], out_rgba: &mut RGBAIm) {
+    for (px, &g) in out_rgba.arr.chunks_exact_mut(4).zip(gray) {
+        px.copy_from_slice(&[g, g, g, 255]);
+    }
+}
+
+/// Simulate `toolpaths[..upto]` on a copy of `base` (recording their `cuts`).
+fn sim_upto(base: &Lum16Im, toolpaths: &mut [ToolPath], upto: usize) -> Lum16Im {
+    let mut sim = base.clone();
+    let n = upto.min(toolpaths.len());
+    if n > 0 {
+        let _ = crate::sim::sim_toolpaths(
+            &mut sim,
+            &mut toolpaths[..n],
+            None,
+            None,
+            crate::sim::SimOptions::default(),
+        );
+    }
+    sim
+}
+
+fn put_rgb(im: &mut RGBAIm, x: i32, y: i32, rgb: [u8; 3]) {
+    if x < 0 || y < 0 || x as usize >= im.w || y as usize >= im.h {
+        return;
+    }
+    let base = y as usize * im.s + x as usize * 4;
+    im.arr[base..base + 3].copy_from_slice(&rgb);
+    im.arr[base + 3] = 255;
+}
+
+fn draw_line_rgb(im: &mut RGBAIm, x0: i32, y0: i32, x1: i32, y1: i32, rgb: [u8; 3]) {
+    // Bresenham.
+    let dx = (x1 - x0).abs();
+    let dy = -(y1 - y0).abs();
+    let sx = if x0 < x1 { 1 } else { -1 };
+    let sy = if y0 < y1 { 1 } else { -1 };
+    let (mut x, mut y) = (x0, y0);
+    let mut err = dx + dy;
+    loop {
+        put_rgb(im, x, y, rgb);
+        if x == x1 && y == y1 {
+            break;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+}
+
+fn draw_dot_rgb(im: &mut RGBAIm, x: i32, y: i32, rgb: [u8; 3]) {
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            put_rgb(im, x + dx, y + dy, rgb);
+        }
+    }
+}
+
+/// Render what the toolpath movie shows at frame `upto`: the gray sim after applying
+/// `toolpaths[..upto]`, with the last applied toolpath drawn as a red polyline (yellow for
+/// traverses), a green start dot and a blue end dot. `upto == 0` is just the base.
+pub fn render_toolpaths_overlay(base: &Lum16Im, toolpaths: &[ToolPath], upto: usize) -> RGBAIm {
+    let upto = upto.min(toolpaths.len());
+    let sim = sim_upto(base, &mut toolpaths[..upto].to_vec(), upto);
+    let mut rgba = RGBAIm::new(sim.w, sim.h);
+    render_sim_gray(&sim, 1.0, &mut rgba);
+
+    let Some(tp) = upto.checked_sub(1).and_then(|i| toolpaths.get(i)) else {
+        return rgba;
+    };
+    if tp.points.len() < 2 {
+        return rgba;
+    }
+
+    let path_rgb = if tp.is_traverse {
+        OVERLAY_TRAVERSE_RGB
+    } else {
+        OVERLAY_PATH_RGB
+    };
+    for seg in tp.points.windows(2) {
+        draw_line_rgb(&mut rgba, seg[0].x, seg[0].y, seg[1].x, seg[1].y, path_rgb);
+    }
+    let start = tp.points[0];
+    let end = tp.points[tp.points.len() - 1];
+    draw_dot_rgb(&mut rgba, start.x, start.y, OVERLAY_START_RGB);
+    draw_dot_rgb(&mut rgba, end.x, end.y, OVERLAY_END_RGB);
+    rgba
+}
+
+/// The first cutting segment of `tp` whose tool footprint covers pixel (x, y), with its
+/// recorded cut. Used by the movie viewer to show the hovered segment's cut.
+#[cfg(all(feature = "debug_ui", not(feature = "cli_only")))]
+fn segment_cut_at(tp: &ToolPath, x: i32, y: i32) -> Option<(usize, crate::toolpath::CutPixels)> {
+    let radius_pix = tp.tool_dia_pix / 2;
+    tp.points.windows(2).enumerate().find_map(|(seg_i, seg)| {
+        let covers =
+            seg[0].z == seg[1].z && crate::sim::point_in_capsule(x, y, seg[0], seg[1], radius_pix);
+        covers.then(|| (seg_i, tp.cuts.get(seg_i).copied().unwrap_or_default()))
+    })
+}
 
 #[cfg(all(feature = "debug_ui", not(feature = "cli_only")))]
 mod imp {
@@ -14,6 +149,7 @@ mod imp {
     use crate::region_tree::{PlyIm, RegionIm};
     use crate::toolpath::ToolPath;
     use eframe::egui;
+    use std::path::Path;
     use std::sync::{Mutex, OnceLock};
 
     #[derive(Clone, Debug)]
@@ -81,35 +217,19 @@ mod imp {
                 }
 
                 (SourcePixels::U8_1 { arr, max }, VizMode::GrayAutoMax) => {
-                    let maxf = (*max as f32).max(1.0);
-                    let mul = params.mul.max(0.0);
-                    for y in 0..self.h {
-                        for x in 0..self.w {
-                            let v = arr[y * self.w + x] as f32;
-                            let scaled = ((v / maxf) * 255.0 * mul).clamp(0.0, 255.0) as u8;
-                            let base = (y * self.w + x) * 4;
-                            out_rgba.arr[base] = scaled;
-                            out_rgba.arr[base + 1] = scaled;
-                            out_rgba.arr[base + 2] = scaled;
-                            out_rgba.arr[base + 3] = 255;
-                        }
-                    }
+                    let gray: Vec<u8> = arr
+                        .iter()
+                        .map(|&v| super::gray_auto_max(v as f32, *max as f32, params.mul))
+                        .collect();
+                    super::gray_to_rgba(&gray, out_rgba);
                 }
 
                 (SourcePixels::U16_1 { arr, max }, VizMode::GrayAutoMax) => {
-                    let maxf = (*max as f32).max(1.0);
-                    let mul = params.mul.max(0.0);
-                    for y in 0..self.h {
-                        for x in 0..self.w {
-                            let v = arr[y * self.w + x] as f32;
-                            let scaled = ((v / maxf) * 255.0 * mul).clamp(0.0, 255.0) as u8;
-                            let base = (y * self.w + x) * 4;
-                            out_rgba.arr[base] = scaled;
-                            out_rgba.arr[base + 1] = scaled;
-                            out_rgba.arr[base + 2] = scaled;
-                            out_rgba.arr[base + 3] = 255;
-                        }
-                    }
+                    let gray: Vec<u8> = arr
+                        .iter()
+                        .map(|&v| super::gray_auto_max(v as f32, *max as f32, params.mul))
+                        .collect();
+                    super::gray_to_rgba(&gray, out_rgba);
                 }
 
                 _ => {
@@ -158,57 +278,15 @@ mod imp {
     }
 
     fn pack_u8_1<S>(im: &Im<u8, 1, S>) -> (Vec<u8>, u8) {
-        let mut out = vec![0u8; im.w * im.h];
-        let mut maxv = 0u8;
-        for y in 0..im.h {
-            for x in 0..im.w {
-                let v = unsafe { *im.get_unchecked(x, y, 0) };
-                maxv = maxv.max(v);
-                out[y * im.w + x] = v;
-            }
-        }
-        (out, maxv)
-    }
-
-    fn pack_u8_4<S>(im: &Im<u8, 4, S>) -> Vec<u8> {
-        let mut out = vec![0u8; im.w * im.h * 4];
-        for y in 0..im.h {
-            for x in 0..im.w {
-                let base = (y * im.w + x) * 4;
-                out[base] = unsafe { *im.get_unchecked(x, y, 0) };
-                out[base + 1] = unsafe { *im.get_unchecked(x, y, 1) };
-                out[base + 2] = unsafe { *im.get_unchecked(x, y, 2) };
-                out[base + 3] = unsafe { *im.get_unchecked(x, y, 3) };
-            }
-        }
-        out
+        let arr = im.to_packed().arr;
+        let maxv = arr.iter().copied().max().unwrap_or(0);
+        (arr, maxv)
     }
 
     fn pack_u16_1<S>(im: &Im<u16, 1, S>) -> (Vec<u16>, u16) {
-        let mut out = vec![0u16; im.w * im.h];
-        let mut maxv = 0u16;
-        for y in 0..im.h {
-            for x in 0..im.w {
-                let v = unsafe { *im.get_unchecked(x, y, 0) };
-                maxv = maxv.max(v);
-                out[y * im.w + x] = v;
-            }
-        }
-        (out, maxv)
-    }
-
-    fn pack_lum16(im: &Lum16Im) -> Lum16Im {
-        if im.s == im.w {
-            return im.clone();
-        }
-
-        let mut packed = Lum16Im::new(im.w, im.h);
-        for y in 0..im.h {
-            let row0 = y * im.s;
-            let row = &im.arr[row0..row0 + im.w];
-            packed.arr[y * packed.s..y * packed.s + im.w].copy_from_slice(row);
-        }
-        packed
+        let arr = im.to_packed().arr;
+        let maxv = arr.iter().copied().max().unwrap_or(0);
+        (arr, maxv)
     }
 
     // Public API (collector)
@@ -236,7 +314,7 @@ mod imp {
     }
 
     pub fn add_u8_4<S>(title: &str, im: &Im<u8, 4, S>) {
-        let arr = pack_u8_4(im);
+        let arr = im.to_packed().arr;
         let src = SourceIm {
             w: im.w,
             h: im.h,
@@ -344,11 +422,81 @@ mod imp {
         let mut g = global_state().lock().unwrap();
         g.items.push(DebugItemData::ToolpathMovie(DebugToolpathMovieData {
             title: title.to_owned(),
-            base: pack_lum16(base),
+            base: base.to_packed(),
             toolpaths: toolpaths.to_vec(),
         }));
     }
 
+    // Headless dump
+    // -------------------------------------------------------------------------
+
+    /// Title reduced to characters that are safe in a file name.
+    #[cfg(feature = "im-io")]
+    fn file_stem(title: &str) -> String {
+        title
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+
+    /// Write every collected item to `dir` as numbered PNGs (`000_<title>.png`, ...), rendered
+    /// the way the viewer first shows them. A toolpath movie becomes two files: the final
+    /// frame (`_final.png`) and that frame with every toolpath drawn over it (`_overlay.png`).
+    /// Items are left in place so a later `show()` still sees them.
+    #[cfg(feature = "im-io")]
+    pub fn dump_all(dir: &Path) {
+        let items = global_state().lock().unwrap().items.clone();
+
+        // Debugger ergonomics: if this fails, just panic.
+        std::fs::create_dir_all(dir).unwrap();
+        for (i, item) in items.iter().enumerate() {
+            match item {
+                DebugItemData::Image(data) => {
+                    let mut rgba = RGBAIm::new(data.src.w, data.src.h);
+                    let params = VizParams { mul: 1.0 };
+                    data.src
+                        .render_to_rgba8(data.src.default_mode(), params, &mut rgba);
+                    let path = dir.join(format!("{i:03}_{}.png", file_stem(&data.title)));
+                    rgba.save_png(path).unwrap();
+                }
+                DebugItemData::ToolpathMovie(data) => {
+                    let stem = format!("{i:03}_{}", file_stem(&data.title));
+                    let mut toolpaths = data.toolpaths.clone();
+                    let n = toolpaths.len();
+                    let sim = super::sim_upto(&data.base, &mut toolpaths, n);
+                    let mut rgba = RGBAIm::new(sim.w, sim.h);
+                    super::render_sim_gray(&sim, 1.0, &mut rgba);
+                    rgba.save_png(dir.join(format!("{stem}_final.png")))
+                        .unwrap();
+
+                    for tp in &toolpaths {
+                        let rgb = if tp.is_traverse {
+                            super::OVERLAY_TRAVERSE_RGB
+                        } else {
+                            super::OVERLAY_PATH_RGB
+                        };
+                        for seg in tp.points.windows(2) {
+                            super::draw_line_rgb(
+                                &mut rgba, seg[0].x, seg[0].y, seg[1].x, seg[1].y, rgb,
+                            );
+                        }
+                    }
+                    rgba.save_png(dir.join(format!("{stem}_overlay.png")))
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    #[cfg(not(feature = "im-io"))]
+    pub fn dump_all(_dir: &Path) {}
+
     // Legacy (single-window) APIs used by existing helpers
     // -------------------------------------------------------------------------
 
@@ -363,7 +511,7 @@ mod imp {
     }
 
     pub fn show_u8_4<S>(im: &Im<u8, 4, S>, title: &str) -> Result<(), String> {
-        let arr = pack_u8_4(im);
+        let arr = im.to_packed().arr;
         let src = SourceIm {
             w: im.w,
             h: im.h,
@@ -383,7 +531,7 @@ mod imp {
     }
 
     pub fn show_toolpath_movie(base: &Lum16Im, toolpaths: &[ToolPath], title: &str) -> Result<(), String> {
-        let base = pack_lum16(base);
+        let base = base.to_packed();
         let toolpaths = toolpaths.to_vec();
         run_single_movie(title, base, toolpaths)
     }
@@ -517,6 +665,10 @@ mod imp {
         }
     }
 
+    fn rgb32(rgb: [u8; 3]) -> egui::Color32 {
+        egui::Color32::from_rgb(rgb[0], rgb[1], rgb[2])
+    }
+
     fn monospace_wrap(ui: &mut egui::Ui, text: impl Into<String>) {
         ui.add(egui::Label::new(egui::RichText::new(text.into()).monospace()).wrap());
     }
@@ -839,32 +991,11 @@ mod imp {
         fn recompute_sim(&mut self) {
             debug_assert_eq!(self.base.w, self.sim.w);
             debug_assert_eq!(self.base.h, self.sim.h);
-            self.sim.arr.copy_from_slice(&self.base.arr);
-
-            if self.applied_count > 0 {
-                let n = self.applied_count.min(self.movie_toolpaths.len());
-                if n > 0 {
-                    crate::sim::sim_toolpaths(&mut self.sim, &mut self.movie_toolpaths[..n], None);
-                }
-            }
+            self.sim = super::sim_upto(&self.base, &mut self.movie_toolpaths, self.applied_count);
         }
 
         fn render_sim_to_rgba(&mut self) {
-            let maxv = self.sim.arr.iter().copied().max().unwrap_or(0);
-            let maxf = (maxv as f32).max(1.0);
-            let mul = self.params.mul.max(0.0);
-
-            for y in 0..self.sim.h {
-                for x in 0..self.sim.w {
-                    let v = self.sim.arr[y * self.sim.s + x] as f32;
-                    let scaled = ((v / maxf) * 255.0 * mul).clamp(0.0, 255.0) as u8;
-                    let base = (y * self.sim.w + x) * 4;
-                    self.rgba.arr[base] = scaled;
-                    self.rgba.arr[base + 1] = scaled;
-                    self.rgba.arr[base + 2] = scaled;
-                    self.rgba.arr[base + 3] = 255;
-                }
-            }
+            super::render_sim_gray(&self.sim, self.params.mul, &mut self.rgba);
         }
 
         fn render_if_needed(&mut self, ctx: &egui::Context) {
@@ -1099,15 +1230,15 @@ mod imp {
                             }
 
                             let stroke = if tp.is_traverse {
-                                egui::Stroke::new(1.5, egui::Color32::from_rgb(240, 200, 40))
+                                egui::Stroke::new(1.5, rgb32(super::OVERLAY_TRAVERSE_RGB))
                             } else {
-                                egui::Stroke::new(1.5, egui::Color32::from_rgb(255, 40, 40))
+                                egui::Stroke::new(1.5, rgb32(super::OVERLAY_PATH_RGB))
                             };
                             painter.add(egui::Shape::line(pts.clone(), stroke));
 
                             if let (Some(start), Some(end)) = (pts.first().copied(), pts.last().copied()) {
-                                painter.circle_filled(start, 3.0, egui::Color32::from_rgb(40, 255, 40));
-                                painter.circle_filled(end, 3.0, egui::Color32::from_rgb(40, 160, 255));
+                                painter.circle_filled(start, 3.0, rgb32(super::OVERLAY_START_RGB));
+                                painter.circle_filled(end, 3.0, rgb32(super::OVERLAY_END_RGB));
 
                                 // If a traverse toolpath changes Z at either endpoint (retract/plunge),
                                 // draw an open ring around the filled marker to indicate vertical motion.
@@ -1123,18 +1254,18 @@ mod imp {
                                     if start_dz != 0 {
                                         let c = if start_dz > 0 {
                                             // Z up (retract)
-                                            egui::Color32::from_rgb(40, 255, 40)
+                                            rgb32(super::OVERLAY_START_RGB)
                                         } else {
                                             // Z down (plunge)
-                                            egui::Color32::from_rgb(40, 160, 255)
+                                            rgb32(super::OVERLAY_END_RGB)
                                         };
                                         painter.circle_stroke(start, ring_r, egui::Stroke::new(ring_w, c));
                                     }
                                     if end_dz != 0 {
                                         let c = if end_dz > 0 {
-                                            egui::Color32::from_rgb(40, 255, 40)
+                                            rgb32(super::OVERLAY_START_RGB)
                                         } else {
-                                            egui::Color32::from_rgb(40, 160, 255)
+                                            rgb32(super::OVERLAY_END_RGB)
                                         };
                                         painter.circle_stroke(end, ring_r, egui::Stroke::new(ring_w, c));
                                     }
@@ -1155,6 +1286,17 @@ mod imp {
                         let src = self.src_text_at(x, y);
                         let viz = self.rgba_text_at(x, y);
                         self.hover_text = format!("x={x} y={y} {src} {viz}");
+
+                        let seg_cut = self
+                            .active_toolpath_index()
+                            .and_then(|i| self.movie_toolpaths.get(i))
+                            .and_then(|tp| super::segment_cut_at(tp, x as i32, y as i32));
+                        if let Some((seg_i, cut)) = seg_cut {
+                            self.hover_text += &format!(
+                                " seg={seg_i} seg_cut_pixels={} seg_depth_sum_thou={}",
+                                cut.pixels_changed, cut.depth_sum_thou
+                            );
+                        }
                     }
                 }
 
@@ -1227,18 +1369,18 @@ mod imp {
                                                     );
 
                                                     ui.separator();
-                                                    let mut cut_pixels: u64 = 0;
-                                                    let mut cut_depth_sum_thou: u64 = 0;
-                                                    for c in tp.cuts.iter() {
-                                                        cut_pixels += c.pixels_changed;
-                                                        cut_depth_sum_thou += c.depth_sum_thou;
-                                                    }
+                                                    let total_cut = tp.total_cut();
+                                                    let avg_depth = total_cut
+                                                        .avg_depth_thou()
+                                                        .map_or("-".to_string(), |d| format!("{d:.1}"));
                                                     monospace_wrap(
                                                         ui,
                                                         format!(
-                                                            "cut_pixels={} cut_depth_sum_thou={}"
+                                                            "cut_pixels={} cut_depth_sum_thou={} avg_depth_thou={}"
                                                             ,
-                                                            cut_pixels, cut_depth_sum_thou
+                                                            total_cut.pixels_changed,
+                                                            total_cut.depth_sum_thou,
+                                                            avg_depth
                                                         ),
                                                     );
                                                 }
@@ -1362,6 +1504,8 @@ mod imp {
 
     pub fn add_toolpath_movie(_title: &str, _base: &Lum16Im, _toolpaths: &[ToolPath]) {}
 
+    pub fn dump_all(_dir: &std::path::Path) {}
+
     pub fn show_u8_1<S>(_im: &Im<u8, 1, S>, _title: &str) -> Result<(), String> {
         Ok(())
     }
@@ -1380,3 +1524,123 @@ mod imp {
 }
 
 pub use imp::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toolpath::{CutPixels, IV3};
+
+    #[test]
+    fn render_toolpaths_overlay_marks_active_path() {
+        let mut base = Lum16Im::new(12, 8);
+        base.arr.fill(1000);
+        let toolpaths = vec![ToolPath {
+            points: vec![IV3 { x: 2, y: 4, z: 500 }, IV3 { x: 9, y: 4, z: 500 }],
+            closed: false,
+            tool_dia_pix: 1,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![CutPixels::default(); 2],
+            is_traverse: false,
+            is_raster: true,
+            is_plunge_run: false,
+        }];
+
+        let rgb_at = |im: &RGBAIm, x: usize, y: usize| {
+            let i = y * im.s + x * 4;
+            [im.arr[i], im.arr[i + 1], im.arr[i + 2]]
+        };
+
+        // Frame 0 is just the (uniform) base.
+        let rgba = render_toolpaths_overlay(&base, &toolpaths, 0);
+        assert_eq!(rgb_at(&rgba, 5, 4), [255, 255, 255]);
+
+        let rgba = render_toolpaths_overlay(&base, &toolpaths, 1);
+        assert_eq!(rgb_at(&rgba, 5, 4), OVERLAY_PATH_RGB);
+        assert_eq!(rgb_at(&rgba, 2, 4), OVERLAY_START_RGB);
+        assert_eq!(rgb_at(&rgba, 9, 4), OVERLAY_END_RGB);
+        // Off the path the sim shows through unchanged.
+        assert_eq!(rgb_at(&rgba, 5, 0), [255, 255, 255]);
+    }
+
+    #[cfg(all(feature = "debug_ui", not(feature = "cli_only"), feature = "im-io"))]
+    #[test]
+    fn dump_all_writes_numbered_pngs() {
+        let dir = std::env::temp_dir().join(format!("rcarve_dump_all_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut mask = crate::im::MaskIm::new(4, 3);
+        mask.arr[1] = 255;
+        let mut base = Lum16Im::new(12, 8);
+        base.arr.fill(1000);
+        let toolpaths = vec![ToolPath {
+            points: vec![IV3 { x: 2, y: 4, z: 500 }, IV3 { x: 9, y: 4, z: 500 }],
+            closed: false,
+            tool_dia_pix: 1,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![CutPixels::default(); 2],
+            is_traverse: false,
+            is_raster: true,
+            is_plunge_run: false,
+        }];
+
+        init("dump test");
+        add_mask_im("a mask", &mask);
+        add_toolpath_movie("movie", &base, &toolpaths);
+        dump_all(&dir);
+
+        let mask_png = image::open(dir.join("000_a_mask.png"))
+            .unwrap()
+            .into_rgba8();
+        assert_eq!((mask_png.width(), mask_png.height()), (4, 3));
+        assert_eq!(mask_png.get_pixel(1, 0).0, [255, 255, 255, 255]);
+        assert_eq!(mask_png.get_pixel(0, 0).0, [0, 0, 0, 255]);
+
+        let final_png = image::open(dir.join("001_movie_final.png"))
+            .unwrap()
+            .into_rgba8();
+        let overlay_png = image::open(dir.join("001_movie_overlay.png"))
+            .unwrap()
+            .into_rgba8();
+        // The cut shows darker than the untouched base; the overlay draws the path on top.
+        assert!(final_png.get_pixel(5, 4).0[0] < final_png.get_pixel(5, 0).0[0]);
+        assert_eq!(overlay_png.get_pixel(5, 4).0[..3], OVERLAY_PATH_RGB);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(all(feature = "debug_ui", not(feature = "cli_only")))]
+    #[test]
+    fn segment_cut_at_finds_the_hovered_segment() {
+        let v = |x: i32, y: i32| IV3 { x, y, z: 500 };
+        let cut = |pixels_changed: u64| CutPixels {
+            pixels_changed,
+            ..Default::default()
+        };
+        let tp = ToolPath {
+            points: vec![v(2, 2), v(10, 2), v(10, 9)],
+            closed: false,
+            tool_dia_pix: 3,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![cut(7), cut(11), CutPixels::default()],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+
+        assert_eq!(
+            segment_cut_at(&tp, 5, 3).map(|(i, c)| (i, c.pixels_changed)),
+            Some((0, 7))
+        );
+        assert_eq!(
+            segment_cut_at(&tp, 11, 6).map(|(i, c)| (i, c.pixels_changed)),
+            Some((1, 11))
+        );
+        assert!(segment_cut_at(&tp, 5, 6).is_none());
+    }
+}
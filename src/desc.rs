@@ -1,10 +1,10 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::collections::HashMap;
 
 macro_rules! transparent_newtype {
     ($name:ident($inner:ty)) => {
-        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
         #[serde(transparent)]
         pub struct $name(pub $inner);
     };
@@ -12,13 +12,70 @@ macro_rules! transparent_newtype {
 
 macro_rules! transparent_newtype_copy {
     ($name:ident($inner:ty)) => {
-        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+        #[derive(
+            Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+        )]
         #[serde(transparent)]
         pub struct $name(pub $inner);
     };
 }
 
 transparent_newtype_copy!(Thou(i32));
+impl Thou {
+    /// Apply a signed delta (e.g. a pride, which may be negative to cut deeper),
+    /// clamping the result to what a `Lum16Im` can hold: `[0, u16::MAX]`.
+    pub fn offset_clamped(self, delta: Thou) -> Thou {
+        Thou(self.0.saturating_add(delta.0).clamp(0, u16::MAX as i32))
+    }
+
+    /// The value as a heightmap sample, clamped to `[0, u16::MAX]`.
+    pub fn to_u16_clamped(self) -> u16 {
+        self.0.clamp(0, u16::MAX as i32) as u16
+    }
+
+    /// Whether this height falls in `range` (see `ThouRange::contains`).
+    pub fn in_band(self, range: ThouRange) -> bool {
+        range.contains(self)
+    }
+}
+
+// `min`, `max` and `clamp` come from the derived `Ord`.
+impl std::ops::Add for Thou {
+    type Output = Thou;
+    fn add(self, rhs: Thou) -> Thou {
+        Thou(self.0 + rhs.0)
+    }
+}
+
+impl std::ops::Sub for Thou {
+    type Output = Thou;
+    fn sub(self, rhs: Thou) -> Thou {
+        Thou(self.0 - rhs.0)
+    }
+}
+
+/// A band of heights: `top` is exclusive and `bot` is inclusive, so adjacent bands sharing
+/// a boundary never both claim a ply sitting exactly on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThouRange {
+    pub top: Thou, // Exclusive
+    pub bot: Thou, // Inclusive
+}
+
+impl ThouRange {
+    pub fn new(top: Thou, bot: Thou) -> Self {
+        Self { top, bot }
+    }
+
+    /// `bot <= t < top`.
+    pub fn contains(&self, t: Thou) -> bool {
+        self.bot <= t && t < self.top
+    }
+
+    pub fn depth(&self) -> Thou {
+        self.top - self.bot
+    }
+}
 
 transparent_newtype!(Guid(String));
 impl fmt::Display for Guid {
@@ -52,18 +109,88 @@ pub struct CompDesc {
     pub layer_desc_by_guid: HashMap<Guid, LayerDesc>,
     #[serde(default)]
     pub tool_descs: Vec<ToolDesc>,
+    /// Tools keyed by guid, an alternative to `tool_descs`; see `CompDesc::tool`.
+    #[serde(default, deserialize_with = "deserialize_tools_by_guid")]
+    pub tools_by_guid: HashMap<Guid, ToolDesc>,
     pub carve_desc: CarveDesc,
     #[serde(default)]
     pub bands: Vec<BandDesc>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "ToolDescRaw")]
 pub struct ToolDesc {
     pub guid: Guid,
     pub units: Units,
     pub kind: String,
     pub diameter: f64,
     pub length: f64,
+    pub feed_ipm: Option<f64>,
+    pub rpm: Option<f64>,
+    pub max_doc_inch: Option<f64>,
+}
+
+impl ToolDesc {
+    pub fn dia_inch(&self) -> f64 {
+        match self.units {
+            Units::Inch => self.diameter,
+            Units::Mm => self.diameter / 25.4,
+        }
+    }
+}
+
+/// A tool is either `{ guid, units, kind, diameter, length }` (in `tool_descs`) or
+/// `{ dia_inch, kind, feed_ipm, rpm, max_doc_inch }` (in `tools_by_guid`, keyed by guid).
+#[derive(Debug, Clone, Deserialize)]
+struct ToolDescRaw {
+    guid: Option<Guid>,
+    units: Option<Units>,
+    kind: String,
+    diameter: Option<f64>,
+    dia_inch: Option<f64>,
+    #[serde(default)]
+    length: f64,
+    feed_ipm: Option<f64>,
+    rpm: Option<f64>,
+    max_doc_inch: Option<f64>,
+}
+
+impl TryFrom<ToolDescRaw> for ToolDesc {
+    type Error = String;
+
+    fn try_from(raw: ToolDescRaw) -> Result<Self, Self::Error> {
+        let (units, diameter) = match (raw.dia_inch, raw.diameter, raw.units) {
+            (Some(dia_inch), None, _) => (Units::Inch, dia_inch),
+            (None, Some(diameter), Some(units)) => (units, diameter),
+            (None, Some(_), None) => return Err("tool `diameter` needs `units`".to_string()),
+            (Some(_), Some(_), _) => {
+                return Err("tool has both `dia_inch` and `diameter`".to_string());
+            }
+            (None, None, _) => return Err("tool needs `dia_inch` or `diameter`".to_string()),
+        };
+        Ok(Self {
+            // `tools_by_guid` entries get their guid from the map key.
+            guid: raw.guid.unwrap_or_else(|| Guid(String::new())),
+            units,
+            kind: raw.kind,
+            diameter,
+            length: raw.length,
+            feed_ipm: raw.feed_ipm,
+            rpm: raw.rpm,
+            max_doc_inch: raw.max_doc_inch,
+        })
+    }
+}
+
+fn deserialize_tools_by_guid<'de, D>(deserializer: D) -> Result<HashMap<Guid, ToolDesc>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let mut tools = HashMap::<Guid, ToolDesc>::deserialize(deserializer)?;
+    for (guid, tool) in tools.iter_mut() {
+        tool.guid = guid.clone();
+    }
+    Ok(tools)
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -150,11 +277,65 @@ pub struct CarveDesc {
     pub detail_tool_guid: Option<Guid>,
 }
 
+/// What gets cut for the nodes of a band, see `create_toolpaths_from_region_tree`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClearMode {
+    /// The surface clearing (when the pass generates surfaces) and the perimeters.
+    #[default]
+    Full,
+    /// Only the perimeters, e.g. for thin outline plies where a surface clear is wasted.
+    PerimeterOnly,
+    /// Only the surface clearing, no perimeters.
+    RasterOnly,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "BandDescRaw")]
 pub struct BandDesc {
     pub top_thou: Thou,
     pub bot_thou: Thou,
+    /// The pass this band belongs to ("rough", "refine", ...).
     pub cut_pass: String,
+    pub clear_mode: ClearMode,
+}
+
+/// A band as written in the JSON, before checking that it has a positive height.
+#[derive(Debug, Clone, Deserialize)]
+struct BandDescRaw {
+    top_thou: Thou,
+    bot_thou: Thou,
+    /// Older JSON calls this `which`.
+    #[serde(alias = "which")]
+    cut_pass: String,
+    #[serde(default)]
+    clear_mode: ClearMode,
+}
+
+impl TryFrom<BandDescRaw> for BandDesc {
+    type Error = String;
+
+    fn try_from(raw: BandDescRaw) -> Result<Self, Self::Error> {
+        // An inverted or flat band holds no plies; most likely a typo.
+        if raw.top_thou <= raw.bot_thou {
+            return Err(format!(
+                "band top_thou {} <= bot_thou {}; bands need a positive height",
+                raw.top_thou.0, raw.bot_thou.0
+            ));
+        }
+        Ok(Self {
+            top_thou: raw.top_thou,
+            bot_thou: raw.bot_thou,
+            cut_pass: raw.cut_pass,
+            clear_mode: raw.clear_mode,
+        })
+    }
+}
+
+impl BandDesc {
+    pub fn range(&self) -> ThouRange {
+        ThouRange::new(self.top_thou, self.bot_thou)
+    }
 }
 
 pub fn parse_comp_json(json_text: &str) -> Result<CompDesc, serde_json::Error> {
@@ -162,6 +343,35 @@ pub fn parse_comp_json(json_text: &str) -> Result<CompDesc, serde_json::Error> {
 }
 
 impl CompDesc {
+    /// The tool with `guid` from `tools_by_guid`, else from `tool_descs`, so e.g.
+    /// `carve_desc.rough_tool_guid` resolves to the tool's geometry and feeds.
+    pub fn tool(&self, guid: &Guid) -> Option<&ToolDesc> {
+        self.tools_by_guid
+            .get(guid)
+            .or_else(|| self.tool_descs.iter().find(|td| &td.guid == guid))
+    }
+
+    /// Every tool indexed by `tool_i`: `tool_descs` in order, then the `tools_by_guid`
+    /// entries in guid order, so the numbering is stable across loads. A guid in both is
+    /// listed once, as the `tools_by_guid` entry `tool` resolves it to.
+    pub fn tools(&self) -> Vec<&ToolDesc> {
+        let mut by_guid: Vec<&ToolDesc> = self.tools_by_guid.values().collect();
+        by_guid.sort_by(|a, b| a.guid.0.cmp(&b.guid.0));
+        self.tool_descs
+            .iter()
+            .filter(|td| !self.tools_by_guid.contains_key(&td.guid))
+            .chain(by_guid)
+            .collect()
+    }
+
+    /// The `tool_i` of the tool `guid` resolves to (see `CompDesc::tools`) and the tool.
+    pub fn tool_i(&self, guid: &Guid) -> Option<(usize, &ToolDesc)> {
+        self.tools()
+            .into_iter()
+            .enumerate()
+            .find(|(_, td)| &td.guid == guid)
+    }
+
     /// Applies an axis-aligned affine transform to every `mpoly` in every `PlyDesc`.
     ///
     /// The transform is applied as: `x' = round(x * sx) + dx`, `y' = round(y * sy) + dy`.
@@ -235,6 +445,34 @@ pub fn polydesc_to_mpoly(polydesc: &PolyDesc, ply_xform: &Mat3) -> MPoly {
 mod tests {
     use super::*;
 
+    #[test]
+    fn thou_offset_clamped_handles_signed_deltas() {
+        assert_eq!(Thou(500).offset_clamped(Thou(20)), Thou(520));
+        assert_eq!(Thou(500).offset_clamped(Thou(-20)), Thou(480));
+        assert_eq!(Thou(10).offset_clamped(Thou(-20)), Thou(0));
+        assert_eq!(Thou(65_530).offset_clamped(Thou(20)), Thou(u16::MAX as i32));
+        assert_eq!(Thou(-5).to_u16_clamped(), 0);
+        assert_eq!(Thou(70_000).to_u16_clamped(), u16::MAX);
+    }
+
+    #[test]
+    fn thou_range_is_bottom_inclusive_top_exclusive() {
+        assert_eq!(Thou(500) + Thou(20), Thou(520));
+        assert_eq!(Thou(500) - Thou(520), Thou(-20));
+        assert_eq!(Thou(700).clamp(Thou(0), Thou(650)), Thou(650));
+
+        let upper = ThouRange::new(Thou(1000), Thou(650));
+        let lower = ThouRange::new(Thou(650), Thou(0));
+        assert_eq!(upper.depth(), Thou(350));
+        assert!(upper.contains(Thou(999)));
+        assert!(!upper.contains(Thou(1000)));
+        // The shared boundary belongs to the upper band only.
+        assert!(upper.contains(Thou(650)));
+        assert!(!lower.contains(Thou(650)));
+        assert!(Thou(0).in_band(lower));
+        assert!(!Thou(-1).in_band(lower));
+    }
+
     #[test]
     fn mpolys_scale_from_ply_mat_normalized_units_into_pixels() {
         // JSON vertices are in 0..500 units. `ply_mat` scales by 0.002, so coordinates become 0..1
@@ -304,6 +542,94 @@ mod tests {
         assert_eq!(pts, vec![(260, 260), (740, 260), (740, 740), (260, 740)]);
     }
 
+    #[test]
+    fn band_desc_accepts_which_as_cut_pass() {
+        let band: BandDesc =
+            serde_json::from_str(r#"{ "top_thou": 400, "bot_thou": 200, "which": "refine" }"#)
+                .expect("band with `which` should deserialize");
+        assert_eq!(band.cut_pass, "refine");
+    }
+
+    #[test]
+    fn band_desc_rejects_inverted_and_flat_bands() {
+        for json in [
+            r#"{ "top_thou": 200, "bot_thou": 400, "cut_pass": "rough" }"#,
+            r#"{ "top_thou": 300, "bot_thou": 300, "cut_pass": "rough" }"#,
+        ] {
+            let err = serde_json::from_str::<BandDesc>(json).unwrap_err();
+            assert!(
+                err.to_string().contains("bands need a positive height"),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn comp_desc_resolves_tools_by_guid() {
+        let sample = r#"
+        {
+            "version": 3,
+            "guid": "G",
+            "dim_desc": {
+                "bulk_d_inch": 1.0,
+                "bulk_w_inch": 4.0,
+                "bulk_h_inch": 4.0,
+                "padding_inch": 0.0,
+                "frame_inch": 0.5
+            },
+            "ply_desc_by_guid": {},
+            "layer_desc_by_guid": {},
+            "tool_descs": [
+                { "guid": "T6", "units": "mm", "kind": "flat", "diameter": 6.35, "length": 20 }
+            ],
+            "tools_by_guid": {
+                "T1": {
+                    "dia_inch": 0.25,
+                    "kind": "ball",
+                    "feed_ipm": 80,
+                    "rpm": 18000,
+                    "max_doc_inch": 0.125
+                }
+            },
+            "carve_desc": {
+                "grain_y": true,
+                "rough_tool_guid": "T1",
+                "refine_tool_guid": "T6",
+                "detail_tool_guid": null
+            }
+        }
+        "#;
+
+        let comp = parse_comp_json(sample).expect("sample json should deserialize");
+        let rough = comp
+            .tool(comp.carve_desc.rough_tool_guid.as_ref().unwrap())
+            .expect("rough tool should resolve");
+        assert_eq!(rough.guid, Guid("T1".to_string()));
+        assert_eq!(rough.kind, "ball");
+        assert_eq!(rough.dia_inch(), 0.25);
+        assert_eq!(rough.feed_ipm, Some(80.0));
+        assert_eq!(rough.rpm, Some(18000.0));
+        assert_eq!(rough.max_doc_inch, Some(0.125));
+
+        // Tools in `tool_descs` resolve too, without feeds.
+        let refine = comp.tool(&Guid("T6".to_string())).unwrap();
+        assert!((refine.dia_inch() - 0.25).abs() < 1e-12);
+        assert_eq!(refine.feed_ipm, None);
+        assert!(comp.tool(&Guid("missing".to_string())).is_none());
+
+        // `tools_by_guid` entries are numbered after `tool_descs`.
+        assert_eq!(comp.tools().len(), 2);
+        let (rough_i, _) = comp.tool_i(&Guid("T1".to_string())).unwrap();
+        let (refine_i, _) = comp.tool_i(&Guid("T6".to_string())).unwrap();
+        assert_eq!((refine_i, rough_i), (0, 1));
+        assert_eq!(comp.tools()[rough_i].feed_ipm, Some(80.0));
+        assert!(comp.tool_i(&Guid("missing".to_string())).is_none());
+
+        // A tool needs a diameter.
+        let err = serde_json::from_str::<ToolDesc>(r#"{ "kind": "flat" }"#).unwrap_err();
+        assert!(err.to_string().contains("needs `dia_inch` or `diameter`"));
+    }
+
     #[test]
     fn comp_desc_deserializes_sample_json() {
         let sample = r#"
@@ -419,6 +745,7 @@ mod tests {
         assert_eq!(comp.version, 2);
         assert_eq!(comp.guid, Guid("JGYYJQBHTX".to_string()));
         assert!(comp.carve_desc.detail_tool_guid.is_none());
+        assert!(comp.tools_by_guid.is_empty());
         assert_eq!(comp.ply_desc_by_guid.len(), 3);
 
         assert_eq!(comp.bands.len(), 3);
@@ -429,16 +756,19 @@ mod tests {
                     top_thou: Thou(1000),
                     bot_thou: Thou(900),
                     cut_pass: "refine".to_string(),
+                    clear_mode: ClearMode::Full,
                 },
                 BandDesc {
                     top_thou: Thou(900),
                     bot_thou: Thou(800),
                     cut_pass: "refine".to_string(),
+                    clear_mode: ClearMode::Full,
                 },
                 BandDesc {
                     top_thou: Thou(1000),
                     bot_thou: Thou(200),
                     cut_pass: "rough".to_string(),
+                    clear_mode: ClearMode::Full,
                 }
             ]
         );
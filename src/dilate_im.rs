@@ -1,4 +1,5 @@
-use crate::im::MaskIm;
+use crate::im::{Im, MaskIm};
+use std::collections::VecDeque;
 
 // -----------------------------------------------------------------------------
 // Window-based dilation op (like WinDilationOp)
@@ -6,7 +7,10 @@ use crate::im::MaskIm;
 #[derive(Clone, Debug)]
 struct WinDilationOp {
     dia_pix: usize,
+    im_pitch: usize,
     offsets: Vec<isize>,
+    // The same disk as (dx, dy), for the bounds-checked edges where flat offsets would wrap rows.
+    offsets_xy: Vec<(isize, isize)>,
 }
 
 impl WinDilationOp {
@@ -18,19 +22,23 @@ impl WinDilationOp {
 
         // Upper bound (square), we’ll shrink by only pushing points in the disk.
         let mut offsets = Vec::with_capacity((dia_pix + 1) * (dia_pix + 1));
+        let mut offsets_xy = Vec::with_capacity((dia_pix + 1) * (dia_pix + 1));
 
         let pitch = im_pitch as isize;
         for y in -r_pix..=r_pix {
             for x in -r_pix..=r_pix {
                 if x * x + y * y <= r2_pix {
                     offsets.push(y * pitch + x);
+                    offsets_xy.push((x, y));
                 }
             }
         }
 
         Self {
             dia_pix,
+            im_pitch,
             offsets,
+            offsets_xy,
         }
     }
 }
@@ -47,7 +55,6 @@ fn im_dilate_win_with_op(src: &MaskIm, dst: &mut MaskIm, op: &WinDilationOp) {
 
     let w = src.w;
     let h = src.h;
-    let wh = w * h;
 
     let src_arr: &[u8] = &src.arr;
     let dst_arr: &mut [u8] = &mut dst.arr;
@@ -80,7 +87,7 @@ fn im_dilate_win_with_op(src: &MaskIm, dst: &mut MaskIm, op: &WinDilationOp) {
                 if src_arr[curr] == 0 {
                     let mut found = false;
                     for &off in &op.offsets {
-                        // curr is in [0,wh), and core margin ensures neighbor is in-bounds.
+                        // curr is in [0,w*h), and core margin ensures neighbor is in-bounds.
                         let k = (curr as isize + off) as usize;
                         if src_arr[k] > 0 {
                             found = true;
@@ -167,21 +174,21 @@ fn im_dilate_win_with_op(src: &MaskIm, dst: &mut MaskIm, op: &WinDilationOp) {
         for y in t0e..b0e {
             let mut curr = idx(w, l0e, y);
             for x in l0e..r0e {
-                let _ = x; // just for symmetry with C; curr drives index
                 if src_arr[curr] == 0 {
                     let mut found = false;
-                    for &off in &op.offsets {
-                        let k_signed = curr as isize + off;
-                        if k_signed < 0 || k_signed >= wh as isize {
-                            continue;
-                        }
-                        let k = k_signed as usize;
-                        let kx = k % w;
-                        let ky = k / w;
-
-                        if kx < l1e || kx >= r1e || ky < t1e || ky >= b1e {
+                    // Offset in x/y (not flat) so a neighbor past the left/right edge can't wrap
+                    // onto the adjacent row.
+                    for &(dx, dy) in &op.offsets_xy {
+                        let kx = x as isize + dx;
+                        let ky = y as isize + dy;
+                        if kx < l1e as isize
+                            || kx >= r1e as isize
+                            || ky < t1e as isize
+                            || ky >= b1e as isize
+                        {
                             continue;
                         }
+                        let k = idx(w, kx as usize, ky as usize);
                         if src_arr[k] > 0 {
                             found = true;
                             break;
@@ -201,13 +208,18 @@ fn im_dilate_win_with_op(src: &MaskIm, dst: &mut MaskIm, op: &WinDilationOp) {
     }
 }
 
-fn im_dilate_win(src: &MaskIm, dst: &mut MaskIm, dia_pix: usize) {
+fn im_dilate_win(src: &MaskIm, dst: &mut MaskIm, dia_pix: usize, scratch: &mut DilateScratch) {
     assert_eq!(src.w, dst.w);
     assert_eq!(src.h, dst.h);
     assert!(dia_pix > 0 && dia_pix <= src.w && dia_pix <= src.h);
 
-    let op = WinDilationOp::new(dia_pix, src.w);
+    // The offsets only depend on the diameter and pitch, so keep the last op around.
+    let op = match scratch.win_op.take() {
+        Some(op) if op.dia_pix == dia_pix && op.im_pitch == src.w => op,
+        _ => WinDilationOp::new(dia_pix, src.w),
+    };
     im_dilate_win_with_op(src, dst, &op);
+    scratch.win_op = Some(op);
 }
 
 // -----------------------------------------------------------------------------
@@ -263,7 +275,7 @@ fn edt_1d(f_in: &[i32], d_out: &mut [i32], v: &mut [usize], z: &mut [i32]) {
     }
 }
 
-fn im_dilate_edt(src: &MaskIm, dst: &mut MaskIm, dia_pix: usize) {
+fn im_dilate_edt(src: &MaskIm, dst: &mut MaskIm, dia_pix: usize, scratch: &mut DilateScratch) {
     assert_eq!(src.w, dst.w);
     assert_eq!(src.h, dst.h);
     assert!(dia_pix <= src.w && dia_pix <= src.h);
@@ -278,16 +290,50 @@ fn im_dilate_edt(src: &MaskIm, dst: &mut MaskIm, dia_pix: usize) {
     let dst_arr: &mut [u8] = &mut dst.arr;
 
     // dt: 0 where src!=0 else inf
-    let mut dt = vec![0i32; wh];
+    let dt = &mut scratch.dt;
+    dt.clear();
+    dt.extend(src_arr[..wh].iter().map(|&v| if v != 0 { 0 } else { inf }));
+
+    edt_sq_2d_with(dt, w, h, &mut scratch.edt);
+
+    let radius = (dia_pix / 2) as i32;
+    let radius_sq = radius * radius;
+
     for i in 0..wh {
-        dt[i] = if src_arr[i] != 0 { 0 } else { inf };
+        dst_arr[i] = if dt[i] <= radius_sq { 255 } else { 0 };
     }
+}
+
+/// 1D buffers for `edt_sq_2d`, sized for the longer side of the grid.
+#[derive(Default)]
+struct EdtScratch {
+    scratch_in: Vec<i32>,
+    scratch_out: Vec<i32>,
+    v: Vec<usize>,
+    z: Vec<i32>,
+}
+
+/// In-place 2D squared EDT of a w*h grid holding 0 at seeds and "inf" elsewhere.
+fn edt_sq_2d(dt: &mut [i32], w: usize, h: usize) {
+    edt_sq_2d_with(dt, w, h, &mut EdtScratch::default());
+}
+
+fn edt_sq_2d_with(dt: &mut [i32], w: usize, h: usize, scratch: &mut EdtScratch) {
+    assert_eq!(dt.len(), w * h);
 
     let scratch_len = w.max(h);
-    let mut scratch_in = vec![0i32; scratch_len];
-    let mut scratch_out = vec![0i32; scratch_len];
-    let mut v = vec![0usize; scratch_len];
-    let mut z = vec![0i32; scratch_len + 1];
+    if scratch.z.len() < scratch_len + 1 {
+        scratch.scratch_in.resize(scratch_len, 0);
+        scratch.scratch_out.resize(scratch_len, 0);
+        scratch.v.resize(scratch_len, 0);
+        scratch.z.resize(scratch_len + 1, 0);
+    }
+    let EdtScratch {
+        scratch_in,
+        scratch_out,
+        v,
+        z,
+    } = scratch;
 
     // horizontal pass
     for y in 0..h {
@@ -307,19 +353,170 @@ fn im_dilate_edt(src: &MaskIm, dst: &mut MaskIm, dia_pix: usize) {
             dt[y * w + x] = scratch_out[y];
         }
     }
+}
 
-    let radius = (dia_pix / 2) as i32;
-    let radius_sq = radius * radius;
+// -----------------------------------------------------------------------------
+// Distance transform
+// -----------------------------------------------------------------------------
 
-    for i in 0..wh {
-        dst_arr[i] = if dt[i] <= radius_sq { 255 } else { 0 };
+/// For each set pixel, the exact Euclidean distance (rounded to the nearest pixel) to the
+/// nearest unset pixel; unset pixels are 0. Pixels outside the image count as unset,
+/// so a set pixel on the image edge is 1.
+///
+/// Uses the same Felzenszwalb & Huttenlocher squared EDT as the dilation above.
+pub fn distance_transform(mask: &MaskIm) -> Im<u16, 1> {
+    let w = mask.w;
+    let h = mask.h;
+    let mut dist_im = Im::<u16, 1>::new(w, h);
+    if w == 0 || h == 0 {
+        return dist_im;
+    }
+
+    let inf: i32 = i32::MAX / 4;
+
+    // Pad by one unset pixel on every side so the image edge acts as a wall.
+    let pw = w + 2;
+    let ph = h + 2;
+    let mut dt = vec![0i32; pw * ph];
+    for y in 0..h {
+        for x in 0..w {
+            if mask.arr[y * mask.s + x] != 0 {
+                dt[(y + 1) * pw + (x + 1)] = inf;
+            }
+        }
+    }
+
+    edt_sq_2d(&mut dt, pw, ph);
+
+    for y in 0..h {
+        for x in 0..w {
+            let d = (dt[(y + 1) * pw + (x + 1)] as f64).sqrt().round();
+            dist_im.arr[y * dist_im.s + x] = d.min(u16::MAX as f64) as u16;
+        }
+    }
+    dist_im
+}
+
+// -----------------------------------------------------------------------------
+// Box dilation (separable sliding-window max)
+// -----------------------------------------------------------------------------
+/// `out[i] = max(src[i-r..=i+r])` (clipped to the ends) using a monotonic deque, O(n) in `r`.
+fn sliding_max_1d(src: &[u8], out: &mut [u8], r: usize, deque: &mut VecDeque<usize>) {
+    let n = src.len();
+    assert_eq!(out.len(), n);
+    deque.clear();
+
+    // `j` is the index entering the window; the window centered on `i = j - r` is complete.
+    for j in 0..n + r {
+        if j < n {
+            while deque.back().is_some_and(|&b| src[b] <= src[j]) {
+                deque.pop_back();
+            }
+            deque.push_back(j);
+        }
+        if j >= r {
+            let i = j - r;
+            while deque.front().is_some_and(|&f| f + r < i) {
+                deque.pop_front();
+            }
+            out[i] = src[*deque.front().unwrap()];
+        }
+    }
+}
+
+/// Dilate with a square structuring element of side `2 * (dia_pix / 2) + 1` (the same radius
+/// convention as `im_dilate`). Separable: a horizontal then a vertical sliding-window max, so the
+/// cost is O(pixels) regardless of the diameter.
+/// Faster than `im_dilate_disk` for large diameters but over-reaches in the diagonals.
+pub fn im_dilate_box(src: &MaskIm, dst: &mut MaskIm, dia_pix: usize) {
+    assert_eq!(src.w, dst.w);
+    assert_eq!(src.h, dst.h);
+    assert!(dia_pix <= src.w && dia_pix <= src.h);
+
+    let w = src.w;
+    let h = src.h;
+    let r = dia_pix / 2;
+    let mut deque: VecDeque<usize> = VecDeque::with_capacity(2 * r + 2);
+
+    // Horizontal pass: src rows -> dst rows.
+    for y in 0..h {
+        let src_row = &src.arr[y * src.s..y * src.s + w];
+        let dst_row = &mut dst.arr[y * dst.s..y * dst.s + w];
+        sliding_max_1d(src_row, dst_row, r, &mut deque);
+    }
+
+    // Vertical pass in place on dst, one column at a time.
+    let mut col_in = vec![0u8; h];
+    let mut col_out = vec![0u8; h];
+    for x in 0..w {
+        for (y, v) in col_in.iter_mut().enumerate() {
+            *v = dst.arr[y * dst.s + x];
+        }
+        sliding_max_1d(&col_in, &mut col_out, r, &mut deque);
+        for (y, &v) in col_out.iter().enumerate() {
+            dst.arr[y * dst.s + x] = v;
+        }
     }
 }
 
 // -----------------------------------------------------------------------------
 // Tuned method selection (your crossover table + dia<2 copy)
 // -----------------------------------------------------------------------------
+/// Dilate with a disk of radius `dia_pix / 2`. The disk matches the tool footprint; see
+/// `im_dilate_box` for a faster square approximation.
+///
+/// This is the low-level diameter primitive: even diameters round down to the odd one below,
+/// so `dia_pix` 4 and 5 give the same disk. Planning code thinks in radii and should call
+/// `im_dilate_radius` instead of converting by hand.
 pub fn im_dilate(src: &MaskIm, dst: &mut MaskIm, dia_pix: usize) {
+    im_dilate_disk(src, dst, dia_pix);
+}
+
+/// Disk dilation, picking the window or EDT method by the tuned crossover table.
+pub fn im_dilate_disk(src: &MaskIm, dst: &mut MaskIm, dia_pix: usize) {
+    im_dilate_with_scratch(src, dst, dia_pix, &mut DilateScratch::default());
+}
+
+/// Dilate with the disk of pixels within `radius_pix` of a set pixel (`dx*dx + dy*dy <= r*r`).
+/// Radius 0 is a copy and every +1 grows the disk. The disk is capped at the largest the image
+/// allows (diameter `min(w, h)`), which the diameter primitive requires.
+pub fn im_dilate_radius(src: &MaskIm, dst: &mut MaskIm, radius_pix: usize) {
+    im_dilate_radius_with_scratch(src, dst, radius_pix, &mut DilateScratch::default());
+}
+
+/// `im_dilate_radius` reusing `scratch`. The output is identical.
+pub fn im_dilate_radius_with_scratch(
+    src: &MaskIm,
+    dst: &mut MaskIm,
+    radius_pix: usize,
+    scratch: &mut DilateScratch,
+) {
+    // `2*r+1` is the odd diameter whose `dia/2` is exactly `r`.
+    let max_dia_pix = src.w.min(src.h).max(1);
+    let dia_pix = radius_pix
+        .saturating_mul(2)
+        .saturating_add(1)
+        .min(max_dia_pix);
+    im_dilate_with_scratch(src, dst, dia_pix, scratch);
+}
+
+/// Buffers kept between disk dilations (the EDT grid and 1D scratch, and the last window op)
+/// so repeated calls, e.g. per node and perimeter, stop reallocating. Any image size works;
+/// the buffers grow as needed.
+#[derive(Default)]
+pub struct DilateScratch {
+    dt: Vec<i32>,
+    edt: EdtScratch,
+    win_op: Option<WinDilationOp>,
+}
+
+/// `im_dilate_disk` reusing `scratch`. The output is identical.
+pub fn im_dilate_with_scratch(
+    src: &MaskIm,
+    dst: &mut MaskIm,
+    dia_pix: usize,
+    scratch: &mut DilateScratch,
+) {
     assert_eq!(src.w, dst.w);
     assert_eq!(src.h, dst.h);
     assert!(dia_pix <= src.w && dia_pix <= src.h);
@@ -375,17 +572,112 @@ pub fn im_dilate(src: &MaskIm, dst: &mut MaskIm, dia_pix: usize) {
     }
 
     if use_win_method {
-        im_dilate_win(src, dst, dia_pix);
+        im_dilate_win(src, dst, dia_pix, scratch);
     } else {
-        im_dilate_edt(src, dst, dia_pix);
+        im_dilate_edt(src, dst, dia_pix, scratch);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::im_dilate;
+    use super::{
+        DilateScratch, distance_transform, im_dilate, im_dilate_box, im_dilate_disk,
+        im_dilate_radius, im_dilate_radius_with_scratch, im_dilate_with_scratch,
+    };
     use crate::im::MaskIm;
 
+    /// Deterministic scattered test pattern (an LCG), with roughly 1 in `density` pixels set.
+    fn scattered_mask(w: usize, h: usize, density: u32, seed: u32) -> MaskIm {
+        let mut m = MaskIm::new(w, h);
+        let mut state = seed;
+        for v in m.arr.iter_mut() {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            if (state >> 16) % density == 0 {
+                *v = 255;
+            }
+        }
+        m
+    }
+
+    fn naive_box_dilate(src: &MaskIm, dia_pix: usize) -> MaskIm {
+        let r = (dia_pix / 2) as isize;
+        let mut dst = MaskIm::new(src.w, src.h);
+        for y in 0..src.h as isize {
+            for x in 0..src.w as isize {
+                let mut v = 0u8;
+                for yy in (y - r).max(0)..=(y + r).min(src.h as isize - 1) {
+                    for xx in (x - r).max(0)..=(x + r).min(src.w as isize - 1) {
+                        v = v.max(src.arr[yy as usize * src.s + xx as usize]);
+                    }
+                }
+                dst.arr[y as usize * dst.s + x as usize] = v;
+            }
+        }
+        dst
+    }
+
+    #[test]
+    fn dilate_box_matches_naive_square_window() {
+        for (w, h, density, seed) in [(23, 17, 40, 1), (31, 31, 150, 7), (9, 40, 12, 3)] {
+            let src = scattered_mask(w, h, density, seed);
+            for dia_pix in [0, 1, 2, 3, 6, 9] {
+                let mut dst = MaskIm::new(w, h);
+                dst.arr.fill(9);
+                im_dilate_box(&src, &mut dst, dia_pix);
+                let expected = naive_box_dilate(&src, dia_pix);
+                assert_eq!(dst, expected, "{w}x{h} dia={dia_pix}");
+
+                // The square always covers the disk of the same radius.
+                let mut disk = MaskIm::new(w, h);
+                im_dilate_disk(&src, &mut disk, dia_pix);
+                let covered = disk.arr.iter().zip(&dst.arr).all(|(&d, &b)| d == 0 || b != 0);
+                assert!(covered, "disk {w}x{h} dia={dia_pix}");
+            }
+        }
+    }
+
+    #[test]
+    fn dilate_with_scratch_matches_fresh_dilation() {
+        // One scratch across changing sizes, diameters and (via the crossover table) methods.
+        let mut scratch = DilateScratch::default();
+        for (w, h, seed) in [(40, 30, 1), (200, 180, 2), (40, 30, 3), (9, 60, 4)] {
+            let src = scattered_mask(w, h, 60, seed);
+            for dia_pix in [1, 3, 5, 3, 8, 9] {
+                let mut fresh = MaskIm::new(w, h);
+                im_dilate(&src, &mut fresh, dia_pix);
+                let mut reused = MaskIm::new(w, h);
+                reused.arr.fill(7);
+                im_dilate_with_scratch(&src, &mut reused, dia_pix, &mut scratch);
+                assert_eq!(reused, fresh, "{w}x{h} dia={dia_pix}");
+            }
+            let dia_pix = 60.min(w).min(h);
+            let mut fresh = MaskIm::new(w, h);
+            im_dilate(&src, &mut fresh, dia_pix);
+            let mut reused = MaskIm::new(w, h);
+            im_dilate_with_scratch(&src, &mut reused, dia_pix, &mut scratch);
+            assert_eq!(reused, fresh, "{w}x{h} dia={dia_pix}");
+        }
+    }
+
+    /// Rough timing of box vs disk over growing diameters:
+    /// `cargo test --release dilate_box_benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn dilate_box_benchmark() {
+        let (w, h) = (1024, 1024);
+        let src = scattered_mask(w, h, 500, 11);
+        let mut dst = MaskIm::new(w, h);
+        for dia_pix in [3, 9, 25, 65, 129] {
+            let t0 = std::time::Instant::now();
+            im_dilate_disk(&src, &mut dst, dia_pix);
+            let disk_t = t0.elapsed();
+            let t0 = std::time::Instant::now();
+            im_dilate_box(&src, &mut dst, dia_pix);
+            let box_t = t0.elapsed();
+            println!("dia={dia_pix:>4} disk={disk_t:>10.2?} box={box_t:>10.2?}");
+        }
+    }
+
     #[test]
     fn dilate_win_disk_radius_1_and_overwrites_dst() {
         let w = 7;
@@ -408,6 +700,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn dilate_win_edges_do_not_wrap_across_rows() {
+        // A pixel on the right edge must not leak onto the left edge of its neighboring rows.
+        let w = 12;
+        let h = 6;
+        let mut src = MaskIm::new(w, h);
+        src.arr[w - 1] = 255;
+
+        let mut dst = MaskIm::new(w, h);
+        im_dilate(&src, &mut dst, 5);
+        for y in 0..h {
+            for x in 0..3 {
+                assert_eq!(dst.arr[y * w + x], 0, "wrapped at ({x},{y})");
+            }
+        }
+        assert_eq!(dst.arr[2 * w + (w - 1)], 255);
+    }
+
     #[test]
     fn dilate_edt_path_matches_expected_thresholds() {
         let w = 50;
@@ -430,6 +740,103 @@ mod tests {
         assert_eq!(at(49, 49), 0);
     }
 
+    #[test]
+    fn distance_transform_measures_to_nearest_unset_pixel() {
+        let w = 7;
+        let h = 5;
+        let mut mask = MaskIm::new(w, h);
+        // A 5x3 block with one unset pixel in the middle row.
+        for y in 1..4 {
+            for x in 1..6 {
+                mask.arr[y * w + x] = 255;
+            }
+        }
+        mask.arr[2 * w + 5] = 0;
+
+        let dist_im = distance_transform(&mask);
+        let at = |x: usize, y: usize| dist_im.arr[y * dist_im.s + x];
+        assert_eq!(at(0, 0), 0);
+        assert_eq!(at(5, 2), 0);
+        assert_eq!(at(1, 1), 1);
+        assert_eq!(at(3, 2), 2);
+        assert_eq!(at(2, 2), 2);
+        assert_eq!(at(4, 2), 1);
+
+        // The image edge counts as unset.
+        let mut full = MaskIm::new(5, 5);
+        full.arr.fill(255);
+        let dist_im = distance_transform(&full);
+        assert_eq!(dist_im.arr[0], 1);
+        assert_eq!(dist_im.arr[2 * 5 + 2], 3);
+    }
+
+    #[test]
+    fn dilate_radius_sets_exactly_the_disk() {
+        let (w, h) = (9, 9);
+        let mut src = MaskIm::new(w, h);
+        src.arr[4 * w + 4] = 255;
+
+        let set_rows = |dst: &MaskIm| -> Vec<String> {
+            (0..h)
+                .map(|y| {
+                    (0..w)
+                        .map(|x| if dst.arr[y * w + x] != 0 { '#' } else { '.' })
+                        .collect()
+                })
+                .collect()
+        };
+
+        let mut dst = MaskIm::new(w, h);
+        dst.arr.fill(9);
+        im_dilate_radius(&src, &mut dst, 0);
+        assert_eq!(dst, src);
+
+        im_dilate_radius(&src, &mut dst, 1);
+        #[rustfmt::skip]
+        assert_eq!(set_rows(&dst), [
+            ".........",
+            ".........",
+            ".........",
+            "....#....",
+            "...###...",
+            "....#....",
+            ".........",
+            ".........",
+            ".........",
+        ]);
+
+        im_dilate_radius(&src, &mut dst, 2);
+        #[rustfmt::skip]
+        assert_eq!(set_rows(&dst), [
+            ".........",
+            ".........",
+            "....#....",
+            "...###...",
+            "..#####..",
+            "...###...",
+            "....#....",
+            ".........",
+            ".........",
+        ]);
+
+        // The radius version is the odd-diameter primitive, with or without scratch.
+        let mut scratch = DilateScratch::default();
+        for radius_pix in 0..4 {
+            let mut by_dia = MaskIm::new(w, h);
+            im_dilate(&src, &mut by_dia, 2 * radius_pix + 1);
+            let mut by_rad = MaskIm::new(w, h);
+            im_dilate_radius_with_scratch(&src, &mut by_rad, radius_pix, &mut scratch);
+            assert_eq!(by_rad, by_dia, "radius={radius_pix}");
+        }
+
+        // A radius past the image clamps to the widest disk (diameter 9, radius 4) instead of
+        // panicking.
+        im_dilate_radius(&src, &mut dst, 100);
+        let mut widest = MaskIm::new(w, h);
+        im_dilate_radius(&src, &mut widest, 4);
+        assert_eq!(dst, widest);
+    }
+
     #[test]
     fn dilate_dia_lt_2_is_copy() {
         let w = 9;
@@ -0,0 +1,538 @@
+use crate::desc::Thou;
+use crate::toolpath::{ToolPath, mark_reversals};
+use crate::units::{Pix, PixScale};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+// TODO: Softcode
+const CLEARANCE_Z_INCH: f64 = 0.1;
+const FEED_IPM: f64 = 60.0;
+const PLUNGE_IPM: f64 = 30.0;
+
+/// What `write_gcode` needs besides the toolpaths: the unit conversion and the header info.
+#[derive(Debug, Clone)]
+pub struct GcodeOpts {
+    pub pix_scale: PixScale,
+    pub tool_guid: String,
+    pub tool_i: usize,
+    pub tool_dia_inch: f64,
+    pub tool_dia_pix: usize,
+    /// Number of tiles the job was carved in, for the per-tile comments.
+    pub tile_n: usize,
+    /// Pause at sharp direction reversals within a cut (see `mark_reversals`); `None` to disable.
+    pub reversal_dwell: Option<ReversalDwell>,
+    /// Scale each cut segment's feed by its `CutPixels::feed_scale` (set by the sim from
+    /// engagement). Off emits every cut at the nominal feed.
+    pub scale_feed_by_engagement: bool,
+}
+
+/// A `G4` dwell of `dwell_s` seconds after every cut vertex that turns by more than
+/// `angle_thresh_deg`, for machines that lose steps when the axes reverse abruptly.
+#[derive(Debug, Clone, Copy)]
+pub struct ReversalDwell {
+    pub angle_thresh_deg: f64,
+    pub dwell_s: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ModalState {
+    x_pix: Option<i32>,
+    y_pix: Option<i32>,
+    z_thou: Option<i32>,
+    last_feed_ipm: Option<f64>,
+}
+
+/// Streams G-code one line at a time, dropping words that don't change the modal state.
+struct GcodeWriter<'a, W: Write> {
+    w: &'a mut W,
+    st: ModalState,
+    pix_scale: PixScale,
+    line: String,
+}
+
+impl<W: Write> GcodeWriter<'_, W> {
+    fn raw(&mut self, s: &str) -> io::Result<()> {
+        self.w.write_all(s.as_bytes())
+    }
+
+    fn comment(&mut self, s: &str) -> io::Result<()> {
+        // Use `()`-style comments for compatibility with many CNC controllers.
+        // Replace any ')' to avoid prematurely closing the comment.
+        let safe = s.replace(')', "]");
+        writeln!(self.w, "({safe})")
+    }
+
+    /// Append the changed axis words to `self.line`; returns whether any changed.
+    fn push_axes(&mut self, x_pix: Option<i32>, y_pix: Option<i32>, z_thou: Option<i32>) -> bool {
+        let mut any = false;
+        if let Some(x) = x_pix
+            && self.st.x_pix != Some(x)
+        {
+            let _ = write!(
+                self.line,
+                " X{:.4}",
+                self.pix_scale.px_to_export_x_inch(Pix(x))
+            );
+            self.st.x_pix = Some(x);
+            any = true;
+        }
+        if let Some(y) = y_pix
+            && self.st.y_pix != Some(y)
+        {
+            let _ = write!(
+                self.line,
+                " Y{:.4}",
+                self.pix_scale.px_to_export_y_inch(Pix(y))
+            );
+            self.st.y_pix = Some(y);
+            any = true;
+        }
+        if let Some(z) = z_thou
+            && self.st.z_thou != Some(z)
+        {
+            let _ = write!(self.line, " Z{:.4}", self.pix_scale.thou_to_inch(Thou(z)));
+            self.st.z_thou = Some(z);
+            any = true;
+        }
+        any
+    }
+
+    fn g0(
+        &mut self,
+        x_pix: Option<i32>,
+        y_pix: Option<i32>,
+        z_thou: Option<i32>,
+    ) -> io::Result<()> {
+        self.line.clear();
+        self.line.push_str("G0");
+        if self.push_axes(x_pix, y_pix, z_thou) {
+            self.line.push('\n');
+            self.w.write_all(self.line.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn g1(
+        &mut self,
+        x_pix: Option<i32>,
+        y_pix: Option<i32>,
+        z_thou: Option<i32>,
+        feed_ipm: Option<f64>,
+    ) -> io::Result<()> {
+        self.line.clear();
+        self.line.push_str("G1");
+        let any_axis = self.push_axes(x_pix, y_pix, z_thou);
+        let mut any_feed = false;
+        // Emit F when it differs from the last F we emitted.
+        if let Some(f) = feed_ipm
+            && self.st.last_feed_ipm.is_none_or(|lf| (lf - f).abs() > 1e-9)
+        {
+            let _ = write!(self.line, " F{:.1}", f);
+            self.st.last_feed_ipm = Some(f);
+            any_feed = true;
+        }
+        if any_axis || any_feed {
+            self.line.push('\n');
+            self.w.write_all(self.line.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Retract, stop the spindle, load `tool_i` (as 1-based tool number `T{tool_i + 1}`) and
+    /// restart. The machine may move during the change, so the modal state is forgotten and
+    /// every axis word is emitted again afterwards.
+    fn tool_change(&mut self, tool_i: usize, safe_z_thou: i32) -> io::Result<()> {
+        self.g0(None, None, Some(safe_z_thou))?;
+        self.raw("M05 (Spindle off)\n")?;
+        let tool_n = tool_i + 1;
+        writeln!(self.w, "T{tool_n} M06 (Tool change to tool_i={tool_i})")?;
+        self.raw("M03 (Spindle on)\n")?;
+        self.st = ModalState::default();
+        self.g0(None, None, Some(safe_z_thou))
+    }
+}
+
+/// Write `toolpaths` (in cutting order with traverses interleaved) as G-code, a line at a
+/// time, so a large job never has to be held in memory as one `String`.
+///
+/// The job starts with `opts.tool_i` loaded; whenever a toolpath's `tool_i` differs from the
+/// previous one a tool change (`M06`) is emitted, so the output of
+/// `group_by_tool_then_order` can be written as one file.
+///
+/// Cuts become G1 moves and traverses G0 moves:
+/// - X/Y are inches in absolute coordinates derived from pixels via `opts.pix_scale`, per axis
+///   so non-square pixels export at the right size, with Y flipped to point up if
+///   `PixScale::with_flip_y` was set.
+/// - Z is inches derived from `thou`, assuming toolpath `z` is a height above zero.
+/// - Every cut starts with a retract to a safe Z above the highest point, a reposition and a plunge.
+pub fn write_gcode<W: Write>(
+    w: &mut W,
+    toolpaths: &[ToolPath],
+    opts: &GcodeOpts,
+) -> io::Result<()> {
+    // Compute a safe Z in thou (exact integer units) so later comparisons are exact.
+    let max_z_thou: i32 = toolpaths
+        .iter()
+        .flat_map(|tp| tp.points.iter())
+        .map(|p| p.z)
+        .fold(0, i32::max);
+    let clearance_thou: i32 = (CLEARANCE_Z_INCH * 1000.0).round() as i32;
+    let safe_z_thou: i32 = max_z_thou.saturating_add(clearance_thou);
+
+    let pix_scale = opts.pix_scale;
+    let ppi = if pix_scale.is_square() {
+        format!("{}", pix_scale.px_per_inch_x())
+    } else {
+        format!(
+            "{}x{}",
+            pix_scale.px_per_inch_x(),
+            pix_scale.px_per_inch_y()
+        )
+    };
+
+    let mut g = GcodeWriter {
+        w,
+        st: ModalState::default(),
+        pix_scale,
+        line: String::new(),
+    };
+    g.comment("rcarve toolpaths")?;
+    g.comment(&format!(
+        "tool_guid={} tool_i={} tool_dia_inch={:.6} tool_dia_pix={} ppi={}",
+        opts.tool_guid, opts.tool_i, opts.tool_dia_inch, opts.tool_dia_pix, ppi
+    ))?;
+
+    g.raw("G20 (Unit is inches)\n")?;
+    g.raw(&format!("G0 Z${} (Unit is inches)\n", CLEARANCE_Z_INCH))?;
+    g.raw("M03 (Spindle on)\n")?;
+
+    // Start with a retract to safe Z (Z-only).
+    g.g0(None, None, Some(safe_z_thou))?;
+
+    let reversals: Option<Vec<Vec<bool>>> = opts
+        .reversal_dwell
+        .map(|d| mark_reversals(toolpaths, d.angle_thresh_deg));
+
+    let mut last_cut_tile_i: Option<usize> = None;
+    let mut curr_tool_i = opts.tool_i;
+
+    for (tp_i, tp) in toolpaths.iter().enumerate() {
+        let Some(first) = tp.points.first() else {
+            continue;
+        };
+
+        if tp.tool_i != curr_tool_i {
+            g.tool_change(tp.tool_i, safe_z_thou)?;
+            curr_tool_i = tp.tool_i;
+        }
+
+        if !tp.is_traverse {
+            if last_cut_tile_i != Some(tp.tile_i) {
+                let tile_1 = tp.tile_i.saturating_add(1);
+                let tile_n = opts.tile_n.max(tile_1);
+                g.comment(&format!(
+                    "============================= TILE {tile_1} of {tile_n} ============================="
+                ))?;
+                last_cut_tile_i = Some(tp.tile_i);
+            }
+            let total_cut = tp.total_cut();
+            g.comment(&format!(
+                "tp[{tp_i}] cuts=[{}, {}]",
+                total_cut.pixels_changed, total_cut.depth_sum_thou
+            ))?;
+
+            // Always retract to safe Z before any XY reposition.
+            g.g0(None, None, Some(safe_z_thou))?;
+            // Reposition in XY at safe Z (omit Z if already at safe).
+            g.g0(Some(first.x), Some(first.y), None)?;
+            // Plunge (Z-only) at plunge feed.
+            g.g1(None, None, Some(first.z), Some(PLUNGE_IPM))?;
+
+            // Follow the polyline at cut feed, optionally scaled per segment by engagement.
+            for (seg_i, p) in tp.points.iter().skip(1).enumerate() {
+                let feed_scale = if opts.scale_feed_by_engagement {
+                    tp.cuts.get(seg_i).map_or(1.0, |c| c.feed_scale) as f64
+                } else {
+                    1.0
+                };
+                // Round to the emitted precision so tiny scale jitter doesn't spam F words.
+                let feed_ipm = (FEED_IPM * feed_scale * 10.0).round() / 10.0;
+                g.g1(Some(p.x), Some(p.y), Some(p.z), Some(feed_ipm))?;
+                if let (Some(reversals), Some(dwell)) = (&reversals, opts.reversal_dwell)
+                    && reversals[tp_i][seg_i + 1]
+                {
+                    g.raw(&format!("G4 P{:.3}\n", dwell.dwell_s))?;
+                }
+            }
+        } else {
+            g.comment(&format!("---- tp[{tp_i}] traverse"))?;
+            // Enforce a conservative traverse policy:
+            // retract to safe Z, traverse in XY at constant safe Z, and let the next cut handle plunging.
+
+            // Retract (Z-only).
+            g.g0(None, None, Some(safe_z_thou))?;
+            // Traverse through the points in XY (no Z changes during traverse).
+            for p in &tp.points {
+                g.g0(Some(p.x), Some(p.y), None)?;
+            }
+        }
+    }
+
+    // Final retract.
+    g.g0(None, None, Some(safe_z_thou))?;
+    g.raw("M2\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toolpath::{CutPixels, IV3};
+
+    #[test]
+    fn write_gcode_streams_modal_moves() {
+        let tp = |points: Vec<IV3>, is_traverse: bool| ToolPath {
+            cuts: vec![CutPixels::default(); points.len()],
+            points,
+            closed: false,
+            tool_dia_pix: 10,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            is_traverse,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+        let v = |x: i32, y: i32, z: i32| IV3 { x, y, z };
+        let toolpaths = vec![
+            tp(vec![v(0, 0, 500), v(100, 0, 500), v(100, 50, 500)], false),
+            tp(vec![v(100, 50, 600), v(200, 50, 600)], true),
+        ];
+        let opts = GcodeOpts {
+            pix_scale: PixScale::new_anisotropic(100.0, 50.0),
+            tool_guid: "t(1)".to_string(),
+            tool_i: 0,
+            tool_dia_inch: 0.1,
+            tool_dia_pix: 10,
+            tile_n: 1,
+            reversal_dwell: None,
+            scale_feed_by_engagement: false,
+        };
+
+        let mut out: Vec<u8> = Vec::new();
+        write_gcode(&mut out, &toolpaths, &opts).unwrap();
+        let gcode = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = gcode.lines().collect();
+
+        assert_eq!(lines[0], "(rcarve toolpaths)");
+        assert!(lines[1].contains("tool_guid=t(1]") && lines[1].ends_with("ppi=100x50)"));
+        let moves: Vec<&str> = lines
+            .iter()
+            .copied()
+            .filter(|l| l.starts_with("G0 X") || l.starts_with("G0 Z0") || l.starts_with("G1"))
+            .collect();
+        assert_eq!(
+            moves,
+            vec![
+                "G0 Z0.7000",
+                "G0 X0.0000 Y0.0000",
+                "G1 Z0.5000 F30.0",
+                // Y is scaled by its own resolution and unchanged words are dropped.
+                "G1 X1.0000 F60.0",
+                "G1 Y1.0000",
+                "G0 Z0.7000",
+                "G0 X2.0000",
+            ]
+        );
+        assert_eq!(lines.last(), Some(&"M2"));
+    }
+
+    #[test]
+    fn write_gcode_dwells_at_reversals() {
+        let v = |x: i32, y: i32| IV3 { x, y, z: 500 };
+        let points = vec![v(0, 0), v(100, 0), v(100, 1), v(0, 1)];
+        let toolpaths = vec![ToolPath {
+            cuts: vec![CutPixels::default(); points.len()],
+            points,
+            closed: false,
+            tool_dia_pix: 10,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            is_traverse: false,
+            is_raster: true,
+            is_plunge_run: false,
+        }];
+        let mut opts = GcodeOpts {
+            pix_scale: PixScale::new(100.0),
+            tool_guid: "t".to_string(),
+            tool_i: 0,
+            tool_dia_inch: 0.1,
+            tool_dia_pix: 10,
+            tile_n: 1,
+            reversal_dwell: None,
+            scale_feed_by_engagement: false,
+        };
+
+        let gcode_with = |opts: &GcodeOpts| {
+            let mut out: Vec<u8> = Vec::new();
+            write_gcode(&mut out, &toolpaths, opts).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert!(!gcode_with(&opts).contains("G4"));
+
+        opts.reversal_dwell = Some(ReversalDwell {
+            angle_thresh_deg: 120.0,
+            dwell_s: 0.05,
+        });
+        let gcode = gcode_with(&opts);
+        let lines: Vec<&str> = gcode.lines().collect();
+        let dwell_i = lines.iter().position(|l| l.starts_with("G4")).unwrap();
+        // One dwell, right after reaching the row end.
+        assert_eq!(
+            lines[dwell_i - 1..=dwell_i],
+            ["G1 X1.0000 F60.0", "G4 P0.050"]
+        );
+        assert_eq!(lines.iter().filter(|l| l.starts_with("G4")).count(), 1);
+    }
+
+    #[test]
+    fn write_gcode_scales_feed_only_when_asked() {
+        let v = |x: i32| IV3 { x, y: 0, z: 500 };
+        let slotting = CutPixels {
+            pixels_changed: 100,
+            feed_scale: 0.75,
+            ..Default::default()
+        };
+        let toolpaths = vec![ToolPath {
+            cuts: vec![slotting, CutPixels::default(), CutPixels::default()],
+            points: vec![v(0), v(100), v(200)],
+            closed: false,
+            tool_dia_pix: 10,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        }];
+        let mut opts = GcodeOpts {
+            pix_scale: PixScale::new(100.0),
+            tool_guid: "t".to_string(),
+            tool_i: 0,
+            tool_dia_inch: 0.1,
+            tool_dia_pix: 10,
+            tile_n: 1,
+            reversal_dwell: None,
+            scale_feed_by_engagement: false,
+        };
+        let cut_moves = |opts: &GcodeOpts| {
+            let mut out: Vec<u8> = Vec::new();
+            write_gcode(&mut out, &toolpaths, opts).unwrap();
+            String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .filter(|l| l.starts_with("G1 X"))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(cut_moves(&opts), vec!["G1 X1.0000 F60.0", "G1 X2.0000"]);
+
+        opts.scale_feed_by_engagement = true;
+        assert_eq!(
+            cut_moves(&opts),
+            vec!["G1 X1.0000 F45.0", "G1 X2.0000 F60.0"]
+        );
+    }
+
+    #[test]
+    fn write_gcode_flips_y_up_from_the_image_bottom() {
+        let v = |x: i32, y: i32| IV3 { x, y, z: 500 };
+        let toolpaths = vec![ToolPath {
+            cuts: vec![CutPixels::default(); 2],
+            points: vec![v(0, 0), v(0, 100)],
+            closed: false,
+            tool_dia_pix: 10,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        }];
+        let opts = GcodeOpts {
+            // A 101-px tall image: pixel row 0 is (H - 1) = 100 px = 1" up.
+            pix_scale: PixScale::new(100.0).with_flip_y(101),
+            tool_guid: "t".to_string(),
+            tool_i: 0,
+            tool_dia_inch: 0.1,
+            tool_dia_pix: 10,
+            tile_n: 1,
+            reversal_dwell: None,
+            scale_feed_by_engagement: false,
+        };
+
+        let mut out: Vec<u8> = Vec::new();
+        write_gcode(&mut out, &toolpaths, &opts).unwrap();
+        let gcode = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = gcode.lines().collect();
+        assert!(lines.contains(&"G0 X0.0000 Y1.0000"));
+        assert!(lines.contains(&"G1 Y0.0000 F60.0"));
+    }
+
+    #[test]
+    fn write_gcode_changes_tools_between_groups() {
+        let v = |x: i32| IV3 { x, y: 0, z: 500 };
+        let tp = |tool_i: usize, x: i32| ToolPath {
+            cuts: vec![CutPixels::default(); 2],
+            points: vec![v(x), v(x + 100)],
+            closed: false,
+            tool_dia_pix: 10,
+            tool_i,
+            tile_i: 0,
+            tree_node_id: 0,
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+        let toolpaths = vec![tp(2, 0), tp(2, 100), tp(0, 0)];
+        let opts = GcodeOpts {
+            pix_scale: PixScale::new(100.0),
+            tool_guid: "rough".to_string(),
+            tool_i: 2,
+            tool_dia_inch: 0.1,
+            tool_dia_pix: 10,
+            tile_n: 1,
+            reversal_dwell: None,
+            scale_feed_by_engagement: false,
+        };
+
+        let mut out: Vec<u8> = Vec::new();
+        write_gcode(&mut out, &toolpaths, &opts).unwrap();
+        let gcode = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = gcode.lines().collect();
+
+        // The starting tool needs no change; the switch to tool 0 retracts first.
+        let changes: Vec<usize> = (0..lines.len())
+            .filter(|&i| lines[i].contains("M06"))
+            .collect();
+        assert_eq!(changes.len(), 1);
+        let i = changes[0];
+        assert_eq!(
+            lines[i - 3..i + 3],
+            [
+                "G1 X2.0000 F60.0",
+                "G0 Z0.6000",
+                "M05 (Spindle off)",
+                "T1 M06 (Tool change to tool_i=0)",
+                "M03 (Spindle on)",
+                "G0 Z0.6000",
+            ]
+        );
+        // Positions are re-emitted in full after the change even where unchanged.
+        assert_eq!(
+            lines[i + 3..i + 5],
+            ["(tp[2] cuts=[0, 0])", "G0 X0.0000 Y0.0000"]
+        );
+    }
+}
@@ -76,6 +76,32 @@ impl<T: Copy + Default, const N_CH: usize, S> Im<T, N_CH, S> {
     }
 }
 
+// Packing and blitting
+// -----------------------------------------------------------------------------
+impl<T: Copy + Default, const N_CH: usize, S> Im<T, N_CH, S> {
+    /// A copy with `s == w * N_CH`, for code that reads `arr` as contiguous rows.
+    pub fn to_packed(&self) -> Self {
+        let mut packed = Self::new(self.w, self.h);
+        packed.copy_from(self, (0, 0));
+        packed
+    }
+
+    /// Blit `src` into `self` with its top-left corner at `at` (x, y); whatever falls outside
+    /// `self` is clipped.
+    pub fn copy_from<S2>(&mut self, src: &Im<T, N_CH, S2>, at: (usize, usize)) {
+        let (x0, y0) = at;
+        if x0 >= self.w || y0 >= self.h {
+            return;
+        }
+        let n = src.w.min(self.w - x0) * N_CH;
+        for y in 0..src.h.min(self.h - y0) {
+            let src_i = y * src.s;
+            let dst_i = (y0 + y) * self.s + x0 * N_CH;
+            self.arr[dst_i..dst_i + n].copy_from_slice(&src.arr[src_i..src_i + n]);
+        }
+    }
+}
+
 impl<T, const N_CH: usize, S> Im<T, N_CH, S> {
     /// Change the semantic tag type parameter `S` without touching pixel data.
     ///
@@ -92,6 +118,22 @@ impl<T, const N_CH: usize, S> Im<T, N_CH, S> {
         }
     }
 
+    /// The value-transforming cousin of `retag`: apply `f` to every sample, producing a new
+    /// image with the same size/stride and any element type and tag
+    /// (e.g. `PlyIm` -> `Lum16Im` via a ply-index to thou lookup).
+    pub fn map_values<T2, S2>(&self, f: impl Fn(T) -> T2) -> Im<T2, N_CH, S2>
+    where
+        T: Copy,
+    {
+        Im {
+            w: self.w,
+            h: self.h,
+            s: self.s,
+            arr: self.arr.iter().map(|&v| f(v)).collect(),
+            _phantom: PhantomData,
+        }
+    }
+
     #[inline(always)]
     pub fn get_or_default(&self, x: usize, y: usize, ch: usize, default: T) -> T
     where
@@ -308,17 +350,7 @@ impl<S> Im<u16, 1, S> {
 
 impl<S> Im<i32, 1, S> {
     pub fn to_mask_im(&self) -> MaskIm {
-        let mut mask_im = MaskIm::new(self.w, self.h);
-        for y in 0..self.h {
-            for x in 0..self.w {
-                let v = unsafe { *self.get_unchecked(x, y, 0) };
-                let m: u8 = if v != 0 { 255 } else { 0 };
-                unsafe {
-                    *mask_im.get_unchecked_mut(x, y, 0) = m;
-                }
-            }
-        }
-        mask_im
+        self.map_values(|v| if v != 0 { 255 } else { 0 })
     }
 }
 
@@ -358,6 +390,77 @@ pub fn copy_mask_im_to_rgba_im(src: &MaskIm, dst: &mut RGBAIm, r: u8, g: u8, b:
     }
 }
 
+// 2x downsampling (for building coarse mip levels)
+// -----------------------------------------------------------------------------
+
+/// How a 2x2 block collapses to one pixel in the `downsample_2x*` functions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reduce {
+    /// Rounded mean. Right for heightmaps; meaningless for labels.
+    Average,
+    /// Largest value. Keeps thin features alive (plies, masks).
+    Max,
+    /// The block's top-left value. Safe for labels.
+    Nearest,
+}
+
+/// Halve each dimension (rounding up), reducing each 2x2 block by `reduce`.
+/// Blocks on the right/bottom edge of an odd-sized image only use the pixels that exist.
+fn downsample_2x_by<T: Copy + Default, S>(
+    src: &Im<T, 1, S>,
+    reduce: impl Fn(&[T]) -> T,
+) -> Im<T, 1, S> {
+    let w = src.w.div_ceil(2);
+    let h = src.h.div_ceil(2);
+    let mut dst = Im::<T, 1, S>::new(w, h);
+    let mut block: Vec<T> = Vec::with_capacity(4);
+    for y in 0..h {
+        for x in 0..w {
+            block.clear();
+            for sy in (2 * y)..(2 * y + 2).min(src.h) {
+                for sx in (2 * x)..(2 * x + 2).min(src.w) {
+                    block.push(src.arr[sy * src.s + sx]);
+                }
+            }
+            dst.arr[y * dst.s + x] = reduce(&block);
+        }
+    }
+    dst
+}
+
+impl<S> Im<u16, 1, S> {
+    /// Half-resolution copy. Works for any u16 image (`Lum16Im`, ply and region label images);
+    /// use `Reduce::Max` or `Reduce::Nearest` for labels.
+    pub fn downsample_2x(&self, reduce: Reduce) -> Self {
+        match reduce {
+            Reduce::Average => downsample_2x_by(self, |b| {
+                let sum: u32 = b.iter().map(|&v| v as u32).sum();
+                let n = b.len() as u32;
+                ((sum + n / 2) / n) as u16
+            }),
+            Reduce::Max => downsample_2x_by(self, |b| b.iter().copied().max().unwrap_or(0)),
+            Reduce::Nearest => downsample_2x_by(self, |b| b[0]),
+        }
+    }
+}
+
+pub fn downsample_2x_lum16(src: &Lum16Im, reduce: Reduce) -> Lum16Im {
+    src.downsample_2x(reduce)
+}
+
+/// Half-resolution mask. `Reduce::Max` sets a pixel if any of its block is set;
+/// `Reduce::Average` needs at least half of the block set.
+pub fn downsample_2x_mask(src: &MaskIm, reduce: Reduce) -> MaskIm {
+    match reduce {
+        Reduce::Average => downsample_2x_by(src, |b| {
+            let n_set = b.iter().filter(|&&v| v != 0).count();
+            if 2 * n_set >= b.len() { 255 } else { 0 }
+        }),
+        Reduce::Max => downsample_2x_by(src, |b| if b.iter().any(|&v| v != 0) { 255 } else { 0 }),
+        Reduce::Nearest => downsample_2x_by(src, |b| b[0]),
+    }
+}
+
 impl Im<u8, 1, Binary> {
     pub fn invert(&mut self) -> &mut Self {
         for y in 0..self.h {
@@ -372,6 +475,24 @@ impl Im<u8, 1, Binary> {
         self
     }
 
+    /// Set every pixel inside `roi` (clamped to the image) to `v`, leaving the rest untouched.
+    pub fn fill_roi(&mut self, roi: &roi::ROI, v: u8) -> &mut Self {
+        let l = roi.l.min(self.w);
+        let r = roi.r.min(self.w);
+        if l >= r {
+            return self;
+        }
+        for y in roi.t.min(self.h)..roi.b.min(self.h) {
+            let row_i = y * self.s;
+            self.arr[row_i + l..row_i + r].fill(v);
+        }
+        self
+    }
+
+    /// Reset only the working window to 0, instead of the whole `arr.fill(0)`.
+    pub fn clear_roi(&mut self, roi: &roi::ROI) -> &mut Self {
+        self.fill_roi(roi, 0)
+    }
 }
 
 #[cfg(test)]
@@ -390,6 +511,54 @@ mod tests {
         assert_eq!(im.arr, vec![200, 255, 255]);
     }
 
+    #[test]
+    fn downsample_2x_reduces_blocks_and_keeps_odd_edges() {
+        let mut im = Lum16Im::new(3, 2);
+        im.arr.copy_from_slice(&[1, 4, 7, 2, 3, 9]);
+
+        let avg = downsample_2x_lum16(&im, Reduce::Average);
+        assert_eq!((avg.w, avg.h), (2, 1));
+        assert_eq!(avg.arr, vec![3, 8]); // (1+4+2+3)/4 = 2.5 -> 3, (7+9)/2 = 8
+
+        assert_eq!(im.downsample_2x(Reduce::Max).arr, vec![4, 9]);
+        assert_eq!(im.downsample_2x(Reduce::Nearest).arr, vec![1, 7]);
+
+        // A one-pixel-wide line survives a max reduction but not an average.
+        let mut m = MaskIm::new(4, 4);
+        for y in 0..4 {
+            m.arr[y * m.s + 1] = 255;
+        }
+        assert_eq!(
+            downsample_2x_mask(&m, Reduce::Max).arr,
+            vec![255, 0, 255, 0]
+        );
+        let mut thin = MaskIm::new(2, 2);
+        thin.arr[0] = 255;
+        assert_eq!(downsample_2x_mask(&thin, Reduce::Average).arr, vec![0]);
+    }
+
+    #[test]
+    fn map_values_converts_and_retag_round_trips() {
+        #[derive(Debug)]
+        struct PlyTag;
+        let mut ply = Im::<u16, 1, PlyTag>::new(3, 2);
+        ply.arr.copy_from_slice(&[0, 1, 2, 2, 1, 0]);
+
+        // Ply index -> thou, as a plain grayscale heightmap.
+        let top_thou_by_ply = [0_u16, 250, 500];
+        let heights: Lum16Im = ply.map_values(|v| top_thou_by_ply[v as usize]);
+        assert_eq!((heights.w, heights.h, heights.s), (3, 2, 3));
+        assert_eq!(heights.arr, vec![0, 250, 500, 500, 250, 0]);
+
+        // Back through a map and a retag gives the original image.
+        let back: Im<u16, 1, PlyTag> = heights.map_values(|v| v / 250);
+        assert_eq!(back, ply);
+        assert_eq!(back.retag::<Grayscale>().retag::<PlyTag>(), ply);
+
+        let mask: MaskIm = ply.map_values(|v| if v > 0 { 255 } else { 0 });
+        assert_eq!(mask.arr, vec![0, 255, 255, 255, 255, 0]);
+    }
+
     #[test]
     fn mask_im_inverted_flips_zero_and_nonzero() {
         let mut m = MaskIm::new(3, 1);
@@ -428,6 +597,58 @@ mod tests {
         assert_eq!(m.get_or_default(4, 3, 0, 0), 0);
     }
 
+    #[test]
+    fn strided_im_round_trips_to_packed() {
+        // A 3x2 view into a buffer with 5-pixel rows.
+        let mut im = Lum16Im::new(5, 2);
+        im.arr.copy_from_slice(&[1, 2, 3, 90, 91, 4, 5, 6, 92, 93]);
+        im.w = 3;
+
+        let packed = im.to_packed();
+        assert_eq!((packed.w, packed.h, packed.s), (3, 2, 3));
+        assert_eq!(packed.arr, vec![1, 2, 3, 4, 5, 6]);
+        for y in 0..2 {
+            for x in 0..3 {
+                let v = im.get_or_default(x, y, 0, 0);
+                assert_eq!(packed.get_or_default(x, y, 0, 0), v);
+            }
+        }
+
+        // Blitting clips at the right and bottom edges.
+        let mut dst = Im::<u16, 1>::new(4, 3);
+        dst.copy_from(&packed, (2, 2));
+        assert_eq!(dst.arr, vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn mask_im_clear_roi_leaves_outside_untouched() {
+        let mut m = MaskIm::new(5, 4);
+        m.arr.fill(255);
+        m.clear_roi(&roi::ROI {
+            l: 1,
+            t: 1,
+            r: 3,
+            b: 3,
+        });
+        let cleared: Vec<usize> = (0..m.arr.len()).filter(|&i| m.arr[i] == 0).collect();
+        assert_eq!(cleared, vec![6, 7, 11, 12]);
+
+        // The ROI is clamped to the image.
+        m.fill_roi(
+            &roi::ROI {
+                l: 3,
+                t: 2,
+                r: 9,
+                b: 9,
+            },
+            7,
+        );
+        assert_eq!(m.get_or_default(4, 3, 0, 0), 7);
+        assert_eq!(m.get_or_default(3, 2, 0, 0), 7);
+        assert_eq!(m.get_or_default(2, 2, 0, 0), 0);
+        assert_eq!(m.get_or_default(3, 1, 0, 0), 255);
+    }
+
     #[test]
     fn mask_im_one_pixel_border_on_image_edges_over_roi_span_touches_only_image_edges() {
         let mut m = MaskIm::new(5, 4);
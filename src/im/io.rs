@@ -1,4 +1,4 @@
-use super::core::Im;
+use super::core::{Im, Lum16Im};
 use image::ImageResult;
 use std::path::Path;
 
@@ -30,8 +30,45 @@ fn unpack_rgba8_as_i32(raw_rgba: &[u8]) -> Result<Vec<i32>, image::ImageError> {
     Ok(out)
 }
 
+// Scale a heightmap's [min,max] to [0,255] ([255,0] if `invert`). A flat map is mid-gray.
+fn heightmap_to_gray8(im: &Lum16Im, invert: bool) -> Vec<u8> {
+    let mut min_v = u16::MAX;
+    let mut max_v = u16::MIN;
+    for y in 0..im.h {
+        for &v in &im.arr[y * im.s..y * im.s + im.w] {
+            min_v = min_v.min(v);
+            max_v = max_v.max(v);
+        }
+    }
+
+    let range = max_v.saturating_sub(min_v) as f32;
+    let mut out: Vec<u8> = Vec::with_capacity(im.w * im.h);
+    for y in 0..im.h {
+        for &v in &im.arr[y * im.s..y * im.s + im.w] {
+            let t = if range > 0.0 {
+                (v - min_v) as f32 / range
+            } else {
+                0.5
+            };
+            let t = if invert { 1.0 - t } else { t };
+            out.push((t * 255.0).round() as u8);
+        }
+    }
+    out
+}
+
 // PNG I/O
 // -----------------------------------------------------------------------------
+
+/// Save a heightmap (e.g. the result of `sim_toolpaths`) as a viewable 8-bit gray PNG,
+/// auto-scaled so its min maps to black and its max to white (reversed if `invert`).
+/// This is the headless equivalent of the debug viewer's auto-scaled gray rendering.
+pub fn save_heightmap_png(im: &Lum16Im, path: &Path, invert: bool) -> ImageResult<()> {
+    let img = image::GrayImage::from_raw(im.w as u32, im.h as u32, heightmap_to_gray8(im, invert))
+        .ok_or_else(dim_mismatch_err)?;
+
+    img.save_with_format(path, image::ImageFormat::Png)
+}
 impl<S> Im<u8, 1, S> {
     pub fn save_png<P: AsRef<Path>>(&self, path: P) -> ImageResult<()> {
         let img = image::GrayImage::from_raw(self.w as u32, self.h as u32, self.arr.clone())
@@ -138,4 +175,15 @@ mod tests {
         let unpacked = unpack_rgba8_as_i32(&packed).unwrap();
         assert_eq!(unpacked, src);
     }
+
+    #[test]
+    fn heightmap_gray8_scales_min_max() {
+        let mut im = Lum16Im::new(3, 1);
+        im.arr.copy_from_slice(&[1000, 1500, 2000]);
+        assert_eq!(heightmap_to_gray8(&im, false), vec![0, 128, 255]);
+        assert_eq!(heightmap_to_gray8(&im, true), vec![255, 128, 0]);
+
+        im.arr.fill(700);
+        assert_eq!(heightmap_to_gray8(&im, false), vec![128, 128, 128]);
+    }
 }
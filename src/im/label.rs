@@ -1,7 +1,11 @@
-use super::core::Im;
+use super::core::{Im, MaskIm};
 use super::roi::ROI;
 use std::collections::HashMap;
+use std::fmt;
+
 /// Flood-fill a connected component in a single-channel image.
+/// `label_im` no longer uses this; it backs `flood_region` and is the reference the tests
+/// compare `label_im` against.
 fn flood_im<SrcT, TarT, S>(
     src_im: &Im<SrcT, 1, S>,
     dst_im: &mut Im<TarT, 1>,
@@ -95,6 +99,19 @@ where
     (filled, pixel_iz, roi)
 }
 
+/// The 4-connected component of equal values containing (x, y) in `src_im` (e.g. a `PlyIm`
+/// clicked in an editor), as a mask (255 inside) and its bounding ROI. Unlike `label_im`, the
+/// background value is not special: seeding on it floods that background component.
+/// Panics if (x, y) is outside the image.
+pub fn flood_region<T, S>(src_im: &Im<T, 1, S>, x: usize, y: usize) -> (MaskIm, ROI)
+where
+    T: Copy + PartialEq,
+{
+    let mut mask_im = Im::<u8, 1>::new(src_im.w, src_im.h);
+    let (_, _, roi) = flood_im(src_im, &mut mask_im, x, y, 255);
+    (mask_im.retag(), roi)
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct LabelInfo {
     pub size: usize,
@@ -102,67 +119,285 @@ pub struct LabelInfo {
     pub start_y: usize,
     pub roi: ROI,
     pub pixel_iz: Vec<usize>,
+    /// Neighbor id -> shared boundary pixel count (min of the two sides' counts).
     pub neighbors: HashMap<usize, usize>,
+    /// Neighbor id -> number of pixel edges between this label and the neighbor.
+    pub shared_edges: HashMap<usize, usize>,
+}
+
+impl LabelInfo {
+    /// Shared perimeter length with `other_id`, in pixel edges.
+    /// Unlike `neighbors`, a pixel that touches the other label on several sides counts once per side,
+    /// which is the measure the overcut cost model in `region_tree.rs` is phrased in.
+    pub fn shared_perimeter(&self, other_id: usize) -> usize {
+        self.shared_edges.get(&other_id).copied().unwrap_or(0)
+    }
+}
+
+/// Union-find root of `i` with path halving.
+fn uf_find(parent: &mut [u32], mut i: u32) -> u32 {
+    while parent[i as usize] != i {
+        let grand = parent[parent[i as usize] as usize];
+        parent[i as usize] = grand;
+        i = grand;
+    }
+    i
+}
+
+fn uf_union(parent: &mut [u32], a: u32, b: u32) -> u32 {
+    let ra = uf_find(parent, a);
+    let rb = uf_find(parent, b);
+    // Keep the smaller (earlier) label as the root.
+    let (root, child) = if ra <= rb { (ra, rb) } else { (rb, ra) };
+    parent[child as usize] = root;
+    root
+}
+
+/// Why `label_im` couldn't label an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LabelError {
+    /// More components than the target pixel type can hold (`max_labels` is its largest value,
+    /// e.g. 65535 for `u16`). Usually a noisy input; denoise it or merge tiny specks first.
+    TooManyLabels { max_labels: usize },
+}
+
+impl fmt::Display for LabelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LabelError::TooManyLabels { max_labels } => write!(
+                f,
+                "too many connected components: the label image holds at most {max_labels}"
+            ),
+        }
+    }
 }
 
-/// Label a single channel image's connected components.
-pub fn label_im<SrcT, TarT, S>(src_im: &Im<SrcT, 1, S>) -> (Im<TarT, 1>, Vec<LabelInfo>)
+impl std::error::Error for LabelError {}
+
+/// Label a single channel image's connected components (4-connected, equal values,
+/// `SrcT::default()` is background).
+///
+/// Two-pass scanline labeling over one shared union-find: the first pass assigns provisional
+/// labels and merges them, the second assigns final ids in row-major order of each
+/// component's first pixel (so ids are deterministic) and fills in the `LabelInfo`s.
+///
+/// Ids run 1..=N with 0 reserved, so at most `TarT`'s max value components can be labeled
+/// (65535 for `u16`); more than that returns `LabelError::TooManyLabels`.
+///
+/// `SrcT::default()` (0) is the background and is left unlabeled; see
+/// `label_im_with_background` to pick another value.
+pub fn label_im<SrcT, TarT, S>(
+    src_im: &Im<SrcT, 1, S>,
+) -> Result<(Im<TarT, 1>, Vec<LabelInfo>), LabelError>
 where
     SrcT: Copy + Default + PartialEq,
     TarT: Copy + Default + PartialEq + TryFrom<usize> + TryInto<usize>,
+{
+    label_im_with_background(src_im, Some(SrcT::default()))
+}
+
+/// `label_im` with the background value given explicitly: pixels equal to `background` are
+/// left at 0 and get no `LabelInfo`, or with `None` every value (including 0) is labeled.
+/// Useful when the "air" ply isn't 0.
+pub fn label_im_with_background<SrcT, TarT, S>(
+    src_im: &Im<SrcT, 1, S>,
+    background: Option<SrcT>,
+) -> Result<(Im<TarT, 1>, Vec<LabelInfo>), LabelError>
+where
+    SrcT: Copy + PartialEq,
+    TarT: Copy + Default + PartialEq + TryFrom<usize> + TryInto<usize>,
 {
     let w = src_im.w;
     let h = src_im.h;
 
     let mut dst_im: Im<TarT, 1> = Im::<TarT, 1>::new(w, h);
 
-    // Mirror the JS behavior: allocate/clear destination labels to 0.
-    let dst_default = TarT::default();
+    // Pass 1: provisional labels (0 = background) and their equivalences.
+    let mut prov: Vec<u32> = vec![0; w * h];
+    let mut parent: Vec<u32> = vec![0];
+    for y in 0..h {
+        let src_row = y * src_im.s;
+        for x in 0..w {
+            let v = src_im.arr[src_row + x];
+            if background == Some(v) {
+                continue;
+            }
 
-    let src_bg = SrcT::default();
+            let left = if x > 0 && src_im.arr[src_row + x - 1] == v {
+                prov[y * w + x - 1]
+            } else {
+                0
+            };
+            let up = if y > 0 && src_im.arr[src_row - src_im.s + x] == v {
+                prov[(y - 1) * w + x]
+            } else {
+                0
+            };
+
+            prov[y * w + x] = match (left, up) {
+                (0, 0) => {
+                    let l = parent.len() as u32;
+                    parent.push(l);
+                    l
+                }
+                (l, 0) | (0, l) => l,
+                (l, u) => uf_union(&mut parent, l, u),
+            };
+        }
+    }
 
+    // Pass 2: final ids and per-label info.
     // group_info is indexed by group id (and [0] is reserved, do not use it!).
     let mut group_info: Vec<LabelInfo> = vec![LabelInfo::default()];
-
-    let mut group_i: usize = 1;
+    let mut final_by_root: Vec<usize> = vec![0; parent.len()];
     for y in 0..h {
         for x in 0..w {
-            let src_i = y * src_im.s + x;
-            let dst_i = y * dst_im.s + x;
-
-            if src_im.arr[src_i] == src_bg {
-                // Background pixel
+            let p = prov[y * w + x];
+            if p == 0 {
                 continue;
             }
-            if dst_im.arr[dst_i] != dst_default {
-                // Already labeled
-                continue;
+
+            let root = uf_find(&mut parent, p) as usize;
+            if final_by_root[root] == 0 {
+                final_by_root[root] = group_info.len();
+                group_info.push(LabelInfo {
+                    size: 0,
+                    start_x: x,
+                    start_y: y,
+                    roi: ROI {
+                        l: x,
+                        t: y,
+                        r: x + 1,
+                        b: y + 1,
+                    },
+                    pixel_iz: Vec::new(),
+                    neighbors: HashMap::new(),
+                    shared_edges: HashMap::new(),
+                });
             }
+            let group_i = final_by_root[root];
+
+            let Ok(label_val) = TarT::try_from(group_i) else {
+                return Err(LabelError::TooManyLabels {
+                    max_labels: group_i - 1,
+                });
+            };
+            dst_im.arr[y * dst_im.s + x] = label_val;
+
+            // Row-major visiting keeps pixel_iz sorted.
+            let info = &mut group_info[group_i];
+            info.size += 1;
+            info.pixel_iz.push(y * src_im.s + x);
+            info.roi.l = info.roi.l.min(x);
+            info.roi.r = info.roi.r.max(x + 1);
+            info.roi.b = info.roi.b.max(y + 1);
+        }
+    }
+
+    fill_neighbors(&dst_im, &mut group_info);
 
-            let label_val: TarT = TarT::try_from(group_i)
-                .ok()
-                .unwrap_or_else(|| panic!("label value overflow at group_i={group_i}"));
-
-            // Use flood_im to write this label into dst for the whole connected region.
-            let (filled, pixel_iz, roi) = flood_im(src_im, &mut dst_im, x, y, label_val);
-
-            // Ensure our table stays aligned with group ids.
-            debug_assert_eq!(group_info.len(), group_i);
-            group_info.push(LabelInfo {
-                size: filled,
-                start_x: x,
-                start_y: y,
-                roi,
-                pixel_iz,
-                neighbors: HashMap::new(),
-            });
-
-            group_i += 1;
+    Ok((dst_im, group_info))
+}
+
+/// `label_im` with ids derived from the components rather than the scan: ids are assigned by
+/// (size descending, start_y, start_x), so moving or adding small components elsewhere in the
+/// image doesn't renumber the big ones. The image is relabeled and `neighbors`/`shared_edges`
+/// are rekeyed to match; `pixel_iz` and `roi` move with their `LabelInfo`.
+pub fn label_im_sorted<SrcT, TarT, S>(
+    src_im: &Im<SrcT, 1, S>,
+) -> Result<(Im<TarT, 1>, Vec<LabelInfo>), LabelError>
+where
+    SrcT: Copy + Default + PartialEq,
+    TarT: Copy + Default + PartialEq + TryFrom<usize> + TryInto<usize>,
+{
+    let (mut dst_im, mut infos) = label_im::<SrcT, TarT, S>(src_im)?;
+
+    // new id -> old id, and back. Start pixels are unique, so the order is total.
+    let mut order: Vec<usize> = (1..infos.len()).collect();
+    order.sort_by_key(|&id| {
+        (
+            std::cmp::Reverse(infos[id].size),
+            infos[id].start_y,
+            infos[id].start_x,
+        )
+    });
+    let mut new_by_old: Vec<usize> = vec![0; infos.len()];
+    for (new_i, &old_id) in order.iter().enumerate() {
+        new_by_old[old_id] = new_i + 1;
+    }
+
+    // Every id already fit in `TarT` when `label_im` wrote it.
+    let to_tar = |id: usize| -> TarT {
+        TarT::try_from(id).unwrap_or_else(|_| panic!("label id did not convert back to TarT"))
+    };
+    let bg = TarT::default();
+    for y in 0..dst_im.h {
+        let row = y * dst_im.s;
+        for v in &mut dst_im.arr[row..row + dst_im.w] {
+            if *v != bg {
+                let old_id: usize = (*v)
+                    .try_into()
+                    .unwrap_or_else(|_| panic!("label value did not convert to usize"));
+                *v = to_tar(new_by_old[old_id]);
+            }
+        }
+    }
+
+    let rekey = |m: &HashMap<usize, usize>| -> HashMap<usize, usize> {
+        m.iter().map(|(&id, &n)| (new_by_old[id], n)).collect()
+    };
+    let mut sorted: Vec<LabelInfo> = Vec::with_capacity(infos.len());
+    sorted.push(LabelInfo::default());
+    for &old_id in &order {
+        let mut info = std::mem::take(&mut infos[old_id]);
+        info.neighbors = rekey(&info.neighbors);
+        info.shared_edges = rekey(&info.shared_edges);
+        sorted.push(info);
+    }
+
+    Ok((dst_im, sorted))
+}
+
+/// Compute per-label neighbor shared-border counts from the finished label image.
+/// This is separate from the labeling so neighbors can be computed purely in label-space.
+fn fill_neighbors<TarT>(dst_im: &Im<TarT, 1>, group_info: &mut [LabelInfo])
+where
+    TarT: Copy + Default + PartialEq + TryInto<usize>,
+{
+    let w = dst_im.w;
+    let h = dst_im.h;
+    let label_id = |v: TarT| -> usize {
+        v.try_into()
+            .unwrap_or_else(|_| panic!("label value did not convert to usize"))
+    };
+
+    // Shared edges: each right/down pixel pair with two different non-background labels is one edge.
+    let mut shared_edges: Vec<HashMap<usize, usize>> = vec![HashMap::new(); group_info.len()];
+    let mut add_edge = |a: TarT, b: TarT| {
+        let bg = TarT::default();
+        if a == bg || b == bg || a == b {
+            return;
+        }
+        let (a_id, b_id) = (label_id(a), label_id(b));
+        if a_id == 0 || b_id == 0 || a_id >= shared_edges.len() || b_id >= shared_edges.len() {
+            return;
+        }
+        *shared_edges[a_id].entry(b_id).or_insert(0) += 1;
+        *shared_edges[b_id].entry(a_id).or_insert(0) += 1;
+    };
+    for y in 0..h {
+        let row = y * dst_im.s;
+        for x in 0..w {
+            let a = dst_im.arr[row + x];
+            if x + 1 < w {
+                add_edge(a, dst_im.arr[row + x + 1]);
+            }
+            if y + 1 < h {
+                add_edge(a, dst_im.arr[row + dst_im.s + x]);
+            }
         }
     }
 
-    // Compute per-label neighbor shared-border counts from the finished label image.
-    // This is separate from the flood-fill so neighbors can be computed purely in label-space.
     let mut neighbors: Vec<HashMap<usize, usize>> = vec![HashMap::new(); group_info.len()];
     if w >= 2 && h >= 2 {
         let bg = TarT::default();
@@ -247,11 +482,36 @@ where
 
     for a in 1..group_info.len() {
         group_info[a].neighbors = std::mem::take(&mut neighbors[a]);
+        group_info[a].shared_edges = std::mem::take(&mut shared_edges[a]);
     }
-
-    (dst_im, group_info)
 }
 
+/// Graphviz DOT of the region neighbor graph in `infos` (as returned by `label_im`), for
+/// eyeballing how regions touch. Nodes are labeled with their id and size, edges with the
+/// shared-border count from `LabelInfo::neighbors`. Undirected, one edge per pair (a < b),
+/// sorted so the output is stable. Id 0 (the unused background slot) is skipped.
+pub fn neighbors_to_dot(infos: &[LabelInfo]) -> String {
+    use std::fmt::Write as _;
+
+    let mut out = String::from("graph regions {\n");
+    for (id, info) in infos.iter().enumerate().skip(1) {
+        let _ = writeln!(out, "    {id} [label=\"{id}\\nsize={}\"];", info.size);
+    }
+    for (a, info) in infos.iter().enumerate().skip(1) {
+        let mut edges: Vec<(usize, usize)> = info
+            .neighbors
+            .iter()
+            .filter(|&(&b, _)| b > a && b < infos.len())
+            .map(|(&b, &shared)| (b, shared))
+            .collect();
+        edges.sort_unstable();
+        for (b, shared) in edges {
+            let _ = writeln!(out, "    {a} -- {b} [label=\"{shared}\"];");
+        }
+    }
+    out.push_str("}\n");
+    out
+}
 
 // Tests
 // -----------------------------------------------------------------------------
@@ -288,6 +548,123 @@ mod tests {
         im
     }
 
+    /// The original flood-fill labeling, kept to check `label_im` against.
+    fn label_im_by_flood(src_im: &Im<u16, 1>) -> (Im<u16, 1>, Vec<LabelInfo>) {
+        let mut dst_im = Im::<u16, 1>::new(src_im.w, src_im.h);
+        let mut group_info: Vec<LabelInfo> = vec![LabelInfo::default()];
+        for y in 0..src_im.h {
+            for x in 0..src_im.w {
+                let i = y * src_im.s + x;
+                if src_im.arr[i] == 0 || dst_im.arr[i] != 0 {
+                    continue;
+                }
+                let group_i = group_info.len();
+                let (size, pixel_iz, roi) = flood_im(src_im, &mut dst_im, x, y, group_i as u16);
+                group_info.push(LabelInfo {
+                    size,
+                    start_x: x,
+                    start_y: y,
+                    roi,
+                    pixel_iz,
+                    neighbors: HashMap::new(),
+                    shared_edges: HashMap::new(),
+                });
+            }
+        }
+        fill_neighbors(&dst_im, &mut group_info);
+        (dst_im, group_info)
+    }
+
+    #[test]
+    fn label_im_matches_flood_fill_reference() {
+        let fixtures = [
+            r#"
+                00000
+                01120
+                01120
+                01120
+                00000
+            "#,
+            r#"
+                11311
+                12221
+                12221
+                12221
+                11111
+            "#,
+            r#"
+                1100
+                1000
+                0002
+            "#,
+            // U shapes and spirals need the union step to merge labels.
+            r#"
+                1010101
+                1010101
+                1111101
+                0000001
+                2222221
+                2000001
+                2022221
+            "#,
+            r#"
+                3333333
+                3000003
+                3033303
+                3030303
+                3030003
+                3033333
+            "#,
+        ];
+        for fixture in fixtures {
+            let src = labels_from_ascii(fixture);
+            let (dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&src).unwrap();
+            let (ref_dst, ref_infos) = label_im_by_flood(&src);
+            assert_eq!(dst, ref_dst, "{fixture}");
+            assert_eq!(infos, ref_infos, "{fixture}");
+        }
+    }
+
+    #[test]
+    fn label_im_with_background_skips_the_given_value() {
+        let src = labels_from_ascii(
+            r#"
+                11111
+                10221
+                10221
+                11111
+            "#,
+        );
+
+        // With 1 as background, the 0s and the 2s are the components.
+        let (dst, infos): (Im<u16, 1>, Vec<LabelInfo>) =
+            label_im_with_background(&src, Some(1)).unwrap();
+        assert_eq!(
+            crate::test_helpers::im_u16_to_ascii(&dst),
+            "00000\n01220\n01220\n00000\n"
+        );
+        assert_eq!(infos.len(), 3);
+        assert_eq!((infos[1].size, infos[2].size), (2, 4));
+        assert_eq!(infos[1].shared_edges, HashMap::from([(2, 2)]));
+
+        // The default matches plain `label_im`.
+        let (dst_default, infos_default): (Im<u16, 1>, Vec<LabelInfo>) =
+            label_im_with_background(&src, Some(0)).unwrap();
+        let (dst_plain, infos_plain): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&src).unwrap();
+        assert_eq!(dst_default, dst_plain);
+        assert_eq!(infos_default, infos_plain);
+
+        // With no background every value is labeled, the 1 ring included.
+        let (dst, infos): (Im<u16, 1>, Vec<LabelInfo>) =
+            label_im_with_background(&src, None).unwrap();
+        assert_eq!(
+            crate::test_helpers::im_u16_to_ascii(&dst),
+            "11111\n12331\n12331\n11111\n"
+        );
+        assert_eq!(infos.len(), 4);
+        assert_eq!(infos[1].size, 14);
+    }
+
     #[test]
     fn flood_im_fills_connected_component() {
         const DIM: usize = 5;
@@ -337,7 +714,7 @@ mod tests {
         src.arr[idx(4, 0)] = 9;
         src.arr[idx(5, 0)] = 9;
 
-        let (dst, groups): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&src);
+        let (dst, groups): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&src).unwrap();
 
         // [0] is reserved.
         assert_eq!(groups.len(), 3);
@@ -383,7 +760,7 @@ mod tests {
             "#,
         );
 
-        let (dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&labels);
+        let (dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&labels).unwrap();
         assert_eq!(infos.len(), 3);
 
         // Map original src values -> new label ids.
@@ -403,6 +780,31 @@ mod tests {
         assert_eq!(infos[id2].neighbors.get(&id1).copied(), Some(3));
     }
 
+    #[test]
+    fn neighbors_to_dot_lists_each_edge_once() {
+        let labels = labels_from_ascii(
+            r#"
+                11122
+                11122
+                33333
+            "#,
+        );
+        let (_dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&labels).unwrap();
+        assert_eq!(infos.len(), 4);
+
+        let dot = neighbors_to_dot(&infos);
+        let edges: Vec<&str> = dot.lines().filter(|l| l.contains(" -- ")).collect();
+        assert_eq!(edges.len(), 3);
+        for (a, b) in [(1, 2), (1, 3), (2, 3)] {
+            let shared = infos[a].neighbors[&b];
+            let edge = format!("    {a} -- {b} [label=\"{shared}\"];");
+            assert!(edges.contains(&edge.as_str()), "{dot}");
+        }
+        assert!(dot.starts_with("graph regions {\n"));
+        assert!(dot.contains("    1 [label=\"1\\nsize=6\"];"));
+        assert!(dot.ends_with("}\n"));
+    }
+
     #[test]
     fn neighbor_map_counts_boundary_pixels_when_one_surrounds_another() {
         // 5x5 label image where region 1 surrounds region 2.
@@ -417,7 +819,7 @@ mod tests {
             "#,
         );
 
-        let (_dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&labels);
+        let (_dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&labels).unwrap();
         assert_eq!(infos.len(), 3);
 
         let mut id_by_src: std::collections::HashMap<u16, usize> = std::collections::HashMap::new();
@@ -432,6 +834,37 @@ mod tests {
         assert_eq!(infos[id2].neighbors.get(&id1).copied(), Some(8));
     }
 
+    #[test]
+    fn shared_perimeter_counts_edges_not_pixels() {
+        // A single pixel of 2 poking into 1: one boundary pixel, but four shared edges.
+        let labels = labels_from_ascii(
+            r#"
+                111
+                121
+                111
+            "#,
+        );
+        let (_dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&labels).unwrap();
+        assert_eq!(infos[2].neighbors.get(&1).copied(), Some(1));
+        assert_eq!(infos[1].shared_perimeter(2), 4);
+        assert_eq!(infos[2].shared_perimeter(1), 4);
+        assert_eq!(infos[1].shared_perimeter(3), 0);
+
+        // The surrounded 3x3 block shares its whole 12-edge perimeter.
+        let labels = labels_from_ascii(
+            r#"
+                11111
+                12221
+                12221
+                12221
+                11111
+            "#,
+        );
+        let (_dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&labels).unwrap();
+        assert_eq!(infos[1].shared_perimeter(2), 12);
+        assert_eq!(infos[2].shared_perimeter(1), 12);
+    }
+
     #[test]
     fn foo() {
         let labels = labels_from_ascii(
@@ -444,7 +877,7 @@ mod tests {
             "#,
         );
 
-        let (_dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&labels);
+        let (_dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&labels).unwrap();
         assert_eq!(infos.len(), 4);
 
         let mut id_by_src: std::collections::HashMap<u16, usize> = std::collections::HashMap::new();
@@ -477,7 +910,7 @@ mod tests {
             "#,
         );
 
-        let (_dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&labels);
+        let (_dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&labels).unwrap();
         assert_eq!(infos.len(), 3);
 
         let mut id_by_src: std::collections::HashMap<u16, usize> = std::collections::HashMap::new();
@@ -495,4 +928,116 @@ mod tests {
         assert_eq!(infos[id2].pixel_iz, vec![2 * 4 + 3]);
         assert_eq!(infos[id2].roi, ROI { l: 3, t: 2, r: 4, b: 3 });
     }
+
+    #[test]
+    fn flood_region_matches_the_labeled_component() {
+        let src = labels_from_ascii(
+            r#"
+                11022
+                01022
+                11100
+                00133
+            "#,
+        );
+        let (_, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&src).unwrap();
+        let n_set = |mask: &MaskIm| mask.arr.iter().filter(|&&v| v == 255).count();
+
+        for info in infos.iter().skip(1) {
+            let (mask, roi) = flood_region(&src, info.start_x, info.start_y);
+            assert_eq!(roi, info.roi);
+            let set: Vec<usize> = (0..mask.arr.len())
+                .filter(|&i| mask.arr[i] == 255)
+                .collect();
+            assert_eq!(set, info.pixel_iz);
+        }
+
+        // Seeding anywhere in a component gives the same region, including on the background.
+        let (mask, roi) = flood_region(&src, 2, 3);
+        assert_eq!(roi, infos[1].roi);
+        assert_eq!(n_set(&mask), infos[1].size);
+        let (mask, roi) = flood_region(&src, 0, 3);
+        assert_eq!(n_set(&mask), 2);
+        assert_eq!((roi.l, roi.t, roi.r, roi.b), (0, 3, 2, 4));
+    }
+
+    #[test]
+    fn label_im_reports_too_many_labels() {
+        // Isolated dots on every other pixel: 16 * 16 = 256 components.
+        let mut src: Im<u8, 1> = Im::new(32, 32);
+        for y in (0..32).step_by(2) {
+            for x in (0..32).step_by(2) {
+                src.arr[y * src.s + x] = 1;
+            }
+        }
+
+        // u8 labels top out at 255 (0 is reserved).
+        let err = label_im::<u8, u8, _>(&src).unwrap_err();
+        assert_eq!(err, LabelError::TooManyLabels { max_labels: 255 });
+        assert!(err.to_string().contains("255"));
+
+        let (_dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&src).unwrap();
+        assert_eq!(infos.len(), 257);
+    }
+
+    #[test]
+    fn label_im_sorted_numbers_by_size_and_stays_consistent() {
+        let src = labels_from_ascii(
+            r#"
+                01122
+                01122
+                03333
+                03333
+                04443
+            "#,
+        );
+        let (dst, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im_sorted(&src).unwrap();
+
+        // 3 (9 px), then 1 and 2 (4 px each, by start_x), then 4 (3 px).
+        let sizes: Vec<usize> = infos.iter().skip(1).map(|info| info.size).collect();
+        assert_eq!(sizes, vec![9, 4, 4, 3]);
+        let starts: Vec<(usize, usize)> = infos
+            .iter()
+            .skip(1)
+            .map(|info| (info.start_x, info.start_y))
+            .collect();
+        assert_eq!(starts, vec![(1, 2), (1, 0), (3, 0), (1, 4)]);
+
+        // The image, pixel lists and ROIs agree on the new ids.
+        for (id, info) in infos.iter().enumerate().skip(1) {
+            assert!(
+                info.pixel_iz.iter().all(|&i| dst.arr[i] == id as u16),
+                "id {id}"
+            );
+            let n_in_im = dst.arr.iter().filter(|&&v| v == id as u16).count();
+            assert_eq!(n_in_im, info.size);
+            let (x, y) = (info.pixel_iz[0] % dst.s, info.pixel_iz[0] / dst.s);
+            assert!(info.roi.l <= x && x < info.roi.r && info.roi.t <= y && y < info.roi.b);
+        }
+
+        // The neighbor maps match recomputing them on the relabeled image.
+        let mut recomputed = infos.clone();
+        fill_neighbors(&dst, &mut recomputed);
+        assert_eq!(recomputed, infos);
+        assert_eq!(infos[1].neighbors.len(), 3);
+        assert_eq!(infos[1].shared_perimeter(4), 4);
+
+        // A new speck first in scan order renumbers everything in `label_im`, but not here.
+        let specked = labels_from_ascii(
+            r#"
+                51122
+                01122
+                03333
+                03333
+                04443
+            "#,
+        );
+        let (_, scan_infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&specked).unwrap();
+        assert_eq!(scan_infos[1].size, 1);
+        let (_, specked_infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im_sorted(&specked).unwrap();
+        assert_eq!(specked_infos.len(), 6);
+        for id in 1..5 {
+            assert_eq!(specked_infos[id].pixel_iz, infos[id].pixel_iz, "id {id}");
+        }
+        assert_eq!(specked_infos[5].size, 1);
+    }
 }
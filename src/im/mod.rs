@@ -1,6 +1,9 @@
 pub mod core;
 #[allow(unused_imports)]
-pub use core::{copy_mask_im_to_rgba_im, Im, Im1Mut, Lum16Im, Lum8Im, MaskIm, RGBAIm};
+pub use core::{
+    copy_mask_im_to_rgba_im, downsample_2x_lum16, downsample_2x_mask, Im, Im1Mut, Lum16Im,
+    Lum8Im, MaskIm, RGBAIm, Reduce,
+};
 
 pub mod roi;
 #[allow(unused_imports)]
@@ -12,12 +15,19 @@ pub use roi::ROI;
 #[cfg(feature = "im-io")]
 pub mod io;
 
+#[cfg(feature = "im-io")]
+#[allow(unused_imports)]
+pub use io::save_heightmap_png;
+
 #[cfg(feature = "im-label")]
 pub mod label;
 
 #[cfg(feature = "im-label")]
 #[allow(unused_imports)]
-pub use label::{label_im, LabelInfo};
+pub use label::{
+    flood_region, label_im, label_im_sorted, label_im_with_background, neighbors_to_dot,
+    LabelError, LabelInfo,
+};
 
 // Debug UI window
 // -----------------------------------------------------------------------------
@@ -34,4 +34,63 @@ impl ROI {
         self.r = self.r.max(other.r);
         self.b = self.b.max(other.b);
     }
+
+    /// True when the ROI covers no pixels (`l >= r` or `t >= b`).
+    pub fn is_empty(&self) -> bool {
+        self.l >= self.r || self.t >= self.b
+    }
+
+    /// The overlap of the two ROIs. Disjoint ROIs give an empty ROI (check with `is_empty`).
+    pub fn intersect(&self, other: ROI) -> ROI {
+        ROI {
+            l: self.l.max(other.l),
+            t: self.t.max(other.t),
+            r: self.r.min(other.r),
+            b: self.b.min(other.b),
+        }
+    }
+
+    /// This ROI clipped to a `w` x `h` image, possibly empty.
+    pub fn clamp_to(&self, w: usize, h: usize) -> ROI {
+        self.intersect(ROI {
+            l: 0,
+            t: 0,
+            r: w,
+            b: h,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roi(l: usize, t: usize, r: usize, b: usize) -> ROI {
+        ROI { l, t, r, b }
+    }
+
+    #[test]
+    fn intersect_and_clamp_to() {
+        let a = roi(2, 3, 10, 8);
+        let b = roi(6, 0, 20, 5);
+        let both = a.intersect(b);
+        assert_eq!(both, roi(6, 3, 10, 5));
+        assert_eq!((both.w(), both.h()), (4, 2));
+        assert!(!both.is_empty());
+        assert_eq!(b.intersect(a), both);
+
+        assert_eq!(a.clamp_to(7, 100), roi(2, 3, 7, 8));
+        assert!(a.clamp_to(2, 100).is_empty());
+        assert!(a.clamp_to(0, 0).is_empty());
+    }
+
+    #[test]
+    fn disjoint_rois_intersect_to_empty() {
+        let a = roi(0, 0, 4, 4);
+        // Side by side, touching edges (exclusive bounds), and diagonal.
+        assert!(a.intersect(roi(4, 0, 8, 4)).is_empty());
+        assert!(a.intersect(roi(0, 4, 4, 8)).is_empty());
+        assert!(a.intersect(roi(5, 5, 9, 9)).is_empty());
+        assert!(ROI::default().is_empty());
+    }
 }
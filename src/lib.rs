@@ -8,13 +8,16 @@ pub mod bucket_vec;
 pub mod debug_ui;
 pub mod desc;
 pub mod dilate_im;
+pub mod gcode;
 pub mod im;
 pub mod mat3;
+pub mod metrics;
 pub mod mpoly;
 pub mod region_tree;
 pub mod sim;
 pub mod toolpath;
 pub mod trace;
+pub mod units;
 
 #[cfg(test)]
 pub mod test_helpers;
@@ -1,16 +1,18 @@
 use rcarve::debug_ui;
-use rcarve::desc::{CompDesc, Guid, PlyDesc, Thou, ToolDesc, Units, parse_comp_json};
+use rcarve::desc::{CompDesc, Guid, PlyDesc, Thou, parse_comp_json};
 use rcarve::dilate_im::im_dilate;
+use rcarve::gcode;
 use rcarve::im::label::{LabelInfo, label_im};
 use rcarve::im::{Lum16Im, MaskIm, ROI};
 use rcarve::mpoly::{IntPath, IntPoint, MPoly};
 use rcarve::region_tree;
 use rcarve::sim;
 use rcarve::toolpath;
+use rcarve::units;
 
 use std::collections::HashMap;
 use std::fs;
-use std::io::BufWriter;
+use std::io::{BufWriter, Write};
 use std::sync::Arc;
 use std::sync::mpsc;
 use std::sync::Mutex;
@@ -18,32 +20,48 @@ use std::time::Instant;
 
 use serde::Serialize;
 
-fn tool_i_and_dia_pix(tool_descs: &[ToolDesc], tool_guid: &Guid, ppi: usize) -> (usize, usize) {
-    let (tool_i, tool_desc) = tool_descs
-        .iter()
-        .enumerate()
-        .find(|(_, td)| &td.guid == tool_guid)
-        .unwrap_or_else(|| {
-            panic!(
-                "tool_guid {} not found in tool_descs (len={})",
-                tool_guid,
-                tool_descs.len()
-            )
-        });
+/// Resolve `tool_guid` through `CompDesc::tool_i`, so tools from either `tool_descs` or
+/// `tools_by_guid` work.
+fn tool_i_and_dia_pix(comp_desc: &CompDesc, tool_guid: &Guid, ppi: usize) -> (usize, usize) {
+    let (tool_i, tool_desc) = comp_desc.tool_i(tool_guid).unwrap_or_else(|| {
+        panic!(
+            "tool_guid {} not found in tool_descs or tools_by_guid (n_tools={})",
+            tool_guid,
+            comp_desc.tools().len()
+        )
+    });
 
-    let tool_dia_in = match tool_desc.units {
-        Units::Inch => tool_desc.diameter,
-        Units::Mm => tool_desc.diameter / 25.4,
-    };
-    let tool_dia_pix = ((tool_dia_in * ppi as f64).round() as usize).max(1);
+    let tool_dia_pix = ((tool_desc.dia_inch() * ppi as f64).round() as usize).max(1);
     (tool_i, tool_dia_pix)
 }
 
-fn tool_dia_inch(tool_desc: &ToolDesc) -> f64 {
-    match tool_desc.units {
-        Units::Inch => tool_desc.diameter,
-        Units::Mm => tool_desc.diameter / 25.4,
+/// Map each cut pass name to the tool named for it in `carve_desc`.
+/// Passes whose tool guid is null are left out so their bands get skipped.
+fn tools_by_pass(comp_desc: &CompDesc, ppi: usize) -> HashMap<String, toolpath::ToolDef> {
+    let carve_desc = &comp_desc.carve_desc;
+    let mut out = HashMap::new();
+    for (pass, tool_guid) in [
+        ("rough", &carve_desc.rough_tool_guid),
+        ("refine", &carve_desc.refine_tool_guid),
+        ("detail", &carve_desc.detail_tool_guid),
+    ] {
+        if let Some(tool_guid) = tool_guid {
+            let (tool_i, tool_dia_pix) = tool_i_and_dia_pix(comp_desc, tool_guid, ppi);
+            out.insert(
+                pass.to_string(),
+                toolpath::ToolDef {
+                    tool_i,
+                    tool_dia_pix,
+                    max_doc_thou: comp_desc
+                        .tool(tool_guid)
+                        .and_then(|tool_desc| tool_desc.max_doc_inch)
+                        .map(|inch| Thou((inch * 1000.0).round() as i32))
+                        .filter(|&max_doc_thou| max_doc_thou > Thou(0)),
+                },
+            );
+        }
     }
+    out
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -62,16 +80,13 @@ struct ToolpathOut {
     is_cut: bool,
     cuts: [u64; 2],
     points: Vec<i32>,
+    /// Per-segment feed multipliers parallel to the points (last entry unused).
+    feed_scales: Vec<f32>,
     tile_i: usize,
 }
 
 fn toolpath_to_toolpath_out(tp: &toolpath::ToolPath) -> ToolpathOut {
-    let mut pixels_changed: u64 = 0;
-    let mut depth_sum_thou: u64 = 0;
-    for c in &tp.cuts {
-        pixels_changed += c.pixels_changed;
-        depth_sum_thou += c.depth_sum_thou;
-    }
+    let total_cut = tp.total_cut();
 
     let mut points: Vec<i32> = Vec::with_capacity(tp.points.len().saturating_mul(3));
     for p in &tp.points {
@@ -80,10 +95,14 @@ fn toolpath_to_toolpath_out(tp: &toolpath::ToolPath) -> ToolpathOut {
         points.push(p.z);
     }
 
+    let mut feed_scales: Vec<f32> = tp.cuts.iter().map(|c| c.feed_scale).collect();
+    feed_scales.resize(tp.points.len(), 1.0);
+
     ToolpathOut {
         is_cut: !tp.is_traverse,
-        cuts: [pixels_changed, depth_sum_thou],
+        cuts: [total_cut.pixels_changed, total_cut.depth_sum_thou],
         points,
+        feed_scales,
         tile_i: tp.tile_i,
     }
 }
@@ -241,24 +260,15 @@ fn make_prod_im(
 ) -> Lum16Im {
     // Build prod view at the refine tool_dia_pix scale
     // For each play from bottom to top
-    let mut ply_mask_im = MaskIm::new(w, h);
     let mut dil_ply_mask_im = MaskIm::new(w, h);
     let mut prod_im = Lum16Im::new(w, h);
 
     for (ply_i, ply_desc) in sorted_ply_descs.iter().enumerate().skip(1) {
-        ply_mask_im.arr.fill(0);
         dil_ply_mask_im.arr.fill(0);
 
         // Set the ply_mask_im to 255 where ply_im is >= ply_i
-        for y in 0..h {
-            for x in 0..w {
-                let v = unsafe { *ply_im.get_unchecked(x, y, 0) };
-                unsafe {
-                    *ply_mask_im.get_unchecked_mut(x, y, 0) =
-                        if v as usize >= ply_i { 255_u8 } else { 0_u8 }
-                }
-            }
-        }
+        let mut ply_mask_im: MaskIm =
+            ply_im.map_values(|v| if v as usize >= ply_i { 255_u8 } else { 0_u8 });
 
         im_dilate(&ply_mask_im, &mut dil_ply_mask_im, tool_dia_pix);
 
@@ -556,7 +566,10 @@ fn carve_roi(comp_desc: &CompDesc, global_roi: ROI, roi: ROI, ppi: usize) -> Vec
         }
     }
 
-    let (region_im_raw, region_infos): (rcarve::im::Im<u16, 1>, Vec<LabelInfo>) = label_im(&ply_im);
+    let (region_im_raw, region_infos): (rcarve::im::Im<u16, 1>, Vec<LabelInfo>) =
+        label_im(&ply_im).unwrap_or_else(|e| {
+            panic!("failed to label the ply image ({e}); the input is likely too noisy, try denoising it")
+        });
     let region_im: region_tree::RegionIm = region_im_raw.retag::<region_tree::RegionI>();
 
     // debug_ui::add_region_im("region_im", &region_im);
@@ -564,8 +577,9 @@ fn carve_roi(comp_desc: &CompDesc, global_roi: ROI, roi: ROI, ppi: usize) -> Vec
     let max_segment_len_inch = 4.0_f64;
     let max_segment_len_pix = ((max_segment_len_inch * ppi as f64).round() as usize).max(1);
 
-    let mut sim_im = Lum16Im::new(w, h);
-    sim_im.arr.fill(bulk_top_thou.0 as u16);
+    let mut sim_im = sim::make_stock(w, h, bulk_top_thou.0 as u16);
+
+    let tools_by_pass = tools_by_pass(comp_desc, ppi);
 
     // Rough setup
     let rough_cut_bands = region_tree::create_cut_bands(
@@ -581,9 +595,8 @@ fn carve_roi(comp_desc: &CompDesc, global_roi: ROI, roi: ROI, ppi: usize) -> Vec
         .rough_tool_guid
         .as_ref()
         .expect("No rough tool guid in carve_desc");
-    let (rough_tool_i, rough_tool_dia_pix) =
-        tool_i_and_dia_pix(&comp_desc.tool_descs, rough_tool_guid, ppi);
-    let rough_region_root = region_tree::create_region_tree(&rough_cut_bands, &region_infos);
+    let (rough_tool_i, rough_tool_dia_pix) = tool_i_and_dia_pix(comp_desc, rough_tool_guid, ppi);
+    let rough_region_root = region_tree::create_region_tree(&rough_cut_bands, &region_infos, 0);
     let rough_margin_pix = rough_tool_dia_pix.saturating_mul(2) / 5;
     let rough_pride_thou = Thou(0);
 
@@ -596,15 +609,14 @@ fn carve_roi(comp_desc: &CompDesc, global_roi: ROI, roi: ROI, ppi: usize) -> Vec
         &region_infos,
         &sorted_ply_descs,
     );
-    let refine_region_root = region_tree::create_region_tree(&refine_cut_bands, &region_infos);
+    let refine_region_root = region_tree::create_region_tree(&refine_cut_bands, &region_infos, 0);
     let refine_tool_guid = comp_desc
         .carve_desc
         .refine_tool_guid
         .as_ref()
         .expect("No refine tool guid in carve_desc");
-    let (refine_tool_i, refine_tool_dia_pix) =
-        tool_i_and_dia_pix(&comp_desc.tool_descs, refine_tool_guid, ppi);
-    
+    let (refine_tool_i, refine_tool_dia_pix) = tool_i_and_dia_pix(comp_desc, refine_tool_guid, ppi);
+
     // TODO: I need two modes on gen_surcaces. One for all surfaces (rough)
     // and another for just the final surfaces (refine if rough pride > 0)
     // let refine_gen_surfaces = rough_pride_thou.0 > 0;
@@ -612,27 +624,38 @@ fn carve_roi(comp_desc: &CompDesc, global_roi: ROI, roi: ROI, ppi: usize) -> Vec
     // Rough create
     let rough_toolpaths = {
         let mut rough_toolpaths = toolpath::create_toolpaths_from_region_tree(
-            "rough",
             &rough_region_root,
             &rough_cut_bands,
-            rough_tool_i,
-            rough_tool_dia_pix,
-            (rough_tool_dia_pix.saturating_mul(4) / 5).max(1),
-            rough_margin_pix,
-            rough_pride_thou,
             &ply_im,
             &region_im,
-            None,
             &region_infos,
-            0,
-            (rough_tool_dia_pix.saturating_mul(4) / 5).max(1),
-            true,
+            &toolpath::RegionToolpathOpts {
+                tool_i: rough_tool_i,
+                tool_dia_pix: rough_tool_dia_pix,
+                tools_by_pass: Some(&tools_by_pass),
+                stepover: toolpath::Stepover::Pix(rough_tool_dia_pix.saturating_mul(4) / 5),
+                margin_pix: rough_margin_pix,
+                pride_thou: rough_pride_thou,
+                n_perimeters: 0,
+                perimeter_step_size_pix: (rough_tool_dia_pix.saturating_mul(4) / 5).max(1),
+                ..Default::default()
+            },
             None,
         );
 
-        toolpath::sort_toolpaths(&mut rough_toolpaths, &rough_region_root);
+        toolpath::sort_toolpaths(
+            &mut rough_toolpaths,
+            &rough_region_root,
+            &toolpath::DefaultOrderCost,
+        );
         toolpath::break_long_toolpaths(&mut rough_toolpaths, max_segment_len_pix);
-        sim::sim_toolpaths(&mut sim_im, &mut rough_toolpaths, None);
+        let _ = sim::sim_toolpaths(
+            &mut sim_im,
+            &mut rough_toolpaths,
+            None,
+            None,
+            sim::SimOptions::default(),
+        );
         toolpath::cull_empty_toolpaths(&mut rough_toolpaths);
 
         rough_toolpaths
@@ -641,27 +664,37 @@ fn carve_roi(comp_desc: &CompDesc, global_roi: ROI, roi: ROI, ppi: usize) -> Vec
     // Refine create
     let refine_toolpaths = {
         let mut refine_toolpaths = toolpath::create_toolpaths_from_region_tree(
-            "refine",
             &refine_region_root,
             &refine_cut_bands,
-            refine_tool_i,
-            refine_tool_dia_pix,
-            (refine_tool_dia_pix.saturating_mul(4) / 5).max(1),
-            0_usize,
-            Thou(0),
             &ply_im,
             &region_im,
-            None,
             &region_infos,
-            3,
-            (refine_tool_dia_pix.saturating_mul(4) / 5).max(1),
-            false,
+            &toolpath::RegionToolpathOpts {
+                tool_i: refine_tool_i,
+                tool_dia_pix: refine_tool_dia_pix,
+                tools_by_pass: Some(&tools_by_pass),
+                stepover: toolpath::Stepover::Pix(refine_tool_dia_pix.saturating_mul(4) / 5),
+                n_perimeters: 3,
+                perimeter_step_size_pix: (refine_tool_dia_pix.saturating_mul(4) / 5).max(1),
+                gen_surfaces: false,
+                ..Default::default()
+            },
             None,
         );
 
-        toolpath::sort_toolpaths(&mut refine_toolpaths, &refine_region_root);
+        toolpath::sort_toolpaths(
+            &mut refine_toolpaths,
+            &refine_region_root,
+            &toolpath::DefaultOrderCost,
+        );
         toolpath::break_long_toolpaths(&mut refine_toolpaths, max_segment_len_pix);
-        sim::sim_toolpaths(&mut sim_im, &mut refine_toolpaths, None);
+        let _ = sim::sim_toolpaths(
+            &mut sim_im,
+            &mut refine_toolpaths,
+            None,
+            None,
+            sim::SimOptions::default(),
+        );
         toolpath::cull_empty_toolpaths(&mut refine_toolpaths);
 
         refine_toolpaths
@@ -689,35 +722,82 @@ fn carve_roi(comp_desc: &CompDesc, global_roi: ROI, roi: ROI, ppi: usize) -> Vec
     // Run the refine toolpaths again with the diff_mask to try to clean up the diff areas
     let diff_refine_toolpaths = {
         let mut diff_refine_toolpaths = toolpath::create_toolpaths_from_region_tree(
-            "refine",
             &refine_region_root,
             &refine_cut_bands,
-            refine_tool_i,
-            refine_tool_dia_pix,
-            (refine_tool_dia_pix.saturating_mul(2) / 5).max(1),
-            0_usize,
-            Thou(0),
             &ply_im,
             &region_im,
-            Some(&diff_mask_im),
             &region_infos,
-            0,
-            (refine_tool_dia_pix.saturating_mul(2) / 5).max(1),
-            true,
+            &toolpath::RegionToolpathOpts {
+                tool_i: refine_tool_i,
+                tool_dia_pix: refine_tool_dia_pix,
+                tools_by_pass: Some(&tools_by_pass),
+                stepover: toolpath::Stepover::Pix(refine_tool_dia_pix.saturating_mul(2) / 5),
+                n_perimeters: 0,
+                perimeter_step_size_pix: (refine_tool_dia_pix.saturating_mul(2) / 5).max(1),
+                diff_mask_im: Some(&diff_mask_im),
+                ..Default::default()
+            },
             None,
         );
 
-        toolpath::sort_toolpaths(&mut diff_refine_toolpaths, &refine_region_root);
+        toolpath::sort_toolpaths(
+            &mut diff_refine_toolpaths,
+            &refine_region_root,
+            &toolpath::DefaultOrderCost,
+        );
         toolpath::break_long_toolpaths(&mut diff_refine_toolpaths, max_segment_len_pix);
-        sim::sim_toolpaths(&mut sim_im, &mut diff_refine_toolpaths, None);
+        let _ = sim::sim_toolpaths(
+            &mut sim_im,
+            &mut diff_refine_toolpaths,
+            None,
+            None,
+            sim::SimOptions::default(),
+        );
         toolpath::cull_empty_toolpaths(&mut diff_refine_toolpaths);
 
         diff_refine_toolpaths
     };
 
+    // Detail create: pencil-trace the wall bottoms the refine tool couldn't reach.
+    let detail_toolpaths = match tools_by_pass.get("detail") {
+        Some(detail_tool) => {
+            let detail_prod_im = make_prod_im(
+                w,
+                h,
+                &sorted_ply_descs,
+                &ply_im,
+                detail_tool.tool_dia_pix,
+                bulk_top_thou,
+                local_roi,
+            );
+            let detail_min_wall_thou = 10_u16;
+            let mut detail_toolpaths = toolpath::create_detail_toolpaths(
+                &sim_im,
+                &detail_prod_im,
+                detail_tool.tool_i,
+                detail_tool.tool_dia_pix,
+                detail_min_wall_thou,
+            );
+
+            toolpath::break_long_toolpaths(&mut detail_toolpaths, max_segment_len_pix);
+            let _ = sim::sim_toolpaths(
+                &mut sim_im,
+                &mut detail_toolpaths,
+                None,
+                None,
+                sim::SimOptions::default(),
+            );
+            toolpath::cull_empty_toolpaths(&mut detail_toolpaths);
+
+            detail_toolpaths
+        }
+        None => Vec::new(),
+    };
+
     let mut all_toolpaths = rough_toolpaths;
     all_toolpaths.extend(refine_toolpaths);
     all_toolpaths.extend(diff_refine_toolpaths);
+    all_toolpaths.extend(detail_toolpaths);
 
     // Convert ROI-local pixel coords to global pixel coords.
     let dx: i32 = roi.l.try_into().expect("roi.l too large for i32");
@@ -727,223 +807,6 @@ fn carve_roi(comp_desc: &CompDesc, global_roi: ROI, roi: ROI, ppi: usize) -> Vec
     all_toolpaths
 }
 
-// COnvert the toolpaths per tool into gcode lines
-// Each is_cut will be a G1 move, each traverse a G0 move
-fn to_gcode(json: &SingleToolOut) -> String {
-    // Convention:
-    // - X/Y are inches in absolute coordinates derived from pixels via `ppi`.
-    // - Z is inches derived from `thou` via /1000.0.
-    //   (This assumes `z` in the toolpaths is a height-above-zero value in thou.)
-    const CLEARANCE_Z_INCH: f64 = 0.1;
-    const FEED_IPM: f64 = 60.0;
-    const PLUNGE_IPM: f64 = 30.0;
-
-    let ppi_f = json.ppi as f64;
-
-    // Compute a safe Z in thou (exact integer units) so later comparisons are exact.
-    let mut max_z_thou: i32 = 0;
-    for tp in &json.toolpaths {
-        for xyz in tp.points.chunks_exact(3) {
-            max_z_thou = max_z_thou.max(xyz[2]);
-        }
-    }
-    let clearance_thou: i32 = (CLEARANCE_Z_INCH * 1000.0).round() as i32;
-    let safe_z_thou: i32 = max_z_thou.saturating_add(clearance_thou);
-
-    #[derive(Debug, Clone, Copy, Default)]
-    struct ModalState {
-        x_pix: Option<i32>,
-        y_pix: Option<i32>,
-        z_thou: Option<i32>,
-        last_feed_ipm: Option<f64>,
-    }
-
-    fn fmt_xy_inch(pix: i32, ppi: f64) -> f64 {
-        (pix as f64) / ppi
-    }
-
-    fn fmt_z_inch(thou: i32) -> f64 {
-        (thou as f64) / 1000.0
-    }
-
-    fn push_g0(out: &mut String, st: &mut ModalState, x_pix: Option<i32>, y_pix: Option<i32>, z_thou: Option<i32>, ppi_f: f64) {
-        let mut any = false;
-        out.push_str("G0");
-
-        if let Some(x) = x_pix {
-            if st.x_pix.map_or(true, |px| px != x) {
-                out.push_str(&format!(" X{:.4}", fmt_xy_inch(x, ppi_f)));
-                st.x_pix = Some(x);
-                any = true;
-            }
-        }
-        if let Some(y) = y_pix {
-            if st.y_pix.map_or(true, |py| py != y) {
-                out.push_str(&format!(" Y{:.4}", fmt_xy_inch(y, ppi_f)));
-                st.y_pix = Some(y);
-                any = true;
-            }
-        }
-        if let Some(z) = z_thou {
-            if st.z_thou.map_or(true, |pz| pz != z) {
-                out.push_str(&format!(" Z{:.4}", fmt_z_inch(z)));
-                st.z_thou = Some(z);
-                any = true;
-            }
-        }
-
-        if any {
-            out.push('\n');
-        } else {
-            // No axis changed; roll back the prefix.
-            out.truncate(out.len().saturating_sub(2));
-        }
-    }
-
-    fn push_g1(out: &mut String, st: &mut ModalState, x_pix: Option<i32>, y_pix: Option<i32>, z_thou: Option<i32>, feed_ipm: Option<f64>, ppi_f: f64) {
-        let mut any_axis = false;
-        let mut any_feed = false;
-
-        out.push_str("G1");
-
-        if let Some(x) = x_pix {
-            if st.x_pix.map_or(true, |px| px != x) {
-                out.push_str(&format!(" X{:.4}", fmt_xy_inch(x, ppi_f)));
-                st.x_pix = Some(x);
-                any_axis = true;
-            }
-        }
-        if let Some(y) = y_pix {
-            if st.y_pix.map_or(true, |py| py != y) {
-                out.push_str(&format!(" Y{:.4}", fmt_xy_inch(y, ppi_f)));
-                st.y_pix = Some(y);
-                any_axis = true;
-            }
-        }
-        if let Some(z) = z_thou {
-            if st.z_thou.map_or(true, |pz| pz != z) {
-                out.push_str(&format!(" Z{:.4}", fmt_z_inch(z)));
-                st.z_thou = Some(z);
-                any_axis = true;
-            }
-        }
-
-        if let Some(f) = feed_ipm {
-            // Emit F when it differs from the last F we emitted.
-            if st.last_feed_ipm.map_or(true, |lf| (lf - f).abs() > 1e-9) {
-                out.push_str(&format!(" F{:.1}", f));
-                st.last_feed_ipm = Some(f);
-                any_feed = true;
-            }
-        }
-
-        if any_axis || any_feed {
-            out.push('\n');
-        } else {
-            out.truncate(out.len().saturating_sub(2));
-        }
-    }
-
-    fn push_comment(out: &mut String, s: &str) {
-        // Use `()`-style comments for compatibility with many CNC controllers.
-        // Replace any ')' to avoid prematurely closing the comment.
-        let safe = s.replace(')', "]");
-        out.push('(');
-        out.push_str(&safe);
-        out.push_str(")\n");
-    }
-
-    let mut out = String::new();
-    push_comment(&mut out, "rcarve toolpaths");
-    push_comment(
-        &mut out,
-        &format!(
-            "tool_guid={} tool_i={} tool_dia_inch={:.6} tool_dia_pix={} ppi={}",
-            json.tool_guid, json.tool_i, json.tool_dia_inch, json.tool_dia_pix, json.ppi
-        ),
-    );
-
-    // TODO: Softcode
-    out.push_str("G20 (Unit is inches)\n");
-    out.push_str(&format!("G0 Z${} (Unit is inches)\n", CLEARANCE_Z_INCH));
-    out.push_str("M03 (Spindle on)\n");
-
-    let mut st = ModalState::default();
-    // Start with a retract to safe Z (Z-only).
-    push_g0(&mut out, &mut st, None, None, Some(safe_z_thou), ppi_f);
-
-    let mut last_cut_tile_i: Option<usize> = None;
-
-    for (tp_i, tp) in json.toolpaths.iter().enumerate() {
-        let mut pts = tp.points.chunks_exact(3);
-        let Some(first) = pts.next() else {
-            continue;
-        };
-
-        let x0_pix: i32 = first[0];
-        let y0_pix: i32 = first[1];
-        let z0_thou: i32 = first[2];
-
-        if tp.is_cut {
-            if last_cut_tile_i != Some(tp.tile_i) {
-                let tile_1 = tp.tile_i.saturating_add(1);
-                let tile_n = json.tile_n.max(tile_1);
-                push_comment(
-                    &mut out,
-                    &format!("============================= TILE {tile_1} of {tile_n} ============================="),
-                );
-                last_cut_tile_i = Some(tp.tile_i);
-            }
-            push_comment(&mut out, &format!("tp[{tp_i}] cuts=[{}, {}]", tp.cuts[0], tp.cuts[1]));
-
-            // Always retract to safe Z before any XY reposition.
-            push_g0(&mut out, &mut st, None, None, Some(safe_z_thou), ppi_f);
-            // Reposition in XY at safe Z (omit Z if already at safe).
-            push_g0(&mut out, &mut st, Some(x0_pix), Some(y0_pix), None, ppi_f);
-            // Plunge (Z-only) at plunge feed.
-            push_g1(&mut out, &mut st, None, None, Some(z0_thou), Some(PLUNGE_IPM), ppi_f);
-
-            // Follow the polyline at cut feed.
-            for xyz in pts {
-                let x_pix: i32 = xyz[0];
-                let y_pix: i32 = xyz[1];
-                let z_thou: i32 = xyz[2];
-                push_g1(
-                    &mut out,
-                    &mut st,
-                    Some(x_pix),
-                    Some(y_pix),
-                    Some(z_thou),
-                    Some(FEED_IPM),
-                    ppi_f,
-                );
-            }
-        } else {
-            push_comment(
-                &mut out,
-                &format!("---- tp[{tp_i}] traverse"),
-            );
-            // Enforce a conservative traverse policy:
-            // retract to safe Z, traverse in XY at constant safe Z, and let the next cut handle plunging.
-
-            // Retract (Z-only).
-            push_g0(&mut out, &mut st, None, None, Some(safe_z_thou), ppi_f);
-            // Traverse to the first point in XY (no Z changes during traverse).
-            push_g0(&mut out, &mut st, Some(x0_pix), Some(y0_pix), None, ppi_f);
-            for xyz in pts {
-                let x_pix: i32 = xyz[0];
-                let y_pix: i32 = xyz[1];
-                push_g0(&mut out, &mut st, Some(x_pix), Some(y_pix), None, ppi_f);
-            }
-        }
-    }
-
-    // Final retract.
-    push_g0(&mut out, &mut st, None, None, Some(safe_z_thou), ppi_f);
-    out.push_str("M2\n");
-    out
-}
-
 fn main() {
     // Pixels per inch used for conversions between inches and pixels.
     let ppi: usize = 100_usize;
@@ -1006,8 +869,7 @@ fn main() {
         .rough_tool_guid
         .as_ref()
         .expect("No rough tool guid in carve_desc");
-    let (_rough_tool_i, rough_tool_dia_pix) =
-        tool_i_and_dia_pix(&comp_desc.tool_descs, rough_tool_guid, ppi);
+    let (_rough_tool_i, rough_tool_dia_pix) = tool_i_and_dia_pix(&comp_desc, rough_tool_guid, ppi);
     let overlap_pix = rough_tool_dia_pix;
 
     let bulk_top_thou = Thou((comp_desc.dim_desc.bulk_d_inch * 1000.0).round() as i32);
@@ -1048,8 +910,7 @@ fn main() {
     let mut toolpaths_by_tool_i = regroup_toolpaths_by_tool(all_toolpaths);
 
     // Add traverse moves after merging, so transitions can span tile boundaries.
-    let mut base_im = Lum16Im::new(roi.w(), roi.h());
-    base_im.arr.fill(bulk_top_thou.0 as u16);
+    let base_im = sim::make_stock(roi.w(), roi.h(), bulk_top_thou.0 as u16);
     let mut sim_im_for_traverse = base_im.clone();
 
     let n_total_toolpaths: usize = toolpaths_by_tool_i.values().map(|tps| tps.len()).sum();
@@ -1071,12 +932,15 @@ fn main() {
     // Total entries = sum_k (toolpaths_k + traverses_k) = sum_k (2*toolpaths_k - 1).
     let mut all_toolpaths = Vec::with_capacity(n_total_toolpaths * 2);
     for (tool_dia_pix, tool_i) in tools {
-        let tool_desc = comp_desc
-            .tool_descs
-            .get(tool_i)
-            .unwrap_or_else(|| panic!("tool_i {tool_i} out of range for tool_descs (len={})", comp_desc.tool_descs.len()));
+        let tools = comp_desc.tools();
+        let tool_desc = tools.get(tool_i).unwrap_or_else(|| {
+            panic!(
+                "tool_i {tool_i} out of range for the comp's tools (len={})",
+                tools.len()
+            )
+        });
         let tool_guid = tool_desc.guid.to_string();
-        let tool_dia_inch = tool_dia_inch(tool_desc);
+        let tool_dia_inch = tool_desc.dia_inch();
         let safe_tool_guid: String = tool_guid
             .chars()
             .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
@@ -1099,6 +963,7 @@ fn main() {
         let mut toolpaths_iter = toolpaths.into_iter();
         let mut traverses_iter = traverse_toolpaths.into_iter();
         let mut json_toolpaths: Vec<ToolpathOut> = Vec::new();
+        let tool_start_i = all_toolpaths.len();
         while let Some(tp) = toolpaths_iter.next() {
             json_toolpaths.push(toolpath_to_toolpath_out(&tp));
             all_toolpaths.push(tp);
@@ -1131,8 +996,21 @@ fn main() {
         // let gcode_dir = std::path::Path::new("target/gcode");
         // fs::create_dir_all(out_dir).expect("failed to create target/gcode");
         let gcode_path = out_dir.join(format!("tool_{tool_i}_{safe_tool_guid}.nc"));
-        let gcode = to_gcode(&out);
-        fs::write(&gcode_path, gcode)
+        let gcode_opts = gcode::GcodeOpts {
+            pix_scale: units::PixScale::new(out.ppi as f64),
+            tool_guid: out.tool_guid.clone(),
+            tool_i,
+            tool_dia_inch,
+            tool_dia_pix,
+            tile_n,
+            reversal_dwell: None,
+            scale_feed_by_engagement: false,
+        };
+        let f = std::fs::File::create(&gcode_path)
+            .unwrap_or_else(|e| panic!("failed to create {}: {e}", gcode_path.display()));
+        let mut w = BufWriter::new(f);
+        gcode::write_gcode(&mut w, &all_toolpaths[tool_start_i..], &gcode_opts)
+            .and_then(|()| w.flush())
             .unwrap_or_else(|e| panic!("failed to write {}: {e}", gcode_path.display()));
     }
 
@@ -1,3 +1,5 @@
+use std::ops::Mul;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Mat3 {
     // Row-major 3x3 matrix.
@@ -11,6 +13,29 @@ impl Mat3 {
         }
     }
 
+    /// Translation by `(tx, ty)`.
+    pub const fn translate(tx: f64, ty: f64) -> Self {
+        Self {
+            m: [[1.0, 0.0, tx], [0.0, 1.0, ty], [0.0, 0.0, 1.0]],
+        }
+    }
+
+    /// Scale by `sx` along x and `sy` along y, about the origin.
+    pub const fn scale(sx: f64, sy: f64) -> Self {
+        Self {
+            m: [[sx, 0.0, 0.0], [0.0, sy, 0.0], [0.0, 0.0, 1.0]],
+        }
+    }
+
+    /// Rotation by `angle_rad` about the origin, counterclockwise with y up (so clockwise on
+    /// screen in image coordinates, where y points down).
+    pub fn rotate(angle_rad: f64) -> Self {
+        let (sin, cos) = angle_rad.sin_cos();
+        Self {
+            m: [[cos, -sin, 0.0], [sin, cos, 0.0], [0.0, 0.0, 1.0]],
+        }
+    }
+
     /// Constructs a homogeneous 3x3 matrix from a 2D affine transform.
     ///
     /// The expected 6-element layout is `[a, b, c, d, e, f]` such that:
@@ -48,6 +73,57 @@ impl Mat3 {
         (x2, y2)
     }
 
+    /// Applies this transform to a 2D point with the full homogeneous divide, so it also
+    /// handles projective matrices (`transform_point2` assumes the bottom row is `0 0 1`).
+    #[inline]
+    pub fn transform_point(&self, x: f64, y: f64) -> (f64, f64) {
+        let (x2, y2) = self.transform_point2(x, y);
+        let w = self.m[2][0] * x + self.m[2][1] * y + self.m[2][2];
+        (x2 / w, y2 / w)
+    }
+
+    /// The inverse transform, or `None` if the matrix is singular (e.g. a zero scale), so a
+    /// warp can map destination pixels back into the source.
+    pub fn try_inverse(&self) -> Option<Self> {
+        let m = &self.m;
+        // Cofactors of the first row give the determinant.
+        let c00 = m[1][1] * m[2][2] - m[1][2] * m[2][1];
+        let c01 = m[1][2] * m[2][0] - m[1][0] * m[2][2];
+        let c02 = m[1][0] * m[2][1] - m[1][1] * m[2][0];
+        let det = m[0][0] * c00 + m[0][1] * c01 + m[0][2] * c02;
+        if det.abs() < 1e-12 || !det.is_finite() {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        // The inverse is the transposed cofactor matrix over the determinant.
+        let adj = [
+            [
+                c00,
+                m[0][2] * m[2][1] - m[0][1] * m[2][2],
+                m[0][1] * m[1][2] - m[0][2] * m[1][1],
+            ],
+            [
+                c01,
+                m[0][0] * m[2][2] - m[0][2] * m[2][0],
+                m[0][2] * m[1][0] - m[0][0] * m[1][2],
+            ],
+            [
+                c02,
+                m[0][1] * m[2][0] - m[0][0] * m[2][1],
+                m[0][0] * m[1][1] - m[0][1] * m[1][0],
+            ],
+        ];
+        Some(Self {
+            m: adj.map(|row| row.map(|v| v * inv_det)),
+        })
+    }
+
+    /// The inverse transform. Panics if the matrix is singular; see `try_inverse`.
+    pub fn inverse(&self) -> Self {
+        self.try_inverse()
+            .unwrap_or_else(|| panic!("Mat3 is singular and has no inverse: {self:?}"))
+    }
+
     /// Returns a transform that applies this matrix, then translates by `(tx, ty)`.
     ///
     /// This is equivalent to left-multiplying by a translation matrix `T(tx, ty)`.
@@ -65,3 +141,52 @@ impl Default for Mat3 {
         Self::identity()
     }
 }
+
+/// Composition: `a * b` applies `b` first, then `a`.
+impl Mul for Mat3 {
+    type Output = Mat3;
+
+    fn mul(self, rhs: Mat3) -> Mat3 {
+        let mut m = [[0.0; 3]; 3];
+        for (r, row) in m.iter_mut().enumerate() {
+            for (c, v) in row.iter_mut().enumerate() {
+                *v = (0..3).map(|k| self.m[r][k] * rhs.m[k][c]).sum();
+            }
+        }
+        Mat3 { m }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    fn assert_near(a: &Mat3, b: &Mat3) {
+        for r in 0..3 {
+            for c in 0..3 {
+                assert!((a.m[r][c] - b.m[r][c]).abs() < 1e-9, "{a:?} != {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn compose_rotate_and_invert() {
+        let quarter = Mat3::rotate(FRAC_PI_2);
+        let (x, y) = quarter.transform_point(1.0, 0.0);
+        assert!(x.abs() < 1e-12 && (y - 1.0).abs() < 1e-12);
+        assert_near(&(quarter * quarter * quarter * quarter), &Mat3::identity());
+
+        // The right-hand matrix applies first: scale, then translate.
+        let m = Mat3::translate(10.0, -5.0) * Mat3::scale(2.0, 3.0);
+        assert_eq!(m.transform_point(1.0, 1.0), (12.0, -2.0));
+        assert_eq!(m.transform_point2(1.0, 1.0), (12.0, -2.0));
+
+        let inv = (m * quarter).inverse();
+        assert_near(&(inv * m * quarter), &Mat3::identity());
+        let (x, y) = inv.transform_point(12.0, -2.0);
+        assert!((x - 1.0).abs() < 1e-12 && (y + 1.0).abs() < 1e-12);
+
+        assert_eq!(Mat3::scale(0.0, 1.0).try_inverse(), None);
+    }
+}
@@ -0,0 +1,48 @@
+use std::time::{Duration, Instant};
+
+/// Counters and per-phase wall time for profiling toolpath planning.
+///
+/// Set one as `toolpath::RegionToolpathOpts::metrics` to have the planner count its work and
+/// time its phases ("clear", "splat", "above_mask", "dilate", "surfaces", "perimeters").
+/// Callers can time their own phases (e.g. labeling, band building, the tree) into the same
+/// struct with `time`. Reusing one `Metrics` across calls accumulates.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// Region tree nodes toolpaths were generated for (after the band filter).
+    pub nodes_visited: usize,
+    /// Disk dilations run (two per node and perimeter pass: the above and cut masks).
+    pub dilations_run: usize,
+    /// Contours traced from cut masks, before simplification or offsetting.
+    pub contours_traced: usize,
+    /// Mask pixels written or scanned: full-image clears, splats, and the padded ROI of each
+    /// dilation pass. Useful for measuring how much ROI-limited work would save.
+    pub pixels_touched: u64,
+    /// Elapsed time per phase, in the order each phase was first recorded.
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+impl Metrics {
+    /// Add `elapsed` to `phase`'s total.
+    pub fn add_time(&mut self, phase: &'static str, elapsed: Duration) {
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+    }
+
+    /// Run `f`, adding its wall time to `phase`.
+    pub fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let t0 = Instant::now();
+        let out = f();
+        self.add_time(phase, t0.elapsed());
+        out
+    }
+
+    /// Total time recorded for `phase` (zero if it never ran).
+    pub fn phase_time(&self, phase: &str) -> Duration {
+        self.phases
+            .iter()
+            .find(|(name, _)| *name == phase)
+            .map_or(Duration::ZERO, |(_, total)| *total)
+    }
+}
@@ -1,15 +1,18 @@
-use crate::desc::{BandDesc, PlyDesc};
-use crate::desc::{Guid, Thou};
+use crate::desc::{BandDesc, ClearMode, PlyDesc};
+use crate::desc::{Guid, Thou, ThouRange};
+use crate::dilate_im::im_dilate;
 use crate::im::Im;
 use crate::im::MaskIm;
 use crate::im::label::LabelInfo;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
 
 macro_rules! newtype {
     ($name:ident($inner:ty)) => {
-        #[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+        #[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
+        #[serde(transparent)]
         pub struct $name(pub $inner);
     };
 }
@@ -55,6 +58,12 @@ pub struct CutBand {
     pub cut_planes: Vec<CutPlane>,
 }
 
+impl CutBand {
+    pub fn range(&self) -> ThouRange {
+        ThouRange::new(self.top_thou, self.bot_thou)
+    }
+}
+
 /// Consider a height-map like the following.
 ///   For convenience call "1" the ocean; "3" the island; "4" the volcano; and "2" the lake.
 ///   Note that there is some sea level area inside the lake. We say that "1" has two disjoint regions.
@@ -167,6 +176,9 @@ pub struct CutBand {
 /// create_cut_bands creates the CutBands for a given cut_pass
 /// Create one CutBand instance per BandDesc that matches the cut_pass.
 /// Create 1+ CutPlanes for each CutBand; one per labeled region in the ply_im that falls within the band's thou range plus a floor.
+/// No matching bands gives no CutBands. Panics on a matching band with `top_thou <= bot_thou`,
+/// which would hold no plies; `parse_comp_json` already rejects those, so only hand-built band
+/// descs can get here.
 pub fn create_cut_bands(
     cut_pass: &str, // A name for the cut pass, e.g. "rough", "refine_smooth", "refine_perimeter", "detail"
     ply_im: &PlyIm, // The ply vals are sorted so that higher thou values have higher ply vals.
@@ -185,6 +197,15 @@ pub fn create_cut_bands(
         "ply_descs[0] must be a dummy ply with top_thou 0 and hidden=true"
     );
 
+    for (band_i, bd) in band_descs.iter().enumerate() {
+        assert!(
+            bd.cut_pass != cut_pass || bd.top_thou > bd.bot_thou,
+            "band_descs[{band_i}] has top_thou {} <= bot_thou {}; bands need a positive height",
+            bd.top_thou.0,
+            bd.bot_thou.0
+        );
+    }
+
     let mut cut_bands: Vec<CutBand> = band_descs
         .iter()
         .filter(|bd| bd.cut_pass == cut_pass)
@@ -204,14 +225,9 @@ pub fn create_cut_bands(
         }
 
         let ply_i = PlyI(ply_i_usize as u16);
-        let thou = &ply_desc.top_thou;
-
         // Find the band that this ply belongs to
         for band in cut_bands.iter_mut() {
-            let band_top = &band.top_thou;
-            let band_bot = &band.bot_thou;
-
-            if band_bot <= thou && thou < band_top {
+            if band.range().contains(ply_desc.top_thou) {
                 // This ply belongs to this band
                 let cut_plane = CutPlane {
                     ply_guid: ply_desc.guid.clone(),
@@ -263,7 +279,7 @@ pub fn create_cut_bands(
             match (a.is_floor, b.is_floor) {
                 (true, false) => Ordering::Greater,
                 (false, true) => Ordering::Less,
-                _ => b.top_thou.0.cmp(&a.top_thou.0),
+                _ => b.top_thou.cmp(&a.top_thou),
             }
         });
 
@@ -317,6 +333,223 @@ pub fn create_cut_bands(
     cut_bands
 }
 
+/// The regions whose `LabelInfo` satisfies `pred`, e.g. to drop slivers before building the
+/// region tree (see `retain_regions`). The background (`infos[0]`) is never included.
+/// Predicates compose as closures, e.g. `|info| min_area(20)(info) && max_aspect(8.0)(info)`.
+pub fn filter_regions(infos: &[LabelInfo], pred: impl Fn(&LabelInfo) -> bool) -> Vec<RegionI> {
+    infos
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, info)| pred(info))
+        .map(|(i, _)| RegionI(i as u16))
+        .collect()
+}
+
+/// `filter_regions` predicate: at least `min_px` pixels.
+pub fn min_area(min_px: usize) -> impl Fn(&LabelInfo) -> bool {
+    move |info| info.size >= min_px
+}
+
+/// `filter_regions` predicate: the region fills at least `min_ratio` of its ROI.
+/// Thin diagonal slivers fill little of their bounding box.
+pub fn min_fill_ratio(min_ratio: f64) -> impl Fn(&LabelInfo) -> bool {
+    move |info| {
+        let roi_area = info.roi.w() * info.roi.h();
+        roi_area > 0 && info.size as f64 / roi_area as f64 >= min_ratio
+    }
+}
+
+/// `filter_regions` predicate: the ROI's long side is at most `max_ratio` times its short side.
+pub fn max_aspect(max_ratio: f64) -> impl Fn(&LabelInfo) -> bool {
+    move |info| {
+        let (w, h) = (info.roi.w(), info.roi.h());
+        let short = w.min(h);
+        short > 0 && w.max(h) as f64 / short as f64 <= max_ratio
+    }
+}
+
+/// Drop every region not in `keep` from the cut planes, so `create_region_tree` builds no
+/// nodes for them (and floors don't count them as below).
+pub fn retain_regions(cut_bands: &mut [CutBand], keep: &[RegionI]) {
+    for band in cut_bands.iter_mut() {
+        for cut_plane in band.cut_planes.iter_mut() {
+            cut_plane.region_iz.retain(|r| keep.contains(r));
+        }
+    }
+}
+
+/// The largest ply index whose top_thou is <= `depth_thou` (0 if none).
+/// Pixels with a higher ply index are "above" this depth and must be excluded from a cut there.
+pub fn ply_threshold_at_depth(cut_bands: &[CutBand], depth_thou: Thou) -> u16 {
+    let mut best: u16 = 0;
+    for band in cut_bands {
+        for cp in &band.cut_planes {
+            if cp.is_floor {
+                continue;
+            }
+            if cp.top_thou <= depth_thou {
+                best = best.max(cp.ply_i.0);
+            }
+        }
+    }
+    best
+}
+
+/// Fill in every CutPlane's `pos_work_im` and `cut_im`.
+/// The pos_work_im is the pixels of the plane's regions; for a floor it is every ply at or below
+/// the band's bottom (the same threshold `create_toolpaths_from_region_tree` uses).
+/// The cut_im is the pos_work_im dilated by the tool radius, minus everything above the plane
+/// (and the image edges) dilated by the same radius.
+/// The dummy plane (non-floor ply_i 0) is left as None.
+pub fn render_cut_plane_masks(
+    cut_bands: &mut [CutBand],
+    ply_im: &PlyIm,
+    region_infos: &[LabelInfo],
+    tool_dia_pix: usize,
+) {
+    let w = ply_im.w;
+    let h = ply_im.h;
+    if w == 0 || h == 0 {
+        return;
+    }
+
+    // Same radius -> diameter convention as the toolpath generator.
+    let rad_pix = tool_dia_pix / 2;
+    let dia_pix = rad_pix
+        .saturating_mul(2)
+        .saturating_add(1)
+        .min(w.min(h).max(1));
+
+    let floor_thresholds: Vec<u16> = cut_bands
+        .iter()
+        .map(|band| ply_threshold_at_depth(cut_bands, band.bot_thou))
+        .collect();
+
+    let full_roi = crate::im::ROI {
+        l: 0,
+        t: 0,
+        r: w,
+        b: h,
+    };
+    let mut above_im = MaskIm::new(w, h);
+    let mut dil_above_im = MaskIm::new(w, h);
+
+    for (band_i, band) in cut_bands.iter_mut().enumerate() {
+        for cut_plane in band.cut_planes.iter_mut() {
+            if !cut_plane.is_floor && cut_plane.ply_i.0 == 0 {
+                continue;
+            }
+
+            let mut pos_work_im = MaskIm::new(w, h);
+            let threshold = if cut_plane.is_floor {
+                let threshold = floor_thresholds[band_i];
+                for y in 0..h {
+                    for x in 0..w {
+                        let v = ply_im.arr[y * ply_im.s + x];
+                        if v != 0 && v <= threshold {
+                            pos_work_im.arr[y * pos_work_im.s + x] = 255;
+                        }
+                    }
+                }
+                threshold
+            } else {
+                for region_i in &cut_plane.region_iz {
+                    for &i in &region_infos[region_i.0 as usize].pixel_iz {
+                        // pixel_iz are ply_im indices; map them through the stride.
+                        let (x, y) = (i % ply_im.s, i / ply_im.s);
+                        pos_work_im.arr[y * pos_work_im.s + x] = 255;
+                    }
+                }
+                cut_plane.ply_i.0
+            };
+
+            above_im.arr.fill(0);
+            for y in 0..h {
+                for x in 0..w {
+                    if ply_im.arr[y * ply_im.s + x] > threshold {
+                        above_im.arr[y * above_im.s + x] = 255;
+                    }
+                }
+            }
+            above_im.one_pixel_border_on_image_edges_over_roi_span(full_roi, 255);
+            im_dilate(&above_im, &mut dil_above_im, dia_pix);
+
+            let mut cut_im = MaskIm::new(w, h);
+            im_dilate(&pos_work_im, &mut cut_im, dia_pix);
+            for (c, &a) in cut_im.arr.iter_mut().zip(dil_above_im.arr.iter()) {
+                if a > 0 {
+                    *c = 0;
+                }
+            }
+
+            cut_plane.pos_work_im = Some(pos_work_im);
+            cut_plane.cut_im = Some(cut_im);
+        }
+    }
+}
+
+/// (ply value, pixel count) for every value present in the ply_im, sorted by value.
+pub fn ply_value_histogram(ply_im: &PlyIm) -> Vec<(u16, usize)> {
+    let mut counts: HashMap<u16, usize> = HashMap::new();
+    for y in 0..ply_im.h {
+        let row = y * ply_im.s;
+        for &v in &ply_im.arr[row..row + ply_im.w] {
+            *counts.entry(v).or_insert(0) += 1;
+        }
+    }
+    let mut hist: Vec<(u16, usize)> = counts.into_iter().collect();
+    hist.sort_by_key(|&(v, _)| v);
+    hist
+}
+
+/// Propose bands (top to bottom) covering every ply present in the ply_im so that no band
+/// is deeper than `max_doc_thou`. Plies are grouped greedily from the top; a single ply step
+/// deeper than `max_doc_thou` still gets its own band since it can't be split here.
+///
+/// Bands follow the `bot_thou <= ply.top_thou < top_thou` membership used by `create_cut_bands`,
+/// so each band's top is the one above's bottom and the first band's top is one thou above
+/// the highest ply.
+pub fn suggest_bands(
+    ply_im: &PlyIm,
+    ply_descs: &[PlyDesc], // Indexed by ply value, [0] is the dummy
+    max_doc_thou: Thou,
+    cut_pass: &str,
+) -> Vec<BandDesc> {
+    // Distinct ply tops present in the image, top to bottom.
+    let mut levels: Vec<i32> = ply_value_histogram(ply_im)
+        .into_iter()
+        .filter(|&(v, _)| v != 0)
+        .filter_map(|(v, _)| ply_descs.get(v as usize).map(|pd| pd.top_thou.0))
+        .collect();
+    levels.sort_unstable_by(|a, b| b.cmp(a));
+    levels.dedup();
+
+    let mut bands: Vec<BandDesc> = Vec::new();
+    let Some(&highest) = levels.first() else {
+        return bands;
+    };
+
+    let mut top = highest + 1;
+    let mut i = 0;
+    while i < levels.len() {
+        // Always take at least one level, then keep going while the band stays shallow enough.
+        let mut j = i;
+        while j + 1 < levels.len() && top - levels[j + 1] <= max_doc_thou.0 {
+            j += 1;
+        }
+        bands.push(BandDesc {
+            top_thou: Thou(top),
+            bot_thou: Thou(levels[j]),
+            cut_pass: cut_pass.to_string(),
+            clear_mode: ClearMode::Full,
+        });
+        top = levels[j];
+        i = j + 1;
+    }
+    bands
+}
+
 pub fn debug_print_cut_bands(cut_bands: &Vec<CutBand>) {
     for (band_i, band) in cut_bands.iter().enumerate() {
         println!(
@@ -347,40 +580,134 @@ pub fn debug_print_cut_bands(cut_bands: &Vec<CutBand>) {
 
 // Siblings are represented by ordering within a Vec in the parent.
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(from = "RegionRootRaw")]
 pub struct RegionRoot {
     children: Vec<RegionNode>,
-    /// Lookup table: `node_id -> *const RegionNode`.
-    ///
-    /// This is the Rust equivalent of a C `RegionNode* node_i_to_region_node[]`.
+    /// Flattened lookup table: `node_id -> sibling-index path from the root`.
     ///
-    /// Safety/invariant: pointers are valid as long as the tree is not structurally mutated
-    /// (no pushes, no swaps, no reordering) after `create_region_tree` returns.
-    node_i_to_region_node: Vec<*const RegionNode>,
+    /// Node ids are dense (`0..get_n_nodes()`) and assigned in carve (pre-order DFS) order,
+    /// so callers can index per-node data with a `Vec` instead of a map.
+    /// Paths (rather than pointers) keep the table valid across `Clone`.
+    /// Derived from `children`, so it is not serialized but rebuilt on load.
+    #[serde(skip)]
+    node_i_to_path: Vec<Vec<usize>>,
+}
+
+/// A region tree as written in the JSON: just the nodes, without the id lookup table.
+#[derive(Deserialize)]
+struct RegionRootRaw {
+    children: Vec<RegionNode>,
+}
+
+impl From<RegionRootRaw> for RegionRoot {
+    fn from(raw: RegionRootRaw) -> Self {
+        RegionRoot::from_sorted_roots(raw.children)
+    }
 }
 
 impl RegionRoot {
+    /// Wrap a finished, sorted forest: (re)assign node ids and parent links in carve order
+    /// and build the `node_i_to_path` lookup table.
+    fn from_sorted_roots(mut roots: Vec<RegionNode>) -> Self {
+        let mut next_id = 0usize;
+        assign_ids_and_parents(&mut roots, None, &mut next_id);
+
+        // Build node_id -> path LUT.
+        let mut node_i_to_path: Vec<Vec<usize>> = vec![Vec::new(); next_id];
+        fn fill_lut(nodes: &[RegionNode], path: &mut Vec<usize>, lut: &mut [Vec<usize>]) {
+            for (i, n) in nodes.iter().enumerate() {
+                path.push(i);
+                let id = n.get_id();
+                if id < lut.len() {
+                    lut[id] = path.clone();
+                }
+                if let RegionNode::Floor { children, .. } = n {
+                    fill_lut(children, path, lut);
+                }
+                path.pop();
+            }
+        }
+        fill_lut(&roots, &mut Vec::new(), &mut node_i_to_path);
+        debug_assert!(
+            node_i_to_path.iter().all(|p| !p.is_empty()),
+            "invariant: every node_id must have a valid path in the LUT"
+        );
+
+        RegionRoot {
+            children: roots,
+            node_i_to_path,
+        }
+    }
+
     pub fn children(&self) -> &[RegionNode] {
         &self.children
     }
 
-    pub fn get_node_by_id(&self, node_id: usize) -> Option<&RegionNode> {
-        let ptr = *self.node_i_to_region_node.get(node_id)?;
-        if ptr.is_null() {
-            return None;
+    /// Look up a node by its `node_id` (e.g. a `ToolPath::tree_node_id`).
+    pub fn node_by_id(&self, node_id: usize) -> Option<&RegionNode> {
+        let path = self.node_i_to_path.get(node_id)?;
+        let (&first, rest) = path.split_first()?;
+        let mut node = self.children.get(first)?;
+        for &child_i in rest {
+            let RegionNode::Floor { children, .. } = node else {
+                return None;
+            };
+            node = children.get(child_i)?;
         }
-        // SAFETY: `node_i_to_region_node` is built from addresses of nodes owned by `self.children`.
-        // `children` is private, so external callers cannot structurally mutate the tree and
-        // invalidate pointers.
-        Some(unsafe { &*ptr })
+        Some(node)
     }
 
+    /// The number of nodes in the tree. Node ids are exactly `0..get_n_nodes()`.
     pub fn get_n_nodes(&self) -> usize {
-        self.node_i_to_region_node.len()
+        self.node_i_to_path.len()
+    }
+
+    /// Iterate every node in carve order: each node is followed immediately by its
+    /// subtree (a floor reveals its children), siblings in as-built order.
+    /// This is the order `sort_toolpaths` visits nodes in.
+    pub fn iter_dfs(&self) -> impl Iterator<Item = &RegionNode> {
+        RegionDfsIter {
+            stack: vec![self.children.iter()],
+        }
+    }
+
+    /// Iterate only the Cut leaves, in carve order.
+    pub fn iter_cut_leaves(&self) -> impl Iterator<Item = &RegionNode> {
+        self.iter_dfs()
+            .filter(|n| matches!(n, RegionNode::Cut { .. }))
     }
 }
 
-#[derive(Clone, Debug)]
+/// Pre-order depth-first walk over a RegionNode forest using an explicit stack of sibling iterators.
+struct RegionDfsIter<'a> {
+    stack: Vec<std::slice::Iter<'a, RegionNode>>,
+}
+
+impl<'a> Iterator for RegionDfsIter<'a> {
+    type Item = &'a RegionNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let siblings = self.stack.last_mut()?;
+            match siblings.next() {
+                Some(node) => {
+                    if let RegionNode::Floor { children, .. } = node
+                        && !children.is_empty()
+                    {
+                        self.stack.push(children.iter());
+                    }
+                    return Some(node);
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum RegionNode {
     /// A floor node gates access to deeper bands.
     /// Cutting the floor reveals its children, which are 1+ regions in lower bands.
@@ -421,6 +748,13 @@ impl RegionNode {
             RegionNode::Cut { node_id, .. } => *node_id,
         }
     }
+
+    pub fn band_i(&self) -> usize {
+        match self {
+            RegionNode::Floor { band_i, .. } => *band_i,
+            RegionNode::Cut { band_i, .. } => *band_i,
+        }
+    }
 }
 
 impl fmt::Display for RegionNode {
@@ -500,6 +834,74 @@ fn assign_ids_and_parents(nodes: &mut [RegionNode], parent_id: Option<usize>, ne
     }
 }
 
+/// Which parent floor component (`region_to_floor` value) contains a child made of `region_iz`.
+///
+/// Normally one of the child's own regions is in a parent floor. If none is, walk the region
+/// adjacency outwards (breadth first) to the nearest region that is, so a child is never attached
+/// to a floor component that it doesn't touch. Returns None when nothing connects.
+fn floor_containing_regions(
+    region_iz: &[usize],
+    region_to_floor: &HashMap<usize, usize>,
+    region_infos: &[LabelInfo],
+) -> Option<usize> {
+    if let Some(floor_off) = region_iz
+        .iter()
+        .find_map(|r| region_to_floor.get(r).copied())
+    {
+        return Some(floor_off);
+    }
+
+    let mut visited: Vec<bool> = vec![false; region_infos.len()];
+    let mut queue: std::collections::VecDeque<usize> = std::collections::VecDeque::new();
+    for &r in region_iz {
+        if r != 0 && r < region_infos.len() && !visited[r] {
+            visited[r] = true;
+            queue.push_back(r);
+        }
+    }
+    while let Some(cur) = queue.pop_front() {
+        if let Some(&floor_off) = region_to_floor.get(&cur) {
+            return Some(floor_off);
+        }
+        // Sort the neighbors so ties resolve the same way on every run.
+        let mut neighbors: Vec<usize> = region_infos[cur].neighbors.keys().copied().collect();
+        neighbors.sort_unstable();
+        for n in neighbors {
+            if n != 0 && n < region_infos.len() && !visited[n] {
+                visited[n] = true;
+                queue.push_back(n);
+            }
+        }
+    }
+    None
+}
+
+/// The order siblings are kept in, so the tree (and the cutting order `sort_toolpaths` derives
+/// from it) doesn't depend on how the nodes were bucketed: cuts before the floors (which stay
+/// a suffix), then by band, cut plane and region. Region labels are unique within a band
+/// (a floor keys on its lowest one), so the order is total.
+fn sibling_order_key(node: &RegionNode) -> (bool, usize, usize, u16) {
+    match node {
+        RegionNode::Cut {
+            band_i,
+            cut_plane_i,
+            region_i,
+            ..
+        } => (false, *band_i, *cut_plane_i, region_i.0),
+        RegionNode::Floor {
+            band_i,
+            cut_plane_i,
+            region_iz,
+            ..
+        } => (
+            true,
+            *band_i,
+            *cut_plane_i,
+            region_iz.iter().map(|r| r.0).min().unwrap_or(0),
+        ),
+    }
+}
+
 /// A RegionNode represents a single region. But the Floor nodes are special, they represent the union multiple regions below them. Many of those child regions will be contiguous but sometimes there mught be discontiguous parts. In the discontiguous case there'd be more than one floor RegionNode in a given band. So I need to rethink how to model this,. I'm thinknig that in create_region_tree in each band I make a new maskIm for each floor by using the ply_im to extrqact all pixels where the ply_i < the smallest ply_i of the current band. Then we call label on
 
 /// Create a region tree for depth-first traversal.
@@ -514,7 +916,16 @@ fn assign_ids_and_parents(nodes: &mut [RegionNode], parent_id: Option<usize>, ne
 ///
 /// The returned root is a synthetic entry point that owns only the node forest;
 /// `cut_bands` remain owned by the caller.
-pub fn create_region_tree(cut_bands: &[CutBand], region_infos: &[LabelInfo]) -> RegionRoot {
+///
+/// Cut leaves for regions smaller than `min_region_area_px` pixels are dropped (0 keeps all),
+/// since specks only produce degenerate toolpaths. Floors are still built from every region
+/// below their band, so a small region never removes a floor; a floor is only pruned if it
+/// ends up gating nothing.
+pub fn create_region_tree(
+    cut_bands: &[CutBand],
+    region_infos: &[LabelInfo],
+    min_region_area_px: usize,
+) -> RegionRoot {
     if cut_bands.is_empty() {
         return RegionRoot::default();
     }
@@ -571,6 +982,13 @@ pub fn create_region_tree(cut_bands: &[CutBand], region_infos: &[LabelInfo]) ->
                 continue;
             }
             for &region_i in &cut_plane.region_iz {
+                let region_size = region_infos
+                    .get(region_i.0 as usize)
+                    .map(|ri| ri.size)
+                    .unwrap_or(0);
+                if region_size < min_region_area_px {
+                    continue;
+                }
                 let z_thou = cut_plane.top_thou.clone();
 
                 nodes_within_band.push(RegionNode::Cut {
@@ -581,10 +999,7 @@ pub fn create_region_tree(cut_bands: &[CutBand], region_infos: &[LabelInfo]) ->
                     ply_guid: cut_plane.ply_guid.clone(),
                     top_thou: cut_plane.top_thou.clone(),
                     region_i,
-                    region_size: region_infos
-                        .get(region_i.0 as usize)
-                        .map(|ri| ri.size)
-                        .unwrap_or(0),
+                    region_size,
                     z_thou
                 });
             }
@@ -595,7 +1010,7 @@ pub fn create_region_tree(cut_bands: &[CutBand], region_infos: &[LabelInfo]) ->
         let mut is_below: Vec<bool> = vec![false; region_infos.len()];
         for region_id in 1..region_top_thou.len() {
             if let Some(thou) = &region_top_thou[region_id] {
-                if thou.0 < band.bot_thou.0 {
+                if *thou < band.bot_thou {
                     is_below[region_id] = true;
                 }
             }
@@ -699,14 +1114,17 @@ pub fn create_region_tree(cut_bands: &[CutBand], region_infos: &[LabelInfo]) ->
 
         let mut buckets: Vec<Vec<RegionNode>> = vec![Vec::new(); parent_floors_len];
         for child in next_band_nodes {
-            let rep_region: Option<usize> = match &child {
-                RegionNode::Cut { region_i, .. } => Some(region_i.0 as usize),
-                RegionNode::Floor { region_iz, .. } => region_iz.first().map(|r| r.0 as usize),
+            let child_region_iz: Vec<usize> = match &child {
+                RegionNode::Cut { region_i, .. } => vec![region_i.0 as usize],
+                RegionNode::Floor { region_iz, .. } => {
+                    region_iz.iter().map(|r| r.0 as usize).collect()
+                }
             };
 
-            let floor_off = rep_region
-                .and_then(|rid| region_to_floor.get(&rid).copied())
-                .unwrap_or(0);
+            // Only the degenerate (empty) floor has nothing to route by; send it to floor 0.
+            let floor_off =
+                floor_containing_regions(&child_region_iz, &region_to_floor, region_infos)
+                    .unwrap_or(0);
             buckets[floor_off].push(child);
         }
 
@@ -715,6 +1133,7 @@ pub fn create_region_tree(cut_bands: &[CutBand], region_infos: &[LabelInfo]) ->
             match node {
                 RegionNode::Floor { children: c, .. } => {
                     *c = std::mem::take(&mut buckets[floor_off]);
+                    c.sort_by_key(sibling_order_key);
                 }
                 RegionNode::Cut { .. } => unreachable!("floors suffix must contain only floors"),
             }
@@ -737,35 +1156,25 @@ pub fn create_region_tree(cut_bands: &[CutBand], region_infos: &[LabelInfo]) ->
     }
 
     let mut roots = std::mem::take(&mut nodes_per_band[0]);
+    roots.sort_by_key(sibling_order_key);
     prune_empty_floors(&mut roots);
 
     // Now that the tree structure is finalized, assign stable ids and parent links.
-    let mut next_id = 0usize;
-    assign_ids_and_parents(&mut roots, None, &mut next_id);
-
-    // Build node_id -> pointer LUT.
-    let mut node_i_to_region_node: Vec<*const RegionNode> = vec![std::ptr::null(); next_id];
-    fn fill_lut(nodes: &[RegionNode], lut: &mut [*const RegionNode]) {
-        for n in nodes {
-            let id = n.get_id();
-            if id < lut.len() {
-                lut[id] = n as *const RegionNode;
-            }
-            if let RegionNode::Floor { children, .. } = n {
-                fill_lut(children, lut);
-            }
-        }
-    }
-    fill_lut(&roots, &mut node_i_to_region_node);
-    debug_assert!(
-        node_i_to_region_node.iter().all(|p| !p.is_null()),
-        "invariant: every node_id must have a valid pointer in the LUT"
-    );
+    RegionRoot::from_sorted_roots(roots)
+}
 
-    RegionRoot {
-        children: roots,
-        node_i_to_region_node,
-    }
+/// Serialize a region tree to JSON so it can be cached alongside its toolpaths.
+///
+/// Nodes refer to `cut_bands` (`band_i`, `cut_plane_i`) and to the labeling's `region_infos`
+/// (`region_i`, `region_iz`) by index only; neither is stored. A reloaded tree is only
+/// meaningful against the same cut bands and region labeling it was built from.
+pub fn region_tree_to_json(root: &RegionRoot) -> String {
+    serde_json::to_string(root).expect("RegionRoot serialization cannot fail")
+}
+
+/// Inverse of `region_tree_to_json`. See there for what the tree must be paired with.
+pub fn region_tree_from_json(json_text: &str) -> Result<RegionRoot, serde_json::Error> {
+    serde_json::from_str(json_text)
 }
 
 pub fn debug_print_region_tree(
@@ -844,6 +1253,232 @@ mod tests {
         out
     }
 
+    #[test]
+    fn filter_regions_drops_slivers() {
+        // A 4x4 block of ply 2, a one-pixel-wide sliver of ply 3, and a lone ply 4 pixel.
+        let ply_im = ply_im_from_ascii(
+            r#"
+                1111111111
+                1222211111
+                1222211111
+                1222213333
+                1222211114
+            "#,
+        );
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+        let region_im: RegionIm = region_im_raw.retag::<RegionI>();
+        let region_of = |x: usize, y: usize| RegionI(region_im.arr[y * region_im.s + x]);
+        let background = region_of(0, 0);
+        let block = region_of(1, 1);
+        let sliver = region_of(6, 3);
+        let speck = region_of(9, 4);
+
+        let all = filter_regions(&region_infos, |_| true);
+        assert_eq!(all.len(), region_infos.len() - 1);
+
+        let kept = filter_regions(&region_infos, min_area(2));
+        assert!(kept.contains(&block) && kept.contains(&sliver) && !kept.contains(&speck));
+
+        let kept = filter_regions(&region_infos, max_aspect(2.0));
+        assert!(kept.contains(&block) && !kept.contains(&sliver) && kept.contains(&speck));
+
+        // The L-shaped background fills little of its ROI.
+        let kept = filter_regions(&region_infos, min_fill_ratio(0.9));
+        assert!(kept.contains(&block) && kept.contains(&sliver) && !kept.contains(&background));
+
+        // The sliver and the speck each fail one; the tree then has no nodes for them.
+        let kept = filter_regions(&region_infos, |info| {
+            min_area(2)(info) && max_aspect(2.0)(info)
+        });
+        let mut expected = vec![background, block];
+        expected.sort();
+        assert_eq!(kept, expected);
+
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+            stub_ply_desc("ply200", 200, false),
+            stub_ply_desc("ply300", 300, false),
+            stub_ply_desc("ply400", 400, false),
+        ];
+        let band_descs = vec![stub_band_desc(500, 0, "rough")];
+        let mut cut_bands = create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+        retain_regions(&mut cut_bands, &kept);
+        let root = create_region_tree(&cut_bands, &region_infos, 0);
+        let mut cut_regions: Vec<RegionI> = root
+            .iter_dfs()
+            .filter_map(|node| match node {
+                RegionNode::Cut { region_i, .. } => Some(*region_i),
+                RegionNode::Floor { .. } => None,
+            })
+            .collect();
+        cut_regions.sort();
+        assert_eq!(cut_regions, kept);
+    }
+
+    #[test]
+    fn empty_band_descs_give_an_empty_tree() {
+        let ply_im = ply_im_from_ascii(
+            r#"
+                1111
+                1221
+                1111
+            "#,
+        );
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+            stub_ply_desc("ply200", 200, false),
+        ];
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+        let region_im: RegionIm = region_im_raw.retag::<RegionI>();
+
+        // Inverted bands of other passes are not this pass's problem.
+        let band_descs = vec![stub_band_desc(0, 300, "refine")];
+        let cut_bands = create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+        assert!(cut_bands.is_empty());
+        let root = create_region_tree(&cut_bands, &region_infos, 0);
+        assert_eq!(root.get_n_nodes(), 0);
+        assert!(root.children().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "band_descs[0] has top_thou 0 <= bot_thou 300")]
+    fn inverted_band_is_rejected() {
+        let ply_im = ply_im_from_ascii(
+            r#"
+                1111
+                1221
+                1111
+            "#,
+        );
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+            stub_ply_desc("ply200", 200, false),
+        ];
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+        let region_im: RegionIm = region_im_raw.retag::<RegionI>();
+        let band_descs = vec![stub_band_desc(0, 300, "rough")];
+        create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+    }
+
+    #[test]
+    fn ply_histogram_and_suggested_bands() {
+        let ply_im = ply_im_from_ascii(
+            r#"
+                11111
+                12231
+                12241
+            "#,
+        );
+        assert_eq!(
+            ply_value_histogram(&ply_im),
+            vec![(1, 9), (2, 4), (3, 1), (4, 1)]
+        );
+
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+            stub_ply_desc("ply200", 200, false),
+            stub_ply_desc("ply300", 300, false),
+            stub_ply_desc("ply400", 400, false),
+        ];
+        let bands = suggest_bands(&ply_im, &ply_descs, Thou(150), "rough");
+        let ranges: Vec<(i32, i32)> = bands.iter().map(|b| (b.top_thou.0, b.bot_thou.0)).collect();
+        assert_eq!(ranges, vec![(401, 300), (300, 200), (200, 100)]);
+        assert!(bands.iter().all(|b| b.cut_pass == "rough"));
+
+        // Every present ply lands in exactly one suggested band.
+        for ply_i in 1..=4 {
+            let t = ply_descs[ply_i].top_thou;
+            let n = bands.iter().filter(|b| b.range().contains(t)).count();
+            assert_eq!(n, 1, "ply_i={ply_i}");
+        }
+    }
+
+    #[test]
+    fn render_cut_plane_masks_fills_pos_work_and_cut_ims() {
+        let ply_im = ply_im_from_ascii(
+            r#"
+                111111111
+                122222221
+                122232221
+                122222221
+                111111111
+            "#,
+        );
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+            stub_ply_desc("ply200", 200, false),
+            stub_ply_desc("ply300", 300, false),
+        ];
+        let band_descs = vec![stub_band_desc(400, 100, "rough")];
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+        let region_im: RegionIm = region_im_raw.retag::<RegionI>();
+        let mut cut_bands = create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+        let n_set = |m: &MaskIm| m.arr.iter().filter(|&&v| v > 0).count();
+        let plane = |cut_bands: &[CutBand], ply_i: u16, is_floor: bool| -> (MaskIm, MaskIm) {
+            let cp = cut_bands[0]
+                .cut_planes
+                .iter()
+                .find(|cp| cp.is_floor == is_floor && (is_floor || cp.ply_i.0 == ply_i))
+                .unwrap();
+            (cp.pos_work_im.clone().unwrap(), cp.cut_im.clone().unwrap())
+        };
+
+        // A 1-pixel tool: the cut masks are just where each ply is, less the image edges.
+        render_cut_plane_masks(&mut cut_bands, &ply_im, &region_infos, 1);
+        let (pos3, cut3) = plane(&cut_bands, 3, false);
+        assert_eq!((n_set(&pos3), n_set(&cut3)), (1, 1));
+        let (pos2, cut2) = plane(&cut_bands, 2, false);
+        assert_eq!((n_set(&pos2), n_set(&cut2)), (20, 20));
+        assert_eq!(pos2.arr[2 * pos2.s + 4], 0);
+        // The floor sits at ply 1 (band bottom) and everything higher is above it.
+        let (pos_floor, cut_floor) = plane(&cut_bands, 0, true);
+        assert_eq!(n_set(&pos_floor), 24);
+        assert_eq!(n_set(&cut_floor), 0);
+
+        // A 3-pixel tool: ply 2 can only be cut where the tool centre keeps a pixel away
+        // from the ply 3 bump and from the image edges.
+        render_cut_plane_masks(&mut cut_bands, &ply_im, &region_infos, 3);
+        let (_, cut2) = plane(&cut_bands, 2, false);
+        let set: Vec<(usize, usize)> = (0..cut2.h)
+            .flat_map(|y| (0..cut2.w).map(move |x| (x, y)))
+            .filter(|&(x, y)| cut2.arr[y * cut2.s + x] > 0)
+            .collect();
+        assert_eq!(set, vec![(2, 2), (6, 2)]);
+    }
+
     #[test]
     fn it_creates_bands() {
         let ply_im = ply_im_from_ascii(
@@ -972,13 +1607,225 @@ mod tests {
             &ply_descs,
         );
 
-        let root = create_region_tree(&cut_bands, &region_infos);
+        let root = create_region_tree(&cut_bands, &region_infos, 0);
 
         // With no labeled regions, there are no Cut nodes, and (after pruning)
         // there is no need to keep Floor nodes that don't gate anything.
         assert_eq!(root.children().len(), 0);
     }
 
+    #[test]
+    fn region_tree_round_trips_through_json() {
+        let ply_im = ply_im_from_ascii(
+            r#"
+                3333333333333
+                3222233222223
+                3211233221223
+                3222233222223
+                3333333333333
+            "#,
+        );
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+            stub_ply_desc("ply400", 400, false),
+            stub_ply_desc("ply700", 700, false),
+        ];
+        let band_descs = vec![
+            stub_band_desc(1000, 600, "rough"),
+            stub_band_desc(600, 300, "rough"),
+            stub_band_desc(300, 0, "rough"),
+        ];
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+        let region_im: RegionIm = region_im_raw.retag::<RegionI>();
+        let cut_bands = create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+        let root = create_region_tree(&cut_bands, &region_infos, 0);
+        assert!(root.get_n_nodes() > 0);
+
+        let json = region_tree_to_json(&root);
+        let reloaded = region_tree_from_json(&json).unwrap();
+
+        // Same shape, same node payloads, and the id lookup still works.
+        assert_eq!(reloaded.get_n_nodes(), root.get_n_nodes());
+        let fmt_all = |r: &RegionRoot| r.iter_dfs().map(|n| format!("{n:?}")).collect::<Vec<_>>();
+        assert_eq!(fmt_all(&reloaded), fmt_all(&root));
+        for id in 0..root.get_n_nodes() {
+            assert_eq!(reloaded.node_by_id(id).unwrap().get_id(), id);
+        }
+        assert_eq!(region_tree_to_json(&reloaded), json);
+        assert!(!json.contains("node_i_to_path"));
+
+        assert!(region_tree_from_json("{\"children\": 3}").is_err());
+    }
+
+    #[test]
+    fn separate_islands_keep_their_own_sub_pockets() {
+        let ply_im = ply_im_from_ascii(
+            r#"
+                3333333333333
+                3222233222223
+                3211233221223
+                3222233222223
+                3333333333333
+            "#,
+        );
+
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false), // [1] pockets
+            stub_ply_desc("ply400", 400, false), // [2] islands
+            stub_ply_desc("ply700", 700, false), // [3] top
+        ];
+        let band_descs = vec![
+            stub_band_desc(1000, 600, "rough"),
+            stub_band_desc(600, 300, "rough"),
+            stub_band_desc(300, 0, "rough"),
+        ];
+
+        let (region_im, region_infos): (RegionIm, Vec<LabelInfo>) = {
+            let (im, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&ply_im).unwrap();
+            (im.retag::<RegionI>(), infos)
+        };
+        let cut_bands = create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+        let root = create_region_tree(&cut_bands, &region_infos, 0);
+
+        let region_of = |x: usize, y: usize| region_im.arr[y * region_im.s + x] as usize;
+        let (left_island, left_pocket) = (region_of(1, 1), region_of(2, 2));
+        let (right_island, right_pocket) = (region_of(7, 1), region_of(9, 2));
+
+        fn cut_regions(nodes: &[RegionNode], out: &mut Vec<usize>) {
+            for n in nodes {
+                match n {
+                    RegionNode::Cut { region_i, .. } => out.push(region_i.0 as usize),
+                    RegionNode::Floor { children, .. } => cut_regions(children, out),
+                }
+            }
+        }
+
+        // Top band: the surround plus one floor per island.
+        let floors: Vec<&RegionNode> = root
+            .children()
+            .iter()
+            .filter(|n| matches!(n, RegionNode::Floor { .. }))
+            .collect();
+        assert_eq!(floors.len(), 2);
+
+        let mut subtrees: Vec<Vec<usize>> = floors
+            .iter()
+            .map(|f| {
+                let mut out = Vec::new();
+                cut_regions(std::slice::from_ref(*f), &mut out);
+                out.sort_unstable();
+                out
+            })
+            .collect();
+        subtrees.sort();
+        let mut expected = vec![
+            vec![left_island, left_pocket],
+            vec![right_island, right_pocket],
+        ];
+        for e in expected.iter_mut() {
+            e.sort_unstable();
+        }
+        expected.sort();
+        assert_eq!(subtrees, expected);
+
+        // A child whose regions aren't in any floor is routed through the adjacency instead.
+        let mut region_to_floor = HashMap::new();
+        region_to_floor.insert(left_island, 0);
+        region_to_floor.insert(right_island, 1);
+        assert_eq!(
+            floor_containing_regions(&[right_pocket], &region_to_floor, &region_infos),
+            Some(1)
+        );
+        assert_eq!(
+            floor_containing_regions(&[left_pocket], &region_to_floor, &region_infos),
+            Some(0)
+        );
+        assert_eq!(
+            floor_containing_regions(&[], &region_to_floor, &region_infos),
+            None
+        );
+    }
+
+    #[test]
+    fn min_region_area_drops_speck_cuts() {
+        let ply_im = ply_im_from_ascii(
+            r#"
+                333333
+                323333
+                333223
+                333223
+                333333
+            "#,
+        );
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false), // [1] unused
+            stub_ply_desc("ply400", 400, false), // [2] speck + block
+            stub_ply_desc("ply700", 700, false), // [3] surround
+        ];
+        let band_descs = vec![
+            stub_band_desc(1000, 600, "rough"),
+            stub_band_desc(600, 0, "rough"),
+        ];
+        let (region_im, region_infos): (RegionIm, Vec<LabelInfo>) = {
+            let (im, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&ply_im).unwrap();
+            (im.retag::<RegionI>(), infos)
+        };
+        let cut_bands = create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+
+        let cut_sizes = |root: &RegionRoot| {
+            let mut sizes: Vec<usize> = root
+                .iter_cut_leaves()
+                .map(|n| match n {
+                    RegionNode::Cut { region_size, .. } => *region_size,
+                    RegionNode::Floor { .. } => unreachable!(),
+                })
+                .collect();
+            sizes.sort_unstable();
+            sizes
+        };
+        let n_floors = |root: &RegionRoot| {
+            root.iter_dfs()
+                .filter(|n| matches!(n, RegionNode::Floor { .. }))
+                .count()
+        };
+
+        let all = create_region_tree(&cut_bands, &region_infos, 0);
+        assert_eq!(cut_sizes(&all), vec![1, 4, 25]);
+        assert_eq!(n_floors(&all), 2);
+
+        // The speck's cut goes, and so does the floor that only gated it.
+        let culled = create_region_tree(&cut_bands, &region_infos, 2);
+        assert_eq!(cut_sizes(&culled), vec![4, 25]);
+        assert_eq!(n_floors(&culled), 1);
+        for id in 0..culled.get_n_nodes() {
+            assert_eq!(culled.node_by_id(id).map(|n| n.get_id()), Some(id));
+        }
+    }
+
     #[test]
     fn it_builds_complex_tree() {
         let ply_im = ply_im_from_ascii(
@@ -1012,7 +1859,7 @@ mod tests {
         ];
 
         let (region_im, region_infos): (RegionIm, Vec<LabelInfo>) = {
-            let (im, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&ply_im);
+            let (im, infos): (Im<u16, 1>, Vec<LabelInfo>) = label_im(&ply_im).unwrap();
             (im.retag::<RegionI>(), infos)
         };
 
@@ -1079,7 +1926,7 @@ mod tests {
         assert_eq!(region_counts_by_ply_i.get(&3).copied(), Some(1));
         assert_eq!(region_counts_by_ply_i.get(&4).copied(), Some(1));
 
-        let region_root = create_region_tree(&cut_bands, &region_infos);
+        let region_root = create_region_tree(&cut_bands, &region_infos, 0);
         let root_children = region_root.children();
 
         let root_floors: Vec<&RegionNode> = root_children
@@ -1115,28 +1962,17 @@ mod tests {
         assert_eq!(total_children, 3, "next band should contribute 3 cut nodes");
 
         // Count Cut nodes per band index.
-        fn accumulate_cut_counts(nodes: &[RegionNode], counts: &mut Vec<usize>) {
-            for n in nodes {
-                match n {
-                    RegionNode::Floor { band_i, children, .. } => {
-                        if *band_i >= counts.len() {
-                            counts.resize(*band_i + 1, 0);
-                        }
-                        accumulate_cut_counts(children, counts);
-                    }
-                    RegionNode::Cut { band_i, .. } => {
-                        if *band_i >= counts.len() {
-                            counts.resize(*band_i + 1, 0);
-                        }
-                        counts[*band_i] += 1;
-                    }
-                }
+        let mut cut_counts_by_band: Vec<usize> = Vec::new();
+        for n in region_root.iter_cut_leaves() {
+            let RegionNode::Cut { band_i, .. } = n else {
+                unreachable!();
+            };
+            if *band_i >= cut_counts_by_band.len() {
+                cut_counts_by_band.resize(*band_i + 1, 0);
             }
+            cut_counts_by_band[*band_i] += 1;
         }
 
-        let mut cut_counts_by_band: Vec<usize> = Vec::new();
-        accumulate_cut_counts(region_root.children(), &mut cut_counts_by_band);
-
         // Band 0 has ply 700 and 900 => 2 regions.
         // Band 1 has ply 100 (2 regions) and 400 (1 region) => 3 regions.
         assert_eq!(cut_counts_by_band.get(0).copied(), Some(2));
@@ -1147,10 +1983,53 @@ mod tests {
 
         debug_print_region_tree(&region_root, &cut_bands, &region_infos, 0);
 
-        // Spot-check the LUT works.
-        if let Some(first) = region_root.children().first() {
-            let id = first.get_id();
-            assert!(region_root.get_node_by_id(id).is_some());
+        // Ids are dense and the LUT round-trips every id, including after a clone.
+        let cloned_root = region_root.clone();
+        for id in 0..region_root.get_n_nodes() {
+            assert_eq!(region_root.node_by_id(id).map(|n| n.get_id()), Some(id));
+            assert_eq!(cloned_root.node_by_id(id).map(|n| n.get_id()), Some(id));
         }
+        assert!(region_root.node_by_id(region_root.get_n_nodes()).is_none());
+
+        // The iterator must visit every node exactly once, in the same pre-order as a recursive walk.
+        fn recurse(nodes: &[RegionNode], out: &mut Vec<usize>) {
+            for n in nodes {
+                out.push(n.get_id());
+                if let RegionNode::Floor { children, .. } = n {
+                    recurse(children, out);
+                }
+            }
+        }
+        let mut expected_order = Vec::new();
+        recurse(region_root.children(), &mut expected_order);
+        let dfs_order: Vec<usize> = region_root.iter_dfs().map(|n| n.get_id()).collect();
+        assert_eq!(dfs_order, expected_order);
+        assert_eq!(dfs_order.len(), region_root.get_n_nodes());
+        assert_eq!(region_root.iter_cut_leaves().count(), 5);
+
+        // Siblings are in a fixed order: cuts by band, plane and region, then the floors.
+        let dfs_nodes: Vec<(usize, &str, Vec<u16>)> = region_root
+            .iter_dfs()
+            .map(|n| match n {
+                RegionNode::Cut {
+                    band_i, region_i, ..
+                } => (*band_i, "cut", vec![region_i.0]),
+                RegionNode::Floor {
+                    band_i, region_iz, ..
+                } => (*band_i, "floor", region_iz.iter().map(|r| r.0).collect()),
+            })
+            .collect();
+        assert_eq!(
+            dfs_nodes,
+            vec![
+                (0, "cut", vec![2]),
+                (0, "cut", vec![3]),
+                (0, "floor", vec![1]),
+                (1, "cut", vec![1]),
+                (0, "floor", vec![4, 5]),
+                (1, "cut", vec![4]),
+                (1, "cut", vec![5]),
+            ]
+        );
     }
 }
@@ -1,6 +1,8 @@
-use crate::im::{Im1Mut, Lum16Im};
+use crate::desc::DimDesc;
+use crate::im::{Im1Mut, Lum16Im, MaskIm, RGBAIm};
 use crate::toolpath::{CutPixels, IV3, ToolPath};
 use std::collections::{BTreeSet, HashMap};
+use std::ops::ControlFlow;
 
 trait CapsulePixelOp {
     #[inline(always)]
@@ -99,8 +101,8 @@ fn splat_pixel_iz_no_bounds_op<const WRITE: bool, O: CapsulePixelOp>(
 }
 
 fn splat_pixel_iz_bounded_op<const WRITE: bool, O: CapsulePixelOp>(
-    cen_x: usize,
-    cen_y: usize,
+    cen_x: isize,
+    cen_y: isize,
     w_usize: usize,
     h_usize: usize,
     stride: usize,
@@ -112,8 +114,6 @@ fn splat_pixel_iz_bounded_op<const WRITE: bool, O: CapsulePixelOp>(
 ) {
     let w = w_usize as isize;
     let h = h_usize as isize;
-    let cen_x_i = cen_x as isize;
-    let cen_y_i = cen_y as isize;
     let r = radius_pix as isize;
 
     for &di in pixel_iz {
@@ -131,8 +131,8 @@ fn splat_pixel_iz_bounded_op<const WRITE: bool, O: CapsulePixelOp>(
             dy += 1;
         }
 
-        let x = cen_x_i + dx;
-        let y = cen_y_i + dy;
+        let x = cen_x + dx;
+        let y = cen_y + dy;
         if x < 0 || x >= w || y < 0 || y >= h {
             continue;
         }
@@ -541,8 +541,8 @@ pub fn splat_pixel_iz_bounded(
     let arr_ptr = arr.as_mut_ptr();
     let mut op = DepthWriteOp { cut };
     splat_pixel_iz_bounded_op::<true, _>(
-        cen_x,
-        cen_y,
+        cen_x as isize,
+        cen_y as isize,
         w_usize,
         h_usize,
         stride,
@@ -597,6 +597,123 @@ fn point_near_bounds(p: IV3, radius_pix: usize, w: usize, h: usize) -> bool {
     let r = radius_pix as i32;
     p.x < r || p.y < r || p.x.saturating_add(r) >= w as i32 || p.y.saturating_add(r) >= h as i32
 }
+
+/// How the straight part of a segment's capsule is rasterized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CapsuleRaster {
+    /// The quad's corners come from an `f64` unit normal, rounded.
+    #[default]
+    Float,
+    /// The same corners computed with integer arithmetic only, so the pixels cut are
+    /// identical on every platform (e.g. for golden-image tests). The corners are the exactly
+    /// rounded ones, so the shape matches `Float` except where the float error tips a corner
+    /// lying within an ulp or so of a half pixel the other way: a one-pixel shift of one edge.
+    Integer,
+}
+
+/// How `sim_toolpaths` simulates: what it resets first and how it rasterizes. The default keeps
+/// the heights, so passes simulated one call after another (e.g. rough then refine) accumulate
+/// into the same map, and resets each toolpath's `cuts`, so they hold only this call's
+/// contribution.
+#[derive(Clone, Copy)]
+pub struct SimOptions<'a> {
+    /// Raise every pixel back to the map's highest value first: flat stock at the original top,
+    /// as long as some pixel was never cut.
+    pub reset_heights: bool,
+    /// Reset each toolpath's `cuts` first. When false, this call's accounting is merged into
+    /// the existing `cuts` (see `CutPixels::merge`), e.g. to total a toolpath over several runs.
+    pub reset_cuts: bool,
+    /// How constant-Z segments are rasterized (see `CapsuleRaster`).
+    pub capsule_raster: CapsuleRaster,
+    /// Cut Z-changing segments (retracts, plunges, ramps) with `draw_toolpath_segment_sloped`
+    /// and account them like any other segment. Off, they are skipped as non-cutting moves.
+    pub allow_sloped_cuts: bool,
+    /// Maps each cutting segment to its `CutPixels::feed_scale`; None uses `default_feed_scale`.
+    pub feed_scale_fn: Option<&'a FeedScaleFn<'a>>,
+}
+
+impl Default for SimOptions<'_> {
+    fn default() -> Self {
+        Self {
+            reset_heights: false,
+            reset_cuts: true,
+            capsule_raster: CapsuleRaster::default(),
+            allow_sloped_cuts: false,
+            feed_scale_fn: None,
+        }
+    }
+}
+
+/// `round(sqrt(num / den))`, rounding halves up, in integers only.
+fn round_sqrt_ratio(num: u128, den: u128) -> i64 {
+    let n = (num / den).isqrt();
+    // sqrt(num / den) >= n + 0.5  <=>  4 * num >= (2n + 1)^2 * den
+    let up = 4 * num >= (2 * n + 1) * (2 * n + 1) * den;
+    (n + up as u128) as i64
+}
+
+/// The corners `a, b, c, d` of the quad between the end discs of the capsule around
+/// `p0 -> p1`: each end offset by `radius_pix` along the segment's normal, rounded to whole
+/// pixels. `None` for a zero-length segment.
+fn capsule_quad(
+    p0: IV3,
+    p1: IV3,
+    radius_pix: usize,
+    raster: CapsuleRaster,
+) -> Option<[(isize, isize); 4]> {
+    match raster {
+        CapsuleRaster::Float => {
+            let rf = radius_pix as f64;
+
+            let p0x = p0.x as f64;
+            let p0y = p0.y as f64;
+            let p1x = p1.x as f64;
+            let p1y = p1.y as f64;
+
+            let px = p0x - p1x;
+            let py = p0y - p1y;
+            let p_mag = (px * px + py * py).sqrt();
+            if p_mag == 0.0 {
+                return None;
+            }
+            let nx = px / p_mag;
+            let ny = py / p_mag;
+            let qx = -ny * rf;
+            let qy = nx * rf;
+
+            Some([
+                ((p0x - qx).round() as isize, (p0y - qy).round() as isize),
+                ((p0x + qx).round() as isize, (p0y + qy).round() as isize),
+                ((p1x + qx).round() as isize, (p1y + qy).round() as isize),
+                ((p1x - qx).round() as isize, (p1y - qy).round() as isize),
+            ])
+        }
+        CapsuleRaster::Integer => {
+            let px = p0.x as i64 - p1.x as i64;
+            let py = p0.y as i64 - p1.y as i64;
+            if px == 0 && py == 0 {
+                return None;
+            }
+            // |q.x| = r * |py| / |p| and |q.y| = r * |px| / |p|. With integer endpoints a
+            // corner is only a half-pixel tie if q is, and q never is: that would need
+            // (2k + 1) * |p| = 2 * r * |py| with |p| whole, and dividing out the Pythagorean
+            // triple's common factor leaves an odd hypotenuse on the left and an even right.
+            let len2 = (px * px + py * py) as u128;
+            let r2 = (radius_pix as u128) * (radius_pix as u128);
+            let qx = (round_sqrt_ratio(r2 * (py * py) as u128, len2) * -py.signum()) as isize;
+            let qy = (round_sqrt_ratio(r2 * (px * px) as u128, len2) * px.signum()) as isize;
+            let (p0x, p0y) = (p0.x as isize, p0.y as isize);
+            let (p1x, p1y) = (p1.x as isize, p1.y as isize);
+            Some([
+                (p0x - qx, p0y - qy),
+                (p0x + qx, p0y + qy),
+                (p1x + qx, p1y + qy),
+                (p1x - qx, p1y - qy),
+            ])
+        }
+    }
+}
+
 /// Draw a line with rounded ends into a Lum16Im, interpolating the height values along the line.
 /// Clip the line to the image bounds before starting.
 /// Only set the pixel value if the new value is lower (deeper cut).
@@ -606,42 +723,36 @@ pub fn draw_toolpath_segment_single_depth(
     p1: IV3,
     radius_pix: usize,
     circle_pixel_iz: &[isize],
+) -> CutPixels {
+    draw_toolpath_segment_single_depth_with(
+        im,
+        p0,
+        p1,
+        radius_pix,
+        circle_pixel_iz,
+        CapsuleRaster::Float,
+    )
+}
+
+/// `draw_toolpath_segment_single_depth` with the capsule rasterization chosen by `raster`.
+pub fn draw_toolpath_segment_single_depth_with(
+    im: &mut Lum16Im,
+    p0: IV3,
+    p1: IV3,
+    radius_pix: usize,
+    circle_pixel_iz: &[isize],
+    raster: CapsuleRaster,
 ) -> CutPixels {
     debug_assert!(p0.z == p1.z);
     let z_u16 = p0.z.clamp(0, u16::MAX as i32) as u16;
 
     let mut cut = CutPixels::default();
 
-    // let dx = p1.0 as isize - p0.0 as isize;
-    // let dy = p1.1 as isize - p0.1 as isize;
-    // let tmp;
-    // let mut q0 = p0;
-    // let mut q1 = p1;
-
     let use_bounded = use_bounded_for_segment(im, p0, p1, radius_pix);
 
-    let rf = radius_pix as f64;
-
-    let p0x = p0.x as f64;
-    let p0y = p0.y as f64;
-    let p1x = p1.x as f64;
-    let p1y = p1.y as f64;
-
-    let px = p0x - p1x;
-    let py = p0y - p1y;
-    let p_mag = (px * px + py * py).sqrt();
-    if p_mag == 0.0 {
+    let Some([a, b, c, d]) = capsule_quad(p0, p1, radius_pix, raster) else {
         return cut;
-    }
-    let nx = px / p_mag;
-    let ny = py / p_mag;
-    let qx = -ny * rf;
-    let qy = nx * rf;
-
-    let a = ((p0x - qx).round() as isize, (p0y - qy).round() as isize);
-    let b = ((p0x + qx).round() as isize, (p0y + qy).round() as isize);
-    let c = ((p1x + qx).round() as isize, (p1y + qy).round() as isize);
-    let d = ((p1x - qx).round() as isize, (p1y - qy).round() as isize);
+    };
 
     {
         let stride = im.s;
@@ -671,8 +782,8 @@ pub fn draw_toolpath_segment_single_depth(
 
         if use_bounded {
             splat_pixel_iz_bounded_op::<true, _>(
-                p0x_usize,
-                p0y_usize,
+                p0.x as isize,
+                p0.y as isize,
                 w_usize,
                 h_usize,
                 stride,
@@ -697,8 +808,8 @@ pub fn draw_toolpath_segment_single_depth(
 
         if use_bounded {
             splat_pixel_iz_bounded_op::<true, _>(
-                p1x_usize,
-                p1y_usize,
+                p1.x as isize,
+                p1.y as isize,
                 w_usize,
                 h_usize,
                 stride,
@@ -725,6 +836,175 @@ pub fn draw_toolpath_segment_single_depth(
     cut
 }
 
+/// Cut a segment whose Z changes (a ramp or a 3D contour move) into `im`: the tool's disc is
+/// stamped at one-pixel steps along the segment with Z interpolated between the endpoints, and
+/// like the constant-Z version a pixel only ever goes deeper. A Z-only move (a plunge) stamps the
+/// disc once at the lower Z. Stations off the image still cut the part of their disc that is on it.
+pub fn draw_toolpath_segment_sloped(
+    im: &mut Lum16Im,
+    p0: IV3,
+    p1: IV3,
+    radius_pix: usize,
+    circle_pixel_iz: &[isize],
+) -> CutPixels {
+    let mut cut = CutPixels::default();
+
+    let dx = (p1.x - p0.x) as f64;
+    let dy = (p1.y - p0.y) as f64;
+    let dz = (p1.z - p0.z) as f64;
+    let n_steps = dx.abs().max(dy.abs()).ceil().max(1.0) as usize;
+
+    let stride = im.s;
+    let w = im.w;
+    let h = im.h;
+    let arr = im.arr_mut();
+    let arr_ptr = arr.as_mut_ptr();
+    let arr_len = arr.len();
+    let mut op = DepthWriteOp { cut: &mut cut };
+
+    let mut last: Option<IV3> = None;
+    for step in 0..=n_steps {
+        let t = step as f64 / n_steps as f64;
+        let mut p = IV3 {
+            x: (p0.x as f64 + t * dx).round() as i32,
+            y: (p0.y as f64 + t * dy).round() as i32,
+            z: (p0.z as f64 + t * dz).round() as i32,
+        };
+        if dx == 0.0 && dy == 0.0 {
+            p.z = p0.z.min(p1.z);
+        }
+        if last == Some(p) {
+            continue;
+        }
+        last = Some(p);
+        // Skip stations whose disc misses the image entirely.
+        let r = radius_pix as i32;
+        if p.x + r < 0 || p.y + r < 0 || p.x - r >= w as i32 || p.y - r >= h as i32 {
+            continue;
+        }
+
+        let z_u16 = p.z.clamp(0, u16::MAX as i32) as u16;
+        if point_near_bounds(p, radius_pix, w, h) {
+            splat_pixel_iz_bounded_op::<true, _>(
+                p.x as isize,
+                p.y as isize,
+                w,
+                h,
+                stride,
+                arr_ptr,
+                z_u16,
+                radius_pix,
+                circle_pixel_iz,
+                &mut op,
+            );
+        } else {
+            splat_pixel_iz_no_bounds_op::<true, _>(
+                p.x as usize,
+                p.y as usize,
+                stride,
+                arr_ptr,
+                arr_len,
+                z_u16,
+                circle_pixel_iz,
+                &mut op,
+            );
+        }
+    }
+
+    cut
+}
+
+/// Cut a single constant-Z segment into `im` without wrapping it in a `ToolPath`.
+/// Handy for interactive previews and tests; `sim_toolpaths` is the batch version.
+/// Like there, a Z-changing segment is treated as a non-cutting move.
+/// `feed_scale` is left at its default.
+pub fn sim_single_segment(im: &mut Lum16Im, p0: IV3, p1: IV3, tool_dia_pix: usize) -> CutPixels {
+    if p0.z != p1.z {
+        return CutPixels::default();
+    }
+    let radius_pix = tool_dia_pix / 2;
+    let circle_pix = circle_pixel_iz(radius_pix, im.s);
+    let max_engagement_frac = leading_arc_engagement(im, p0, p1, radius_pix);
+    let mut cut = draw_toolpath_segment_single_depth(im, p0, p1, radius_pix, &circle_pix);
+    cut.max_engagement_frac = max_engagement_frac;
+    cut
+}
+
+/// Whether pixel (x, y) is inside the capsule of radius `radius_pix` around `p0 -> p1`,
+/// i.e. within `radius_pix` of the segment (the disc test of `circle_pixel_iz`, swept).
+pub fn point_in_capsule(x: i32, y: i32, p0: IV3, p1: IV3, radius_pix: usize) -> bool {
+    let (px, py) = (x as f64, y as f64);
+    let (ax, ay) = (p0.x as f64, p0.y as f64);
+    let (abx, aby) = (p1.x as f64 - ax, p1.y as f64 - ay);
+    let len2 = abx * abx + aby * aby;
+    let t = if len2 == 0.0 {
+        0.0
+    } else {
+        (((px - ax) * abx + (py - ay) * aby) / len2).clamp(0.0, 1.0)
+    };
+    let dx = px - (ax + t * abx);
+    let dy = py - (ay + t * aby);
+    let r = radius_pix as f64;
+    dx * dx + dy * dy <= r * r
+}
+
+/// Largest fraction of the leading semicircle of the tool that is above the tool Z (i.e. in
+/// material) at any pixel step along the segment `p0 -> p1`, read from `im` before the segment
+/// is cut. On a sloped segment the tool Z at each step is interpolated from `p0.z` to `p1.z`. The leading arc is never inside the capsule swept earlier in
+/// the same segment, so the pre-cut heights are exactly what the cutter edge meets there.
+/// Off-image samples are ignored. Returns 0.0 for a zero-length segment or radius.
+pub fn leading_arc_engagement(im: &Lum16Im, p0: IV3, p1: IV3, radius_pix: usize) -> f32 {
+    let dx = (p1.x - p0.x) as f64;
+    let dy = (p1.y - p0.y) as f64;
+    let len = (dx * dx + dy * dy).sqrt();
+    if len == 0.0 || radius_pix == 0 {
+        return 0.0;
+    }
+    let (ux, uy) = (dx / len, dy / len);
+    let dz = (p1.z - p0.z) as f64;
+    // Sample a pixel inside the cutter edge so rounding never lands outside the footprint
+    // the sim actually cuts (a re-run of the same pass then reads no material).
+    let r = (radius_pix as f64 - 1.0).max(0.5);
+
+    // About one sample per pixel of arc, from the right side through the heading to the left.
+    let n_arc = ((std::f64::consts::PI * r).ceil() as usize).max(4);
+    let arc: Vec<(f64, f64)> = (0..=n_arc)
+        .map(|k| {
+            let a = std::f64::consts::PI * (k as f64 / n_arc as f64 - 0.5);
+            let (sin, cos) = a.sin_cos();
+            (r * (cos * ux - sin * uy), r * (sin * ux + cos * uy))
+        })
+        .collect();
+
+    let n_stations = len.ceil() as usize;
+    let mut max_frac = 0.0f32;
+    for station_i in 0..=n_stations {
+        let t = (station_i as f64).min(len);
+        let cx = p0.x as f64 + ux * t;
+        let cy = p0.y as f64 + uy * t;
+        let z_u16 = (p0.z as f64 + dz * t / len)
+            .round()
+            .clamp(0.0, u16::MAX as f64) as u16;
+        let mut n_in_material = 0usize;
+        let mut n_samples = 0usize;
+        for &(ox, oy) in &arc {
+            let x = (cx + ox).round();
+            let y = (cy + oy).round();
+            if x < 0.0 || y < 0.0 || x >= im.w as f64 || y >= im.h as f64 {
+                continue;
+            }
+            n_samples += 1;
+            if im.arr[y as usize * im.s + x as usize] > z_u16 {
+                n_in_material += 1;
+            }
+        }
+        if n_samples > 0 {
+            max_frac = max_frac.max(n_in_material as f32 / n_samples as f32);
+        }
+    }
+    max_frac
+}
+
 /// Scan the same capsule footprint as `draw_toolpath_segment_single_depth`, but instead of
 /// modifying the image, return the maximum `u16` value observed anywhere inside the capsule.
 ///
@@ -763,8 +1043,8 @@ pub fn scan_toolpath_segment_max_u16(
         let cy = p0.y.max(0) as usize;
         if use_bounded {
             splat_pixel_iz_bounded_op::<false, _>(
-                cx,
-                cy,
+                p0.x as isize,
+                p0.y as isize,
                 w_usize,
                 h_usize,
                 stride,
@@ -818,8 +1098,8 @@ pub fn scan_toolpath_segment_max_u16(
 
     if use_bounded {
         splat_pixel_iz_bounded_op::<false, _>(
-            p0x_usize,
-            p0y_usize,
+            p0.x as isize,
+            p0.y as isize,
             w_usize,
             h_usize,
             stride,
@@ -830,8 +1110,8 @@ pub fn scan_toolpath_segment_max_u16(
             &mut op,
         );
         splat_pixel_iz_bounded_op::<false, _>(
-            p1x_usize,
-            p1y_usize,
+            p1.x as isize,
+            p1.y as isize,
             w_usize,
             h_usize,
             stride,
@@ -867,12 +1147,205 @@ pub fn scan_toolpath_segment_max_u16(
     op.max
 }
 
-/// Simulate toolpaths into a `Lum16Im` representing the result.
-/// Toolpath points are in pixel X/Y and thou Z, and are assumed to already be ordered.
-/// The toolpaths are mutable because the cut annotations will be recorded into them.
+/// A place where the tool shank / holder would hit material above the flutes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClearanceHit {
+    pub toolpath_i: usize,
+    /// Index of the segment's first point (or of the point itself for single-point toolpaths).
+    pub point_i: usize,
+    pub p: IV3,
+    /// Lowest Z (thou) the shank reaches at this point: tip Z + `shank_above_tip_thou`.
+    pub shank_z_thou: i32,
+    /// Highest material (thou) found under the shank footprint.
+    pub max_height_thou: u16,
+    /// How far the material rises into the shank (thou). Always > 0.
+    pub excess_thou: i32,
+}
+
+/// Check that the tool shank clears the material along every cutting toolpath.
 ///
-/// If `on_step` is provided, it will be called after each segment is applied, with a read-only
-/// view of the current `im` state.
+/// For each segment, the capsule of diameter `shank_dia_pix` is scanned in `heights` with the
+/// same footprint machinery as `scan_toolpath_segment_max_u16`. The shank starts
+/// `shank_above_tip_thou` above the lower of the segment's two tip Zs; any material above that
+/// is a hit. `heights` should be the surface the tool sees (e.g. the sim result so far).
+/// Segments that run off the image are clipped to it (Z interpolated along the segment);
+/// segments entirely off it are skipped. Traverses are skipped. Returns the hits sorted
+/// worst (largest excess) first.
+pub fn check_shank_clearance(
+    heights: &Lum16Im,
+    toolpaths: &[ToolPath],
+    shank_dia_pix: usize,
+    shank_above_tip_thou: u16,
+) -> Vec<ClearanceHit> {
+    let shank_radius_pix = shank_dia_pix / 2;
+    let circle_pix = circle_pixel_iz(shank_radius_pix, heights.s);
+
+    let mut hits: Vec<ClearanceHit> = Vec::new();
+    let mut check = |toolpath_i: usize, point_i: usize, p0: IV3, p1: IV3| {
+        let Some((p0, p1)) = clip_segment_to_im(p0, p1, heights.w, heights.h) else {
+            return;
+        };
+        let max_height_thou =
+            scan_toolpath_segment_max_u16(heights, p0, p1, shank_radius_pix, &circle_pix);
+        let shank_z_thou = p0.z.min(p1.z) + shank_above_tip_thou as i32;
+        let excess_thou = max_height_thou as i32 - shank_z_thou;
+        if excess_thou > 0 {
+            let p = if p1.z < p0.z { p1 } else { p0 };
+            hits.push(ClearanceHit {
+                toolpath_i,
+                point_i,
+                p,
+                shank_z_thou,
+                max_height_thou,
+                excess_thou,
+            });
+        }
+    };
+
+    for (toolpath_i, toolpath) in toolpaths.iter().enumerate() {
+        if toolpath.is_traverse {
+            continue;
+        }
+        match toolpath.points.as_slice() {
+            [] => {}
+            [p] => check(toolpath_i, 0, *p, *p),
+            points => {
+                for (seg_i, seg) in points.windows(2).enumerate() {
+                    check(toolpath_i, seg_i, seg[0], seg[1]);
+                }
+            }
+        }
+    }
+
+    hits.sort_by(|a, b| {
+        b.excess_thou
+            .cmp(&a.excess_thou)
+            .then(a.toolpath_i.cmp(&b.toolpath_i))
+            .then(a.point_i.cmp(&b.point_i))
+    });
+    hits
+}
+
+/// Clip the segment `p0 -> p1` to the pixels of a `w` x `h` image (Liang-Barsky), interpolating
+/// Z at the new ends. Returns None if no part of the segment is on the image.
+fn clip_segment_to_im(p0: IV3, p1: IV3, w: usize, h: usize) -> Option<(IV3, IV3)> {
+    if w == 0 || h == 0 {
+        return None;
+    }
+    let (dx, dy) = ((p1.x - p0.x) as f64, (p1.y - p0.y) as f64);
+    let (mut t0, mut t1) = (0.0_f64, 1.0_f64);
+    // Each edge as (p, q): the segment is inside where p * t <= q.
+    let edges = [
+        (-dx, p0.x as f64),
+        (dx, (w - 1) as f64 - p0.x as f64),
+        (-dy, p0.y as f64),
+        (dy, (h - 1) as f64 - p0.y as f64),
+    ];
+    for (p, q) in edges {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            t0 = t0.max(q / p);
+        } else {
+            t1 = t1.min(q / p);
+        }
+    }
+    if t0 > t1 {
+        return None;
+    }
+    let at = |t: f64| IV3 {
+        x: (p0.x as f64 + dx * t).round() as i32,
+        y: (p0.y as f64 + dy * t).round() as i32,
+        z: (p0.z as f64 + (p1.z - p0.z) as f64 * t).round() as i32,
+    };
+    Some((at(t0), at(t1)))
+}
+
+/// Create a stock image of the given size with every pixel at `top_thou` (the uncut surface).
+pub fn make_stock(w: usize, h: usize, top_thou: u16) -> Lum16Im {
+    let mut im = Lum16Im::new(w, h);
+    im.arr.fill(top_thou);
+    im
+}
+
+/// Create the stock image for a composition: the bulk plus the frame on every side,
+/// with the surface at `bulk_d_inch` (in thou).
+pub fn make_stock_from_dim(dim: &DimDesc, px_per_inch: f64) -> Lum16Im {
+    let w_inch = dim.bulk_w_inch + 2.0 * dim.frame_inch;
+    let h_inch = dim.bulk_h_inch + 2.0 * dim.frame_inch;
+    let w = (w_inch * px_per_inch).round().max(0.0) as usize;
+    let h = (h_inch * px_per_inch).round().max(0.0) as usize;
+    let top_thou = (dim.bulk_d_inch * 1000.0)
+        .round()
+        .clamp(0.0, u16::MAX as f64) as u16;
+    make_stock(w, h, top_thou)
+}
+
+/// What changed between two height maps, see `heightmap_diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffStats {
+    /// Number of pixels whose heights differ.
+    pub n_changed: usize,
+    /// Largest |a - b| over all pixels (thou), 0 when identical.
+    pub max_abs_delta: u16,
+    /// 255 where the heights differ.
+    pub changed_mask: MaskIm,
+}
+
+/// Compare two height maps of the same size, e.g. the sim results before and after a change to
+/// planning parameters; a no-op refactor should give `n_changed == 0`.
+pub fn heightmap_diff(a: &Lum16Im, b: &Lum16Im) -> DiffStats {
+    assert_eq!(a.w, b.w, "width mismatch");
+    assert_eq!(a.h, b.h, "height mismatch");
+
+    let mut changed_mask = MaskIm::new(a.w, a.h);
+    let mut n_changed = 0;
+    let mut max_abs_delta = 0;
+    for y in 0..a.h {
+        for x in 0..a.w {
+            let delta = a.arr[y * a.s + x].abs_diff(b.arr[y * b.s + x]);
+            if delta > 0 {
+                changed_mask.arr[y * changed_mask.s + x] = 255;
+                n_changed += 1;
+                max_abs_delta = max_abs_delta.max(delta);
+            }
+        }
+    }
+    DiffStats {
+        n_changed,
+        max_abs_delta,
+        changed_mask,
+    }
+}
+
+/// Visualize `heightmap_diff`: red where `a` is deeper, blue where `b` is deeper, brighter for
+/// bigger deltas (relative to the largest), and black where they match.
+pub fn heightmap_diff_rgba(a: &Lum16Im, b: &Lum16Im) -> RGBAIm {
+    let max_abs_delta = heightmap_diff(a, b).max_abs_delta.max(1) as u32;
+
+    let mut rgba = RGBAIm::new(a.w, a.h);
+    for y in 0..a.h {
+        for x in 0..a.w {
+            let (va, vb) = (a.arr[y * a.s + x], b.arr[y * b.s + x]);
+            // Keep the smallest change visible against the black background.
+            let level = (64 + 191 * va.abs_diff(vb) as u32 / max_abs_delta) as u8;
+            let rgb = match va.cmp(&vb) {
+                std::cmp::Ordering::Less => [level, 0, 0],
+                std::cmp::Ordering::Greater => [0, 0, level],
+                std::cmp::Ordering::Equal => [0, 0, 0],
+            };
+            let i = y * rgba.s + x * 4;
+            rgba.arr[i..i + 3].copy_from_slice(&rgb);
+            rgba.arr[i + 3] = 255;
+        }
+    }
+    rgba
+}
+
+/// Called by `sim_toolpaths` after each segment is applied, with a read-only view of the
+/// heights so far.
 pub type SimToolpathsStepCallback<'a> = dyn FnMut(
         &Lum16Im,
         usize, /*toolpath_i*/
@@ -880,16 +1353,68 @@ pub type SimToolpathsStepCallback<'a> = dyn FnMut(
         IV3,   /*p0*/
         IV3,   /*p1*/
         CutPixels,
-    ) + 'a;
+    ) -> ControlFlow<()>
+    + 'a;
+
+/// Called after each toolpath is fully simulated with (toolpaths_done, total).
+pub type SimProgressCallback<'a> = dyn FnMut(usize, usize) + 'a;
+
+/// Maps a simulated segment to a feed multiplier stored in `CutPixels::feed_scale`.
+/// Arguments are the segment's cut accounting, its XY length in pixels, and the tool diameter in pixels.
+pub type FeedScaleFn<'a> =
+    dyn Fn(&CutPixels, f64 /*seg_len_pix*/, usize /*tool_dia_pix*/) -> f32 + 'a;
+
+/// The default engagement -> feed mapping used when `sim_toolpaths` is not given one.
+/// Engagement is the fraction of the segment's swept capsule area (len * dia + end disc)
+/// that actually removed material; that ratio is resolution independent.
+/// Full engagement (slotting) runs at half feed, no engagement at full feed.
+pub fn default_feed_scale(cut: &CutPixels, seg_len_pix: f64, tool_dia_pix: usize) -> f32 {
+    let dia = tool_dia_pix.max(1) as f64;
+    let swept_area_pix = seg_len_pix * dia + std::f64::consts::FRAC_PI_4 * dia * dia;
+    let engagement = (cut.pixels_changed as f64 / swept_area_pix).clamp(0.0, 1.0);
+    (1.0 - 0.5 * engagement) as f32
+}
 
+/// Simulate toolpaths into a `Lum16Im` representing the result.
+/// Toolpath points are in pixel X/Y and thou Z, and are assumed to already be ordered.
+/// The toolpaths are mutable because the cut annotations will be recorded into them.
+///
+/// If `on_step` is provided, it will be called after each segment is applied, with a read-only
+/// view of the current `im` state. Returning `ControlFlow::Break` stops the sim right there.
+///
+/// Each cutting segment's `CutPixels::max_engagement_frac` is measured with
+/// `leading_arc_engagement` before the segment is cut, and then its `CutPixels::feed_scale`
+/// is set by `SimOptions::feed_scale_fn` (or `default_feed_scale` when None). Sloped cuts (with
+/// `SimOptions::allow_sloped_cuts`) are measured at the tool Z interpolated along the segment.
+/// Non-cutting (Z-changing) segments keep 1.0 and 0.0.
+///
+/// Returns `ControlFlow::Break` if `on_step` aborted. Every segment simulated so far is then
+/// fully applied to `im` with its `cuts` recorded; the rest of that toolpath's `cuts` are default
+/// (as they were, with `reset_cuts` off) and later toolpaths are untouched.
+///
+/// `options` picks what is reset before simulating and how segments are rasterized
+/// (see `SimOptions`). Accumulating across
+/// calls relies on the monotonic-min invariant: a cut only ever lowers a pixel to
+/// `min(height, tool z)`, never raises it. So simulating A and then B into one map gives the
+/// same heights as simulating A followed by B in one call, and B's `cuts` count only the
+/// material A left behind.
 pub fn sim_toolpaths(
     im: &mut Lum16Im,
     toolpaths: &mut [ToolPath],
     mut on_step: Option<&mut SimToolpathsStepCallback<'_>>,
-) {
+    mut progress: Option<&mut SimProgressCallback<'_>>,
+    options: SimOptions,
+) -> ControlFlow<()> {
+    if options.reset_heights {
+        let top_thou = im.arr.iter().copied().max().unwrap_or(0);
+        im.arr.fill(top_thou);
+    }
     if toolpaths.is_empty() {
-        return;
+        return ControlFlow::Continue(());
     }
+    let n_toolpaths = toolpaths.len();
+
+    let feed_scale_fn: &FeedScaleFn<'_> = options.feed_scale_fn.unwrap_or(&default_feed_scale);
 
     // Pre-pass: collect unique tool diameters used by these toolpaths.
     let mut dia_set: BTreeSet<usize> = BTreeSet::new();
@@ -910,9 +1435,13 @@ pub fn sim_toolpaths(
         // Ensure `cuts` is parallel to `points`.
         if toolpath.cuts.len() != toolpath.points.len() {
             toolpath.cuts = vec![CutPixels::default(); toolpath.points.len()];
-        } else {
+        } else if options.reset_cuts {
+            // Only the counts are reset; holding tabs stay marked.
             for c in toolpath.cuts.iter_mut() {
-                *c = CutPixels::default();
+                *c = CutPixels {
+                    is_tab: c.is_tab,
+                    ..CutPixels::default()
+                };
             }
         }
 
@@ -926,28 +1455,48 @@ pub fn sim_toolpaths(
             let p0 = seg[0];
             let p1 = seg[1];
 
-            // Traverses / retracts may include Z-changing segments; the existing simulation model
-            // only supports constant-Z segments. Treat Z-changing segments as non-cutting moves.
-            if p0.z != p1.z {
+            // Traverses / retracts may include Z-changing segments; unless sloped cuts are
+            // allowed, treat Z-changing segments as non-cutting moves.
+            if p0.z != p1.z && !options.allow_sloped_cuts {
                 let seg_cut = CutPixels::default();
-                if seg_i < toolpath.cuts.len() {
-                    toolpath.cuts[seg_i] = seg_cut;
-                }
 
                 if let Some(cb) = on_step.as_deref_mut() {
-                    cb(&*im, toolpath_i, seg_i, p0, p1, seg_cut);
+                    cb(&*im, toolpath_i, seg_i, p0, p1, seg_cut)?;
                 }
                 continue;
             }
 
-            let seg_cut =
-                draw_toolpath_segment_single_depth(im, p0, p1, tool_radius_pix, circle_pixel_iz);
+            let max_engagement_frac = leading_arc_engagement(im, p0, p1, tool_radius_pix);
+            let mut seg_cut = if p0.z == p1.z {
+                draw_toolpath_segment_single_depth_with(
+                    im,
+                    p0,
+                    p1,
+                    tool_radius_pix,
+                    circle_pixel_iz,
+                    options.capsule_raster,
+                )
+            } else {
+                draw_toolpath_segment_sloped(im, p0, p1, tool_radius_pix, circle_pixel_iz)
+            };
+            seg_cut.max_engagement_frac = max_engagement_frac;
+            let dx = (p1.x - p0.x) as f64;
+            let dy = (p1.y - p0.y) as f64;
+            seg_cut.feed_scale =
+                feed_scale_fn(&seg_cut, (dx * dx + dy * dy).sqrt(), toolpath.tool_dia_pix);
             if seg_i < toolpath.cuts.len() {
-                toolpath.cuts[seg_i] = seg_cut;
+                if options.reset_cuts {
+                    toolpath.cuts[seg_i] = CutPixels {
+                        is_tab: toolpath.cuts[seg_i].is_tab,
+                        ..seg_cut
+                    };
+                } else {
+                    toolpath.cuts[seg_i].merge(seg_cut);
+                }
             }
 
             if let Some(cb) = on_step.as_deref_mut() {
-                cb(&*im, toolpath_i, seg_i, p0, p1, seg_cut);
+                cb(&*im, toolpath_i, seg_i, p0, p1, seg_cut)?;
             }
         }
 
@@ -955,5 +1504,10 @@ pub fn sim_toolpaths(
         if let Some(last) = toolpath.cuts.last_mut() {
             *last = CutPixels::default();
         }
+
+        if let Some(cb) = progress.as_deref_mut() {
+            cb(toolpath_i + 1, n_toolpaths);
+        }
     }
+    ControlFlow::Continue(())
 }
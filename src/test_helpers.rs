@@ -1,9 +1,12 @@
-use crate::region_tree::PlyIm;
-use crate::desc::{BandDesc, Guid, PlyDesc, Thou};
-use crate::im::core::Im;
-use crate::im::ROI;
+use crate::desc::{BandDesc, ClearMode, Guid, PlyDesc, Thou};
 use crate::im::MaskIm;
-use crate::toolpath::ToolPath;
+use crate::im::ROI;
+use crate::im::core::Im;
+use crate::im::label::{LabelInfo, label_im};
+use crate::region_tree::{
+    CutBand, PlyIm, RegionI, RegionIm, RegionRoot, create_cut_bands, create_region_tree,
+};
+use crate::toolpath::{RegionToolpathOpts, ToolPath, create_toolpaths_from_region_tree};
 
 pub fn ply_im_from_ascii(grid: &str) -> PlyIm {
     let rows: Vec<&str> = grid
@@ -50,9 +53,50 @@ pub fn stub_band_desc(top_thou: i32, bot_thou: i32, cut_pass: &str) -> BandDesc
         top_thou: Thou(top_thou),
         bot_thou: Thou(bot_thou),
         cut_pass: cut_pass.to_string(),
+        clear_mode: ClearMode::Full,
     }
 }
 
+/// The planning inputs built from a ply image, as returned by `plan_fixture`.
+pub type PlanFixture = (PlyIm, RegionIm, Vec<LabelInfo>, Vec<CutBand>, RegionRoot);
+
+/// Label `ascii` (see `ply_im_from_ascii`) and build its "rough" cut bands and region tree.
+/// Ply 0 is a hidden dummy and ply `i` is a visible ply topped at `ply_tops_thou[i - 1]`.
+pub fn plan_fixture(ascii: &str, ply_tops_thou: &[i32], band_descs: &[BandDesc]) -> PlanFixture {
+    let ply_im = ply_im_from_ascii(ascii);
+    let mut ply_descs = vec![stub_ply_desc("dummy", 0, true)];
+    for &top_thou in ply_tops_thou {
+        ply_descs.push(stub_ply_desc(&format!("ply{top_thou}"), top_thou, false));
+    }
+
+    let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+    let region_im: RegionIm = region_im_raw.retag::<RegionI>();
+    let cut_bands = create_cut_bands(
+        "rough",
+        &ply_im,
+        band_descs,
+        &region_im,
+        &region_infos,
+        &ply_descs,
+    );
+    let region_root = create_region_tree(&cut_bands, &region_infos, 0);
+    (ply_im, region_im, region_infos, cut_bands, region_root)
+}
+
+/// Plan the toolpaths of a `plan_fixture` with `opts`, without a region mask hook.
+pub fn plan_toolpaths(fixture: &PlanFixture, opts: &RegionToolpathOpts) -> Vec<ToolPath> {
+    let (ply_im, region_im, region_infos, cut_bands, region_root) = fixture;
+    create_toolpaths_from_region_tree(
+        region_root,
+        cut_bands,
+        ply_im,
+        region_im,
+        region_infos,
+        opts,
+        None,
+    )
+}
+
 pub fn im_u16_to_ascii<S>(im: &Im<u16, 1, S>) -> String {
     let mut out = String::new();
     for y in 0..im.h {
@@ -192,6 +236,7 @@ mod tests {
                 cuts: vec![Default::default(); 2],
                 is_traverse: false,
                 is_raster: false,
+                is_plunge_run: false,
             },
             ToolPath {
                 points: vec![IV3 { x: 12, y: 1, z: 0 }, IV3 { x: 17, y: 1, z: 0 }],
@@ -203,6 +248,7 @@ mod tests {
                 cuts: vec![Default::default(); 2],
                 is_traverse: false,
                 is_raster: false,
+                is_plunge_run: false,
             },
             ToolPath {
                 points: vec![IV3 { x: 6, y: 2, z: 0 }, IV3 { x: 10, y: 2, z: 0 }],
@@ -214,6 +260,7 @@ mod tests {
                 cuts: vec![Default::default(); 2],
                 is_traverse: false,
                 is_raster: false,
+                is_plunge_run: false,
             },
         ];
 
@@ -1,25 +1,64 @@
 #[allow(unused_imports)]
 use crate::debug_ui;
 
-use crate::desc::Thou;
-use crate::dilate_im::im_dilate;
+use crate::desc::{ClearMode, Thou};
+use crate::dilate_im::{
+    DilateScratch, distance_transform, im_dilate_box, im_dilate_radius,
+    im_dilate_radius_with_scratch, im_dilate_with_scratch,
+};
 use crate::im::ROI;
 use crate::im::label::LabelInfo;
-use crate::im::{Im, MaskIm};
-use crate::region_tree::{CutBand, PlyIm, RegionI, RegionIm, RegionNode, RegionRoot};
-use crate::trace::{Contour, contours_by_suzuki_abe};
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use crate::im::{Im, Lum16Im, MaskIm};
+use crate::metrics::Metrics;
+use crate::region_tree::{
+    CutBand, PlyIm, RegionI, RegionIm, RegionNode, RegionRoot, ply_threshold_at_depth,
+};
+use crate::trace::{
+    Contour, Iv2, build_contour_tree, contours_by_suzuki_abe, contours_by_suzuki_abe_in_roi,
+    ring_signed_area2,
+};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{ControlFlow, Range};
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct IV3 {
     pub x: i32, // Pixels
     pub y: i32, // Pixels
     pub z: i32, // Thou
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct CutPixels {
     pub pixels_changed: u64,
     pub depth_sum_thou: u64,
+    /// Feed multiplier for this segment, assigned by `sim_toolpaths` from the engagement
+    /// (see `sim::FeedScaleFn`). 1.0 means full feed.
+    pub feed_scale: f32,
+    /// Largest fraction of the tool's leading semicircle found in material along this segment,
+    /// read from the heights before the segment cut (see `sim::sim_toolpaths`). 1.0 is a full
+    /// slot; spikes in corners predict chatter.
+    #[serde(default)]
+    pub max_engagement_frac: f32,
+    /// Set on the segments of a holding tab (see `add_holding_tabs`): the raised run and the
+    /// moves up to and down from it. Kept by `sim_toolpaths` when it resets the cut counts.
+    #[serde(default)]
+    pub is_tab: bool,
+}
+
+impl Default for CutPixels {
+    fn default() -> Self {
+        Self {
+            pixels_changed: 0,
+            depth_sum_thou: 0,
+            feed_scale: 1.0,
+            max_engagement_frac: 0.0,
+            is_tab: false,
+        }
+    }
 }
 
 impl CutPixels {
@@ -36,10 +75,19 @@ impl CutPixels {
     pub fn merge(&mut self, other: CutPixels) {
         self.pixels_changed += other.pixels_changed;
         self.depth_sum_thou += other.depth_sum_thou;
+        // The merged segment is only as fast as its most engaged part.
+        self.feed_scale = self.feed_scale.min(other.feed_scale);
+        self.max_engagement_frac = self.max_engagement_frac.max(other.max_engagement_frac);
+        self.is_tab |= other.is_tab;
+    }
+
+    /// Mean depth removed per changed pixel, or None if nothing was cut.
+    pub fn avg_depth_thou(&self) -> Option<f32> {
+        (self.pixels_changed > 0).then(|| self.depth_sum_thou as f32 / self.pixels_changed as f32)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ToolPath {
     pub points: Vec<IV3>,
     pub closed: bool,
@@ -54,38 +102,252 @@ pub struct ToolPath {
     pub cuts: Vec<CutPixels>,
     pub is_traverse: bool,
     pub is_raster: bool,
+    /// Raster clearing's entry run, which starts at the node's plunge point (see
+    /// `choose_plunge_point`). `sort_toolpaths` keeps it first in its node.
+    #[serde(default)]
+    pub is_plunge_run: bool,
+}
+
+impl ToolPath {
+    /// All segment cuts merged into one (see `CutPixels::merge`).
+    pub fn total_cut(&self) -> CutPixels {
+        let mut total = CutPixels::default();
+        for &cut in &self.cuts {
+            total.merge(cut);
+        }
+        total
+    }
+
+    /// Check the invariants the post-processing passes rely on: `cuts` is parallel to
+    /// `points`, feed scales are finite, an open path has at least one segment, and a closed
+    /// path repeats its first point at the end and has at least 3 distinct (x, y) vertices.
+    pub fn validate(&self) -> Result<(), ToolPathError> {
+        if self.cuts.len() != self.points.len() {
+            return Err(ToolPathError::CutsLenMismatch {
+                n_points: self.points.len(),
+                n_cuts: self.cuts.len(),
+            });
+        }
+        if let Some(seg_i) = self.cuts.iter().position(|c| !c.feed_scale.is_finite()) {
+            return Err(ToolPathError::NonFiniteFeedScale { seg_i });
+        }
+        if !self.closed {
+            if self.points.len() < 2 {
+                return Err(ToolPathError::TooFewPoints {
+                    n_points: self.points.len(),
+                });
+            }
+            return Ok(());
+        }
+
+        if self.points.len() < 2 || self.points.first() != self.points.last() {
+            return Err(ToolPathError::ClosedWithoutClosingVertex);
+        }
+        let mut ring_xy: Vec<(i32, i32)> = self.points[..self.points.len() - 1]
+            .iter()
+            .map(|p| (p.x, p.y))
+            .collect();
+        ring_xy.sort_unstable();
+        ring_xy.dedup();
+        if ring_xy.len() < 3 {
+            return Err(ToolPathError::ClosedTooFewVertices {
+                n_distinct: ring_xy.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Why `ToolPath::validate` rejected a toolpath.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolPathError {
+    /// `cuts` must hold one entry per point (the last one unused).
+    CutsLenMismatch { n_points: usize, n_cuts: usize },
+    /// A segment's `feed_scale` is NaN or infinite.
+    NonFiniteFeedScale { seg_i: usize },
+    /// An open path needs at least two points to move anywhere.
+    TooFewPoints { n_points: usize },
+    /// Closed paths are stored with the first point repeated at the end.
+    ClosedWithoutClosingVertex,
+    /// A closed loop over fewer than 3 distinct (x, y) vertices encloses nothing.
+    ClosedTooFewVertices { n_distinct: usize },
+}
+
+impl ToolPathError {
+    /// Too short to cut anything; `cull_empty_toolpaths` drops these, so the passes before
+    /// it may pass them through.
+    pub fn is_degenerate(&self) -> bool {
+        matches!(
+            self,
+            ToolPathError::TooFewPoints { .. } | ToolPathError::ClosedTooFewVertices { .. }
+        )
+    }
 }
 
+impl fmt::Display for ToolPathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ToolPathError::CutsLenMismatch { n_points, n_cuts } => {
+                write!(f, "{n_cuts} cuts for {n_points} points")
+            }
+            ToolPathError::NonFiniteFeedScale { seg_i } => {
+                write!(f, "segment {seg_i} has a non-finite feed scale")
+            }
+            ToolPathError::TooFewPoints { n_points } => {
+                write!(f, "open path has {n_points} points, needs at least 2")
+            }
+            ToolPathError::ClosedWithoutClosingVertex => {
+                write!(f, "closed path does not end on its first point")
+            }
+            ToolPathError::ClosedTooFewVertices { n_distinct } => write!(
+                f,
+                "closed path has {n_distinct} distinct vertices, needs at least 3"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ToolPathError {}
+
+/// Debug builds only: panic if `step` left a toolpath invalid. With `allow_degenerate`,
+/// paths too short to cut are let through for `cull_empty_toolpaths` to drop.
+fn debug_validate_toolpaths(toolpaths: &[ToolPath], step: &str, allow_degenerate: bool) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    for (tp_i, tp) in toolpaths.iter().enumerate() {
+        if let Err(e) = tp.validate()
+            && !(allow_degenerate && e.is_degenerate())
+        {
+            panic!("{step} left toolpath {tp_i} invalid: {e}");
+        }
+    }
+}
+
+/// Serialize a toolpath set to JSON so planning results can be cached and re-opened later.
+pub fn toolpaths_to_json(tps: &[ToolPath]) -> String {
+    serde_json::to_string(tps).expect("ToolPath serialization cannot fail")
+}
+
+/// Inverse of `toolpaths_to_json`.
+pub fn toolpaths_from_json(json_text: &str) -> Result<Vec<ToolPath>, serde_json::Error> {
+    serde_json::from_str(json_text)
+}
+
+/// One closed perimeter toolpath per contour of a Suzuki–Abe set at `target_z_thou`.
+///
+/// Each exterior is followed by the holes whose `parent` is that exterior, so an island is
+/// cut right after the wall that surrounds it. Exteriors run clockwise and holes
+/// counterclockwise (y up, as exported), which climb-mills both the pocket wall and the
+/// island wall with a clockwise spindle. Holes with no matching exterior come last.
 fn create_perimeter_tool_paths(
-    contour: &Contour,
+    contours: &[Contour],
     target_z_thou: Thou,
     tool_i: usize,
     tool_dia_pix: usize,
     tree_node_id: usize,
 ) -> Vec<ToolPath> {
+    let tree = build_contour_tree(contours);
+    let mut order: Vec<usize> = Vec::with_capacity(contours.len());
+    for (ext_i, ext) in contours.iter().enumerate() {
+        if ext.is_hole {
+            continue;
+        }
+        order.push(ext_i);
+        order.extend(
+            tree.children(ext_i)
+                .iter()
+                .filter(|&&hole_i| contours[hole_i].is_hole),
+        );
+    }
+    order.extend(contours.iter().enumerate().filter_map(|(hole_i, c)| {
+        let has_ext = c
+            .parent
+            .is_some_and(|p| contours.get(p).is_some_and(|e| !e.is_hole));
+        (c.is_hole && !has_ext).then_some(hole_i)
+    }));
+
     let z = target_z_thou.0;
-    let mut points: Vec<IV3> = Vec::with_capacity(contour.points.len());
-    for &pt in &contour.points {
-        points.push(IV3 {
-            x: pt.x as i32,
-            y: pt.y as i32,
-            z,
-        });
+    order
+        .into_iter()
+        .map(|contour_i| {
+            let contour = &contours[contour_i];
+            let mut ring: Vec<Iv2> = contour.points.clone();
+            let is_ccw = ring_signed_area2(&ring) > 0;
+            if is_ccw != contour.is_hole {
+                ring.reverse();
+            }
+            let points: Vec<IV3> = ring
+                .iter()
+                .map(|pt| IV3 {
+                    x: pt.x,
+                    y: pt.y,
+                    z,
+                })
+                .collect();
+            let cuts = vec![CutPixels::default(); points.len()];
+            ToolPath {
+                points,
+                closed: true,
+                tool_dia_pix,
+                tool_i,
+                tile_i: 0,
+                tree_node_id,
+                cuts,
+                is_traverse: false,
+                is_raster: false,
+                is_plunge_run: false,
+            }
+        })
+        .collect()
+}
+
+/// Offset every contour inward by `dist_pix` (see `Contour::offset`), remapping each hole's
+/// `parent` to the first piece of its offset exterior so the set stays self-consistent.
+fn offset_contours_inward(contours: &[Contour], dist_pix: f64) -> Vec<Contour> {
+    let mut first_piece_i: Vec<Option<usize>> = Vec::with_capacity(contours.len());
+    let mut out: Vec<Contour> = Vec::new();
+    for contour in contours {
+        let pieces = contour.offset(-dist_pix);
+        first_piece_i.push((!pieces.is_empty()).then_some(out.len()));
+        out.extend(pieces);
     }
+    for c in &mut out {
+        c.parent = c
+            .parent
+            .and_then(|p| first_piece_i.get(p).copied().flatten());
+    }
+    out
+}
 
-    let cuts = vec![CutPixels::default(); points.len()];
+/// The set pixel of `cut_mask` within `roi` that is furthest from any unset pixel (by the
+/// distance transform), i.e. the safest place to plunge a full-width tool or to begin a ramp.
+/// Pixels outside `roi` count as unset. Ties go to the first in scan order. None if nothing
+/// in `roi` is set.
+pub fn choose_plunge_point(cut_mask: &MaskIm, roi: &ROI) -> Option<(usize, usize)> {
+    let roi = roi.clamp_to(cut_mask.w, cut_mask.h);
+    if roi.is_empty() {
+        return None;
+    }
 
-    vec![ToolPath {
-        points,
-        closed: true,
-        tool_dia_pix,
-        tool_i,
-        tile_i: 0,
-        tree_node_id,
-        cuts,
-        is_traverse: false,
-        is_raster: false,
-    }]
+    let mut crop = MaskIm::new(roi.w(), roi.h());
+    for y in 0..crop.h {
+        let src = (roi.t + y) * cut_mask.s + roi.l;
+        let dst = y * crop.s;
+        crop.arr[dst..dst + crop.w].copy_from_slice(&cut_mask.arr[src..src + crop.w]);
+    }
+    let dist_im = distance_transform(&crop);
+
+    let mut best: Option<(u16, usize, usize)> = None;
+    for y in 0..dist_im.h {
+        for x in 0..dist_im.w {
+            let d = dist_im.arr[y * dist_im.s + x];
+            if d > 0 && best.is_none_or(|(best_d, _, _)| d > best_d) {
+                best = Some((d, x, y));
+            }
+        }
+    }
+    best.map(|(_, x, y)| (roi.l + x, roi.t + y))
 }
 
 /// Given a cut mask image (1-channel, 8-bit), generate raster tool paths
@@ -95,6 +357,11 @@ fn create_perimeter_tool_paths(
 /// Then step down by step_size_pix and repeat until the entire ROI is covered.
 /// Each tool path is represented as a series of V3 points (X,Y,Z) where X,Y are in pixesls and
 /// Z is the tool height (in Thou).
+///
+/// The first tool path starts at the plunge point (see `choose_plunge_point`, snapped to the
+/// nearest raster row) rather than against a wall: the run there moves to the front, is marked
+/// `is_plunge_run`, and runs from the plunge point to its nearer end and then across to the
+/// other, so only the shorter stretch is cut twice.
 fn create_raster_surface_tool_paths_from_cut_mask(
     cut_mask_im: &MaskIm,
     roi: &ROI,
@@ -111,28 +378,21 @@ fn create_raster_surface_tool_paths_from_cut_mask(
         return Vec::new();
     }
 
-    // Clamp ROI to image bounds (ROI right/bottom are exclusive).
-    let mut l = roi.l.min(w);
-    let mut t = roi.t.min(h);
-    let mut r = roi.r.min(w);
-    let mut b = roi.b.min(h);
-    if l >= r || t >= b {
-        return Vec::new();
-    }
-
-    // Ensure we never generate tool-center positions that would place the tool outside the image.
+    // Clamp the ROI to the tool-center positions that keep the tool inside the image.
     let rad = (tool_dia_pix / 2 as usize)
         .min(w.saturating_sub(1))
         .min(h.saturating_sub(1));
-    let max_x_excl = w.saturating_sub(rad);
-    let max_y_excl = h.saturating_sub(rad);
-    l = l.max(rad);
-    t = t.max(rad);
-    r = r.min(max_x_excl);
-    b = b.min(max_y_excl);
-    if l >= r || t >= b {
+    let centerable = ROI {
+        l: rad,
+        t: rad,
+        r: w.saturating_sub(rad),
+        b: h.saturating_sub(rad),
+    };
+    let roi = roi.clamp_to(w, h).intersect(centerable);
+    if roi.is_empty() {
         return Vec::new();
     }
+    let ROI { l, t, r, b } = roi;
 
     let y_step = (tool_step_pix).max(1) as usize;
 
@@ -170,6 +430,7 @@ fn create_raster_surface_tool_paths_from_cut_mask(
                     cuts: vec![CutPixels::default(); 2],
                     is_traverse: false,
                     is_raster: true,
+                    is_plunge_run: false,
                 });
             }
         }
@@ -198,1445 +459,5866 @@ fn create_raster_surface_tool_paths_from_cut_mask(
                 cuts: vec![CutPixels::default(); 2],
                 is_traverse: false,
                 is_raster: true,
+                is_plunge_run: false,
             });
         }
     }
 
-    paths
-}
-
-/// Given a RegionNode tree root, we traverse the tree and rasterize each node's regions
-/// into a pixel image.
-/// There's two working MaskIms:
-///  * One is the curr_node_mask_im which holds the pixels of the current node. We copy it from the LabelInfo.pixel_iz,
-///    then dilate it.
-///  * The other is the above_mask. For that we expand the ROI by the tool_radius
-///    and then copy any pixel above the current threshold inside that ROI into
-///    the above mask. Then we dilate that as well and then we subtract the above_mask
-///    from the curr_node_mask_im.
-/// Then we convert these masks into clearing-paths by traversing the mask
-/// and build a RLE representation of the mask along the standard scanlines.
-pub fn create_toolpaths_from_region_tree(
-    name: &str,
-    region_root: &RegionRoot,
-    cut_bands: &[CutBand],
-    tool_i: usize,
-    tool_dia_pix: usize,
-    step_size_pix: usize,
-    margin_pix: usize,
-    pride_thou: Thou,
-    ply_im: &PlyIm,
-    region_im: &RegionIm,
-    diff_mask_im: Option<&MaskIm>,
-    region_infos: &[LabelInfo],
-    n_perimeters: usize,
-    perimeter_step_size_pix: usize,
-    gen_surfaces: bool,
-    mut on_region_masks: Option<&mut dyn FnMut(&RegionNode, &ROI, &MaskIm, &MaskIm, &MaskIm)>,
-) -> Vec<ToolPath> {
-    let w = region_im.w;
-    let h = region_im.h;
-    if let Some(diff_mask_im) = diff_mask_im {
-        assert_eq!(diff_mask_im.w, w, "diff_mask_im.w must match region_im.w");
-        assert_eq!(diff_mask_im.h, h, "diff_mask_im.h must match region_im.h");
-    }
-
-    let mut cut_mask_im = MaskIm::new(w, h);
-    let mut above_mask_im = MaskIm::new(w, h);
-    let mut dil_above_mask_im = MaskIm::new(w, h);
-    let mut dil_cut_mask_im = MaskIm::new(w, h);
-
-    let mut paths: Vec<ToolPath> = Vec::new();
-
-    fn splat_region_i_into_mask_im(
-        region_i: RegionI,
-        region_infos: &[LabelInfo],
-        mask_im: &mut MaskIm,
-        diff_mask_im: Option<&MaskIm>,
-    ) -> usize {
-        let label_i = region_i.0 as usize;
-        if label_i == 0 || label_i >= region_infos.len() {
-            return 0;
-        }
-        let mut n_pixels: usize = 0;
-        let label_info = &region_infos[label_i];
-        for &pix_i in &label_info.pixel_iz {
-            if pix_i < mask_im.arr.len() {
-                if let Some(diff_mask_im) = diff_mask_im {
-                    if diff_mask_im.arr[pix_i] > 0 {
-                        mask_im.arr[pix_i] = 255;
-                        n_pixels += 1;
-                    }
-                } else {
-                    mask_im.arr[pix_i] = 255;
-                    n_pixels += 1;
-                }
+    if let Some((plunge_x, plunge_y)) = choose_plunge_point(cut_mask_im, &roi) {
+        let n_rows = (b - t).div_ceil(y_step);
+        let row_i = ((plunge_y - t) as f64 / y_step as f64).round() as usize;
+        let row_y = (t + row_i.min(n_rows - 1) * y_step) as i32;
+        let plunge_x = plunge_x as i32;
+        let run_i = paths.iter().position(|tp| {
+            tp.points[0].y == row_y && (tp.points[0].x..=tp.points[1].x).contains(&plunge_x)
+        });
+        if let Some(run_i) = run_i {
+            let mut run = paths.remove(run_i);
+            let (l, r) = (run.points[0], run.points[1]);
+            let (near, far) = if plunge_x - l.x <= r.x - plunge_x {
+                (l, r)
+            } else {
+                (r, l)
+            };
+            run.points = vec![near, far];
+            if near.x != plunge_x {
+                run.points.insert(0, IV3 { x: plunge_x, ..l });
+                run.cuts.push(CutPixels::default());
             }
+            run.is_plunge_run = true;
+            paths.insert(0, run);
         }
-        n_pixels
     }
 
-    // Recurse through the region tree
-    fn recurse_region_tree(
-        name: &str,
-        node: &RegionNode,
-        cut_bands: &[CutBand],
-        cut_mask_im: &mut MaskIm,
-        above_mask_im: &mut MaskIm,
-        dil_abv_mask_im: &mut MaskIm,
-        dil_cut_mask_im: &mut MaskIm,
-        tool_i: usize,
-        tool_dia_pix: usize,
-        step_size_pix: usize,
-        margin_pix: usize,
-        pride_thou: Thou,
-        ply_im: &PlyIm,
-        diff_mask_im: Option<&MaskIm>,
-        region_infos: &[LabelInfo],
-        paths: &mut Vec<ToolPath>,
-        n_perimeters: usize,
-        perimeter_step_size_pix: usize,
-        gen_surfaces: bool,
-        on_region_masks: &mut Option<&mut dyn FnMut(&RegionNode, &ROI, &MaskIm, &MaskIm, &MaskIm)>,
-    ) {
-        // TODO: Optimze by clearing on the ROI after the fact
-        cut_mask_im.arr.fill(0);
-        above_mask_im.arr.fill(0);
-        dil_abv_mask_im.arr.fill(0);
-        dil_cut_mask_im.arr.fill(0);
-
-        let mut roi: ROI = ROI {
-            l: 0_usize,
-            t: 0_usize,
-            r: 0_usize,
-            b: 0_usize,
-        };
-        let curr_ply_i_u16: u16;
-        let z_thou: Thou;
-
-        fn ply_threshold_at_depth(cut_bands: &[CutBand], depth_thou: Thou) -> u16 {
-            // We want the largest ply index whose top_thou is <= the depth we're cutting to.
-            // Pixels with a higher ply index are "above" this depth and must be excluded.
-            let mut best: u16 = 0;
-            for band in cut_bands {
-                for cp in &band.cut_planes {
-                    if cp.is_floor {
-                        continue;
-                    }
-                    if cp.top_thou.0 <= depth_thou.0 {
-                        best = best.max(cp.ply_i.0);
-                    }
-                }
-            }
-            best
-        }
-
-        let _is_node_floor = matches!(node, RegionNode::Floor { .. });
-
-        let tool_rad_pix = tool_dia_pix / 2;
-        let base_rad_pix = tool_rad_pix + margin_pix;
-        let mut n_pixels: usize = 0;
-
-        // Splat in the current node's regions.
-        // For floors there is 1+, for cuts there is 1. And find the ROI
-        match node {
-            RegionNode::Floor {
-                region_iz,
-                bottom_thou,
-                ..
-            } => {
-                for region_i in region_iz {
-                    n_pixels += splat_region_i_into_mask_im(
-                        *region_i,
-                        region_infos,
-                        cut_mask_im,
-                        diff_mask_im,
-                    );
+    paths
+}
 
-                    // debug_ui::add_mask_im(
-                    //     &format!("{} floor_mask after={} region_i={}", name, z_thou.0, region_i.0),
-                    //     cut_mask_im,
-                    // );
+/// The tool assigned to a cut pass (e.g. "rough", "refine"), see `create_toolpaths_from_region_tree`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ToolDef {
+    pub tool_i: usize,
+    pub tool_dia_pix: usize,
+    /// Deepest Z this tool may remove in one pass; deeper nodes are stepped down with
+    /// `enforce_max_doc` from the top of their band. None means no limit.
+    pub max_doc_thou: Option<Thou>,
+}
 
-                    let label_i = region_i.0 as usize;
-                    assert!(label_i < region_infos.len());
-                    let label_info = &region_infos[label_i];
-                    roi.union(label_info.roi);
-                }
+/// `frac` of the tool diameter in pixels (e.g. 0.4 for a 40% stepover), rounded and at least 1.
+pub fn stepover_pix(tool_dia_pix: usize, frac: f32) -> usize {
+    ((tool_dia_pix as f32 * frac).round() as usize).max(1)
+}
 
-                // For a floor, the "above" threshold should be derived from the depth we cut
-                // to (the band's bottom). Using `lowest_ply_i_in_band - 1` can underflow to 0
-                // and incorrectly mark essentially the entire ROI as "above".
-                curr_ply_i_u16 = ply_threshold_at_depth(cut_bands, *bottom_thou);
-                z_thou = *bottom_thou;
-            }
-            RegionNode::Cut {
-                band_i: _,
-                cut_plane_i: _,
-                region_i,
-                z_thou: node_z_thou,
-                ..
-            } => {
-                z_thou = node_z_thou.clone();
+/// Height of the cusp a ball tool of `tool_radius_thou` leaves between neighboring passes
+/// `stepover_thou` apart on a flat floor.
+///
+/// Looking along the passes, each pass cuts a circular arc of radius R. The arcs of two
+/// passes s apart meet halfway, s/2 from either center, where each arc sits
+/// sqrt(R² - (s/2)²) below its center. The cusp is that far below the centers while the
+/// bottoms of the arcs are R below, so:
+///
+///   h = R - sqrt(R² - (s/2)²)
+///
+/// Past s = 2R the arcs no longer meet and uncut floor is left between them, so h is capped at R.
+pub fn scallop_height_thou(tool_radius_thou: f64, stepover_thou: f64) -> f64 {
+    let r = tool_radius_thou.max(0.0);
+    let half_step = (stepover_thou.max(0.0) / 2.0).min(r);
+    r - (r * r - half_step * half_step).sqrt()
+}
 
-                n_pixels +=
-                    splat_region_i_into_mask_im(*region_i, region_infos, cut_mask_im, diff_mask_im);
+/// The stepover that leaves a `scallop_thou` cusp with a ball tool of `tool_radius_thou`, the
+/// inverse of `scallop_height_thou`. Solving h = R - sqrt(R² - (s/2)²) for s:
+///
+///   (s/2)² = R² - (R - h)² = h(2R - h)  =>  s = 2 sqrt(h(2R - h))
+///
+/// A scallop of R or more allows any stepover up to the full diameter (2R).
+pub fn stepover_for_scallop(tool_radius_thou: f64, scallop_thou: f64) -> f64 {
+    let r = tool_radius_thou.max(0.0);
+    let h = scallop_thou.clamp(0.0, r);
+    2.0 * (h * (2.0 * r - h)).sqrt()
+}
 
-                let label_i = region_i.0 as usize;
-                assert!(label_i < region_infos.len());
-                let label_info = &region_infos[label_i];
-                roi.union(label_info.roi);
+/// The distance between neighboring clearing passes (raster rows, spiral rings).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stepover {
+    /// An absolute distance in pixels (at least 1 is used).
+    Pix(usize),
+    /// A fraction of the diameter of the tool doing the clearing, see `stepover_pix`.
+    /// Stays the same relative overlap across tools and resolutions.
+    Frac(f32),
+}
 
-                curr_ply_i_u16 =
-                    ply_im.get_or_default(label_info.start_x, label_info.start_y, 0, 0);
-            }
+impl Stepover {
+    pub fn to_pix(self, tool_dia_pix: usize) -> usize {
+        match self {
+            Stepover::Pix(pix) => pix.max(1),
+            Stepover::Frac(frac) => stepover_pix(tool_dia_pix, frac),
         }
+    }
 
-        // If nothing was splatted into the mask_im, skip this node.
-        // This handles the case in differential mode where the region has
-        // no pixels that align with the cut.
-        if n_pixels == 0 {
-            return;
+    /// The `Frac` stepover that leaves a `scallop_thou` cusp with a ball tool of
+    /// `tool_radius_thou` (see `stepover_for_scallop`), e.g. for a 0.002in scallop spec.
+    /// Being a fraction of the diameter, it resolves to pixels against each tool's `tool_dia_pix`.
+    pub fn for_scallop(tool_radius_thou: f64, scallop_thou: f64) -> Stepover {
+        if tool_radius_thou <= 0.0 {
+            return Stepover::Pix(1);
         }
+        let stepover_thou = stepover_for_scallop(tool_radius_thou, scallop_thou);
+        Stepover::Frac((stepover_thou / (2.0 * tool_radius_thou)) as f32)
+    }
+}
 
-        // Build the above_mask_im by expanding the ROI and copying any ply pixels that
-        // are above the current region's ply threshold.
-        // Recall that ply_im is sorted form the bottom; higher ply indices have higher values.
-        // Expand by the maximum radius we will use across perimeter passes so the subtraction is
-        // correct for all offsets.
-        let n_dilation_passes = n_perimeters.max(1);
-        let max_rad_pix = base_rad_pix.saturating_add(
-            perimeter_step_size_pix.saturating_mul(n_dilation_passes.saturating_sub(1)),
-        );
-        let padded_roi = roi.padded(max_rad_pix, ply_im.w, ply_im.h);
-        for y in padded_roi.t..padded_roi.b {
-            let row = y * ply_im.s;
-            for x in padded_roi.l..padded_roi.r {
-                let i = row + x;
-                if ply_im.arr[i] > curr_ply_i_u16 {
-                    above_mask_im.arr[i] = 255;
-                }
+/// Selects the generator used to clear the interior of each node when `gen_surfaces` is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClearStrategy {
+    /// Horizontal scanline runs (see `create_raster_surface_tool_paths_from_cut_mask`).
+    #[default]
+    Raster,
+    /// Concentric rings stepping inward from the boundary (see `create_spiral_clearing`).
+    Spiral,
+}
+
+/// Selects how the 2nd and later perimeters (`n_perimeters > 1`) are generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PerimeterStrategy {
+    /// Trace the first perimeter once and offset it inward by `perimeter_step_size_pix`
+    /// per pass (see `Contour::offset`), so the loops stay parallel.
+    #[default]
+    Offset,
+    /// Re-dilate the masks by a growing radius and re-trace for every pass.
+    Dilate,
+}
+
+/// Given a cut mask image (1-channel, 8-bit) of tool-center positions, generate pocket-style
+/// clearing paths: trace the boundary of the mask as a ring, erode the mask by `tool_step_pix`,
+/// and repeat until the erosion empties the mask.
+/// Each ring is joined to the ring from the previous level whose end is nearby,
+/// forming inward spirals; a ring with no nearby predecessor (e.g. after the mask splits
+/// into islands), or whose only nearby links would leave the mask (e.g. across a wall
+/// of a concave pocket), starts a new toolpath.
+pub fn create_spiral_clearing(
+    cut_mask_im: &MaskIm,
+    roi: &ROI,
+    tool_i: usize,
+    tool_dia_pix: usize,
+    tool_step_pix: usize,
+    z_thou: Thou,
+    tree_node_id: usize,
+) -> Vec<ToolPath> {
+    let roi = roi.clamp_to(cut_mask_im.w, cut_mask_im.h);
+    if roi.is_empty() {
+        return Vec::new();
+    }
+    let ROI { l, t, r, b } = roi;
+
+    let step_pix = tool_step_pix.max(1);
+
+    // Work in a local image with a zero border wide enough for both the tracer's
+    // 1-pixel margin requirement and the erosion kernel.
+    let pad = step_pix + 1;
+    let lw = (r - l) + 2 * pad;
+    let lh = (b - t) + 2 * pad;
+    let mut ring_mask_im = MaskIm::new(lw, lh);
+    let mut scratch_im = MaskIm::new(lw, lh);
+    for y in t..b {
+        for x in l..r {
+            if cut_mask_im.arr[y * cut_mask_im.s + x] != 0 {
+                ring_mask_im.arr[(y - t + pad) * ring_mask_im.s + (x - l + pad)] = 255;
             }
         }
+    }
+    let dx = l as i32 - pad as i32;
+    let dy = t as i32 - pad as i32;
 
-        // Add a one-pixel border on the image edges (over the padded ROI span) to ensure
-        // the image boundary is excluded from the cut.
-        above_mask_im.one_pixel_border_on_image_edges_over_roi_span(padded_roi, 255);
+    fn dist2_xy(a: &IV3, b: &IV3) -> i64 {
+        let dx = (a.x as i64) - (b.x as i64);
+        let dy = (a.y as i64) - (b.y as i64);
+        dx * dx + dy * dy
+    }
 
-        // debug_ui::add_mask_im(
-        //     &format!("region_above_mask={} is_floor={}", z_thou.0, is_node_floor),
-        //     above_mask_im,
-        // );
+    // True when the straight link a -> b stays on the cut mask, so it can be cut at depth.
+    let link_in_mask = |a: &IV3, b: &IV3| {
+        let n = (a.x - b.x).abs().max((a.y - b.y).abs()).max(1);
+        (0..=n).all(|i| {
+            let t = i as f64 / n as f64;
+            let x = (a.x as f64 + (b.x - a.x) as f64 * t).round() as i32;
+            let y = (a.y as f64 + (b.y - a.y) as f64 * t).round() as i32;
+            x >= 0
+                && y >= 0
+                && (x as usize) < cut_mask_im.w
+                && (y as usize) < cut_mask_im.h
+                && cut_mask_im.arr[y as usize * cut_mask_im.s + x as usize] != 0
+        })
+    };
 
-        // Each perimeter pass uses a larger dilation radius.
-        for dilation_i in 0..n_dilation_passes {
-            let rad_pix =
-                base_rad_pix.saturating_add(perimeter_step_size_pix.saturating_mul(dilation_i));
+    // A ring may continue a spiral only if it starts within about one step of the spiral's end.
+    let link_pix = (2 * step_pix + 2) as i64;
+    let max_link2 = link_pix * link_pix;
 
-            // Convert radius -> diameter for `im_dilate` (which uses `radius = dia/2`).
-            // `2*rad+1` ensures each +1 in radius always changes the dilation.
-            let max_dia_pix = ply_im.w.min(ply_im.h).max(1);
-            let dia_pix = rad_pix.saturating_mul(2).saturating_add(1).min(max_dia_pix);
+    let mut done: Vec<Vec<IV3>> = Vec::new();
+    let mut active: Vec<Vec<IV3>> = Vec::new();
+    let mut contour_im = Im::<i32, 1>::new(lw, lh);
+    loop {
+        for (dst, &src) in contour_im.arr.iter_mut().zip(ring_mask_im.arr.iter()) {
+            *dst = if src != 0 { 1 } else { 0 };
+        }
+        let contours = contours_by_suzuki_abe(&mut contour_im);
+        if contours.is_empty() {
+            break;
+        }
 
-            // Dilate the above mask to the same radius as the current cut mask.
-            im_dilate(above_mask_im, dil_abv_mask_im, dia_pix);
+        let mut next_active: Vec<Vec<IV3>> = Vec::new();
+        for contour in contours {
+            let simp = contour.simplify_by_rdp(1.0);
+            let mut ring: Vec<IV3> = simp
+                .points
+                .iter()
+                .map(|p| IV3 {
+                    x: p.x + dx,
+                    y: p.y + dy,
+                    z: z_thou.0,
+                })
+                .collect();
+            if ring.len() >= 2 && ring.first() == ring.last() {
+                ring.pop();
+            }
+            if ring.is_empty() {
+                continue;
+            }
 
-            // Apply the pride offset at cut time (not the region-plane time).
-            let cut_z_thou = Thou(z_thou.0.saturating_add(pride_thou.0));
-
-            // debug_ui::add_mask_im(
-            //     &format!("{} cut_mask_im before={}", name, cut_z_thou.0),
-            //     cut_mask_im,
-            // );
-
-            // Dilate the current region into tool-centerable space.
-            im_dilate(cut_mask_im, dil_cut_mask_im, dia_pix);
-
-            // if name == "refine" && dilation_i == 0 {
-            //     debug_ui::add_mask_im(
-            //         &format!("{} dil_cut_mask_im before={} dilation_i={}", name, cut_z_thou.0, dilation_i),
-            //         dil_cut_mask_im,
-            //     );
-            // }
-
-            // Subtract dilation above from cut_mask.
-            // TODO: Optimize by limiting the dilation to the padded ROI.
-            for y in padded_roi.t..padded_roi.b {
-                let row = y * ply_im.s;
-                for x in padded_roi.l..padded_roi.r {
-                    let i = row + x;
-                    if dil_abv_mask_im.arr[i] > 0 {
-                        dil_cut_mask_im.arr[i] = 0;
+            // Take the nearest (spiral end, ring point) link among the previous level's
+            // spirals that stays on the mask.
+            let mut links: Vec<(i64, usize, usize)> = Vec::new();
+            for (spiral_i, spiral) in active.iter().enumerate() {
+                let end = spiral.last().expect("spirals are never empty");
+                for (pt_i, pt) in ring.iter().enumerate() {
+                    let d2 = dist2_xy(end, pt);
+                    if d2 <= max_link2 {
+                        links.push((d2, spiral_i, pt_i));
                     }
                 }
             }
+            links.sort_unstable();
+            let best = links.into_iter().find(|&(_, spiral_i, pt_i)| {
+                link_in_mask(
+                    active[spiral_i].last().expect("spirals are never empty"),
+                    &ring[pt_i],
+                )
+            });
 
-            // if name == "refine" && dilation_i == 0 {
-            //     debug_ui::add_mask_im(
-            //         &format!("{} dil_cut_mask_im after={} dilation_i={}", name, cut_z_thou.0, dilation_i),
-            //         dil_cut_mask_im,
-            //     );
-            // }
-
-            let mut node_toolpaths: Vec<ToolPath> = Vec::new();
+            let (mut spiral, start_i) = match best {
+                Some((_, spiral_i, pt_i)) => (active.swap_remove(spiral_i), pt_i),
+                None => (Vec::new(), 0),
+            };
+            ring.rotate_left(start_i);
+            let start = ring[0];
+            spiral.extend(ring);
+            spiral.push(start);
+            next_active.push(spiral);
+        }
+        done.append(&mut active);
+        active = next_active;
+
+        // Erode by step: dilate the complement then invert back.
+        ring_mask_im.invert();
+        im_dilate_radius(&ring_mask_im, &mut scratch_im, step_pix);
+        scratch_im.invert();
+        std::mem::swap(&mut ring_mask_im, &mut scratch_im);
+    }
+    done.append(&mut active);
 
-            if gen_surfaces && dilation_i == 0 {
-                let toolpaths = create_raster_surface_tool_paths_from_cut_mask(
-                    dil_cut_mask_im,
-                    &padded_roi,
-                    tool_i,
-                    tool_dia_pix,
-                    step_size_pix,
-                    cut_z_thou,
-                    node.get_id(),
-                );
-                node_toolpaths.extend(toolpaths);
+    done.into_iter()
+        .filter(|points| !points.is_empty())
+        .map(|points| {
+            let n = points.len();
+            ToolPath {
+                points,
+                closed: false,
+                tool_dia_pix,
+                tool_i,
+                tile_i: 0,
+                tree_node_id,
+                cuts: vec![CutPixels::default(); n],
+                is_traverse: false,
+                is_raster: false,
+                is_plunge_run: false,
             }
+        })
+        .collect()
+}
 
-            if n_perimeters > 0 {
-                // Suzuki–Abe operates on a 1-channel i32 image and mutates it in-place.
-                // TODO: Consider a refactor to generate the masks as i32 directly.
-                // TODO: Move this allocation out of the inner loop.
-                let mut cut_mask_im_i32 = Im::<i32, 1>::new(cut_mask_im.w, cut_mask_im.h);
-                for (dst, &src) in cut_mask_im_i32
-                    .arr
-                    .iter_mut()
-                    .zip(dil_cut_mask_im.arr.iter())
-                {
-                    *dst = if src != 0 { 1 } else { 0 };
-                }
+/// Material left by a previous pass within this many thou of the target is not worth another pass.
+const NEEDS_REFINE_TOL_THOU: u16 = 2;
+
+/// Mark the pixels within `roi` where the `roughed` surface is still above `target`
+/// by more than `tol_thou`, i.e. the leftover scallops a refine pass should clear.
+/// Pixels outside the ROI are left unmarked.
+pub fn mask_needs_refine(target: &Lum16Im, roughed: &Lum16Im, roi: &ROI, tol_thou: u16) -> MaskIm {
+    assert_eq!(target.w, roughed.w, "target.w must match roughed.w");
+    assert_eq!(target.h, roughed.h, "target.h must match roughed.h");
+
+    let mut mask_im = MaskIm::new(target.w, target.h);
+    mark_needs_refine(
+        roughed,
+        |x, y| target.arr[y * target.s + x],
+        roi,
+        tol_thou,
+        &mut mask_im,
+    );
+    mask_im
+}
 
-                let tolerance = 1.0;
-                let contours = contours_by_suzuki_abe(&mut cut_mask_im_i32);
-                for contour in contours {
-                    let simp_contour = contour.simplify_by_rdp(tolerance);
-                    let toolpaths = create_perimeter_tool_paths(
-                        &simp_contour,
-                        cut_z_thou,
-                        tool_i,
-                        tool_dia_pix,
-                        node.get_id(),
-                    );
-                    node_toolpaths.extend(toolpaths);
-                }
+/// The marking of `mask_needs_refine` with the target height at (x, y) from `target_at`,
+/// into `mask_im`. Only sets pixels, so `mask_im` must start clear.
+fn mark_needs_refine(
+    roughed: &Lum16Im,
+    target_at: impl Fn(usize, usize) -> u16,
+    roi: &ROI,
+    tol_thou: u16,
+    mask_im: &mut MaskIm,
+) {
+    let roi = roi.clamp_to(roughed.w, roughed.h);
+    for y in roi.t..roi.b {
+        for x in roi.l..roi.r {
+            let roughed_v = roughed.arr[y * roughed.s + x];
+            if roughed_v.saturating_sub(target_at(x, y)) > tol_thou {
+                mask_im.arr[y * mask_im.s + x] = 255;
             }
-
-            paths.extend(node_toolpaths);
-        }
-
-        // Optional debug/testing hook: after computing masks for a cut leaf.
-        if let Some(cb) = on_region_masks.as_mut() {
-            (**cb)(
-                node,
-                &padded_roi,
-                cut_mask_im,
-                above_mask_im,
-                dil_abv_mask_im,
-            );
         }
+    }
+}
 
-        match node {
-            RegionNode::Floor { children, .. } => {
-                for child in children {
-                    recurse_region_tree(
-                        name,
-                        child,
-                        cut_bands,
-                        cut_mask_im,
-                        above_mask_im,
-                        dil_abv_mask_im,
-                        dil_cut_mask_im,
-                        tool_i,
-                        tool_dia_pix,
-                        step_size_pix,
-                        margin_pix,
-                        pride_thou,
-                        ply_im,
-                        diff_mask_im,
-                        region_infos,
-                        paths,
-                        n_perimeters,
-                        perimeter_step_size_pix,
-                        gen_surfaces,
-                        on_region_masks,
-                    );
-                }
-            }
-            RegionNode::Cut { .. } => {}
-        }
+/// Run `f(y, row)` over the rows `roi.t..roi.b` of a row-major buffer with stride `s`.
+/// With the `parallel` feature the rows are spread over the rayon pool; every row is
+/// written by exactly one call so the result is the same either way.
+fn for_each_roi_row<T: Send>(
+    arr: &mut [T],
+    s: usize,
+    roi: &ROI,
+    f: impl Fn(usize, &mut [T]) + Send + Sync,
+) {
+    if s == 0 {
+        return;
+    }
+    let n_rows = roi.b.saturating_sub(roi.t);
+
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        arr.par_chunks_mut(s)
+            .enumerate()
+            .skip(roi.t)
+            .take(n_rows)
+            .for_each(|(y, row)| f(y, row));
     }
 
-    for child in region_root.children() {
-        recurse_region_tree(
-            name,
-            child,
-            cut_bands,
-            &mut cut_mask_im,
-            &mut above_mask_im,
-            &mut dil_above_mask_im,
-            &mut dil_cut_mask_im,
-            tool_i,
-            tool_dia_pix,
-            step_size_pix,
-            margin_pix,
-            pride_thou,
-            ply_im,
-            diff_mask_im,
-            region_infos,
-            &mut paths,
-            n_perimeters,
-            perimeter_step_size_pix,
-            gen_surfaces,
-            &mut on_region_masks,
-        );
+    #[cfg(not(feature = "parallel"))]
+    {
+        arr.chunks_mut(s)
+            .enumerate()
+            .skip(roi.t)
+            .take(n_rows)
+            .for_each(|(y, row)| f(y, row));
     }
+}
 
-    paths
+/// Set `above_mask_im` to 255 inside `roi` wherever `ply_im` is above `ply_thresh`.
+/// Pixels that are not above are left untouched.
+fn mark_above_ply(ply_im: &PlyIm, above_mask_im: &mut MaskIm, roi: &ROI, ply_thresh: u16) {
+    let ply_s = ply_im.s;
+    for_each_roi_row(&mut above_mask_im.arr, above_mask_im.s, roi, |y, row| {
+        let ply_row = &ply_im.arr[y * ply_s..];
+        for x in roi.l..roi.r {
+            if ply_row[x] > ply_thresh {
+                row[x] = 255;
+            }
+        }
+    });
 }
 
-pub fn break_long_toolpaths(toolpaths: &mut Vec<ToolPath>, max_segment_len_pix: usize) {
-    if toolpaths.is_empty() {
-        return;
-    }
+/// Mark the one-pixel border around the whole image (255) as material above, so dilating
+/// `mask` keeps the tool center a radius off the image edge. This covers every edge pixel,
+/// not just those near a node's ROI: a cut mask can reach the edge outside the padded ROI.
+pub fn exclude_image_border(mask: &mut MaskIm) {
+    let full_roi = ROI {
+        l: 0,
+        t: 0,
+        r: mask.w,
+        b: mask.h,
+    };
+    mask.one_pixel_border_on_image_edges_over_roi_span(full_roi, 255);
+}
 
-    let max_segment_len_pix = max_segment_len_pix.max(1);
-    let max_len2: i64 = (max_segment_len_pix as i64) * (max_segment_len_pix as i64);
+/// Build the above mask for a node: clear `out`, pad `roi` by `pad_rad` (clamped to the
+/// image), mark every ply pixel in the padded ROI above `curr_ply_i`, and then exclude the
+/// image border (see `exclude_image_border`) so the tool never centers on the edge.
+pub fn compute_above_mask(
+    ply_im: &PlyIm,
+    roi: &ROI,
+    curr_ply_i: u16,
+    pad_rad: usize,
+    out: &mut MaskIm,
+) {
+    assert_eq!(ply_im.w, out.w, "ply_im.w must match out.w");
+    assert_eq!(ply_im.h, out.h, "ply_im.h must match out.h");
+
+    out.arr.fill(0);
+    let padded_roi = roi.padded(pad_rad, ply_im.w, ply_im.h);
+    mark_above_ply(ply_im, out, &padded_roi, curr_ply_i);
+    exclude_image_border(out);
+}
 
-    fn dist2_xy(a: &IV3, b: &IV3) -> i64 {
-        let dx = (a.x as i64) - (b.x as i64);
-        let dy = (a.y as i64) - (b.y as i64);
-        dx * dx + dy * dy
-    }
+/// Everywhere a tool of `tool_dia_pix` can be centered at `ply_threshold` without touching
+/// material above it (255) — the masking `create_toolpaths_from_region_tree` applies per
+/// node, over the whole image. Like there, the image edge counts as material above, so the
+/// tool never centers within its radius of the edge.
+pub fn reachable_mask(ply_im: &PlyIm, ply_threshold: u16, tool_dia_pix: usize) -> MaskIm {
+    let (w, h) = (ply_im.w, ply_im.h);
+    let full_roi = ROI {
+        l: 0,
+        t: 0,
+        r: w,
+        b: h,
+    };
+    let mut above_mask_im = MaskIm::new(w, h);
+    compute_above_mask(ply_im, &full_roi, ply_threshold, 0, &mut above_mask_im);
+    let mut dil_above_mask_im = MaskIm::new(w, h);
+    im_dilate_radius(&above_mask_im, &mut dil_above_mask_im, tool_dia_pix / 2);
 
-    let mut new_toolpaths: Vec<ToolPath> = Vec::new();
+    // Every pixel not above the threshold, less the dilated above.
+    let mut reachable_im = MaskIm::new(w, h);
+    for (dst, &above) in reachable_im.arr.iter_mut().zip(&above_mask_im.arr) {
+        if above == 0 {
+            *dst = 255;
+        }
+    }
+    subtract_mask(&mut reachable_im, &dil_above_mask_im, &full_roi);
+    reachable_im
+}
 
-    for tp in toolpaths.drain(..) {
-        if tp.points.len() < 2 {
-            new_toolpaths.push(tp);
+/// Find the regions a tool of `tool_dia_pix` can't be centered anywhere inside: every
+/// pixel of the region is within the tool radius of material above it (or of the image
+/// edge), so toolpath generation would emit nothing for it. Useful for warning the user
+/// before a job that silently leaves pockets uncut.
+pub fn find_unreachable_regions(
+    ply_im: &PlyIm,
+    region_infos: &[LabelInfo],
+    tool_dia_pix: usize,
+) -> Vec<RegionI> {
+    let rad_pix = tool_dia_pix / 2;
+    let mut above_mask_im = MaskIm::new(ply_im.w, ply_im.h);
+    let mut dil_above_mask_im = MaskIm::new(ply_im.w, ply_im.h);
+    let mut dilate_scratch = DilateScratch::default();
+
+    let mut unreachable = Vec::new();
+    for (label_i, info) in region_infos.iter().enumerate().skip(1) {
+        if info.pixel_iz.is_empty() {
             continue;
         }
+        let ply_i = ply_im.get_or_default(info.start_x, info.start_y, 0, 0);
+        compute_above_mask(ply_im, &info.roi, ply_i, rad_pix, &mut above_mask_im);
+        im_dilate_radius_with_scratch(
+            &above_mask_im,
+            &mut dil_above_mask_im,
+            rad_pix,
+            &mut dilate_scratch,
+        );
+        // Both masks share the ply image's stride, so pixel indices carry over.
+        if info.pixel_iz.iter().all(|&i| dil_above_mask_im.arr[i] != 0) {
+            unreachable.push(RegionI(label_i as u16));
+        }
+    }
+    unreachable
+}
 
-        let is_traverse = tp.is_traverse;
-        let is_raster = tp.is_raster;
-
-        let want_closed = tp.closed;
+/// Clear `mask_im` inside `roi` wherever `sub_mask_im` is set.
+fn subtract_mask(mask_im: &mut MaskIm, sub_mask_im: &MaskIm, roi: &ROI) {
+    let sub_s = sub_mask_im.s;
+    for_each_roi_row(&mut mask_im.arr, mask_im.s, roi, |y, row| {
+        let sub_row = &sub_mask_im.arr[y * sub_s..];
+        for x in roi.l..roi.r {
+            if sub_row[x] > 0 {
+                row[x] = 0;
+            }
+        }
+    });
+}
 
-        // Normalize closed loops to a ring without a duplicated closing vertex;
-        // we will explicitly handle the closing edge.
-        let mut pts: Vec<IV3> = tp.points;
-        if want_closed {
-            if pts.len() >= 2 && pts.first() == pts.last() {
-                pts.pop();
+/// Clear `mask_im` inside `roi` wherever `and_mask_im` is not set.
+fn intersect_mask(mask_im: &mut MaskIm, and_mask_im: &MaskIm, roi: &ROI) {
+    let and_s = and_mask_im.s;
+    for_each_roi_row(&mut mask_im.arr, mask_im.s, roi, |y, row| {
+        let and_row = &and_mask_im.arr[y * and_s..];
+        for x in roi.l..roi.r {
+            if and_row[x] == 0 {
+                row[x] = 0;
             }
         }
+    });
+}
 
-        // If no segment exceeds the max length, keep the original path (preserving `closed`).
-        // This avoids converting every toolpath into 2-point segments.
-        let mut needs_split = false;
-        if pts.len() >= 2 {
-            for seg in pts.windows(2) {
-                if dist2_xy(&seg[0], &seg[1]) > max_len2 {
-                    needs_split = true;
-                    break;
-                }
+/// Set `mask_im` inside `roi` wherever `add_mask_im` is set.
+fn union_mask(mask_im: &mut MaskIm, add_mask_im: &MaskIm, roi: &ROI) {
+    let add_s = add_mask_im.s;
+    for_each_roi_row(&mut mask_im.arr, mask_im.s, roi, |y, row| {
+        let add_row = &add_mask_im.arr[y * add_s..];
+        for x in roi.l..roi.r {
+            if add_row[x] > 0 {
+                row[x] = 255;
             }
-            if !needs_split && want_closed {
-                let a = *pts.last().unwrap();
-                let b = pts[0];
-                if dist2_xy(&a, &b) > max_len2 {
-                    needs_split = true;
+        }
+    });
+}
+
+/// Split a closed ring of `keep` flags into maximal runs of kept indices.
+/// Returns (indices, is_closed); a ring kept all the way round is one closed run.
+fn cyclic_kept_runs(keep: &[bool]) -> Vec<(Vec<usize>, bool)> {
+    let n = keep.len();
+    let Some(start) = keep.iter().position(|&k| !k) else {
+        return if n == 0 {
+            Vec::new()
+        } else {
+            vec![((0..n).collect(), true)]
+        };
+    };
+
+    let mut runs = Vec::new();
+    let mut run: Vec<usize> = Vec::new();
+    for i in 1..=n {
+        let idx = (start + i) % n;
+        if keep[idx] {
+            run.push(idx);
+        } else if !run.is_empty() {
+            runs.push((std::mem::take(&mut run), false));
+        }
+    }
+    runs
+}
+
+/// The detail ("pencil") pass: trace the bottoms of steep walls in `target` with a small tool,
+/// wherever `heights` (the sim surface after the earlier passes) still has material left.
+///
+/// A wall bottom is a pixel with a 4-neighbor at least `min_wall_thou` higher. For each floor
+/// height that has wall bottoms (top down), the tool centers are the floor pixels at that height
+/// that are further than the tool radius from anything higher, so the traced contour of that
+/// region hugs the walls without gouging them. Only the stretches next to a wall and to leftover
+/// material are kept; a loop kept all the way round stays closed, otherwise the stretches become
+/// open toolpaths.
+///
+/// The toolpaths aren't tied to a region tree, so `tree_node_id` is 0.
+pub fn create_detail_toolpaths(
+    heights: &Lum16Im,
+    target: &Lum16Im,
+    tool_i: usize,
+    tool_dia_pix: usize,
+    min_wall_thou: u16,
+) -> Vec<ToolPath> {
+    assert_eq!(target.w, heights.w, "target.w must match heights.w");
+    assert_eq!(target.h, heights.h, "target.h must match heights.h");
+
+    let w = target.w;
+    let h = target.h;
+    let mut toolpaths = Vec::new();
+    // Contour tracing needs a 1-pixel border.
+    if w < 3 || h < 3 {
+        return toolpaths;
+    }
+    let target_at = |x: usize, y: usize| target.arr[y * target.s + x];
+
+    // Floor heights that have at least one wall bottom.
+    const NEIGHBORS_4: [(isize, isize); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+    let mut levels: std::collections::BTreeSet<u16> = std::collections::BTreeSet::new();
+    for y in 0..h {
+        for x in 0..w {
+            let v = target_at(x, y);
+            let is_wall_bottom = NEIGHBORS_4.iter().any(|&(dx, dy)| {
+                let (nx, ny) = (x as isize + dx, y as isize + dy);
+                if nx < 0 || ny < 0 || nx as usize >= w || ny as usize >= h {
+                    return false;
                 }
+                let n = target_at(nx as usize, ny as usize);
+                n > v && n - v >= min_wall_thou
+            });
+            if is_wall_bottom {
+                levels.insert(v);
             }
         }
+    }
+    if levels.is_empty() {
+        return toolpaths;
+    }
 
-        if !needs_split {
-            if pts.len() >= 2 {
-                if want_closed {
-                    // Re-close explicitly for consumers that expect it.
-                    if pts.first() != pts.last() {
-                        let first = pts[0];
-                        pts.push(first);
-                    }
-                    let pts_len = pts.len();
-                    new_toolpaths.push(ToolPath {
-                        points: pts,
-                        closed: true,
-                        tool_dia_pix: tp.tool_dia_pix,
-                        tool_i: tp.tool_i,
-                        tile_i: tp.tile_i,
-                        tree_node_id: tp.tree_node_id,
-                        cuts: vec![CutPixels::default(); pts_len],
-                        is_traverse,
-                        is_raster,
-                    });
-                } else {
-                    let pts_len = pts.len();
-                    new_toolpaths.push(ToolPath {
-                        points: pts,
-                        closed: false,
-                        tool_dia_pix: tp.tool_dia_pix,
-                        tool_i: tp.tool_i,
-                        tile_i: tp.tile_i,
-                        tree_node_id: tp.tree_node_id,
-                        cuts: vec![CutPixels::default(); pts_len],
-                        is_traverse,
-                        is_raster,
-                    });
-                }
+    let full_roi = ROI {
+        l: 0,
+        t: 0,
+        r: w,
+        b: h,
+    };
+    let needs_im = mask_needs_refine(target, heights, &full_roi, NEEDS_REFINE_TOL_THOU);
+
+    // Tool-center clearance uses the `2*rad+1` diameter convention of the region-tree masks.
+    // "Next to" a wall or leftover means within one pixel beyond the tool footprint.
+    let rad_pix = tool_dia_pix / 2;
+    let max_dia_pix = w.min(h);
+    let tool_dil_dia_pix = (2 * rad_pix + 1).min(max_dia_pix);
+    let near_dil_dia_pix = (2 * rad_pix + 3).min(max_dia_pix);
+
+    let mut above_mask_im = MaskIm::new(w, h);
+    let mut wall_mask_im = MaskIm::new(w, h);
+    let mut left_mask_im = MaskIm::new(w, h);
+    let mut dil_above_mask_im = MaskIm::new(w, h);
+    let mut dil_wall_mask_im = MaskIm::new(w, h);
+    let mut dil_left_mask_im = MaskIm::new(w, h);
+    let mut contour_im = Im::<i32, 1>::new(w, h);
+    let mut dilate_scratch = DilateScratch::default();
+
+    for &z in levels.iter().rev() {
+        let wall_z = z.saturating_add(min_wall_thou.max(1));
+        let mut any_left = false;
+        for y in 0..h {
+            for x in 0..w {
+                let v = target_at(x, y);
+                let i = y * above_mask_im.s + x;
+                above_mask_im.arr[i] = if v > z { 255 } else { 0 };
+                wall_mask_im.arr[i] = if v >= wall_z { 255 } else { 0 };
+                let left = v == z && needs_im.arr[y * needs_im.s + x] != 0;
+                left_mask_im.arr[i] = if left { 255 } else { 0 };
+                any_left |= left;
             }
+        }
+        if !any_left {
             continue;
         }
 
-        // Helper to emit one or more <=max segments between a and b.
-        let mut emit_subdivided = |a: IV3, b: IV3| {
-            let d2 = dist2_xy(&a, &b);
-            if d2 <= max_len2 {
-                new_toolpaths.push(ToolPath {
-                    points: vec![a, b],
-                    closed: false,
-                    tool_dia_pix: tp.tool_dia_pix,
-                    tool_i: tp.tool_i,
-                    tile_i: tp.tile_i,
-                    tree_node_id: tp.tree_node_id,
-                    cuts: vec![CutPixels::default(); 2],
-                    is_traverse,
-                    is_raster,
+        // A square clearance, not a disk: the path hugs the walls at exactly the tool radius and
+        // the sim rounds a diagonal segment's capsule outward, so a disk lets it nick convex corners.
+        im_dilate_box(&above_mask_im, &mut dil_above_mask_im, tool_dil_dia_pix);
+        im_dilate_with_scratch(
+            &wall_mask_im,
+            &mut dil_wall_mask_im,
+            near_dil_dia_pix,
+            &mut dilate_scratch,
+        );
+        im_dilate_with_scratch(
+            &left_mask_im,
+            &mut dil_left_mask_im,
+            near_dil_dia_pix,
+            &mut dilate_scratch,
+        );
+
+        for y in 0..h {
+            for x in 0..w {
+                let is_center =
+                    target_at(x, y) == z && dil_above_mask_im.arr[y * dil_above_mask_im.s + x] == 0;
+                contour_im.arr[y * contour_im.s + x] = if is_center { 1 } else { 0 };
+            }
+        }
+
+        for contour in contours_by_suzuki_abe(&mut contour_im) {
+            let mut ring = contour.points.clone();
+            if ring.len() >= 2 && ring.first() == ring.last() {
+                ring.pop();
+            }
+            let keep: Vec<bool> = ring
+                .iter()
+                .map(|p| {
+                    let i = p.y as usize * dil_wall_mask_im.s + p.x as usize;
+                    dil_wall_mask_im.arr[i] != 0 && dil_left_mask_im.arr[i] != 0
+                })
+                .collect();
+
+            for (run_iz, closed) in cyclic_kept_runs(&keep) {
+                let mut points: Vec<_> = run_iz.iter().map(|&i| ring[i]).collect();
+                if closed {
+                    points.push(points[0]);
+                }
+                if points.len() < 2 {
+                    continue;
+                }
+                // A zero tolerance only drops collinear points: the path hugs the walls at
+                // exactly the tool radius, so any real simplification could gouge them.
+                let run = Contour {
+                    id: contour.id,
+                    is_hole: contour.is_hole,
+                    parent: None,
+                    points,
+                }
+                .simplify_by_rdp(0.0);
+
+                let points: Vec<IV3> = run
+                    .points
+                    .iter()
+                    .map(|p| IV3 {
+                        x: p.x,
+                        y: p.y,
+                        z: z as i32,
+                    })
+                    .collect();
+                toolpaths.push(ToolPath {
+                    cuts: vec![CutPixels::default(); points.len()],
+                    points,
+                    closed,
+                    tool_dia_pix,
+                    tool_i,
+                    tile_i: 0,
+                    tree_node_id: 0,
+                    is_traverse: false,
+                    is_raster: false,
+                    is_plunge_run: false,
                 });
-                return;
             }
+        }
+    }
 
-            // Subdivide into N segments so each is <= max_segment_len_pix in XY.
-            let dx = (b.x - a.x) as f64;
-            let dy = (b.y - a.y) as f64;
-            let dist = (dx * dx + dy * dy).sqrt();
-            let steps = ((dist / (max_segment_len_pix as f64)).ceil() as usize).max(1);
+    toolpaths
+}
 
-            let mut prev = a;
-            for i in 1..=steps {
-                let t = (i as f64) / (steps as f64);
-                let x = (a.x as f64 + (b.x - a.x) as f64 * t).round() as i32;
-                let y = (a.y as f64 + (b.y - a.y) as f64 * t).round() as i32;
-                let z = (a.z as f64 + (b.z - a.z) as f64 * t).round() as i32;
-                let next = IV3 { x, y, z };
-                if next != prev {
-                    new_toolpaths.push(ToolPath {
-                        points: vec![prev, next],
-                        closed: false,
-                        tool_dia_pix: tp.tool_dia_pix,
-                        tool_i: tp.tool_i,
-                        tile_i: tp.tile_i,
-                        tree_node_id: tp.tree_node_id,
-                        cuts: vec![CutPixels::default(); 2],
-                        is_traverse,
-                        is_raster,
-                    });
-                    prev = next;
+/// Set the pixels of region `region_i` in `mask_im` (only those also in `diff_mask_im`, if
+/// given) and return how many were set. The background label splats nothing.
+fn splat_region_i_into_mask_im(
+    region_i: RegionI,
+    region_infos: &[LabelInfo],
+    mask_im: &mut MaskIm,
+    diff_mask_im: Option<&MaskIm>,
+) -> usize {
+    let label_i = region_i.0 as usize;
+    if label_i == 0 || label_i >= region_infos.len() {
+        return 0;
+    }
+    let mut n_pixels: usize = 0;
+    let label_info = &region_infos[label_i];
+    for &pix_i in &label_info.pixel_iz {
+        if pix_i < mask_im.arr.len() {
+            if let Some(diff_mask_im) = diff_mask_im {
+                if diff_mask_im.arr[pix_i] > 0 {
+                    mask_im.arr[pix_i] = 255;
+                    n_pixels += 1;
                 }
+            } else {
+                mask_im.arr[pix_i] = 255;
+                n_pixels += 1;
             }
-        };
+        }
+    }
+    n_pixels
+}
 
-        if pts.len() >= 2 {
-            for seg in pts.windows(2) {
-                emit_subdivided(seg[0], seg[1]);
-            }
+/// Splat a node's regions (one for a cut, 1+ for a floor) into `cut_mask_im`.
+/// Returns the union ROI of those regions, the ply threshold above which pixels count as
+/// material above the node, the node's depth, and the number of pixels splatted.
+fn splat_node_regions(
+    node: &RegionNode,
+    cut_bands: &[CutBand],
+    ply_im: &PlyIm,
+    region_infos: &[LabelInfo],
+    diff_mask_im: Option<&MaskIm>,
+    cut_mask_im: &mut MaskIm,
+) -> (ROI, u16, Thou, usize) {
+    let mut roi: ROI = ROI {
+        l: 0_usize,
+        t: 0_usize,
+        r: 0_usize,
+        b: 0_usize,
+    };
+    let curr_ply_i_u16: u16;
+    let z_thou: Thou;
+    let mut n_pixels: usize = 0;
+
+    match node {
+        RegionNode::Floor {
+            region_iz,
+            bottom_thou,
+            ..
+        } => {
+            for region_i in region_iz {
+                n_pixels +=
+                    splat_region_i_into_mask_im(*region_i, region_infos, cut_mask_im, diff_mask_im);
 
-            // Closing edge for closed paths.
-            if want_closed {
-                let a = *pts.last().unwrap();
-                let b = pts[0];
-                emit_subdivided(a, b);
+                let label_i = region_i.0 as usize;
+                assert!(label_i < region_infos.len());
+                let label_info = &region_infos[label_i];
+                roi.union(label_info.roi);
             }
+
+            // For a floor, the "above" threshold should be derived from the depth we cut
+            // to (the band's bottom). Using `lowest_ply_i_in_band - 1` can underflow to 0
+            // and incorrectly mark essentially the entire ROI as "above".
+            curr_ply_i_u16 = ply_threshold_at_depth(cut_bands, *bottom_thou);
+            z_thou = *bottom_thou;
+        }
+        RegionNode::Cut {
+            band_i: _,
+            cut_plane_i: _,
+            region_i,
+            z_thou: node_z_thou,
+            ..
+        } => {
+            z_thou = node_z_thou.clone();
+
+            n_pixels +=
+                splat_region_i_into_mask_im(*region_i, region_infos, cut_mask_im, diff_mask_im);
+
+            let label_i = region_i.0 as usize;
+            assert!(label_i < region_infos.len());
+            let label_info = &region_infos[label_i];
+            roi.union(label_info.roi);
+
+            curr_ply_i_u16 = ply_im.get_or_default(label_info.start_x, label_info.start_y, 0, 0);
         }
     }
 
-    *toolpaths = new_toolpaths;
+    (roi, curr_ply_i_u16, z_thou, n_pixels)
 }
 
-pub fn sort_toolpaths(toolpaths: &mut Vec<ToolPath>, region_root: &RegionRoot) {
-    fn band_i(node: &RegionNode) -> usize {
-        match node {
-            RegionNode::Floor { band_i, .. } => *band_i,
-            RegionNode::Cut { band_i, .. } => *band_i,
+/// The full-image masks and scratch buffers a node is planned in. The tree walk allocates one
+/// and reuses it for every node.
+struct NodeScratch {
+    cut_mask_im: MaskIm,
+    above_mask_im: MaskIm,
+    dil_abv_mask_im: MaskIm,
+    dil_cut_mask_im: MaskIm,
+    /// With a roughed surface: the node pixels still left above the cut depth, and the tool
+    /// centers that reach them (see `RegionToolpathOpts::roughed_im`). Else 1x1 and unused.
+    refine_mask_im: MaskIm,
+    dil_refine_mask_im: MaskIm,
+    dilate_scratch: DilateScratch,
+    contour_scratch: Im<i32, 1>,
+}
+
+impl NodeScratch {
+    fn new(w: usize, h: usize, with_refine: bool) -> Self {
+        let (refine_w, refine_h) = if with_refine { (w, h) } else { (1, 1) };
+        Self {
+            cut_mask_im: MaskIm::new(w, h),
+            above_mask_im: MaskIm::new(w, h),
+            dil_abv_mask_im: MaskIm::new(w, h),
+            dil_cut_mask_im: MaskIm::new(w, h),
+            refine_mask_im: MaskIm::new(refine_w, refine_h),
+            dil_refine_mask_im: MaskIm::new(refine_w, refine_h),
+            dilate_scratch: DilateScratch::default(),
+            contour_scratch: Im::<i32, 1>::new(1, 1),
         }
     }
+}
 
-    // Tree traversal for cutting order:
-    // - Keep sibling ordering as-built (caller said siblings can be any order).
-    // - A floor node reveals its children: we visit its subtree immediately after the floor.
-    fn build_node_visit_order(region_root: &RegionRoot) -> Vec<usize> {
-        fn recurse(nodes: &[RegionNode], out: &mut Vec<usize>) {
-            if nodes.is_empty() {
-                return;
-            }
-
-            // Sibling nodes must all be in the same band.
-            let b0 = band_i(&nodes[0]);
-            debug_assert!(nodes.iter().all(|n| band_i(n) == b0));
-            assert!(nodes.iter().all(|n| band_i(n) == b0));
+/// A node's splatted extent and the radii of its dilation passes, from `prepare_node_masks`.
+struct NodeMaskGeom {
+    tool: ToolDef,
+    z_thou: Thou,
+    roi: ROI,
+    /// `roi` padded by the largest radius of any pass; every pass's masks lie within it.
+    padded_roi: ROI,
+    /// Tool radius plus `margin_pix`: the cut mask radius of the first pass.
+    base_rad_pix: usize,
+    /// One per perimeter with `PerimeterStrategy::Dilate`, else one.
+    n_dilation_passes: usize,
+}
 
-            for n in nodes {
-                out.push(n.get_id());
-                if let RegionNode::Floor { children, .. } = n {
-                    recurse(children, out);
-                }
-            }
-        }
+impl NodeMaskGeom {
+    /// The cut mask radius of dilation pass `dilation_i` and the above mask radius, which adds
+    /// the wall stock so the tool stays `wall_stock_pix` further from walls without
+    /// shrinking open areas.
+    fn pass_radii(&self, dilation_i: usize, opts: &RegionToolpathOpts) -> (usize, usize) {
+        let rad_pix = self
+            .base_rad_pix
+            .saturating_add(opts.perimeter_step_size_pix.saturating_mul(dilation_i));
+        (rad_pix, rad_pix.saturating_add(opts.wall_stock_pix))
+    }
+}
 
-        let mut order: Vec<usize> = Vec::new();
-        recurse(region_root.children(), &mut order);
-        order
+/// The tool `node` is cut with: `opts.tool_i`/`opts.tool_dia_pix`, or with
+/// `opts.tools_by_pass` the entry for its band's `cut_pass` (None if there is none).
+fn node_tool(
+    node: &RegionNode,
+    cut_bands: &[CutBand],
+    opts: &RegionToolpathOpts,
+) -> Option<ToolDef> {
+    match opts.tools_by_pass {
+        None => Some(ToolDef {
+            tool_i: opts.tool_i,
+            tool_dia_pix: opts.tool_dia_pix,
+            max_doc_thou: None,
+        }),
+        Some(tools_by_pass) => tools_by_pass
+            .get(&cut_bands[node.band_i()].band_desc.cut_pass)
+            .copied(),
     }
+}
 
-    fn dist2_xy(a: &IV3, b: &IV3) -> i64 {
-        let dx = (a.x as i64) - (b.x as i64);
-        let dy = (a.y as i64) - (b.y as i64);
-        dx * dx + dy * dy
+/// Splat `node`'s regions (only those in `opts.diff_mask_im`, if given) into
+/// `scratch.cut_mask_im` and mark the material above them, plus any keep-outs, in
+/// `scratch.above_mask_im` out to the largest radius of the node's dilation passes.
+/// Returns None if the node is skipped: its pass has no tool or it has no pixels.
+fn prepare_node_masks(
+    node: &RegionNode,
+    cut_bands: &[CutBand],
+    ply_im: &PlyIm,
+    region_infos: &[LabelInfo],
+    opts: &RegionToolpathOpts,
+    scratch: &mut NodeScratch,
+    metrics: &mut Metrics,
+) -> Option<NodeMaskGeom> {
+    let tool = node_tool(node, cut_bands, opts)?;
+
+    // TODO: Optimze by clearing on the ROI after the fact
+    let t0 = Instant::now();
+    scratch.cut_mask_im.arr.fill(0);
+    scratch.dil_abv_mask_im.arr.fill(0);
+    scratch.dil_cut_mask_im.arr.fill(0);
+    metrics.pixels_touched += 3 * scratch.cut_mask_im.arr.len() as u64;
+    metrics.add_time("clear", t0.elapsed());
+
+    // Splat in the current node's regions.
+    // For floors there is 1+, for cuts there is 1. And find the ROI
+    let t0 = Instant::now();
+    let (roi, curr_ply_i_u16, z_thou, n_pixels) = splat_node_regions(
+        node,
+        cut_bands,
+        ply_im,
+        region_infos,
+        opts.diff_mask_im,
+        &mut scratch.cut_mask_im,
+    );
+    metrics.pixels_touched += n_pixels as u64;
+    metrics.add_time("splat", t0.elapsed());
+
+    // If nothing was splatted into the mask_im, skip this node.
+    // This handles the case in differential mode where the region has
+    // no pixels that align with the cut.
+    if n_pixels == 0 {
+        return None;
     }
 
-    fn ensure_cuts_parallel(tp: &mut ToolPath) {
-        if tp.cuts.len() != tp.points.len() {
-            tp.cuts = vec![CutPixels::default(); tp.points.len()];
-        }
+    // Build the above_mask_im by expanding the ROI and copying any ply pixels that
+    // are above the current region's ply threshold.
+    // Recall that ply_im is sorted form the bottom; higher ply indices have higher values.
+    // Expand by the maximum radius we will use across perimeter passes so the subtraction is
+    // correct for all offsets.
+    let n_dilation_passes = match opts.perimeter_strategy {
+        PerimeterStrategy::Offset => 1,
+        PerimeterStrategy::Dilate => opts.n_perimeters.max(1),
+    };
+    let base_rad_pix = tool.tool_dia_pix / 2 + opts.margin_pix;
+    let max_rad_pix = base_rad_pix
+        .saturating_add(opts.wall_stock_pix)
+        .saturating_add(
+            opts.perimeter_step_size_pix
+                .saturating_mul(n_dilation_passes.saturating_sub(1)),
+        );
+    // The one-pixel image border ensures the image boundary is excluded from the cut.
+    let t0 = Instant::now();
+    compute_above_mask(
+        ply_im,
+        &roi,
+        curr_ply_i_u16,
+        max_rad_pix,
+        &mut scratch.above_mask_im,
+    );
+    let padded_roi = roi.padded(max_rad_pix, ply_im.w, ply_im.h);
+
+    // Keep-outs act like material above, so the dilation keeps the whole tool out of them.
+    // A tool center in the dilated cut mask is within `max_rad_pix` of the ROI and is
+    // pushed away by keep-out pixels up to `max_rad_pix` further out.
+    if let Some(keep_out) = opts.keep_out {
+        let keep_out_roi = roi.padded(max_rad_pix.saturating_mul(2), ply_im.w, ply_im.h);
+        union_mask(&mut scratch.above_mask_im, keep_out, &keep_out_roi);
     }
+    metrics.pixels_touched +=
+        (scratch.above_mask_im.arr.len() + padded_roi.w() * padded_roi.h()) as u64;
+    metrics.add_time("above_mask", t0.elapsed());
+
+    Some(NodeMaskGeom {
+        tool,
+        z_thou,
+        roi,
+        padded_roi,
+        base_rad_pix,
+        n_dilation_passes,
+    })
+}
 
-    fn reverse_open_toolpath_in_place(tp: &mut ToolPath) {
-        let n = tp.points.len();
-        if n <= 1 {
-            return;
-        }
-        ensure_cuts_parallel(tp);
+/// Dilate the splatted cut mask by `rad_pix` into tool-centerable space and subtract the
+/// above mask dilated by `abv_rad_pix`, leaving the tool-center mask in
+/// `scratch.dil_cut_mask_im`. The subtraction only covers `padded_roi`, which must contain the
+/// dilated cut mask.
+fn dilate_node_cut_mask(
+    scratch: &mut NodeScratch,
+    padded_roi: &ROI,
+    rad_pix: usize,
+    abv_rad_pix: usize,
+) {
+    let NodeScratch {
+        cut_mask_im,
+        above_mask_im,
+        dil_abv_mask_im,
+        dil_cut_mask_im,
+        dilate_scratch,
+        ..
+    } = scratch;
+    im_dilate_radius_with_scratch(above_mask_im, dil_abv_mask_im, abv_rad_pix, dilate_scratch);
+
+    // Dilate the current region into tool-centerable space.
+    im_dilate_radius_with_scratch(cut_mask_im, dil_cut_mask_im, rad_pix, dilate_scratch);
+
+    // Subtract dilation above from cut_mask.
+    // TODO: Optimize by limiting the dilation to the padded ROI.
+    subtract_mask(dil_cut_mask_im, dil_abv_mask_im, padded_roi);
+}
 
-        // Reverse points, and remap segment cuts so `cuts[i]` still corresponds to
-        // `points[i] -> points[i+1]`.
-        let old_cuts = std::mem::take(&mut tp.cuts);
-        let mut new_cuts = vec![CutPixels::default(); n];
-        if old_cuts.len() == n {
-            // Segments are indices 0..n-1 (exclusive last point). Last entry is unused.
-            // After reversing points, segment i corresponds to old segment (n-2-i).
-            for i in 0..(n - 1) {
-                new_cuts[i] = old_cuts[n - 2 - i];
-            }
-        }
-        tp.cuts = new_cuts;
-        tp.points.reverse();
-    }
+/// The tool-center mask `create_toolpaths_from_region_tree` cuts `node` with on its dilation
+/// pass `dilation_i` (one per perimeter with `PerimeterStrategy::Dilate`, else only pass 0),
+/// built by the same steps from the same `opts`: diff mask, margin, wall stock and
+/// keep-outs included. Returns the mask and the padded ROI it lies within, or None if the
+/// tree skips the node (its pass has no tool or it has no pixels).
+pub fn compute_node_cut_mask(
+    node: &RegionNode,
+    cut_bands: &[CutBand],
+    ply_im: &PlyIm,
+    region_infos: &[LabelInfo],
+    opts: &RegionToolpathOpts,
+    dilation_i: usize,
+) -> Option<(MaskIm, ROI)> {
+    let mut scratch = NodeScratch::new(ply_im.w, ply_im.h, false);
+    let geom = prepare_node_masks(
+        node,
+        cut_bands,
+        ply_im,
+        region_infos,
+        opts,
+        &mut scratch,
+        &mut Metrics::default(),
+    )?;
+    assert!(
+        dilation_i < geom.n_dilation_passes,
+        "dilation pass {dilation_i} of {}",
+        geom.n_dilation_passes
+    );
+    let (rad_pix, abv_rad_pix) = geom.pass_radii(dilation_i, opts);
+    dilate_node_cut_mask(&mut scratch, &geom.padded_roi, rad_pix, abv_rad_pix);
+    Some((scratch.dil_cut_mask_im, geom.padded_roi))
+}
 
-    fn choose_open_orientation(tp: &mut ToolPath, curr: &IV3) {
-        if tp.points.len() <= 1 {
-            return;
-        }
-        ensure_cuts_parallel(tp);
-        let first = tp.points.first().unwrap();
-        let last = tp.points.last().unwrap();
-        let d_first = dist2_xy(curr, first);
-        let d_last = dist2_xy(curr, last);
-        if d_last < d_first {
-            reverse_open_toolpath_in_place(tp);
+/// The planning knobs of `create_toolpaths_from_region_tree` and `create_toolpaths_streaming`.
+/// The default is a 1-pixel tool 0 with a 1-pixel stepover that clears surfaces and traces
+/// one perimeter, with no margin, stock, or pride.
+#[derive(Debug, Clone)]
+pub struct RegionToolpathOpts<'a> {
+    pub tool_i: usize,
+    pub tool_dia_pix: usize,
+    /// If given, each node's tool is looked up by its band's `cut_pass` (overriding
+    /// `tool_i`/`tool_dia_pix`), so one call can emit mixed-tool toolpaths. Nodes whose pass
+    /// has no entry in the map (e.g. a null tool guid) are skipped.
+    pub tools_by_pass: Option<&'a HashMap<String, ToolDef>>,
+    /// Distance between clearing passes; a `Stepover::Frac` is resolved against each node's tool.
+    pub stepover: Stepover,
+    /// Grows the tool-center offset of both the cut and above masks, so it moves the whole pass.
+    pub margin_pix: usize,
+    /// Only inflates the above mask: a finishing allowance that keeps the tool that much
+    /// further from walls while open areas are cleared as before.
+    pub wall_stock_pix: usize,
+    /// Signed offset added to every cut Z (negative cuts slightly deeper).
+    pub pride_thou: Thou,
+    pub n_perimeters: usize,
+    pub perimeter_step_size_pix: usize,
+    /// RDP tolerance the perimeter contours are simplified by (0 disables simplification).
+    pub rdp_tolerance_pix: f64,
+    /// Clear the node interiors. Each band's `BandDesc::clear_mode` can still drop the surface
+    /// clearing (`PerimeterOnly`) or the perimeters (`RasterOnly`) for its nodes.
+    pub gen_surfaces: bool,
+    pub clear_strategy: ClearStrategy,
+    /// With `n_perimeters > 1`, how the inner perimeters are made.
+    pub perimeter_strategy: PerimeterStrategy,
+    /// Areas the tool must not touch (e.g. clamps and fixtures), treated as material above
+    /// every node so the tool stays a radius away from them. Any cutting toolpath that still
+    /// touches a keep-out pixel is split there (see `split_toolpaths_at_keep_out`).
+    pub keep_out: Option<&'a MaskIm>,
+    /// If given, only the node pixels set in it are cut (differential mode).
+    pub diff_mask_im: Option<&'a MaskIm>,
+    /// The sim result of an earlier pass. If given, surface clearing is limited to tool
+    /// centers within a radius of the node's pixels where that surface is still above its
+    /// cut depth (see `mask_needs_refine`).
+    pub roughed_im: Option<&'a Lum16Im>,
+    /// Limits the output to nodes of these bands (e.g. `Some(0..1)`), so a long job can be
+    /// split into runs or a single band re-cut.
+    pub band_range: Option<Range<usize>>,
+    /// If given, the work done and the time spent per phase are added to it (see `Metrics`).
+    pub metrics: Option<&'a RefCell<Metrics>>,
+}
+
+impl Default for RegionToolpathOpts<'_> {
+    fn default() -> Self {
+        Self {
+            tool_i: 0,
+            tool_dia_pix: 1,
+            tools_by_pass: None,
+            stepover: Stepover::Pix(1),
+            margin_pix: 0,
+            wall_stock_pix: 0,
+            pride_thou: Thou(0),
+            n_perimeters: 1,
+            perimeter_step_size_pix: 1,
+            rdp_tolerance_pix: 1.0,
+            gen_surfaces: true,
+            clear_strategy: ClearStrategy::default(),
+            perimeter_strategy: PerimeterStrategy::default(),
+            keep_out: None,
+            diff_mask_im: None,
+            roughed_im: None,
+            band_range: None,
+            metrics: None,
         }
     }
+}
 
-    fn roll_closed_to_nearest(tp: &mut ToolPath, curr: &IV3) {
-        if tp.points.len() <= 1 {
-            return;
-        }
+/// Debug/testing hook `create_toolpaths_from_region_tree` calls with each node's masks once
+/// its last dilation pass is cut:
+/// `(node, padded_roi, cut_mask_im, above_mask_im, dil_abv_mask_im, dil_cut_mask_im)`.
+pub type RegionMasksHook<'a> =
+    &'a mut dyn FnMut(&RegionNode, &ROI, &MaskIm, &MaskIm, &MaskIm, &MaskIm);
 
-        // Work on a ring representation (no duplicated closing vertex) and a parallel
-        // per-edge cut array of length `ring_len`.
-        let mut ring_pts: Vec<IV3> = std::mem::take(&mut tp.points);
-        let old_cuts: Vec<CutPixels> = std::mem::take(&mut tp.cuts);
+/// Given a RegionNode tree root, we traverse the tree and rasterize each node's regions
+/// into a pixel image.
+/// There's two working MaskIms:
+///  * One is the curr_node_mask_im which holds the pixels of the current node. We copy it from the LabelInfo.pixel_iz,
+///    then dilate it.
+///  * The other is the above_mask. For that we expand the ROI by the tool_radius
+///    and then copy any pixel above the current threshold inside that ROI into
+///    the above mask. Then we dilate that as well and then we subtract the above_mask
+///    from the curr_node_mask_im.
+/// Then we convert these masks into clearing-paths by traversing the mask
+/// and build a RLE representation of the mask along the standard scanlines.
+///
+/// The tool, stepover, offsets and perimeter settings, and any diff mask or roughed surface,
+/// come from `opts` (see `RegionToolpathOpts`).
+pub fn create_toolpaths_from_region_tree(
+    region_root: &RegionRoot,
+    cut_bands: &[CutBand],
+    ply_im: &PlyIm,
+    region_im: &RegionIm,
+    region_infos: &[LabelInfo],
+    opts: &RegionToolpathOpts,
+    on_region_masks: Option<RegionMasksHook>,
+) -> Vec<ToolPath> {
+    let mut paths: Vec<ToolPath> = Vec::new();
+    create_toolpaths_streaming(
+        region_root,
+        cut_bands,
+        ply_im,
+        region_im,
+        region_infos,
+        opts,
+        on_region_masks,
+        &mut |toolpath| paths.push(toolpath),
+    );
+    paths
+}
 
-        let had_closing_dup = ring_pts
-            .first()
-            .zip(ring_pts.last())
-            .map(|(a, b)| a == b)
-            .unwrap_or(false);
-        if had_closing_dup {
-            ring_pts.pop();
-        }
+/// `create_toolpaths_from_region_tree` without the `Vec`: each toolpath is handed to
+/// `on_toolpath` as soon as its node is done, so huge parts can be written out incrementally.
+/// Toolpaths arrive in the same order the `Vec` would hold them.
+pub fn create_toolpaths_streaming(
+    region_root: &RegionRoot,
+    cut_bands: &[CutBand],
+    ply_im: &PlyIm,
+    region_im: &RegionIm,
+    region_infos: &[LabelInfo],
+    opts: &RegionToolpathOpts,
+    mut on_region_masks: Option<RegionMasksHook>,
+    on_toolpath: &mut dyn FnMut(ToolPath),
+) {
+    let w = region_im.w;
+    let h = region_im.h;
+    let &RegionToolpathOpts {
+        diff_mask_im,
+        roughed_im,
+        ..
+    } = opts;
+    if let Some(diff_mask_im) = diff_mask_im {
+        assert_eq!(diff_mask_im.w, w, "diff_mask_im.w must match region_im.w");
+        assert_eq!(diff_mask_im.h, h, "diff_mask_im.h must match region_im.h");
+    }
+    if let Some(roughed_im) = roughed_im {
+        assert_eq!(roughed_im.w, w, "roughed_im.w must match region_im.w");
+        assert_eq!(roughed_im.h, h, "roughed_im.h must match region_im.h");
+    }
+    if let Some(keep_out) = opts.keep_out {
+        assert_eq!(keep_out.w, w, "keep_out.w must match region_im.w");
+        assert_eq!(keep_out.h, h, "keep_out.h must match region_im.h");
+    }
+    // No bands for this pass (or nothing in them): skip allocating the scratch images.
+    if region_root.children().is_empty() {
+        return;
+    }
 
-        if ring_pts.len() <= 1 {
-            // Restore the closure representation.
-            if ring_pts.len() == 1 {
-                ring_pts.push(ring_pts[0]);
-            }
-            tp.points = ring_pts;
-            tp.cuts = vec![CutPixels::default(); tp.points.len()];
+    let mut scratch = NodeScratch::new(w, h, roughed_im.is_some());
+    let mut metrics_guard = opts.metrics.map(RefCell::borrow_mut);
+    let mut unused_metrics = Metrics::default();
+    let metrics: &mut Metrics = match metrics_guard.as_deref_mut() {
+        Some(metrics) => metrics,
+        None => &mut unused_metrics,
+    };
+
+    // Generate the toolpaths for a single node of the region tree.
+    fn gen_node_toolpaths(
+        node: &RegionNode,
+        cut_bands: &[CutBand],
+        scratch: &mut NodeScratch,
+        ply_im: &PlyIm,
+        region_infos: &[LabelInfo],
+        on_toolpath: &mut dyn FnMut(ToolPath),
+        opts: &RegionToolpathOpts,
+        metrics: &mut Metrics,
+        on_region_masks: &mut Option<RegionMasksHook>,
+    ) {
+        let &RegionToolpathOpts {
+            stepover,
+            pride_thou,
+            n_perimeters,
+            perimeter_step_size_pix,
+            rdp_tolerance_pix,
+            gen_surfaces,
+            clear_strategy,
+            perimeter_strategy,
+            keep_out,
+            roughed_im,
+            ..
+        } = opts;
+        let band_i = node.band_i();
+        let clear_mode = cut_bands[band_i].band_desc.clear_mode;
+
+        // The same setup `compute_node_cut_mask` does, so its masks match the ones cut here.
+        let Some(geom) = prepare_node_masks(
+            node,
+            cut_bands,
+            ply_im,
+            region_infos,
+            opts,
+            scratch,
+            metrics,
+        ) else {
             return;
-        }
+        };
+        let ToolDef {
+            tool_i,
+            tool_dia_pix,
+            max_doc_thou,
+        } = geom.tool;
+        let step_size_pix = stepover.to_pix(tool_dia_pix);
+        let (roi, padded_roi) = (geom.roi, geom.padded_roi);
 
-        let ring_len = ring_pts.len();
-        let mut seg_cuts: Vec<CutPixels> = vec![CutPixels::default(); ring_len];
+        // debug_ui::add_mask_im(
+        //     &format!("region_above_mask={} is_floor={}", z_thou.0, is_node_floor),
+        //     above_mask_im,
+        // );
 
-        // Best-effort preservation of existing cuts.
-        if had_closing_dup {
-            // points len was ring_len+1; segment cuts occupy [0..ring_len), last entry unused.
-            if old_cuts.len() == ring_len + 1 {
-                seg_cuts.copy_from_slice(&old_cuts[..ring_len]);
-            }
-        } else {
-            // No explicit closing vertex: only segments along windows(2) exist.
-            // Preserve those, and leave the closing edge as default.
-            if old_cuts.len() == ring_len {
-                for i in 0..ring_len.saturating_sub(1) {
-                    seg_cuts[i] = old_cuts[i];
-                }
-            }
-        }
+        // Each perimeter pass uses a larger dilation radius.
+        for dilation_i in 0..geom.n_dilation_passes {
+            let (rad_pix, abv_rad_pix) = geom.pass_radii(dilation_i, opts);
+            let t0 = Instant::now();
+            dilate_node_cut_mask(scratch, &padded_roi, rad_pix, abv_rad_pix);
 
-        let mut best_i = 0usize;
-        let mut best_d = dist2_xy(curr, &ring_pts[0]);
-        for (i, p) in ring_pts.iter().enumerate().skip(1) {
-            let d = dist2_xy(curr, p);
-            if d < best_d {
-                best_d = d;
-                best_i = i;
-            }
-        }
-        if best_i != 0 {
-            ring_pts.rotate_left(best_i);
-            seg_cuts.rotate_left(best_i);
-        }
+            // Apply the pride offset at cut time (not the region-plane time).
+            // Pride is signed: negative cuts slightly deeper (e.g. for a snug floor).
+            let cut_z_thou = geom.z_thou.offset_clamped(pride_thou);
 
-        // Deterministic direction choice at the chosen start: take the direction
-        // with the smaller next-step distance (ties deterministic by (y,x,z)).
-        if ring_pts.len() >= 3 {
-            let next = &ring_pts[1];
-            let prev = &ring_pts[ring_pts.len() - 1];
-            let dn = dist2_xy(&ring_pts[0], next);
-            let dp = dist2_xy(&ring_pts[0], prev);
-            let prev_key = (prev.y, prev.x, prev.z);
-            let next_key = (next.y, next.x, next.z);
-            if dp < dn || (dp == dn && prev_key < next_key) {
-                // Reverse direction while keeping the chosen start at index 0.
-                // Vertex list becomes: v0, v_{n-1}, v_{n-2}, ..., v1.
-                ring_pts[1..].reverse();
-                // Segment order becomes the reverse as well.
-                seg_cuts.reverse();
-            }
-        }
+            metrics.dilations_run += 2;
+            metrics.pixels_touched +=
+                (2 * scratch.cut_mask_im.arr.len() + padded_roi.w() * padded_roi.h()) as u64;
+            metrics.add_time("dilate", t0.elapsed());
 
-        // Re-close the loop explicitly.
-        let first = ring_pts[0];
-        ring_pts.push(first);
+            let mut node_toolpaths: Vec<ToolPath> = Vec::new();
 
-        let mut new_cuts: Vec<CutPixels> = vec![CutPixels::default(); ring_pts.len()];
-        for i in 0..seg_cuts.len() {
-            new_cuts[i] = seg_cuts[i];
-        }
-        tp.points = ring_pts;
-        tp.cuts = new_cuts;
-    }
+            if gen_surfaces && clear_mode != ClearMode::PerimeterOnly && dilation_i == 0 {
+                let t0 = Instant::now();
+                // With a roughed surface, only clear where material is still left above this
+                // depth on the node's pixels, taking every tool center within a radius of it.
+                // (Perimeters still use the full mask below.)
+                let surface_mask_im: &MaskIm = match roughed_im {
+                    Some(roughed_im) => {
+                        let NodeScratch {
+                            cut_mask_im,
+                            dil_cut_mask_im,
+                            refine_mask_im,
+                            dil_refine_mask_im,
+                            dilate_scratch,
+                            ..
+                        } = &mut *scratch;
+                        let cut_z_u16 = cut_z_thou.to_u16_clamped();
+                        refine_mask_im.arr.fill(0);
+                        mark_needs_refine(
+                            roughed_im,
+                            |_, _| cut_z_u16,
+                            &roi,
+                            NEEDS_REFINE_TOL_THOU,
+                            refine_mask_im,
+                        );
+                        intersect_mask(refine_mask_im, cut_mask_im, &roi);
+                        im_dilate_radius_with_scratch(
+                            refine_mask_im,
+                            dil_refine_mask_im,
+                            rad_pix,
+                            dilate_scratch,
+                        );
+                        intersect_mask(dil_refine_mask_im, dil_cut_mask_im, &padded_roi);
+                        dil_refine_mask_im
+                    }
+                    None => &scratch.dil_cut_mask_im,
+                };
 
-    fn order_toolpaths_for_node(mut tps: Vec<ToolPath>, curr: &mut IV3) -> Vec<ToolPath> {
-        // Top-down within the node.
-        tps.sort_by_key(|tp| std::cmp::Reverse(tp.points.first().map(|p| p.z).unwrap_or(0)));
+                let toolpaths = match clear_strategy {
+                    ClearStrategy::Raster => create_raster_surface_tool_paths_from_cut_mask(
+                        surface_mask_im,
+                        &padded_roi,
+                        tool_i,
+                        tool_dia_pix,
+                        step_size_pix,
+                        cut_z_thou,
+                        node.get_id(),
+                    ),
+                    ClearStrategy::Spiral => create_spiral_clearing(
+                        surface_mask_im,
+                        &padded_roi,
+                        tool_i,
+                        tool_dia_pix,
+                        step_size_pix,
+                        cut_z_thou,
+                        node.get_id(),
+                    ),
+                };
+                node_toolpaths.extend(toolpaths);
+                metrics.add_time("surfaces", t0.elapsed());
+            }
 
-        let mut out: Vec<ToolPath> = Vec::with_capacity(tps.len());
-        while !tps.is_empty() {
-            let mut best_i = 0usize;
-            let mut best_cost: (i64, i32, u8, i32, i32, usize) = (i64::MAX, 0, 0, 0, 0, 0);
+            if n_perimeters > 0 && clear_mode != ClearMode::RasterOnly {
+                let t0 = Instant::now();
+                // The dilated cut mask never reaches past the padded ROI, so trace just that
+                // window on the reused scratch image.
+                let traced = contours_by_suzuki_abe_in_roi(
+                    &scratch.dil_cut_mask_im,
+                    &padded_roi,
+                    &mut scratch.contour_scratch,
+                );
+                metrics.contours_traced += traced.len();
+
+                // A zero tolerance keeps the traced contours as-is.
+                let contours: Vec<Contour> = traced
+                    .into_iter()
+                    .map(|contour| {
+                        if rdp_tolerance_pix > 0.0 {
+                            contour.simplify_by_rdp(rdp_tolerance_pix)
+                        } else {
+                            contour
+                        }
+                    })
+                    .collect();
+                node_toolpaths.extend(create_perimeter_tool_paths(
+                    &contours,
+                    cut_z_thou,
+                    tool_i,
+                    tool_dia_pix,
+                    node.get_id(),
+                ));
 
-            for (i, tp) in tps.iter().enumerate() {
-                let start = tp.points.first().unwrap_or(&IV3 { x: 0, y: 0, z: 0 });
-                let end = tp.points.last().unwrap_or(start);
-                let mut d = dist2_xy(curr, start);
-                if !tp.closed {
-                    d = d.min(dist2_xy(curr, end));
-                }
-                let z = start.z;
-                let closed_key = if tp.closed { 0u8 } else { 1u8 };
-                let key = (d, -z, closed_key, start.y, start.x, tp.points.len());
-                if key < best_cost {
-                    best_cost = key;
-                    best_i = i;
+                // The remaining perimeters step inward from the traced ones.
+                if perimeter_strategy == PerimeterStrategy::Offset {
+                    for perimeter_i in 1..n_perimeters {
+                        let dist_pix = (perimeter_step_size_pix * perimeter_i) as f64;
+                        let offset = offset_contours_inward(&contours, dist_pix);
+                        node_toolpaths.extend(create_perimeter_tool_paths(
+                            &offset,
+                            cut_z_thou,
+                            tool_i,
+                            tool_dia_pix,
+                            node.get_id(),
+                        ));
+                    }
                 }
+                metrics.add_time("perimeters", t0.elapsed());
             }
 
-            let mut tp = tps.swap_remove(best_i);
-            if tp.closed {
-                roll_closed_to_nearest(&mut tp, curr);
-            } else {
-                choose_open_orientation(&mut tp, curr);
+            if let Some(keep_out) = keep_out {
+                split_toolpaths_at_keep_out(&mut node_toolpaths, keep_out);
             }
-
-            if let Some(last) = tp.points.last().copied() {
-                *curr = last;
+            if let Some(max_doc_thou) = max_doc_thou {
+                let band_top_thou = cut_bands[band_i].top_thou;
+                enforce_max_doc(&mut node_toolpaths, max_doc_thou, band_top_thou);
+            }
+            for toolpath in node_toolpaths {
+                on_toolpath(toolpath);
             }
-            out.push(tp);
         }
-        out
-    }
 
-    // Bucket toolpaths by node id.
-    let mut per_node: Vec<Vec<ToolPath>> = vec![Vec::new(); region_root.get_n_nodes().max(1)];
-    for tp in toolpaths.drain(..) {
-        if tp.tree_node_id < per_node.len() {
-            per_node[tp.tree_node_id].push(tp);
-        } else {
-            // Unknown node id, keep it in a trailing bucket.
-            per_node[0].push(tp);
+        // Optional debug/testing hook: after computing masks for a cut leaf.
+        if let Some(cb) = on_region_masks.as_mut() {
+            (**cb)(
+                node,
+                &padded_roi,
+                &scratch.cut_mask_im,
+                &scratch.above_mask_im,
+                &scratch.dil_abv_mask_im,
+                &scratch.dil_cut_mask_im,
+            );
         }
     }
 
-    let node_order = build_node_visit_order(region_root);
-    let mut curr = IV3 { x: 0, y: 0, z: 0 };
-    for node_id in node_order {
-        if node_id >= per_node.len() {
+    // Nodes are visited in carve order (a floor, then the subtree it reveals).
+    // Out-of-range nodes are skipped but the walk still descends through them:
+    // each node's masks come from the ply image, not from its ancestors.
+    for node in region_root.iter_dfs() {
+        if let Some(band_range) = &opts.band_range
+            && !band_range.contains(&node.band_i())
+        {
             continue;
         }
-        let bucket = std::mem::take(&mut per_node[node_id]);
-        let ordered = order_toolpaths_for_node(bucket, &mut curr);
-        toolpaths.extend(ordered);
+        metrics.nodes_visited += 1;
+        gen_node_toolpaths(
+            node,
+            cut_bands,
+            &mut scratch,
+            ply_im,
+            region_infos,
+            on_toolpath,
+            opts,
+            metrics,
+            &mut on_region_masks,
+        );
     }
+}
 
-    // Append anything not reached by the traversal (should be rare).
-    for bucket in per_node.into_iter() {
-        toolpaths.extend(bucket);
-    }
+/// Indices of the cutting toolpaths whose footprint covers pixel (x, y), i.e. that pass
+/// within the radius of a `tool_dia_pix` tool of it (see `sim::point_in_capsule`).
+/// Like the sim, traverses and Z-changing segments are not counted as cutting.
+pub fn toolpaths_covering(
+    toolpaths: &[ToolPath],
+    x: i32,
+    y: i32,
+    tool_dia_pix: usize,
+) -> Vec<usize> {
+    let radius_pix = tool_dia_pix / 2;
+    toolpaths
+        .iter()
+        .enumerate()
+        .filter(|(_, tp)| !tp.is_traverse)
+        .filter(|(_, tp)| match tp.points.as_slice() {
+            [p] => crate::sim::point_in_capsule(x, y, *p, *p, radius_pix),
+            points => points.windows(2).any(|seg| {
+                seg[0].z == seg[1].z
+                    && crate::sim::point_in_capsule(x, y, seg[0], seg[1], radius_pix)
+            }),
+        })
+        .map(|(tp_i, _)| tp_i)
+        .collect()
 }
 
-pub fn cull_empty_toolpaths(toolpaths: &mut Vec<ToolPath>) {
-    if toolpaths.is_empty() {
+/// Make sure no pass removes more than `max_doc_thou` of Z, given that the material above
+/// these toolpaths starts at `parent_z`. Cutting toolpaths that reach deeper than
+/// `parent_z - max_doc_thou` are preceded by copies of themselves at intermediate Z levels,
+/// `max_doc_thou` apart and each clamped so no point goes below the original. The levels are
+/// stacked over the whole set, shallowest first: every copy at one level (in input order) before
+/// any at the next, then the originals, so each level is cleared before stepping down.
+/// Traverses are left alone and the copies get fresh `cuts`.
+pub fn enforce_max_doc(toolpaths: &mut Vec<ToolPath>, max_doc_thou: Thou, parent_z: Thou) {
+    assert!(max_doc_thou > Thou(0), "max_doc_thou must be positive");
+
+    let min_z = |tp: &ToolPath| tp.points.iter().map(|p| p.z).min();
+    let Some(deepest_z) = toolpaths
+        .iter()
+        .filter(|tp| !tp.is_traverse)
+        .filter_map(min_z)
+        .min()
+    else {
         return;
-    }
+    };
 
-    fn build_open_toolpath_from_segments(
-        points: Vec<IV3>,
-        seg_cuts: Vec<CutPixels>,
-        tool_dia_pix: usize,
-        tool_i: usize,
-        tile_i: usize,
-        tree_node_id: usize,
-        is_traverse: bool,
-        is_raster: bool,
-    ) -> ToolPath {
-        debug_assert!(points.len() >= 2);
-        debug_assert_eq!(seg_cuts.len(), points.len().saturating_sub(1));
-
-        let mut cuts = vec![CutPixels::default(); points.len()];
-        for (i, c) in seg_cuts.into_iter().enumerate() {
-            if i < cuts.len() {
-                cuts[i] = c;
+    let mut copies: Vec<ToolPath> = Vec::new();
+    let mut level = parent_z - max_doc_thou;
+    while level.0 > deepest_z {
+        for tp in toolpaths.iter() {
+            if tp.is_traverse || min_z(tp).is_none_or(|z| z >= level.0) {
+                continue;
             }
+            let points: Vec<IV3> = tp
+                .points
+                .iter()
+                .map(|p| IV3 {
+                    z: p.z.max(level.0),
+                    ..*p
+                })
+                .collect();
+            copies.push(ToolPath {
+                cuts: vec![CutPixels::default(); points.len()],
+                points,
+                ..tp.clone()
+            });
         }
-        if let Some(last) = cuts.last_mut() {
-            *last = CutPixels::default();
-        }
-
-        ToolPath {
-            points,
-            closed: false,
-            tool_dia_pix,
-            tool_i,
-            tile_i,
-            tree_node_id,
-            cuts,
-            is_traverse,
-            is_raster,
-        }
+        level = level - max_doc_thou;
     }
+    toolpaths.splice(0..0, copies);
+}
 
-    fn cull_open_toolpath(
-        points: Vec<IV3>,
-        cuts_in: Vec<CutPixels>,
-        tool_dia_pix: usize,
-        tool_i: usize,
-        tile_i: usize,
-        tree_node_id: usize,
-        is_traverse: bool,
-        is_raster: bool,
-    ) -> Vec<ToolPath> {
-        if points.len() < 2 {
-            return Vec::new();
+/// Flag the vertices of each toolpath where the direction turns by more than
+/// `angle_thresh_deg`, e.g. the ends of serpentine raster rows, so the G-code emitter can dwell
+/// there instead of slamming the axes into reverse. `out[tp_i][point_i]` is parallel to
+/// `toolpaths[tp_i].points`.
+///
+/// A stepover shorter than the tool radius is too short for the machine to get moving along,
+/// so the turn at the start of such a link is measured to the segment after it: a row end
+/// followed by a 1px step and the next row back counts as one 180° reversal at the row end,
+/// and the far end of the link is not flagged again. Endpoints and zero-length segments are
+/// never flagged.
+pub fn mark_reversals(toolpaths: &[ToolPath], angle_thresh_deg: f64) -> Vec<Vec<bool>> {
+    let dir = |a: &IV3, b: &IV3| ((b.x - a.x) as f64, (b.y - a.y) as f64);
+    let turn_deg = |(ax, ay): (f64, f64), (bx, by): (f64, f64)| {
+        let len2 = (ax * ax + ay * ay) * (bx * bx + by * by);
+        if len2 == 0.0 {
+            return 0.0;
         }
+        ((ax * bx + ay * by) / len2.sqrt())
+            .clamp(-1.0, 1.0)
+            .acos()
+            .to_degrees()
+    };
 
-        let seg_n = points.len() - 1;
-        let mut out: Vec<ToolPath> = Vec::new();
+    toolpaths
+        .iter()
+        .map(|tp| {
+            let pts = &tp.points;
+            let link_len = (tp.tool_dia_pix / 2).max(1) as f64;
+            let mut flags = vec![false; pts.len()];
+            let mut crossed_link = false;
+            for i in 1..pts.len().saturating_sub(1) {
+                // The far end of a link was already judged from its near end.
+                if std::mem::take(&mut crossed_link) {
+                    continue;
+                }
+                let d_in = dir(&pts[i - 1], &pts[i]);
+                let mut d_out = dir(&pts[i], &pts[i + 1]);
+                if d_out.0.hypot(d_out.1) < link_len && i + 2 < pts.len() {
+                    d_out = dir(&pts[i + 1], &pts[i + 2]);
+                    crossed_link = true;
+                }
+                flags[i] = turn_deg(d_in, d_out) > angle_thresh_deg;
+            }
+            flags
+        })
+        .collect()
+}
 
-        let mut run_points: Vec<IV3> = Vec::new();
-        let mut run_cuts: Vec<CutPixels> = Vec::new();
+/// Replace the flat segments of a full-width cut with trochoidal loops: circles of radius
+/// `trochoid_radius_pix` whose centers advance `advance_pix` at a time along the original line,
+/// so the tool only ever engages the front of its loop instead of the whole slot.
+///
+/// Each loop starts and ends on the line, behind its center, and the path moves straight
+/// between loops and over whatever is left at the end of a segment. The radius is capped so the
+/// loops stay within `slot_width_pix` (i.e. `(slot_width_pix - tool_dia_pix) / 2`), and with
+/// `center_mask_im` (pixels where the tool center may go, e.g. a node's dilated cut mask) a loop
+/// that would leave the mask is dropped in favor of the straight move. Segments that change Z or
+/// are too short for a loop stay as they are.
+///
+/// The result replaces `tp` with the same tool, tile and `tree_node_id`, and fresh `cuts`.
+/// Traverses, and paths that can't fit a loop of at least one pixel, come back unchanged.
+pub fn trochoidalize(
+    tp: &ToolPath,
+    slot_width_pix: usize,
+    trochoid_radius_pix: usize,
+    advance_pix: usize,
+    center_mask_im: Option<&MaskIm>,
+) -> Vec<ToolPath> {
+    let max_radius_pix = slot_width_pix.saturating_sub(tp.tool_dia_pix) / 2;
+    let radius = trochoid_radius_pix.min(max_radius_pix) as f64;
+    if tp.is_traverse || tp.points.len() < 2 || radius < 1.0 || advance_pix == 0 {
+        return vec![tp.clone()];
+    }
+    let advance = advance_pix as f64;
+
+    let in_mask = |p: IV3| match center_mask_im {
+        None => true,
+        Some(m) => {
+            p.x >= 0
+                && p.y >= 0
+                && (p.x as usize) < m.w
+                && (p.y as usize) < m.h
+                && m.arr[p.y as usize * m.s + p.x as usize] != 0
+        }
+    };
 
-        for seg_i in 0..seg_n {
-            let cut = cuts_in.get(seg_i).copied().unwrap_or_default();
-            let keep = cut.pixels_changed > 0;
+    // About one pixel of arc per step.
+    let n_steps = ((std::f64::consts::TAU * radius).ceil() as usize).max(8);
+
+    let mut points: Vec<IV3> = vec![tp.points[0]];
+    for seg in tp.points.windows(2) {
+        let (p0, p1) = (seg[0], seg[1]);
+        let dx = (p1.x - p0.x) as f64;
+        let dy = (p1.y - p0.y) as f64;
+        let len = dx.hypot(dy);
+        if p0.z == p1.z && len >= 2.0 * radius {
+            let (ux, uy) = (dx / len, dy / len);
+            let at = |along: f64, across: f64| IV3 {
+                x: (p0.x as f64 + ux * along - uy * across).round() as i32,
+                y: (p0.y as f64 + uy * along + ux * across).round() as i32,
+                z: p0.z,
+            };
 
-            if keep {
-                if run_points.is_empty() {
-                    run_points.push(points[seg_i]);
+            // Loop k is centered `radius + k * advance` along the segment and must end by p1.
+            let mut center = radius;
+            while center + radius <= len {
+                let loop_points: Vec<IV3> = (0..=n_steps)
+                    .map(|step| {
+                        // Start behind the center and go around back to it.
+                        let a = std::f64::consts::TAU * step as f64 / n_steps as f64;
+                        at(center - radius * a.cos(), radius * a.sin())
+                    })
+                    .collect();
+                if loop_points.iter().all(|&p| in_mask(p)) {
+                    points.extend(loop_points);
                 }
-                run_points.push(points[seg_i + 1]);
-                run_cuts.push(cut);
-            } else if !run_points.is_empty() {
-                out.push(build_open_toolpath_from_segments(
-                    std::mem::take(&mut run_points),
-                    std::mem::take(&mut run_cuts),
-                    tool_dia_pix,
-                    tool_i,
-                    tile_i,
-                    tree_node_id,
-                    is_traverse,
-                    is_raster,
-                ));
+                center += advance;
             }
         }
+        points.push(p1);
+    }
+    points.dedup();
 
-        if !run_points.is_empty() {
-            out.push(build_open_toolpath_from_segments(
-                run_points,
-                run_cuts,
-                tool_dia_pix,
-                tool_i,
-                tile_i,
-                tree_node_id,
-                is_traverse,
-                is_raster,
-            ));
-        }
+    vec![ToolPath {
+        cuts: vec![CutPixels::default(); points.len()],
+        points,
+        closed: false,
+        ..tp.clone()
+    }]
+}
 
-        out
+/// Replace the sharp corners of `tp` with short fillet arcs (as polylines of about one pixel per
+/// step), so a high-feed move can sweep through a corner instead of decelerating to zero.
+///
+/// A corner turning by θ gets the largest fillet radius r ≤ `max_radius_pix` whose arc stays
+/// within `max_dev_pix` of the corner (the arc's midpoint is r (1/cos(θ/2) - 1) inside it) and
+/// whose tangent points, r tan(θ/2) back along each segment, stay within half of either segment,
+/// so neighboring fillets never overlap. Corners turning by 90° or more are kept sharp: those are
+/// row ends and wall corners (a square pocket's among them) where the fillet would leave material
+/// the path is there to cut. Corners that change Z, or whose fillet would be under a pixel, are
+/// kept as well.
+///
+/// Closed paths stay closed (the ring's first vertex is rounded too) and the result has fresh
+/// `cuts`. Traverses come back unchanged.
+pub fn round_corners(tp: &ToolPath, max_radius_pix: f64, max_dev_pix: f64) -> ToolPath {
+    const MIN_TURN_DEG: f64 = 1.0;
+
+    let n = tp.points.len();
+    if tp.is_traverse || n < 3 || max_radius_pix <= 0.0 || max_dev_pix <= 0.0 {
+        return tp.clone();
     }
 
-    fn cull_closed_toolpath(
-        mut points: Vec<IV3>,
-        mut cuts_in: Vec<CutPixels>,
-        tool_dia_pix: usize,
-        tool_i: usize,
-        tile_i: usize,
-        tree_node_id: usize,
-        is_traverse: bool,
-        is_raster: bool,
-    ) -> Vec<ToolPath> {
-        // Normalize to an explicitly closed loop (duplicate first point at the end)
-        // with `cuts.len() == points.len()`.
-        if points.len() < 3 {
-            return Vec::new();
+    let fillet = |a: IV3, b: IV3, c: IV3| -> Option<Vec<IV3>> {
+        if a.z != b.z || b.z != c.z {
+            return None;
         }
-
-        let has_dup = points.first() == points.last();
-        if !has_dup {
-            let first = points[0];
-            points.push(first);
-
-            // Best-effort carry over existing per-segment cuts; the closing edge gets default.
-            let mut new_cuts = vec![CutPixels::default(); points.len()];
-            for i in 0..points.len().saturating_sub(2) {
-                if i < cuts_in.len() {
-                    new_cuts[i] = cuts_in[i];
-                }
-            }
-            cuts_in = new_cuts;
-        } else if cuts_in.len() != points.len() {
-            // If we don't have a parallel cut array, we can't safely cull segments.
-            let n = points.len();
-            return vec![ToolPath {
-                points,
-                closed: true,
-                tool_dia_pix,
-                tool_i,
-                tile_i,
-                tree_node_id,
-                cuts: vec![CutPixels::default(); n],
-                is_traverse,
-                is_raster,
-            }];
+        let (d1x, d1y) = ((b.x - a.x) as f64, (b.y - a.y) as f64);
+        let (d2x, d2y) = ((c.x - b.x) as f64, (c.y - b.y) as f64);
+        let (len1, len2) = (d1x.hypot(d1y), d2x.hypot(d2y));
+        if len1 == 0.0 || len2 == 0.0 {
+            return None;
+        }
+        // A turn of 90° or more, checked on the exact integer dot product so right angles are
+        // never rounded by float error.
+        let dot = (b.x - a.x) as i64 * (c.x - b.x) as i64 + (b.y - a.y) as i64 * (c.y - b.y) as i64;
+        if dot <= 0 {
+            return None;
+        }
+        let (u1x, u1y) = (d1x / len1, d1y / len1);
+        let (u2x, u2y) = (d2x / len2, d2y / len2);
+        let turn = (u1x * u2x + u1y * u2y).clamp(-1.0, 1.0).acos();
+        if turn < MIN_TURN_DEG.to_radians() {
+            return None;
         }
 
-        if points.len() < 4 {
-            // Smallest explicit-closure loop is 3 vertices + duplicate.
-            return Vec::new();
+        let half = turn / 2.0;
+        let radius = max_radius_pix
+            .min(max_dev_pix / (1.0 / half.cos() - 1.0))
+            .min(0.5 * len1.min(len2) / half.tan());
+        let tangent_dist = radius * half.tan();
+        if tangent_dist < 1.0 {
+            return None;
         }
 
-        let ring_len = points.len() - 1; // number of ring vertices; number of ring segments
-        let seg_count = ring_len;
+        // The center is on the corner's inner bisector, r / cos(θ/2) from the corner.
+        let (bis_x, bis_y) = (u2x - u1x, u2y - u1y);
+        let bis_len = bis_x.hypot(bis_y);
+        let center_dist = radius / half.cos();
+        let cx = b.x as f64 + bis_x / bis_len * center_dist;
+        let cy = b.y as f64 + bis_y / bis_len * center_dist;
+
+        // Sweep from the tangent point on the incoming segment by θ, in the turn's direction.
+        let t1x = b.x as f64 - u1x * tangent_dist;
+        let t1y = b.y as f64 - u1y * tangent_dist;
+        let start_angle = (t1y - cy).atan2(t1x - cx);
+        let sweep = if u1x * u2y - u1y * u2x >= 0.0 {
+            turn
+        } else {
+            -turn
+        };
+        let n_steps = ((radius * turn).ceil() as usize).max(1);
+        Some(
+            (0..=n_steps)
+                .map(|step| {
+                    let angle = start_angle + sweep * step as f64 / n_steps as f64;
+                    IV3 {
+                        x: (cx + radius * angle.cos()).round() as i32,
+                        y: (cy + radius * angle.sin()).round() as i32,
+                        z: b.z,
+                    }
+                })
+                .collect(),
+        )
+    };
 
-        let mut keep: Vec<bool> = Vec::with_capacity(seg_count);
-        for i in 0..seg_count {
-            let cut = cuts_in.get(i).copied().unwrap_or_default();
-            keep.push(cut.pixels_changed > 0);
+    let mut points: Vec<IV3> = Vec::with_capacity(n);
+    if tp.closed {
+        // Round every vertex of the ring, then close it again.
+        let ring = if tp.points[0] == tp.points[n - 1] {
+            &tp.points[..n - 1]
+        } else {
+            &tp.points[..]
+        };
+        let m = ring.len();
+        for i in 0..m {
+            let (a, b, c) = (ring[(i + m - 1) % m], ring[i], ring[(i + 1) % m]);
+            match fillet(a, b, c) {
+                Some(arc) => points.extend(arc),
+                None => points.push(b),
+            }
         }
-
-        if keep.iter().all(|&k| k) {
-            // Keep as a closed loop.
-            if let Some(last) = cuts_in.last_mut() {
-                *last = CutPixels::default();
+        points.dedup();
+        if points.len() > 1 && points[0] == points[points.len() - 1] {
+            points.pop();
+        }
+        points.push(points[0]);
+    } else {
+        points.push(tp.points[0]);
+        for w in tp.points.windows(3) {
+            match fillet(w[0], w[1], w[2]) {
+                Some(arc) => points.extend(arc),
+                None => points.push(w[1]),
             }
-            return vec![ToolPath {
-                points,
-                closed: true,
-                tool_dia_pix,
-                tool_i,
-                tile_i,
-                tree_node_id,
-                cuts: cuts_in,
-                is_traverse,
-                is_raster,
-            }];
         }
+        points.push(tp.points[n - 1]);
+        points.dedup();
+    }
 
-        if keep.iter().all(|&k| !k) {
-            return Vec::new();
-        }
+    ToolPath {
+        cuts: vec![CutPixels::default(); points.len()],
+        points,
+        ..tp.clone()
+    }
+}
 
-        // Linearize the circular segments by starting immediately after a removed segment.
-        let first_false = keep.iter().position(|&k| !k).unwrap_or(0);
-        let mut idx = (first_false + 1) % seg_count;
+/// Leave `count` holding tabs along a closed perimeter that cuts through the stock, so the part
+/// stays attached to the frame: over each tab the path goes straight up `tab_height_thou`, runs
+/// `tab_len_pix` at that height, and comes straight back down.
+///
+/// Tabs are spread evenly by arc length, each starting from the middle of its share of the
+/// perimeter. Tabs go on straight stretches: a tab (plus the tool radius at either end) may not
+/// cover a vertex turning by more than 30°, nor the ring's first vertex, so it slides along its
+/// share until it fits. A tab that fits nowhere in its share is left out.
+///
+/// The result has fresh `cuts`, with `is_tab` set on the segments of each tab. The up and down
+/// moves change Z, so the sim does not count them as cutting, while the top of a tab cuts at the
+/// raised Z. Open paths, traverses, and rings that change Z come back unchanged.
+pub fn add_holding_tabs(
+    tp: &ToolPath,
+    tab_height_thou: u16,
+    tab_len_pix: usize,
+    count: usize,
+) -> ToolPath {
+    const MAX_STRAIGHT_TURN_DEG: f64 = 30.0;
+
+    let n = tp.points.len();
+    if !tp.closed || tp.is_traverse || n < 4 || tab_height_thou == 0 || tab_len_pix == 0 {
+        return tp.clone();
+    }
+    let ring = if tp.points[0] == tp.points[n - 1] {
+        &tp.points[..n - 1]
+    } else {
+        &tp.points[..]
+    };
+    let m = ring.len();
+    let z = ring[0].z;
+    if count == 0 || ring.iter().any(|p| p.z != z) {
+        return tp.clone();
+    }
 
-        let mut out: Vec<ToolPath> = Vec::new();
-        let mut run_points: Vec<IV3> = Vec::new();
-        let mut run_cuts: Vec<CutPixels> = Vec::new();
+    // Arc length at each vertex; `cum[m]` is the whole perimeter.
+    let seg_len = |i: usize| {
+        let (a, b) = (ring[i], ring[(i + 1) % m]);
+        ((b.x - a.x) as f64).hypot((b.y - a.y) as f64)
+    };
+    let mut cum = vec![0.0; m + 1];
+    for i in 0..m {
+        cum[i + 1] = cum[i] + seg_len(i);
+    }
+    let perimeter = cum[m];
+
+    // Arc positions tabs must stay clear of: sharp vertices and the seam at either end.
+    let mut corners: Vec<f64> = vec![0.0, perimeter];
+    for i in 1..m {
+        let (a, b, c) = (ring[i - 1], ring[i], ring[(i + 1) % m]);
+        let (d1x, d1y) = ((b.x - a.x) as f64, (b.y - a.y) as f64);
+        let (d2x, d2y) = ((c.x - b.x) as f64, (c.y - b.y) as f64);
+        let lens = d1x.hypot(d1y) * d2x.hypot(d2y);
+        if lens == 0.0 {
+            continue;
+        }
+        let turn = ((d1x * d2x + d1y * d2y) / lens).clamp(-1.0, 1.0).acos();
+        if turn > MAX_STRAIGHT_TURN_DEG.to_radians() {
+            corners.push(cum[i]);
+        }
+    }
 
-        for _ in 0..seg_count {
-            if keep[idx] {
-                if run_points.is_empty() {
-                    run_points.push(points[idx]);
-                }
-                run_points.push(points[idx + 1]);
-                run_cuts.push(cuts_in[idx]);
-            } else if !run_points.is_empty() {
-                out.push(build_open_toolpath_from_segments(
-                    std::mem::take(&mut run_points),
-                    std::mem::take(&mut run_cuts),
-                    tool_dia_pix,
-                    tool_i,
-                    tile_i,
-                    tree_node_id,
-                    is_traverse,
-                    is_raster,
-                ));
-            }
+    let tab_len = tab_len_pix as f64;
+    let clearance = (tp.tool_dia_pix / 2) as f64;
+    let share = perimeter / count as f64;
+    let slack = (share - tab_len) / 2.0;
+    if slack < 0.0 {
+        return tp.clone();
+    }
+    let mut tabs: Vec<(f64, f64)> = Vec::with_capacity(count);
+    for tab_i in 0..count {
+        let mid = (tab_i as f64 + 0.5) * share;
+        // Try the middle of the share first, then one pixel further either side at a time.
+        let fits = |center: f64| {
+            let (s0, s1) = (center - tab_len / 2.0, center + tab_len / 2.0);
+            let clear = !corners
+                .iter()
+                .any(|&c| c >= s0 - clearance && c <= s1 + clearance);
+            clear.then_some((s0, s1))
+        };
+        let found = (0..=slack.floor() as usize)
+            .find_map(|shift| fits(mid + shift as f64).or_else(|| fits(mid - shift as f64)));
+        tabs.extend(found);
+    }
+    if tabs.is_empty() {
+        return tp.clone();
+    }
 
-            idx = (idx + 1) % seg_count;
+    let at = |i: usize, s: f64| {
+        let (a, b) = (ring[i], ring[(i + 1) % m]);
+        let len = cum[i + 1] - cum[i];
+        let t = if len > 0.0 { (s - cum[i]) / len } else { 0.0 };
+        IV3 {
+            x: (a.x as f64 + (b.x - a.x) as f64 * t).round() as i32,
+            y: (a.y as f64 + (b.y - a.y) as f64 * t).round() as i32,
+            z,
         }
+    };
+    let raised = |p: IV3| IV3 {
+        z: z + tab_height_thou as i32,
+        ..p
+    };
 
-        if !run_points.is_empty() {
-            out.push(build_open_toolpath_from_segments(
-                run_points,
-                run_cuts,
-                tool_dia_pix,
-                tool_i,
-                tile_i,
-                tree_node_id,
-                is_traverse,
-                is_raster,
-            ));
+    // Walk the ring, stepping up at each tab's start and back down at its end.
+    let mut events: Vec<(f64, bool)> = tabs
+        .iter()
+        .flat_map(|&(s0, s1)| [(s0, true), (s1, false)])
+        .collect();
+    events.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut events = events.into_iter().peekable();
+    let mut in_tab = false;
+    let mut points: Vec<IV3> = Vec::with_capacity(n + 4 * tabs.len());
+    for i in 0..m {
+        points.push(if in_tab { raised(ring[i]) } else { ring[i] });
+        while let Some(&(s, up)) = events.peek() {
+            if s >= cum[i + 1] {
+                break;
+            }
+            events.next();
+            let p = at(i, s);
+            if up {
+                points.extend([p, raised(p)]);
+            } else {
+                points.extend([raised(p), p]);
+            }
+            in_tab = up;
         }
+    }
+    points.dedup();
+    points.push(points[0]);
+
+    // Every tab segment has a raised end: the moves up and down and the run on top.
+    let mut cuts: Vec<CutPixels> = points
+        .windows(2)
+        .map(|seg| CutPixels {
+            is_tab: seg[0].z != z || seg[1].z != z,
+            ..Default::default()
+        })
+        .collect();
+    cuts.push(CutPixels::default());
 
-        out
+    ToolPath {
+        cuts,
+        points,
+        ..tp.clone()
     }
+}
+
+/// Split cutting toolpaths wherever they touch `keep_out`: a segment passing over a keep-out
+/// pixel (checked at one-pixel steps, endpoints included) is removed, and what's left on either
+/// side becomes separate open toolpaths, so a path straddling a keep-out is split rather than
+/// trimmed to its endpoints. A closed loop that is interrupted opens at the gap. Segments keep
+/// their `cuts`; traverses are left alone.
+pub fn split_toolpaths_at_keep_out(toolpaths: &mut Vec<ToolPath>, keep_out: &MaskIm) {
+    let blocked = |p: IV3| {
+        p.x >= 0
+            && p.y >= 0
+            && (p.x as usize) < keep_out.w
+            && (p.y as usize) < keep_out.h
+            && keep_out.arr[p.y as usize * keep_out.s + p.x as usize] != 0
+    };
+    let seg_clear = |p0: IV3, p1: IV3| {
+        let dx = (p1.x - p0.x) as f64;
+        let dy = (p1.y - p0.y) as f64;
+        let n_steps = dx.abs().max(dy.abs()).ceil().max(1.0) as usize;
+        (0..=n_steps).all(|step| {
+            let t = step as f64 / n_steps as f64;
+            !blocked(IV3 {
+                x: (p0.x as f64 + t * dx).round() as i32,
+                y: (p0.y as f64 + t * dy).round() as i32,
+                z: p0.z,
+            })
+        })
+    };
 
     let mut out: Vec<ToolPath> = Vec::with_capacity(toolpaths.len());
     for tp in toolpaths.drain(..) {
-        let ToolPath {
-            points,
-            closed,
-            tool_dia_pix,
-            tool_i,
-            tile_i,
-            tree_node_id,
-            cuts,
-            is_traverse,
-            is_raster,
-        } = tp;
-
-        if points.len() < 2 {
+        if tp.is_traverse {
+            out.push(tp);
+            continue;
+        }
+        if tp.points.len() < 2 {
+            if !tp.points.iter().any(|&p| blocked(p)) {
+                out.push(tp);
+            }
             continue;
         }
 
-        // If the cut annotations aren't parallel, assume we can't make an informed decision.
-        // (This typically means `sim_toolpaths` wasn't run.)
-        if cuts.len() != points.len() {
-            out.push(ToolPath {
-                points,
-                closed,
-                tool_dia_pix,
-                tool_i,
-                tile_i,
-                tree_node_id,
-                cuts,
-                is_traverse,
-                is_raster,
-            });
+        let seg_ok: Vec<bool> = tp
+            .points
+            .windows(2)
+            .map(|s| seg_clear(s[0], s[1]))
+            .collect();
+        if seg_ok.iter().all(|&ok| ok) {
+            out.push(tp);
             continue;
         }
 
-        if closed {
-            out.extend(cull_closed_toolpath(
-                points,
-                cuts,
-                tool_dia_pix,
-                tool_i,
-                tile_i,
-                tree_node_id,
-                is_traverse,
-                is_raster,
-            ));
+        // Runs of consecutive clear segments; a closed loop's runs may wrap around its start.
+        let runs: Vec<Vec<usize>> = if tp.closed && tp.points.first() == tp.points.last() {
+            cyclic_kept_runs(&seg_ok)
+                .into_iter()
+                .map(|(seg_iz, _)| seg_iz)
+                .collect()
         } else {
-            out.extend(cull_open_toolpath(
+            let mut runs: Vec<Vec<usize>> = Vec::new();
+            let mut run: Vec<usize> = Vec::new();
+            for (seg_i, &ok) in seg_ok.iter().enumerate() {
+                if ok {
+                    run.push(seg_i);
+                } else if !run.is_empty() {
+                    runs.push(std::mem::take(&mut run));
+                }
+            }
+            if !run.is_empty() {
+                runs.push(run);
+            }
+            runs
+        };
+
+        for seg_iz in runs {
+            let mut points: Vec<IV3> = vec![tp.points[seg_iz[0]]];
+            let mut cuts: Vec<CutPixels> = Vec::with_capacity(seg_iz.len() + 1);
+            for &seg_i in &seg_iz {
+                points.push(tp.points[seg_i + 1]);
+                cuts.push(tp.cuts.get(seg_i).copied().unwrap_or_default());
+            }
+            cuts.push(CutPixels::default());
+            out.push(ToolPath {
                 points,
                 cuts,
-                tool_dia_pix,
-                tool_i,
-                tile_i,
-                tree_node_id,
-                is_traverse,
-                is_raster,
-            ));
+                closed: false,
+                ..tp.clone()
+            });
         }
     }
-
     *toolpaths = out;
 }
 
+/// Split segments longer than `max_segment_len_pix` (in XY) into 2-point toolpaths.
+/// Paths without a long segment are kept whole; in paths with one, each run of short segments
+/// between long ones stays a single open multi-point toolpath. Each long segment's
+/// `CutPixels` is distributed over its pieces by length so totals are preserved.
+pub fn break_long_toolpaths(toolpaths: &mut Vec<ToolPath>, max_segment_len_pix: usize) {
+    if toolpaths.is_empty() {
+        return;
+    }
 
-///
-/// Insert explicit "traverse"" (air-move) toolpaths between consecutive cutting toolpaths
-/// Args:
-///   - befpre_sim_im: A Lum16Im that contains the starting state for simulation (all pixels are full height)
-///   - toolpaths: A borrowed vector of ToolPath structs representing the cutting toolpaths. Must all be of the same tool
-///     Must be mutable because simulation modifies the the tool paths by setting the cut pixels
-pub fn add_traverse_toolpaths_one_tool<'a>(
-    before_sim_im: &mut crate::im::Lum16Im,
-    toolpaths: &'a mut Vec<ToolPath>,
-    tool_i: usize,
-    tool_dia_pix: usize,
-) -> Vec<ToolPath> {
-    // NOTE: This is a prototype helper. Callers may pass a mixed-tool `toolpaths` list
-    // (as `main.rs` currently does). We therefore only *process* segments belonging to
-    // `tool_i`, and we only *return* references for those toolpaths.
-    //
-    // We must not borrow `toolpaths` inside the sim callback because `sim_toolpaths`
-    // holds a mutable borrow of the slice for the duration of simulation.
-    let tool_meta: Vec<(usize, usize, usize, usize)> = toolpaths
-        .iter()
-        .map(|tp| (tp.tool_i, tp.tool_dia_pix, tp.points.len(), tp.tile_i))
-        .collect();
-
-    let n_toolpaths = toolpaths.len();
-
-    let mut traverse_paths: Vec<ToolPath> = Vec::with_capacity(n_toolpaths);
-
-    let tool_radius_pix: usize = tool_dia_pix / 2;
-    let circle_pix = crate::sim::circle_pixel_iz(tool_radius_pix, before_sim_im.s);
-
-    // Precompute toolpath endpoints so the sim callback doesn't borrow `toolpaths`.
-    // (The simulator mutates cut pixels, not the points themselves.)
-    let toolpath_starts: Vec<Option<IV3>> = toolpaths
-        .iter()
-        .map(|tp| tp.points.first().copied())
-        .collect();
-    let toolpath_ends: Vec<Option<IV3>> = toolpaths
-        .iter()
-        .map(|tp| tp.points.last().copied())
-        .collect();
-    let toolpath_is_rasters: Vec<bool> = toolpaths
-        .iter()
-        .map(|tp| tp.is_raster)
-        .collect();
+    let max_segment_len_pix = max_segment_len_pix.max(1);
+    let max_len2: i64 = (max_segment_len_pix as i64) * (max_segment_len_pix as i64);
 
-    let mut callback = |im: &crate::im::Lum16Im,
-                        toolpath_i: usize,
-                        seg_i: usize,
-                        _p0: IV3,
-                        p1: IV3,
-                        _seg_cut: CutPixels| {
-        let Some(&(tp_tool_i, tp_tool_dia_pix, tp_points_len, tp_tile_i)) = tool_meta.get(toolpath_i) else {
-            return;
-        };
+    fn dist2_xy(a: &IV3, b: &IV3) -> i64 {
+        let dx = (a.x as i64) - (b.x as i64);
+        let dy = (a.y as i64) - (b.y as i64);
+        dx * dx + dy * dy
+    }
 
-        if tp_tool_i != tool_i {
-            return;
+    // Share `cut` over pieces with the given lengths; integer totals are preserved exactly.
+    fn distribute_cut(cut: CutPixels, piece_lens: &[f64]) -> Vec<CutPixels> {
+        let total_len: f64 = piece_lens.iter().sum();
+        let mut out = Vec::with_capacity(piece_lens.len());
+        let mut cum_len = 0.0;
+        let mut prev_pixels = 0u64;
+        let mut prev_depth = 0u64;
+        for (i, &len) in piece_lens.iter().enumerate() {
+            cum_len += len;
+            let (pixels, depth) = if i + 1 == piece_lens.len() || total_len <= 0.0 {
+                (cut.pixels_changed, cut.depth_sum_thou)
+            } else {
+                let t = cum_len / total_len;
+                (
+                    ((cut.pixels_changed as f64 * t).round() as u64).min(cut.pixels_changed),
+                    ((cut.depth_sum_thou as f64 * t).round() as u64).min(cut.depth_sum_thou),
+                )
+            };
+            let pixels = pixels.max(prev_pixels);
+            let depth = depth.max(prev_depth);
+            out.push(CutPixels {
+                pixels_changed: pixels - prev_pixels,
+                depth_sum_thou: depth - prev_depth,
+                feed_scale: cut.feed_scale,
+                max_engagement_frac: cut.max_engagement_frac,
+                is_tab: cut.is_tab,
+            });
+            prev_pixels = pixels;
+            prev_depth = depth;
         }
+        out
+    }
 
-        let last_seg_i = tp_points_len.saturating_sub(2);
-        if seg_i != last_seg_i {
-            return;
-        }
+    let mut new_toolpaths: Vec<ToolPath> = Vec::new();
 
-        // The end point of the last segment is the toolpath's final point.
-        debug_assert_eq!(toolpath_ends.get(toolpath_i).copied().flatten(), Some(p1));
+    for tp in toolpaths.drain(..) {
+        if tp.points.len() < 2 {
+            new_toolpaths.push(tp);
+            continue;
+        }
 
-        // Tool diameter must match. 
-        debug_assert_eq!(
-            tp_tool_dia_pix, tool_dia_pix,
-            "Tool diameter mismatch for tool_i={tool_i}"
-        );
+        let is_traverse = tp.is_traverse;
+        let is_raster = tp.is_raster;
+        let is_plunge_run = tp.is_plunge_run;
 
-        // If we're transitioning between two raster toolpaths, each raster toolpath is a single
-        // segment. If the segments overlap in X and are within one tool diameter in Y, we can
-        // generate a special traverse polyline (e.g. backtrack) instead of retracting.
-        let max_distance_to_backtrack_pix = tool_dia_pix as u32 * 2;
+        let want_closed = tp.closed;
 
-        let mut traverse_verts_opt: Option<Vec<IV3>> = None;
+        // `cuts[i]` belongs to the segment starting at `points[i]`.
+        let mut cuts: Vec<CutPixels> = tp.cuts;
+        cuts.resize(tp.points.len(), CutPixels::default());
 
-        if toolpath_i < n_toolpaths - 1 && toolpath_is_rasters[toolpath_i] && toolpath_is_rasters[toolpath_i + 1] {
-            if let (Some(a0), Some(a1), Some(b0), Some(b1)) = (
-                toolpath_starts.get(toolpath_i).copied().flatten(),
-                toolpath_ends.get(toolpath_i).copied().flatten(),
-                toolpath_starts.get(toolpath_i + 1).copied().flatten(),
-                toolpath_ends.get(toolpath_i + 1).copied().flatten(),
-            ) {
-                debug_assert!(a0.y == a1.y && b0.y == b1.y, "Raster toolpaths should be horizontal");
-                debug_assert!(a0.z == a1.z && b0.z == b1.z, "Raster toolpaths should be constant-Z");
+        // Normalize closed loops to a ring without a duplicated closing vertex;
+        // we will explicitly handle the closing edge.
+        let mut pts: Vec<IV3> = tp.points;
+        let mut closing_cut = CutPixels::default();
+        if want_closed {
+            if pts.len() >= 2 && pts.first() == pts.last() {
+                pts.pop();
+            }
+            // With the duplicate dropped, the last entry is the closing edge's.
+            closing_cut = cuts[pts.len() - 1];
+        }
 
-                if a1.z == b0.z {
-                    let lft = a0.x.min(a1.x); 
-                    let rgt = a0.x.max(a1.x);
-                    let delta_x = b0.x.abs_diff(a1.x);
+        // If no segment exceeds the max length, keep the original path (preserving `closed`).
+        // This avoids converting every toolpath into 2-point segments.
+        let mut needs_split = false;
+        if pts.len() >= 2 {
+            for seg in pts.windows(2) {
+                if dist2_xy(&seg[0], &seg[1]) > max_len2 {
+                    needs_split = true;
+                    break;
+                }
+            }
+            if !needs_split && want_closed {
+                let a = *pts.last().unwrap();
+                let b = pts[0];
+                if dist2_xy(&a, &b) > max_len2 {
+                    needs_split = true;
+                }
+            }
+        }
 
-                    if lft <= b0.x && b0.x <= rgt && delta_x <= max_distance_to_backtrack_pix {
-                        traverse_verts_opt = Some(vec![
-                            IV3 {
-                                x: b0.x,
-                                y: a1.y,
-                                z: a1.z,
-                            },
-                            IV3 {
-                                x: b0.x,
-                                y: b0.y,
-                                z: b0.z,
-                            },
-                        ]);
+        if !needs_split {
+            if pts.len() >= 2 {
+                if want_closed {
+                    // Re-close explicitly for consumers that expect it.
+                    if pts.first() != pts.last() {
+                        let first = pts[0];
+                        pts.push(first);
                     }
+                    let mut cuts = cuts;
+                    cuts.resize(pts.len(), CutPixels::default());
+                    new_toolpaths.push(ToolPath {
+                        points: pts,
+                        closed: true,
+                        tool_dia_pix: tp.tool_dia_pix,
+                        tool_i: tp.tool_i,
+                        tile_i: tp.tile_i,
+                        tree_node_id: tp.tree_node_id,
+                        cuts,
+                        is_traverse,
+                        is_raster,
+                        is_plunge_run,
+                    });
+                } else {
+                    new_toolpaths.push(ToolPath {
+                        points: pts,
+                        closed: false,
+                        tool_dia_pix: tp.tool_dia_pix,
+                        tool_i: tp.tool_i,
+                        tile_i: tp.tile_i,
+                        tree_node_id: tp.tree_node_id,
+                        cuts,
+                        is_traverse,
+                        is_raster,
+                        is_plunge_run,
+                    });
                 }
             }
+            continue;
         }
 
-        traverse_verts_opt = traverse_verts_opt.or_else(|| {
-            // Raster backtrack was not possible so we need a full retract-traverse-plunge.
-            let from_vert: IV3 = p1;
-            let to_vert_opt: Option<IV3> = toolpath_starts.get(toolpath_i + 1).copied().flatten();
-            let Some(to_vert) = to_vert_opt else {
-                return Some(Vec::new());
-            };
+        let make = |points: Vec<IV3>, cuts: Vec<CutPixels>| ToolPath {
+            points,
+            closed: false,
+            tool_dia_pix: tp.tool_dia_pix,
+            tool_i: tp.tool_i,
+            tile_i: tp.tile_i,
+            tree_node_id: tp.tree_node_id,
+            cuts,
+            is_traverse,
+            is_raster,
+            is_plunge_run,
+        };
 
-            // Compute the minimum safe Z to traverse between from_vert and to_vert.
-            let mut safe_z_i32 = crate::sim::scan_toolpath_segment_max_u16(
-                im,
-                from_vert,
-                to_vert,
-                tool_radius_pix,
+        // Every edge with its cut, including the closing edge of a loop.
+        let mut edges: Vec<(IV3, IV3, CutPixels)> = pts
+            .windows(2)
+            .zip(cuts.iter())
+            .map(|(seg, &cut)| (seg[0], seg[1], cut))
+            .collect();
+        if want_closed {
+            edges.push((*pts.last().unwrap(), pts[0], closing_cut));
+            // Start the walk just after a long edge so the short run that wraps past the seam
+            // comes out as one piece.
+            if let Some(long_i) = edges.iter().position(|(a, b, _)| dist2_xy(a, b) > max_len2) {
+                edges.rotate_left(long_i + 1);
+            }
+        }
+
+        // Consecutive short edges stay together as one open multi-point path; only long edges
+        // are subdivided, each into 2-point pieces.
+        let mut run_pts: Vec<IV3> = Vec::new();
+        let mut run_cuts: Vec<CutPixels> = Vec::new();
+        for (a, b, cut) in edges {
+            if dist2_xy(&a, &b) <= max_len2 {
+                if run_pts.is_empty() {
+                    run_pts.push(a);
+                }
+                run_pts.push(b);
+                run_cuts.push(cut);
+                continue;
+            }
+
+            if !run_pts.is_empty() {
+                run_cuts.push(CutPixels::default());
+                new_toolpaths.push(make(
+                    std::mem::take(&mut run_pts),
+                    std::mem::take(&mut run_cuts),
+                ));
+            }
+
+            // Subdivide into N segments so each is <= max_segment_len_pix in XY.
+            let dx = (b.x - a.x) as f64;
+            let dy = (b.y - a.y) as f64;
+            let dist = (dx * dx + dy * dy).sqrt();
+            let steps = ((dist / (max_segment_len_pix as f64)).ceil() as usize).max(1);
+
+            let mut pieces: Vec<(IV3, IV3)> = Vec::with_capacity(steps);
+            let mut prev = a;
+            for i in 1..=steps {
+                let t = (i as f64) / (steps as f64);
+                let x = (a.x as f64 + (b.x - a.x) as f64 * t).round() as i32;
+                let y = (a.y as f64 + (b.y - a.y) as f64 * t).round() as i32;
+                let z = (a.z as f64 + (b.z - a.z) as f64 * t).round() as i32;
+                let next = IV3 { x, y, z };
+                if next != prev {
+                    pieces.push((prev, next));
+                    prev = next;
+                }
+            }
+
+            let piece_lens: Vec<f64> = pieces
+                .iter()
+                .map(|(p, q)| (dist2_xy(p, q) as f64).sqrt())
+                .collect();
+            let piece_cuts = distribute_cut(cut, &piece_lens);
+            for ((p, q), piece_cut) in pieces.into_iter().zip(piece_cuts) {
+                new_toolpaths.push(make(vec![p, q], vec![piece_cut, CutPixels::default()]));
+            }
+        }
+        if !run_pts.is_empty() {
+            run_cuts.push(CutPixels::default());
+            new_toolpaths.push(make(run_pts, run_cuts));
+        }
+    }
+
+    *toolpaths = new_toolpaths;
+    debug_validate_toolpaths(toolpaths, "break_long_toolpaths", true);
+}
+
+/// Join runs of consecutive open toolpaths where one ends exactly where the next starts
+/// (same `tool_i`, `tree_node_id`, kind, and Z at the joint) and the direction changes by at most
+/// `angle_tol_deg` at the joint. The joined path keeps a parallel `cuts` array.
+/// Closed paths are left untouched and break a run.
+pub fn merge_collinear(toolpaths: &mut Vec<ToolPath>, angle_tol_deg: f64) {
+    if toolpaths.len() < 2 {
+        return;
+    }
+
+    let cos_tol = angle_tol_deg.clamp(0.0, 180.0).to_radians().cos();
+
+    fn dir_xy(a: &IV3, b: &IV3) -> (f64, f64) {
+        ((b.x - a.x) as f64, (b.y - a.y) as f64)
+    }
+
+    fn can_merge(a: &ToolPath, b: &ToolPath, cos_tol: f64) -> bool {
+        if a.closed || b.closed || a.points.len() < 2 || b.points.len() < 2 {
+            return false;
+        }
+        if a.tool_i != b.tool_i
+            || a.tree_node_id != b.tree_node_id
+            || a.tool_dia_pix != b.tool_dia_pix
+            || a.is_traverse != b.is_traverse
+            || a.is_raster != b.is_raster
+        {
+            return false;
+        }
+        let n = a.points.len();
+        if a.points[n - 1] != b.points[0] {
+            return false;
+        }
+        let (ax, ay) = dir_xy(&a.points[n - 2], &a.points[n - 1]);
+        let (bx, by) = dir_xy(&b.points[0], &b.points[1]);
+        let len_a = (ax * ax + ay * ay).sqrt();
+        let len_b = (bx * bx + by * by).sqrt();
+        if len_a == 0.0 || len_b == 0.0 {
+            // Z-only moves have no XY direction to compare.
+            return false;
+        }
+        (ax * bx + ay * by) / (len_a * len_b) >= cos_tol - 1e-9
+    }
+
+    let mut merged: Vec<ToolPath> = Vec::with_capacity(toolpaths.len());
+    for tp in toolpaths.drain(..) {
+        if let Some(prev) = merged.last_mut()
+            && can_merge(prev, &tp, cos_tol)
+        {
+            // `prev`'s last cut entry is the unused one; the joint's segment comes from `tp`.
+            prev.cuts.resize(prev.points.len(), CutPixels::default());
+            prev.points.pop();
+            prev.cuts.pop();
+            let mut tp_cuts = tp.cuts;
+            tp_cuts.resize(tp.points.len(), CutPixels::default());
+            prev.points.extend(tp.points);
+            prev.cuts.extend(tp_cuts);
+            continue;
+        }
+        merged.push(tp);
+    }
+
+    *toolpaths = merged;
+}
+
+/// The cost of moving from `curr` (the end of the previous toolpath) to cut `tp` next, used by
+/// `sort_toolpaths` to pick among the toolpaths of a node.
+///
+/// Contract: lower is better, and the cost must be consistent, i.e. a pure function of its
+/// inputs that is never NaN, so the order stays deterministic. Equal costs fall back to the
+/// fixed tie-break order documented on `sort_toolpaths`. Where the chosen toolpath is entered
+/// (open paths reversed, closed loops rolled) is still decided by XY distance.
+pub trait OrderCost {
+    fn cost(&self, curr: &IV3, tp: &ToolPath) -> f64;
+}
+
+/// The XY distance² from `curr` to the start of `tp`, or to its nearer end if it is open.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultOrderCost;
+
+impl OrderCost for DefaultOrderCost {
+    fn cost(&self, curr: &IV3, tp: &ToolPath) -> f64 {
+        let Some(start) = tp.points.first() else {
+            return 0.0;
+        };
+        let end = tp.points.last().unwrap_or(start);
+        let dist2 = |p: &IV3| {
+            let dx = (curr.x as i64) - (p.x as i64);
+            let dy = (curr.y as i64) - (p.y as i64);
+            (dx * dx + dy * dy) as f64
+        };
+        if tp.closed {
+            dist2(start)
+        } else {
+            dist2(start).min(dist2(end))
+        }
+    }
+}
+
+/// Order toolpaths for cutting: nodes in region-tree DFS order, then greedy cheapest-next within
+/// each node (starting from the origin, then from the end of the previous toolpath) by `cost`;
+/// `DefaultOrderCost` makes it nearest-next.
+///
+/// A node with a raster plunge run (`ToolPath::is_plunge_run`) at its top Z starts with that run
+/// unchanged, so raster clearing still plunges where `choose_plunge_point` put it. Other raster
+/// runs are ordered like any other toolpath.
+///
+/// The result depends only on the input, never on hashing or platform. Ties are broken as:
+///   * Next toolpath: lowest `cost` (with `DefaultOrderCost`, smallest XY distance² to its start,
+///     or either end if open), then highest Z,
+///     closed before open, then smaller start (y, x), then fewer points, then input order.
+///   * Open toolpaths are reversed only if the end is strictly nearer than the start.
+///   * Closed toolpaths are rolled to the nearest vertex (the earliest one in ring order on a
+///     tie) but never reversed, so their winding (climb vs conventional) is kept.
+pub fn sort_toolpaths(
+    toolpaths: &mut Vec<ToolPath>,
+    region_root: &RegionRoot,
+    cost: &dyn OrderCost,
+) {
+    // Tree traversal for cutting order:
+    // - Keep sibling ordering as-built (caller said siblings can be any order).
+    // - A floor node reveals its children: we visit its subtree immediately after the floor.
+    fn build_node_visit_order(region_root: &RegionRoot) -> Vec<usize> {
+        // Sibling nodes must all be in the same band.
+        fn check_sibling_bands(nodes: &[RegionNode]) {
+            let Some(first) = nodes.first() else {
+                return;
+            };
+            let b0 = first.band_i();
+            assert!(nodes.iter().all(|n| n.band_i() == b0));
+            for n in nodes {
+                if let RegionNode::Floor { children, .. } = n {
+                    check_sibling_bands(children);
+                }
+            }
+        }
+        check_sibling_bands(region_root.children());
+
+        region_root.iter_dfs().map(|n| n.get_id()).collect()
+    }
+
+    fn dist2_xy(a: &IV3, b: &IV3) -> i64 {
+        let dx = (a.x as i64) - (b.x as i64);
+        let dy = (a.y as i64) - (b.y as i64);
+        dx * dx + dy * dy
+    }
+
+    fn ensure_cuts_parallel(tp: &mut ToolPath) {
+        if tp.cuts.len() != tp.points.len() {
+            tp.cuts = vec![CutPixels::default(); tp.points.len()];
+        }
+    }
+
+    fn reverse_open_toolpath_in_place(tp: &mut ToolPath) {
+        let n = tp.points.len();
+        if n <= 1 {
+            return;
+        }
+        ensure_cuts_parallel(tp);
+
+        // Reverse points, and remap segment cuts so `cuts[i]` still corresponds to
+        // `points[i] -> points[i+1]`.
+        let old_cuts = std::mem::take(&mut tp.cuts);
+        let mut new_cuts = vec![CutPixels::default(); n];
+        if old_cuts.len() == n {
+            // Segments are indices 0..n-1 (exclusive last point). Last entry is unused.
+            // After reversing points, segment i corresponds to old segment (n-2-i).
+            for i in 0..(n - 1) {
+                new_cuts[i] = old_cuts[n - 2 - i];
+            }
+        }
+        tp.cuts = new_cuts;
+        tp.points.reverse();
+    }
+
+    fn choose_open_orientation(tp: &mut ToolPath, curr: &IV3) {
+        if tp.points.len() <= 1 {
+            return;
+        }
+        ensure_cuts_parallel(tp);
+        let first = tp.points.first().unwrap();
+        let last = tp.points.last().unwrap();
+        let d_first = dist2_xy(curr, first);
+        let d_last = dist2_xy(curr, last);
+        if d_last < d_first {
+            reverse_open_toolpath_in_place(tp);
+        }
+    }
+
+    fn roll_closed_to_nearest(tp: &mut ToolPath, curr: &IV3) {
+        if tp.points.len() <= 1 {
+            return;
+        }
+
+        // Work on a ring representation (no duplicated closing vertex) and a parallel
+        // per-edge cut array of length `ring_len`.
+        let mut ring_pts: Vec<IV3> = std::mem::take(&mut tp.points);
+        let old_cuts: Vec<CutPixels> = std::mem::take(&mut tp.cuts);
+
+        let had_closing_dup = ring_pts
+            .first()
+            .zip(ring_pts.last())
+            .map(|(a, b)| a == b)
+            .unwrap_or(false);
+        if had_closing_dup {
+            ring_pts.pop();
+        }
+
+        if ring_pts.len() <= 1 {
+            // Restore the closure representation.
+            if ring_pts.len() == 1 {
+                ring_pts.push(ring_pts[0]);
+            }
+            tp.points = ring_pts;
+            tp.cuts = vec![CutPixels::default(); tp.points.len()];
+            return;
+        }
+
+        let ring_len = ring_pts.len();
+        let mut seg_cuts: Vec<CutPixels> = vec![CutPixels::default(); ring_len];
+
+        // Best-effort preservation of existing cuts.
+        if had_closing_dup {
+            // points len was ring_len+1; segment cuts occupy [0..ring_len), last entry unused.
+            if old_cuts.len() == ring_len + 1 {
+                seg_cuts.copy_from_slice(&old_cuts[..ring_len]);
+            }
+        } else {
+            // No explicit closing vertex: only segments along windows(2) exist.
+            // Preserve those, and leave the closing edge as default.
+            if old_cuts.len() == ring_len {
+                for i in 0..ring_len.saturating_sub(1) {
+                    seg_cuts[i] = old_cuts[i];
+                }
+            }
+        }
+
+        let mut best_i = 0usize;
+        let mut best_d = dist2_xy(curr, &ring_pts[0]);
+        for (i, p) in ring_pts.iter().enumerate().skip(1) {
+            let d = dist2_xy(curr, p);
+            if d < best_d {
+                best_d = d;
+                best_i = i;
+            }
+        }
+        if best_i != 0 {
+            ring_pts.rotate_left(best_i);
+            seg_cuts.rotate_left(best_i);
+        }
+
+        // Re-close the loop explicitly.
+        let first = ring_pts[0];
+        ring_pts.push(first);
+
+        let mut new_cuts: Vec<CutPixels> = vec![CutPixels::default(); ring_pts.len()];
+        for i in 0..seg_cuts.len() {
+            new_cuts[i] = seg_cuts[i];
+        }
+        tp.points = ring_pts;
+        tp.cuts = new_cuts;
+    }
+
+    fn order_toolpaths_for_node(
+        mut tps: Vec<ToolPath>,
+        curr: &mut IV3,
+        cost: &dyn OrderCost,
+    ) -> Vec<ToolPath> {
+        // Top-down within the node.
+        tps.sort_by_key(|tp| std::cmp::Reverse(tp.points.first().map(|p| p.z).unwrap_or(0)));
+
+        let mut out: Vec<ToolPath> = Vec::with_capacity(tps.len());
+        // Raster clearing marks its plunge run (see `choose_plunge_point`): if there is one at
+        // the top Z, keep it as the node's entry point, as is.
+        let top_z = tps.first().and_then(|tp| tp.points.first()).map(|p| p.z);
+        let plunge_run_i = tps
+            .iter()
+            .position(|tp| tp.is_plunge_run && tp.points.first().map(|p| p.z) == top_z);
+        if let Some(plunge_run_i) = plunge_run_i {
+            let tp = tps.remove(plunge_run_i);
+            if let Some(last) = tp.points.last().copied() {
+                *curr = last;
+            }
+            out.push(tp);
+        }
+        while !tps.is_empty() {
+            let mut best_i = 0usize;
+            let mut best_cost = f64::INFINITY;
+            let mut best_key: (i32, u8, i32, i32, usize) = (0, 0, 0, 0, 0);
+
+            for (i, tp) in tps.iter().enumerate() {
+                let start = tp.points.first().unwrap_or(&IV3 { x: 0, y: 0, z: 0 });
+                let c = cost.cost(curr, tp);
+                let z = start.z;
+                let closed_key = if tp.closed { 0u8 } else { 1u8 };
+                let key = (-z, closed_key, start.y, start.x, tp.points.len());
+                let better = match c.total_cmp(&best_cost) {
+                    std::cmp::Ordering::Less => true,
+                    std::cmp::Ordering::Equal => key < best_key,
+                    std::cmp::Ordering::Greater => false,
+                };
+                if i == 0 || better {
+                    best_cost = c;
+                    best_key = key;
+                    best_i = i;
+                }
+            }
+
+            // `remove` (not `swap_remove`) so full ties keep falling back to input order.
+            let mut tp = tps.remove(best_i);
+            if tp.closed {
+                roll_closed_to_nearest(&mut tp, curr);
+            } else {
+                choose_open_orientation(&mut tp, curr);
+            }
+
+            if let Some(last) = tp.points.last().copied() {
+                *curr = last;
+            }
+            out.push(tp);
+        }
+        out
+    }
+
+    // Bucket toolpaths by node id.
+    let mut per_node: Vec<Vec<ToolPath>> = vec![Vec::new(); region_root.get_n_nodes().max(1)];
+    for tp in toolpaths.drain(..) {
+        if tp.tree_node_id < per_node.len() {
+            per_node[tp.tree_node_id].push(tp);
+        } else {
+            // Unknown node id, keep it in a trailing bucket.
+            per_node[0].push(tp);
+        }
+    }
+
+    let node_order = build_node_visit_order(region_root);
+    let mut curr = IV3 { x: 0, y: 0, z: 0 };
+    for node_id in node_order {
+        if node_id >= per_node.len() {
+            continue;
+        }
+        let bucket = std::mem::take(&mut per_node[node_id]);
+        let ordered = order_toolpaths_for_node(bucket, &mut curr, cost);
+        toolpaths.extend(ordered);
+    }
+
+    // Append anything not reached by the traversal (should be rare).
+    for bucket in per_node.into_iter() {
+        toolpaths.extend(bucket);
+    }
+    debug_validate_toolpaths(toolpaths, "sort_toolpaths", true);
+}
+
+/// Assemble a multi-tool job: one group per entry of `passes`, in the given pass order
+/// (rough, then refine, then detail), each ordered with `sort_toolpaths` by `cost` against
+/// that pass's own region tree.
+/// `write_gcode` emits a tool change wherever `tool_i` changes between groups.
+///
+/// `passes` pairs each pass's `tool_i` with the tree its toolpaths' `tree_node_id`s refer to;
+/// a `tool_i` may appear only once, and every toolpath's `tool_i` must be listed. Within a
+/// group the node order stays region-tree DFS order, so a band never cuts before the band
+/// above it.
+pub fn group_by_tool_then_order(
+    toolpaths: Vec<ToolPath>,
+    passes: &[(usize, &RegionRoot)],
+    cost: &dyn OrderCost,
+) -> Vec<ToolPath> {
+    let mut groups: Vec<Vec<ToolPath>> = vec![Vec::new(); passes.len()];
+    for tp in toolpaths {
+        let pass_i = passes
+            .iter()
+            .position(|&(tool_i, _)| tool_i == tp.tool_i)
+            .unwrap_or_else(|| panic!("tool_i {} is not in any pass", tp.tool_i));
+        groups[pass_i].push(tp);
+    }
+
+    let mut out: Vec<ToolPath> = Vec::with_capacity(groups.iter().map(Vec::len).sum());
+    for (pass_i, (mut group, &(tool_i, region_root))) in groups.into_iter().zip(passes).enumerate()
+    {
+        assert!(
+            passes[..pass_i]
+                .iter()
+                .all(|&(earlier, _)| earlier != tool_i),
+            "tool_i {tool_i} is listed in more than one pass"
+        );
+        sort_toolpaths(&mut group, region_root, cost);
+        debug_assert!(
+            {
+                let mut rank_by_node_id: Vec<usize> = vec![usize::MAX; region_root.get_n_nodes()];
+                for (rank, node) in region_root.iter_dfs().enumerate() {
+                    rank_by_node_id[node.get_id()] = rank;
+                }
+                group
+                    .iter()
+                    .map(|tp| {
+                        rank_by_node_id
+                            .get(tp.tree_node_id)
+                            .copied()
+                            .unwrap_or(usize::MAX)
+                    })
+                    .is_sorted()
+            },
+            "tool {tool_i} toolpaths are out of region-tree order"
+        );
+        out.append(&mut group);
+    }
+    out
+}
+
+pub fn cull_empty_toolpaths(toolpaths: &mut Vec<ToolPath>) {
+    if toolpaths.is_empty() {
+        return;
+    }
+
+    fn build_open_toolpath_from_segments(
+        points: Vec<IV3>,
+        seg_cuts: Vec<CutPixels>,
+        tool_dia_pix: usize,
+        tool_i: usize,
+        tile_i: usize,
+        tree_node_id: usize,
+        is_traverse: bool,
+        is_raster: bool,
+    ) -> ToolPath {
+        debug_assert!(points.len() >= 2);
+        debug_assert_eq!(seg_cuts.len(), points.len().saturating_sub(1));
+
+        let mut cuts = vec![CutPixels::default(); points.len()];
+        for (i, c) in seg_cuts.into_iter().enumerate() {
+            if i < cuts.len() {
+                cuts[i] = c;
+            }
+        }
+        if let Some(last) = cuts.last_mut() {
+            *last = CutPixels::default();
+        }
+
+        ToolPath {
+            points,
+            closed: false,
+            tool_dia_pix,
+            tool_i,
+            tile_i,
+            tree_node_id,
+            cuts,
+            is_traverse,
+            is_raster,
+            is_plunge_run: false,
+        }
+    }
+
+    fn cull_open_toolpath(
+        points: Vec<IV3>,
+        cuts_in: Vec<CutPixels>,
+        tool_dia_pix: usize,
+        tool_i: usize,
+        tile_i: usize,
+        tree_node_id: usize,
+        is_traverse: bool,
+        is_raster: bool,
+    ) -> Vec<ToolPath> {
+        if points.len() < 2 {
+            return Vec::new();
+        }
+
+        let seg_n = points.len() - 1;
+        let mut out: Vec<ToolPath> = Vec::new();
+
+        let mut run_points: Vec<IV3> = Vec::new();
+        let mut run_cuts: Vec<CutPixels> = Vec::new();
+
+        for seg_i in 0..seg_n {
+            let cut = cuts_in.get(seg_i).copied().unwrap_or_default();
+            let keep = cut.pixels_changed > 0;
+
+            if keep {
+                if run_points.is_empty() {
+                    run_points.push(points[seg_i]);
+                }
+                run_points.push(points[seg_i + 1]);
+                run_cuts.push(cut);
+            } else if !run_points.is_empty() {
+                out.push(build_open_toolpath_from_segments(
+                    std::mem::take(&mut run_points),
+                    std::mem::take(&mut run_cuts),
+                    tool_dia_pix,
+                    tool_i,
+                    tile_i,
+                    tree_node_id,
+                    is_traverse,
+                    is_raster,
+                ));
+            }
+        }
+
+        if !run_points.is_empty() {
+            out.push(build_open_toolpath_from_segments(
+                run_points,
+                run_cuts,
+                tool_dia_pix,
+                tool_i,
+                tile_i,
+                tree_node_id,
+                is_traverse,
+                is_raster,
+            ));
+        }
+
+        out
+    }
+
+    fn cull_closed_toolpath(
+        mut points: Vec<IV3>,
+        mut cuts_in: Vec<CutPixels>,
+        tool_dia_pix: usize,
+        tool_i: usize,
+        tile_i: usize,
+        tree_node_id: usize,
+        is_traverse: bool,
+        is_raster: bool,
+    ) -> Vec<ToolPath> {
+        // Normalize to an explicitly closed loop (duplicate first point at the end)
+        // with `cuts.len() == points.len()`.
+        if points.len() < 3 {
+            return Vec::new();
+        }
+
+        let has_dup = points.first() == points.last();
+        if !has_dup {
+            let first = points[0];
+            points.push(first);
+
+            // Best-effort carry over existing per-segment cuts; the closing edge gets default.
+            let mut new_cuts = vec![CutPixels::default(); points.len()];
+            for i in 0..points.len().saturating_sub(2) {
+                if i < cuts_in.len() {
+                    new_cuts[i] = cuts_in[i];
+                }
+            }
+            cuts_in = new_cuts;
+        } else if cuts_in.len() != points.len() {
+            // If we don't have a parallel cut array, we can't safely cull segments.
+            let n = points.len();
+            return vec![ToolPath {
+                points,
+                closed: true,
+                tool_dia_pix,
+                tool_i,
+                tile_i,
+                tree_node_id,
+                cuts: vec![CutPixels::default(); n],
+                is_traverse,
+                is_raster,
+                is_plunge_run: false,
+            }];
+        }
+
+        if points.len() < 4 {
+            // Smallest explicit-closure loop is 3 vertices + duplicate.
+            return Vec::new();
+        }
+
+        let ring_len = points.len() - 1; // number of ring vertices; number of ring segments
+        let seg_count = ring_len;
+
+        let mut keep: Vec<bool> = Vec::with_capacity(seg_count);
+        for i in 0..seg_count {
+            let cut = cuts_in.get(i).copied().unwrap_or_default();
+            keep.push(cut.pixels_changed > 0);
+        }
+
+        if keep.iter().all(|&k| k) {
+            // Keep as a closed loop.
+            if let Some(last) = cuts_in.last_mut() {
+                *last = CutPixels::default();
+            }
+            return vec![ToolPath {
+                points,
+                closed: true,
+                tool_dia_pix,
+                tool_i,
+                tile_i,
+                tree_node_id,
+                cuts: cuts_in,
+                is_traverse,
+                is_raster,
+                is_plunge_run: false,
+            }];
+        }
+
+        if keep.iter().all(|&k| !k) {
+            return Vec::new();
+        }
+
+        // Linearize the circular segments by starting immediately after a removed segment.
+        let first_false = keep.iter().position(|&k| !k).unwrap_or(0);
+        let mut idx = (first_false + 1) % seg_count;
+
+        let mut out: Vec<ToolPath> = Vec::new();
+        let mut run_points: Vec<IV3> = Vec::new();
+        let mut run_cuts: Vec<CutPixels> = Vec::new();
+
+        for _ in 0..seg_count {
+            if keep[idx] {
+                if run_points.is_empty() {
+                    run_points.push(points[idx]);
+                }
+                run_points.push(points[idx + 1]);
+                run_cuts.push(cuts_in[idx]);
+            } else if !run_points.is_empty() {
+                out.push(build_open_toolpath_from_segments(
+                    std::mem::take(&mut run_points),
+                    std::mem::take(&mut run_cuts),
+                    tool_dia_pix,
+                    tool_i,
+                    tile_i,
+                    tree_node_id,
+                    is_traverse,
+                    is_raster,
+                ));
+            }
+
+            idx = (idx + 1) % seg_count;
+        }
+
+        if !run_points.is_empty() {
+            out.push(build_open_toolpath_from_segments(
+                run_points,
+                run_cuts,
+                tool_dia_pix,
+                tool_i,
+                tile_i,
+                tree_node_id,
+                is_traverse,
+                is_raster,
+            ));
+        }
+
+        out
+    }
+
+    let mut out: Vec<ToolPath> = Vec::with_capacity(toolpaths.len());
+    for tp in toolpaths.drain(..) {
+        let ToolPath {
+            points,
+            closed,
+            tool_dia_pix,
+            tool_i,
+            tile_i,
+            tree_node_id,
+            cuts,
+            is_traverse,
+            is_raster,
+            is_plunge_run,
+        } = tp;
+
+        if points.len() < 2 {
+            continue;
+        }
+
+        // If the cut annotations aren't parallel, assume we can't make an informed decision.
+        // (This typically means `sim_toolpaths` wasn't run.)
+        if cuts.len() != points.len() {
+            out.push(ToolPath {
+                points,
+                closed,
+                tool_dia_pix,
+                tool_i,
+                tile_i,
+                tree_node_id,
+                cuts,
+                is_traverse,
+                is_raster,
+                is_plunge_run,
+            });
+            continue;
+        }
+
+        if closed {
+            out.extend(cull_closed_toolpath(
+                points,
+                cuts,
+                tool_dia_pix,
+                tool_i,
+                tile_i,
+                tree_node_id,
+                is_traverse,
+                is_raster,
+            ));
+        } else {
+            out.extend(cull_open_toolpath(
+                points,
+                cuts,
+                tool_dia_pix,
+                tool_i,
+                tile_i,
+                tree_node_id,
+                is_traverse,
+                is_raster,
+            ));
+        }
+    }
+
+    *toolpaths = out;
+    debug_validate_toolpaths(toolpaths, "cull_empty_toolpaths", false);
+}
+
+/// Per-band summary produced by `band_report`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BandStat {
+    pub band_i: usize,
+    pub top_thou: Thou,
+    pub bot_thou: Thou,
+    pub n_cut_leaves: usize,
+    /// Sum of the region sizes (pixels) of the band's cut leaves.
+    pub region_area_pix: usize,
+    pub n_toolpaths: usize,
+    /// Total XY length (pixels) of the band's cutting toolpaths.
+    pub cut_len_pix: f64,
+}
+
+/// Aggregate per-band statistics from a planned job (read-only).
+/// Toolpaths are joined to bands via `tree_node_id` -> node -> `band_i`;
+/// traverses and toolpaths whose node id is unknown are ignored.
+pub fn band_report(
+    region_root: &RegionRoot,
+    cut_bands: &[CutBand],
+    region_infos: &[LabelInfo],
+    toolpaths: &[ToolPath],
+) -> Vec<BandStat> {
+    let mut stats: Vec<BandStat> = cut_bands
+        .iter()
+        .enumerate()
+        .map(|(band_i, band)| BandStat {
+            band_i,
+            top_thou: band.top_thou,
+            bot_thou: band.bot_thou,
+            n_cut_leaves: 0,
+            region_area_pix: 0,
+            n_toolpaths: 0,
+            cut_len_pix: 0.0,
+        })
+        .collect();
+
+    for node in region_root.iter_cut_leaves() {
+        let RegionNode::Cut {
+            band_i, region_i, ..
+        } = node
+        else {
+            continue;
+        };
+        let Some(stat) = stats.get_mut(*band_i) else {
+            continue;
+        };
+        stat.n_cut_leaves += 1;
+        stat.region_area_pix += region_infos
+            .get(region_i.0 as usize)
+            .map(|ri| ri.size)
+            .unwrap_or(0);
+    }
+
+    for tp in toolpaths.iter().filter(|tp| !tp.is_traverse) {
+        let Some(node) = region_root.node_by_id(tp.tree_node_id) else {
+            continue;
+        };
+        let Some(stat) = stats.get_mut(node.band_i()) else {
+            continue;
+        };
+        stat.n_toolpaths += 1;
+        for seg in tp.points.windows(2) {
+            let dx = (seg[1].x - seg[0].x) as f64;
+            let dy = (seg[1].y - seg[0].y) as f64;
+            stat.cut_len_pix += (dx * dx + dy * dy).sqrt();
+        }
+    }
+
+    stats
+}
+
+/// Inclusive (min, max) corners over every point of every toolpath, per axis.
+/// Returns None when there are no points.
+pub fn toolpaths_bbox(toolpaths: &[ToolPath]) -> Option<(IV3, IV3)> {
+    toolpath_points_bbox(toolpaths.iter())
+}
+
+/// `toolpaths_bbox` restricted to the toolpaths of one tool.
+pub fn toolpaths_bbox_for_tool(toolpaths: &[ToolPath], tool_i: usize) -> Option<(IV3, IV3)> {
+    toolpath_points_bbox(toolpaths.iter().filter(|tp| tp.tool_i == tool_i))
+}
+
+fn toolpath_points_bbox<'a>(toolpaths: impl Iterator<Item = &'a ToolPath>) -> Option<(IV3, IV3)> {
+    let mut points = toolpaths.flat_map(|tp| tp.points.iter());
+    let first = *points.next()?;
+    Some(points.fold((first, first), |(min, max), p| {
+        (
+            IV3 {
+                x: min.x.min(p.x),
+                y: min.y.min(p.y),
+                z: min.z.min(p.z),
+            },
+            IV3 {
+                x: max.x.max(p.x),
+                y: max.y.max(p.y),
+                z: max.z.max(p.z),
+            },
+        )
+    }))
+}
+
+/// Shift all toolpaths in XY so that the min corner of their points lands at (0,0).
+/// This lets each part's toolpaths be expressed relative to its own origin before placement.
+/// Returns the offset that was applied (-min); subtract it from every point to get back the originals.
+/// Z is untouched and the returned z is always 0. Empty input returns a zero offset.
+pub fn recenter_to_origin(toolpaths: &mut [ToolPath]) -> IV3 {
+    let Some((min, _max)) = toolpaths_bbox(toolpaths) else {
+        return IV3 { x: 0, y: 0, z: 0 };
+    };
+
+    let offset = IV3 {
+        x: -min.x,
+        y: -min.y,
+        z: 0,
+    };
+    if offset.x == 0 && offset.y == 0 {
+        return offset;
+    }
+
+    for tp in toolpaths.iter_mut() {
+        for p in tp.points.iter_mut() {
+            p.x += offset.x;
+            p.y += offset.y;
+        }
+    }
+
+    offset
+}
+
+/// How `insert_retracts` lifts the tool between toolpaths that don't join up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RetractPolicy {
+    /// Leave the toolpaths as they are.
+    #[default]
+    None,
+    /// Retract to this fixed Z for every move, or to the higher endpoint if that is above it.
+    GlobalSafeZ(Thou),
+    /// Retract to `clearance_thou` above the highest pixel under the tool along the move
+    /// (see `sim::scan_toolpath_segment_max_u16`), never below either endpoint.
+    LocalScan(Thou),
+}
+
+/// The Z an air move from `from` to `to` must retract to: `clearance_thou` above the highest
+/// pixel of `heights` under a tool of `radius_pix` along the move, and never below either
+/// endpoint. `circle_pix` is `sim::circle_pixel_iz(radius_pix, heights.s)`.
+fn retract_z(
+    heights: &Lum16Im,
+    from: IV3,
+    to: IV3,
+    radius_pix: usize,
+    circle_pix: &[isize],
+    clearance_thou: Thou,
+) -> i32 {
+    let max_z =
+        crate::sim::scan_toolpath_segment_max_u16(heights, from, to, radius_pix, circle_pix);
+    (max_z as i32 + clearance_thou.0).max(from.z).max(to.z)
+}
+
+/// Insert a traverse toolpath between every pair of consecutive cutting toolpaths where the
+/// first doesn't end where the second starts. Each traverse is a Z-only retract, a move at
+/// the safe Z and a Z-only plunge, so the sim treats the Z moves as non-cutting.
+/// `heights` is the surface `RetractPolicy::LocalScan` scans and is required for it.
+/// Existing traverses are kept and are never bridged.
+pub fn insert_retracts(
+    toolpaths: &mut Vec<ToolPath>,
+    heights: Option<&Lum16Im>,
+    policy: RetractPolicy,
+) {
+    if policy == RetractPolicy::None || toolpaths.len() < 2 {
+        return;
+    }
+    let heights = match policy {
+        RetractPolicy::LocalScan(_) => {
+            Some(heights.expect("RetractPolicy::LocalScan needs the heights to scan"))
+        }
+        _ => None,
+    };
+    let mut circle_lut_by_radius: HashMap<usize, Vec<isize>> = HashMap::new();
+
+    let mut out: Vec<ToolPath> = Vec::with_capacity(toolpaths.len() * 2);
+    let mut prev: Option<ToolPath> = None;
+    for next in toolpaths.drain(..) {
+        if let Some(prev) = prev.take() {
+            let ends = prev
+                .points
+                .last()
+                .copied()
+                .zip(next.points.first().copied());
+            let traverse = match ends {
+                Some((from, to)) if !prev.is_traverse && !next.is_traverse && from != to => {
+                    let safe_z = match policy {
+                        RetractPolicy::None => unreachable!(),
+                        RetractPolicy::GlobalSafeZ(safe_z) => safe_z.0.max(from.z).max(to.z),
+                        RetractPolicy::LocalScan(clearance_thou) => {
+                            let heights = heights.unwrap();
+                            let radius_pix = prev.tool_dia_pix / 2;
+                            let circle_pix =
+                                circle_lut_by_radius.entry(radius_pix).or_insert_with(|| {
+                                    crate::sim::circle_pixel_iz(radius_pix, heights.s)
+                                });
+                            retract_z(heights, from, to, radius_pix, circle_pix, clearance_thou)
+                        }
+                    };
+                    let points = vec![from, IV3 { z: safe_z, ..from }, IV3 { z: safe_z, ..to }, to];
+                    Some(ToolPath {
+                        cuts: vec![CutPixels::default(); points.len()],
+                        points,
+                        closed: false,
+                        tool_dia_pix: prev.tool_dia_pix,
+                        tool_i: prev.tool_i,
+                        tile_i: next.tile_i,
+                        tree_node_id: next.tree_node_id,
+                        is_traverse: true,
+                        is_raster: false,
+                        is_plunge_run: false,
+                    })
+                }
+                _ => None,
+            };
+            out.push(prev);
+            out.extend(traverse);
+        }
+        prev = Some(next);
+    }
+    out.extend(prev);
+    *toolpaths = out;
+}
+
+///
+/// Insert explicit "traverse"" (air-move) toolpaths between consecutive cutting toolpaths
+/// Args:
+///   - befpre_sim_im: A Lum16Im that contains the starting state for simulation (all pixels are full height)
+///   - toolpaths: A borrowed vector of ToolPath structs representing the cutting toolpaths. Must all be of the same tool
+///     Must be mutable because simulation modifies the the tool paths by setting the cut pixels
+pub fn add_traverse_toolpaths_one_tool<'a>(
+    before_sim_im: &mut crate::im::Lum16Im,
+    toolpaths: &'a mut Vec<ToolPath>,
+    tool_i: usize,
+    tool_dia_pix: usize,
+) -> Vec<ToolPath> {
+    // NOTE: This is a prototype helper. Callers may pass a mixed-tool `toolpaths` list
+    // (as `main.rs` currently does). We therefore only *process* segments belonging to
+    // `tool_i`, and we only *return* references for those toolpaths.
+    //
+    // We must not borrow `toolpaths` inside the sim callback because `sim_toolpaths`
+    // holds a mutable borrow of the slice for the duration of simulation.
+    let tool_meta: Vec<(usize, usize, usize, usize)> = toolpaths
+        .iter()
+        .map(|tp| (tp.tool_i, tp.tool_dia_pix, tp.points.len(), tp.tile_i))
+        .collect();
+
+    let n_toolpaths = toolpaths.len();
+
+    let mut traverse_paths: Vec<ToolPath> = Vec::with_capacity(n_toolpaths);
+
+    let tool_radius_pix: usize = tool_dia_pix / 2;
+    let circle_pix = crate::sim::circle_pixel_iz(tool_radius_pix, before_sim_im.s);
+
+    // Precompute toolpath endpoints so the sim callback doesn't borrow `toolpaths`.
+    // (The simulator mutates cut pixels, not the points themselves.)
+    let toolpath_starts: Vec<Option<IV3>> = toolpaths
+        .iter()
+        .map(|tp| tp.points.first().copied())
+        .collect();
+    let toolpath_ends: Vec<Option<IV3>> = toolpaths
+        .iter()
+        .map(|tp| tp.points.last().copied())
+        .collect();
+    let toolpath_is_rasters: Vec<bool> = toolpaths
+        .iter()
+        .map(|tp| tp.is_raster)
+        .collect();
+
+    let mut callback = |im: &crate::im::Lum16Im,
+                        toolpath_i: usize,
+                        seg_i: usize,
+                        _p0: IV3,
+                        p1: IV3,
+                        _seg_cut: CutPixels| {
+        let Some(&(tp_tool_i, tp_tool_dia_pix, tp_points_len, tp_tile_i)) = tool_meta.get(toolpath_i) else {
+            return ControlFlow::Continue(());
+        };
+
+        if tp_tool_i != tool_i {
+            return ControlFlow::Continue(());
+        }
+
+        let last_seg_i = tp_points_len.saturating_sub(2);
+        if seg_i != last_seg_i {
+            return ControlFlow::Continue(());
+        }
+
+        // The end point of the last segment is the toolpath's final point.
+        debug_assert_eq!(toolpath_ends.get(toolpath_i).copied().flatten(), Some(p1));
+
+        // Tool diameter must match. 
+        debug_assert_eq!(
+            tp_tool_dia_pix, tool_dia_pix,
+            "Tool diameter mismatch for tool_i={tool_i}"
+        );
+
+        // If we're transitioning between two raster toolpaths, each raster toolpath is a single
+        // segment. If the segments overlap in X and are within one tool diameter in Y, we can
+        // generate a special traverse polyline (e.g. backtrack) instead of retracting.
+        let max_distance_to_backtrack_pix = tool_dia_pix as u32 * 2;
+
+        let mut traverse_verts_opt: Option<Vec<IV3>> = None;
+
+        if toolpath_i < n_toolpaths - 1 && toolpath_is_rasters[toolpath_i] && toolpath_is_rasters[toolpath_i + 1] {
+            if let (Some(a0), Some(a1), Some(b0), Some(b1)) = (
+                toolpath_starts.get(toolpath_i).copied().flatten(),
+                toolpath_ends.get(toolpath_i).copied().flatten(),
+                toolpath_starts.get(toolpath_i + 1).copied().flatten(),
+                toolpath_ends.get(toolpath_i + 1).copied().flatten(),
+            ) {
+                debug_assert!(a0.y == a1.y && b0.y == b1.y, "Raster toolpaths should be horizontal");
+                debug_assert!(a0.z == a1.z && b0.z == b1.z, "Raster toolpaths should be constant-Z");
+
+                if a1.z == b0.z {
+                    let lft = a0.x.min(a1.x); 
+                    let rgt = a0.x.max(a1.x);
+                    let delta_x = b0.x.abs_diff(a1.x);
+
+                    if lft <= b0.x && b0.x <= rgt && delta_x <= max_distance_to_backtrack_pix {
+                        traverse_verts_opt = Some(vec![
+                            IV3 {
+                                x: b0.x,
+                                y: a1.y,
+                                z: a1.z,
+                            },
+                            IV3 {
+                                x: b0.x,
+                                y: b0.y,
+                                z: b0.z,
+                            },
+                        ]);
+                    }
+                }
+            }
+        }
+
+        traverse_verts_opt = traverse_verts_opt.or_else(|| {
+            // Raster backtrack was not possible so we need a full retract-traverse-plunge.
+            let from_vert: IV3 = p1;
+            let to_vert_opt: Option<IV3> = toolpath_starts.get(toolpath_i + 1).copied().flatten();
+            let Some(to_vert) = to_vert_opt else {
+                return Some(Vec::new());
+            };
+
+            // The minimum safe Z to traverse between from_vert and to_vert.
+            let safe_z_i32 = retract_z(
+                im,
+                from_vert,
+                to_vert,
+                tool_radius_pix,
                 &circle_pix,
-            ) as i32;
+                Thou(0),
+            );
+
+            Some(vec![
+                IV3 {
+                    x: from_vert.x,
+                    y: from_vert.y,
+                    z: from_vert.z,
+                },
+                IV3 {
+                    x: from_vert.x,
+                    y: from_vert.y,
+                    z: safe_z_i32,
+                },
+                IV3 {
+                    x: to_vert.x,
+                    y: to_vert.y,
+                    z: to_vert.z,
+                },
+            ])
+
+        });
+
+        let traverse_verts = traverse_verts_opt.unwrap_or_default();
+
+        let n_verts = traverse_verts.len();
+
+        traverse_paths.push(ToolPath {
+            points: traverse_verts,
+            closed: false,
+            tool_dia_pix,
+            tool_i,
+            tile_i: tp_tile_i,
+            tree_node_id: toolpath_i, // Encode the source toolpath index
+            cuts: vec![CutPixels::default(); n_verts],
+            is_traverse: true,
+            is_raster: false,
+            is_plunge_run: false,
+        });
+        ControlFlow::Continue(())
+    };
+
+    let _ = crate::sim::sim_toolpaths(
+        before_sim_im,
+        &mut toolpaths[..],
+        Some(&mut callback),
+        None,
+        crate::sim::SimOptions::default(),
+    );
+
+    traverse_paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desc::{BandDesc, CompDesc, Guid, parse_comp_json};
+    use crate::im::label::label_im;
+    use crate::region_tree::{create_cut_bands, create_region_tree};
+    use crate::test_helpers::{
+        im_u16_to_ascii, mask_to_ascii, plan_fixture, plan_toolpaths, ply_im_from_ascii,
+        stub_band_desc, stub_ply_desc, toolpaths_to_ascii,
+    };
+
+    #[test]
+    fn surface_tool_path_generation_smoke_test() {
+        // Build a non-trivial region tree (must contain Cut leaves) and ensure
+        // toolpath generation runs without panicking.
+
+        let ply_im = ply_im_from_ascii(
+            r#"
+                11111
+                12221
+                12321
+                12221
+                11111
+            "#,
+        );
+
+        // Dummy + 3 real plies (values 1,2,3). We only need enough info to build cut bands/tree.
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+            stub_ply_desc("ply200", 200, false),
+            stub_ply_desc("ply300", 300, false),
+        ];
+
+        let band_descs = vec![stub_band_desc(400, 0, "rough")];
+
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+        let region_im: RegionIm = region_im_raw.retag::<crate::region_tree::RegionI>();
+
+        let cut_bands = create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+
+        let region_root = create_region_tree(&cut_bands, &region_infos, 0);
+        let total_cut_leaves: usize = region_root.iter_cut_leaves().count();
+        assert!(total_cut_leaves > 0, "test setup must produce cut leaves");
+
+        let tool_dia_pix = 2_usize;
+        let tool_step_pix = 1_usize;
+        let paths = create_toolpaths_from_region_tree(
+            &region_root,
+            &cut_bands,
+            &ply_im,
+            &region_im,
+            &region_infos,
+            &RegionToolpathOpts {
+                tool_dia_pix,
+                stepover: Stepover::Pix(tool_step_pix),
+                n_perimeters: 0,
+                ..Default::default()
+            },
+            None,
+        );
+
+        assert!(!paths.is_empty(), "expected non-empty raster toolpaths");
+        assert!(
+            paths.iter().all(|p| p.points.len() >= 2),
+            "each toolpath should have at least a start and end point"
+        );
+        assert!(
+            paths
+                .iter()
+                .all(|p| p.points.iter().all(|pt| matches!(pt.z, 100 | 200 | 300))),
+            "surface raster z should come from cut plane top_thou"
+        );
+    }
+
+    #[test]
+    fn raster_surface_toolpaths_basic_runs() {
+        let mut m = MaskIm::new(6, 3);
+
+        // y=0: ..###.
+        for x in 2..5 {
+            m.arr[0 * m.s + x] = 255;
+        }
+
+        // y=1: #.#..#
+        m.arr[1 * m.s + 0] = 255;
+        m.arr[1 * m.s + 2] = 255;
+        m.arr[1 * m.s + 5] = 255;
+
+        // y=2: (empty)
+
+        let roi = ROI {
+            l: 0,
+            t: 0,
+            r: 6,
+            b: 3,
+        };
+        let paths = create_raster_surface_tool_paths_from_cut_mask(&m, &roi, 0, 1, 1, Thou(123), 0);
+
+        // Expect 1 run on y=0 and 3 runs on y=1.
+        assert_eq!(paths.len(), 4);
+
+        assert_eq!(paths[0].points[0], IV3 { x: 2, y: 0, z: 123 });
+        assert_eq!(paths[0].points[1], IV3 { x: 4, y: 0, z: 123 });
+
+        assert_eq!(paths[1].points[0], IV3 { x: 0, y: 1, z: 123 });
+        assert_eq!(paths[1].points[1], IV3 { x: 0, y: 1, z: 123 });
+
+        assert_eq!(paths[2].points[0], IV3 { x: 2, y: 1, z: 123 });
+        assert_eq!(paths[2].points[1], IV3 { x: 2, y: 1, z: 123 });
+
+        assert_eq!(paths[3].points[0], IV3 { x: 5, y: 1, z: 123 });
+        assert_eq!(paths[3].points[1], IV3 { x: 5, y: 1, z: 123 });
+    }
+
+    #[test]
+    fn choose_plunge_point_picks_the_middle_of_a_disc() {
+        // A disc of radius 9 centered at (14, 12), against the left of a wider ROI.
+        let mut m = MaskIm::new(40, 30);
+        for y in 0..m.h {
+            for x in 0..m.w {
+                let (dx, dy) = (x as i32 - 14, y as i32 - 12);
+                if dx * dx + dy * dy <= 81 {
+                    m.arr[y * m.s + x] = 255;
+                }
+            }
+        }
+        let roi = ROI {
+            l: 2,
+            t: 1,
+            r: 38,
+            b: 28,
+        };
+        let (x, y) = choose_plunge_point(&m, &roi).unwrap();
+        assert!(x.abs_diff(14) <= 1 && y.abs_diff(12) <= 1, "({x}, {y})");
+
+        // Nothing set in the ROI.
+        let empty_roi = ROI {
+            l: 30,
+            t: 0,
+            r: 40,
+            b: 30,
+        };
+        assert_eq!(choose_plunge_point(&m, &empty_roi), None);
+
+        // Raster clearing starts there instead of at the top row's left end.
+        let paths = create_raster_surface_tool_paths_from_cut_mask(&m, &roi, 0, 1, 1, Thou(50), 0);
+        let start = paths[0].points[0];
+        assert_eq!((start.x, start.y), (x as i32, y as i32));
+        // That run goes to its left end and then all the way across.
+        assert_eq!(paths[0].points.len(), 3);
+        assert_eq!(paths[0].cuts.len(), 3);
+        assert!(paths[0].points[1].x < start.x && paths[0].points[2].x > start.x);
+        // Still one run per row of the disc (y = 3..=21).
+        assert_eq!(paths.len(), 19);
+    }
+
+    #[test]
+    fn surface_tool_path_generation_dump_better_image() {
+        let ply_im = ply_im_from_ascii(
+            r#"
+                111111111111111111111111111111111111111111
+                111111111111111111111111111111111111111111
+                111111111111111111111111111111111111111111
+                111111144444411111111111111111111111111111
+                111111144444433333333333333333331111111111
+                111111144444433333333333333333331111111111
+                111111111333333333333333333333331111111111
+                111111111333222222222222222233331111111111
+                111111111333222211111112222233331111111111
+                111111111333222211111112222233331111111111
+                111111111333222222222222222233331111111111
+                111111111333333333333333333333331111111111
+                111111111333333333333333333333331111111111
+                111111111111111111111111111111111111111111
+                111111111111111111111111111111111111111111
+                111111111111111111111111111111111111111111
+            "#,
+        );
+
+        // Dummy + 4 real plies (values 1..4).
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+            stub_ply_desc("ply200", 200, false),
+            stub_ply_desc("ply300", 300, false),
+            stub_ply_desc("ply400", 400, false),
+        ];
+        let band_descs = vec![
+            stub_band_desc(500, 350, "rough"),
+            stub_band_desc(350, 0, "rough"),
+        ];
+
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+        let region_im: RegionIm = region_im_raw.retag::<crate::region_tree::RegionI>();
+
+        let cut_bands = create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+        let region_root = create_region_tree(&cut_bands, &region_infos, 0);
+
+        // print the z ranges of the cut bands
+        for (i, band) in cut_bands.iter().enumerate() {
+            let top = band.top_thou.0;
+            let bot = band.bot_thou.0;
+            println!("Cut band {i}: top_thou={}, bot_thou={}", top, bot);
+        }
+
+        let tool_dia_pix = 2_usize;
+        let tool_step_pix = 2_usize;
+
+        let mut node_results: Vec<(RegionNode, ROI, MaskIm, MaskIm, MaskIm)> = Vec::new();
+        let snapshot = |src: &MaskIm| {
+            let mut dst = MaskIm::new(src.w, src.h);
+            dst.arr.copy_from_slice(&src.arr);
+            dst
+        };
+        let mut on_region_masks = |node: &RegionNode,
+                                   roi_pad: &ROI,
+                                   cut_mask_im: &MaskIm,
+                                   above_mask_im: &MaskIm,
+                                   dil_abv_mask_im: &MaskIm,
+                                   _dil_cut_mask_im: &MaskIm| {
+            if matches!(node, RegionNode::Cut { .. }) {
+                node_results.push((
+                    node.clone(),
+                    *roi_pad,
+                    snapshot(cut_mask_im),
+                    snapshot(above_mask_im),
+                    snapshot(dil_abv_mask_im),
+                ));
+            }
+        };
+
+        // Primary call under test (should not panic).
+        let _paths = create_toolpaths_from_region_tree(
+            &region_root,
+            &cut_bands,
+            &ply_im,
+            &region_im,
+            &region_infos,
+            &RegionToolpathOpts {
+                tool_dia_pix,
+                stepover: Stepover::Pix(tool_step_pix),
+                n_perimeters: 0,
+                ..Default::default()
+            },
+            Some(&mut on_region_masks),
+        );
+
+        // Dump ascii maps for visual inspection.
+        println!("ply_im:\n{}", im_u16_to_ascii(&ply_im));
+        println!("region_im:\n{}", im_u16_to_ascii(&region_im));
+
+        assert_eq!(
+            node_results.len(),
+            region_root.iter_cut_leaves().count(),
+            "expected one callback per cut leaf"
+        );
+
+        for (i, (region_node, roi_pad, cut_m, above_m, dil_abv_m)) in
+            node_results.iter().enumerate()
+        {
+            // Derive the ply value for this cut leaf via the region label's start_x/start_y.
+            let (label_i, curr_ply_i) = match region_node {
+                RegionNode::Cut { region_i, .. } => {
+                    let label_i = region_i.0 as usize;
+                    if label_i == 0 || label_i >= region_infos.len() {
+                        (label_i, None)
+                    } else {
+                        let label_info = &region_infos[label_i];
+                        let start_i = label_info.start_y * ply_im.s + label_info.start_x;
+                        let curr_ply_v = ply_im.arr.get(start_i).copied();
+                        (label_i, curr_ply_v)
+                    }
+                }
+                _ => (0, None),
+            };
+
+            let mut at_mask = MaskIm::new(ply_im.w, ply_im.h);
+            if label_i != 0 && label_i < region_infos.len() {
+                let label_info = &region_infos[label_i];
+                for &pix_i in &label_info.pixel_iz {
+                    if pix_i < at_mask.arr.len() {
+                        at_mask.arr[pix_i] = 255;
+                    }
+                }
+            }
+
+            println!(
+                "--- Cut leaf {i} masks --------------------------------------------------------"
+            );
+            println!("cut[{i}] region_node: {:?}", region_node);
+            println!("cut[{i}] label_i: {label_i}, curr_ply_i: {:?}", curr_ply_i);
+            // Outline the padded ROI actually used for above-mask extraction.
+            let roi_opt = Some(roi_pad);
+
+            println!(
+                "cut[{i}] at_mask (label pixels):\n{}",
+                mask_to_ascii(&at_mask, None)
+            );
+            println!("cut[{i}] above_mask:\n{}", mask_to_ascii(above_m, roi_opt));
+            println!("cut[{i}] dil_abv_mask:\n{}", mask_to_ascii(dil_abv_m, None));
+            println!("cut[{i}] cut_mask:\n{}", mask_to_ascii(cut_m, None));
+        }
+
+        // Print the paths grouped by their exact Z value.
+        let mut paths_by_z: std::collections::BTreeMap<i32, Vec<ToolPath>> =
+            std::collections::BTreeMap::new();
+
+        for path in &_paths {
+            let z = path.points.first().map(|p| p.z).unwrap_or(0);
+            paths_by_z.entry(z).or_default().push(path.clone());
+        }
+
+        println!("--- Raster toolpaths by Z ---------------------------------------------------");
+
+        for (&z, z_paths) in paths_by_z.iter().rev() {
+            println!(
+                "paths (rasterized) z={}:\n{}",
+                z,
+                toolpaths_to_ascii(z_paths, ply_im.w, ply_im.h)
+            );
+        }
+    }
+
+    #[test]
+    fn break_long_toolpaths_does_not_drop_paths() {
+        let mut toolpaths = vec![
+            ToolPath {
+                points: vec![IV3 { x: 0, y: 0, z: 0 }, IV3 { x: 10, y: 0, z: 0 }],
+                closed: false,
+                tool_dia_pix: 1,
+                tool_i: 0,
+                tile_i: 0,
+                tree_node_id: 0,
+                cuts: vec![CutPixels::default(); 2],
+                is_traverse: false,
+                is_raster: false,
+                is_plunge_run: false,
+            },
+            ToolPath {
+                points: vec![IV3 { x: 5, y: 5, z: 0 }, IV3 { x: 6, y: 6, z: 0 }],
+                closed: false,
+                tool_dia_pix: 1,
+                tool_i: 0,
+                tile_i: 0,
+                tree_node_id: 0,
+                cuts: vec![CutPixels::default(); 2],
+                is_traverse: false,
+                is_raster: false,
+                is_plunge_run: false,
+            },
+        ];
+
+        break_long_toolpaths(&mut toolpaths, 1000);
+        assert_eq!(toolpaths.len(), 2);
+        assert!(toolpaths.iter().all(|tp| tp.points.len() >= 2));
+    }
+
+    #[test]
+    fn break_long_toolpaths_ignores_z_only_jumps() {
+        let mut toolpaths = vec![ToolPath {
+            points: vec![
+                IV3 { x: 0, y: 0, z: 0 },
+                IV3 {
+                    x: 0,
+                    y: 0,
+                    z: 10_000,
+                },
+            ],
+            closed: false,
+            tool_dia_pix: 1,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![CutPixels::default(); 2],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        }];
+
+        // Even though z jumps, XY distance is 0 so it should not be broken.
+        break_long_toolpaths(&mut toolpaths, 1);
+        assert_eq!(toolpaths.len(), 1);
+        assert_eq!(toolpaths[0].points.len(), 2);
+    }
+
+    #[test]
+    fn merge_collinear_joins_straight_runs_only() {
+        fn seg(a: (i32, i32), b: (i32, i32), pixels: u64) -> ToolPath {
+            ToolPath {
+                points: vec![
+                    IV3 {
+                        x: a.0,
+                        y: a.1,
+                        z: 0,
+                    },
+                    IV3 {
+                        x: b.0,
+                        y: b.1,
+                        z: 0,
+                    },
+                ],
+                closed: false,
+                tool_dia_pix: 1,
+                tool_i: 0,
+                tile_i: 0,
+                tree_node_id: 0,
+                cuts: vec![
+                    CutPixels {
+                        pixels_changed: pixels,
+                        ..Default::default()
+                    },
+                    CutPixels::default(),
+                ],
+                is_traverse: false,
+                is_raster: false,
+                is_plunge_run: false,
+            }
+        }
+
+        let mut toolpaths = vec![
+            seg((0, 0), (5, 0), 1),
+            seg((5, 0), (10, 0), 2),
+            seg((10, 0), (15, 1), 3),  // Slight bend, within tolerance
+            seg((15, 1), (15, 10), 4), // Right angle: starts a new path
+        ];
+        merge_collinear(&mut toolpaths, 15.0);
+
+        assert_eq!(toolpaths.len(), 2);
+        let tp = &toolpaths[0];
+        assert_eq!(tp.points.len(), 4);
+        assert_eq!(tp.points[3], IV3 { x: 15, y: 1, z: 0 });
+        assert_eq!(tp.cuts.len(), tp.points.len());
+        let pixels: Vec<u64> = tp.cuts.iter().map(|c| c.pixels_changed).collect();
+        assert_eq!(pixels, vec![1, 2, 3, 0]);
+
+        // With a tight tolerance the bend is kept as a separate path.
+        let mut toolpaths = vec![
+            seg((0, 0), (5, 0), 1),
+            seg((5, 0), (10, 0), 2),
+            seg((10, 0), (15, 1), 3),
+        ];
+        merge_collinear(&mut toolpaths, 1.0);
+        assert_eq!(toolpaths.len(), 2);
+
+        // Closed paths are never merged.
+        let mut closed = seg((5, 0), (10, 0), 2);
+        closed.closed = true;
+        let mut toolpaths = vec![seg((0, 0), (5, 0), 1), closed];
+        merge_collinear(&mut toolpaths, 15.0);
+        assert_eq!(toolpaths.len(), 2);
+    }
+
+    #[test]
+    fn sim_single_segment_matches_sim_toolpaths() {
+        let mut stock = Lum16Im::new(30, 20);
+        stock.arr.fill(1000);
+        let p0 = IV3 { x: 3, y: 5, z: 400 };
+        let p1 = IV3 {
+            x: 24,
+            y: 4,
+            z: 400,
+        };
+
+        let mut expected = stock.clone();
+        let mut toolpaths = vec![ToolPath {
+            points: vec![p0, p1],
+            closed: false,
+            tool_dia_pix: 7,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        }];
+        let _ = crate::sim::sim_toolpaths(
+            &mut expected,
+            &mut toolpaths,
+            None,
+            None,
+            crate::sim::SimOptions::default(),
+        );
+
+        let mut im = stock.clone();
+        let cut = crate::sim::sim_single_segment(&mut im, p0, p1, 7);
+        assert_eq!(im.arr, expected.arr);
+        assert_eq!(cut.pixels_changed, toolpaths[0].cuts[0].pixels_changed);
+        assert_eq!(cut.depth_sum_thou, toolpaths[0].cuts[0].depth_sum_thou);
+        assert_eq!(
+            cut.max_engagement_frac,
+            toolpaths[0].cuts[0].max_engagement_frac
+        );
+        assert!(cut.pixels_changed > 0);
+
+        // Z-changing segments don't cut.
+        let mut im = stock.clone();
+        let plunge = crate::sim::sim_single_segment(&mut im, p0, IV3 { z: 100, ..p1 }, 7);
+        assert_eq!(plunge.pixels_changed, 0);
+        assert_eq!(im.arr, stock.arr);
+    }
+
+    #[test]
+    fn integer_capsule_raster_is_repeatable_and_matches_float() {
+        use crate::sim::{CapsuleRaster, circle_pixel_iz, draw_toolpath_segment_single_depth_with};
+
+        let stock = crate::sim::make_stock(64, 64, 1000);
+        let p0 = IV3 {
+            x: 32,
+            y: 32,
+            z: 400,
+        };
+        let draw_fan = |raster: CapsuleRaster, radius_pix: usize| {
+            let circle = circle_pixel_iz(radius_pix, stock.s);
+            let mut im = stock.clone();
+            let mut cuts = Vec::new();
+            // Endpoints all the way round, at every slope a 20px reach can make.
+            for dy in -20..=20 {
+                for dx in [-20, 20] {
+                    for (x, y) in [(dx, dy), (dy, dx)] {
+                        let mut one = stock.clone();
+                        let p1 = IV3 {
+                            x: 32 + x,
+                            y: 32 + y,
+                            z: 400,
+                        };
+                        let cut = draw_toolpath_segment_single_depth_with(
+                            &mut one, p0, p1, radius_pix, &circle, raster,
+                        );
+                        cuts.push((cut.pixels_changed, cut.depth_sum_thou));
+                        for (dst, &v) in im.arr.iter_mut().zip(&one.arr) {
+                            *dst = (*dst).min(v);
+                        }
+                    }
+                }
+            }
+            (im, cuts)
+        };
+
+        for radius_pix in [1, 3, 6] {
+            let (int_a, cuts_a) = draw_fan(CapsuleRaster::Integer, radius_pix);
+            let (int_b, cuts_b) = draw_fan(CapsuleRaster::Integer, radius_pix);
+            assert_eq!(int_a.arr, int_b.arr);
+            assert_eq!(cuts_a, cuts_b);
+
+            // No corner here is close enough to a half pixel for the float error to matter.
+            let (float, float_cuts) = draw_fan(CapsuleRaster::Float, radius_pix);
+            assert_eq!(int_a.arr, float.arr, "radius_pix={radius_pix}");
+            assert_eq!(cuts_a, float_cuts, "radius_pix={radius_pix}");
+        }
+    }
+
+    #[test]
+    fn sim_cuts_sloped_segments_only_when_allowed() {
+        let stock = crate::sim::make_stock(40, 20, 600);
+        let v = |x: i32, z: i32| IV3 { x, y: 10, z };
+        let ramp = || {
+            vec![ToolPath {
+                points: vec![v(10, 500), v(30, 300)],
+                closed: false,
+                tool_dia_pix: 3,
+                tool_i: 0,
+                tile_i: 0,
+                tree_node_id: 0,
+                cuts: vec![],
+                is_traverse: false,
+                is_raster: false,
+                is_plunge_run: false,
+            }]
+        };
+
+        // By default a Z-changing segment is a non-cutting move.
+        let mut im = stock.clone();
+        let mut toolpaths = ramp();
+        let _ = crate::sim::sim_toolpaths(
+            &mut im,
+            &mut toolpaths,
+            None,
+            None,
+            crate::sim::SimOptions::default(),
+        );
+        assert_eq!(im.arr, stock.arr);
+        assert_eq!(toolpaths[0].cuts[0], CutPixels::default());
+
+        let mut im = stock.clone();
+        let mut toolpaths = ramp();
+        let _ = crate::sim::sim_toolpaths(
+            &mut im,
+            &mut toolpaths,
+            None,
+            None,
+            crate::sim::SimOptions {
+                allow_sloped_cuts: true,
+                ..Default::default()
+            },
+        );
+        let at = |x: usize, y: usize| im.arr[y * im.s + x];
+        // Each pixel ends at the lowest station whose disc covers it, one step ahead here.
+        assert_eq!(at(10, 10), 490);
+        assert_eq!(at(20, 10), 390);
+        assert_eq!(at(30, 10), 300);
+        assert_eq!(at(31, 10), 300);
+        assert_eq!(at(20, 11), 400);
+        assert_eq!(at(20, 13), 600);
+        // Deeper along the ramp, never shallower.
+        assert!((10..30).all(|x| at(x + 1, 10) <= at(x, 10)));
+        assert!(toolpaths[0].cuts[0].pixels_changed > 0);
+
+        // A plunge cuts the disc at the bottom.
+        let mut im = stock.clone();
+        let plunge = crate::sim::draw_toolpath_segment_sloped(
+            &mut im,
+            v(5, 600),
+            v(5, 200),
+            1,
+            &crate::sim::circle_pixel_iz(1, stock.s),
+        );
+        assert_eq!(plunge.pixels_changed, 5);
+        assert_eq!(im.arr[10 * im.s + 5], 200);
+
+        // A plunge just off the image still cuts the edge of its disc that is on it.
+        let mut im = stock.clone();
+        let edge = crate::sim::draw_toolpath_segment_sloped(
+            &mut im,
+            v(-1, 600),
+            v(-1, 200),
+            1,
+            &crate::sim::circle_pixel_iz(1, stock.s),
+        );
+        assert_eq!(edge.pixels_changed, 1);
+        assert_eq!(im.arr[10 * im.s], 200);
+    }
+
+    #[test]
+    fn sim_records_leading_arc_engagement() {
+        let mut im = Lum16Im::new(60, 40);
+        im.arr.fill(1000);
+        let seg = |y: i32| (IV3 { x: 10, y, z: 400 }, IV3 { x: 50, y, z: 400 });
+
+        // A slot into fresh stock engages the whole leading arc.
+        let (p0, p1) = seg(20);
+        let slot = crate::sim::sim_single_segment(&mut im, p0, p1, 11);
+        assert_eq!(slot.max_engagement_frac, 1.0);
+
+        // Stepping over by half the diameter engages roughly half of it.
+        let (p0, p1) = seg(25);
+        let side = crate::sim::sim_single_segment(&mut im, p0, p1, 11);
+        assert!((0.35..0.65).contains(&side.max_engagement_frac), "{side:?}");
+
+        // Re-running the same pass meets no material.
+        let again = crate::sim::sim_single_segment(&mut im, p0, p1, 11);
+        assert_eq!(again.max_engagement_frac, 0.0);
+
+        // A ramp from above the stock down into it is measured at the Z under each station,
+        // not at its start Z (where it would meet no material).
+        let mut stock = Lum16Im::new(60, 40);
+        stock.arr.fill(500);
+        let ramp = |z1: i32| {
+            crate::sim::leading_arc_engagement(
+                &stock,
+                IV3 {
+                    x: 10,
+                    y: 20,
+                    z: 600,
+                },
+                IV3 {
+                    x: 50,
+                    y: 20,
+                    z: z1,
+                },
+                11,
+            )
+        };
+        assert_eq!(ramp(600), 0.0);
+        assert_eq!(ramp(550), 0.0);
+        assert_eq!(ramp(400), 1.0);
+    }
+
+    #[test]
+    fn check_shank_clearance_flags_deep_narrow_pockets() {
+        // Stock at 1000 with a 3-pixel-wide slot cut down to 200.
+        let mut heights = Lum16Im::new(20, 20);
+        heights.arr.fill(1000);
+        for y in 0..20 {
+            for x in 9..12 {
+                heights.arr[y * heights.s + x] = 200;
+            }
+        }
+        let slot = |z: i32| ToolPath {
+            points: vec![IV3 { x: 10, y: 4, z }, IV3 { x: 10, y: 15, z }],
+            closed: false,
+            tool_dia_pix: 3,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+
+        // A 9-pixel shank starting 500 above a tip at 200 reaches the 1000 walls.
+        let hits = crate::sim::check_shank_clearance(&heights, &[slot(200), slot(600)], 9, 500);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].toolpath_i, 0);
+        assert_eq!(hits[0].shank_z_thou, 700);
+        assert_eq!(hits[0].max_height_thou, 1000);
+        assert_eq!(hits[0].excess_thou, 300);
+
+        // A long enough flute length, or a shank that fits in the slot, clears.
+        assert!(crate::sim::check_shank_clearance(&heights, &[slot(200)], 9, 800).is_empty());
+        assert!(crate::sim::check_shank_clearance(&heights, &[slot(200)], 3, 500).is_empty());
+
+        // Traverses are not checked.
+        let mut traverse = slot(200);
+        traverse.is_traverse = true;
+        assert!(crate::sim::check_shank_clearance(&heights, &[traverse], 9, 500).is_empty());
+
+        // A segment running off the image is checked on its on-image part; one entirely off
+        // it is skipped.
+        let mut off_top = slot(200);
+        off_top.points[0].y = -10;
+        let hits = crate::sim::check_shank_clearance(&heights, &[off_top], 9, 500);
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].p.x, hits[0].p.y), (10, 0));
+        let mut off_image = slot(200);
+        for p in &mut off_image.points {
+            p.x = -5;
+        }
+        assert!(crate::sim::check_shank_clearance(&heights, &[off_image], 9, 500).is_empty());
+    }
+
+    #[test]
+    fn sim_toolpaths_aborts_cleanly_and_reports_progress() {
+        let line = |y: i32| ToolPath {
+            points: vec![
+                IV3 { x: 2, y, z: 500 },
+                IV3 { x: 8, y, z: 500 },
+                IV3 { x: 14, y, z: 500 },
+            ],
+            closed: false,
+            tool_dia_pix: 3,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![CutPixels::default(); 3],
+            is_traverse: false,
+            is_raster: true,
+            is_plunge_run: false,
+        };
+        let mut base = Lum16Im::new(16, 12);
+        base.arr.fill(1000);
+
+        // Stop right after the first segment.
+        let mut toolpaths = vec![line(3), line(8)];
+        let mut im = base.clone();
+        let mut progress_calls: Vec<(usize, usize)> = Vec::new();
+        let mut on_step =
+            |_: &Lum16Im, _: usize, _: usize, _: IV3, _: IV3, _: CutPixels| ControlFlow::Break(());
+        let mut on_progress = |done: usize, total: usize| progress_calls.push((done, total));
+        let flow = crate::sim::sim_toolpaths(
+            &mut im,
+            &mut toolpaths,
+            Some(&mut on_step),
+            Some(&mut on_progress),
+            crate::sim::SimOptions::default(),
+        );
+        assert_eq!(flow, ControlFlow::Break(()));
+        assert!(progress_calls.is_empty());
+
+        // Exactly the first segment is applied and accounted for.
+        let mut expected = base.clone();
+        let mut first_seg = vec![ToolPath {
+            points: toolpaths[0].points[..2].to_vec(),
+            cuts: vec![CutPixels::default(); 2],
+            ..line(3)
+        }];
+        let _ = crate::sim::sim_toolpaths(
+            &mut expected,
+            &mut first_seg,
+            None,
+            None,
+            crate::sim::SimOptions::default(),
+        );
+        assert_eq!(im.arr, expected.arr);
+        assert_eq!(toolpaths[0].cuts[0], first_seg[0].cuts[0]);
+        assert!(toolpaths[0].cuts[0].pixels_changed > 0);
+        assert_eq!(toolpaths[0].cuts[1], CutPixels::default());
+        assert!(toolpaths[1].cuts.iter().all(|c| *c == CutPixels::default()));
+
+        // A full run reports each finished toolpath.
+        let mut progress_calls: Vec<(usize, usize)> = Vec::new();
+        let mut on_progress = |done: usize, total: usize| progress_calls.push((done, total));
+        let mut im = base.clone();
+        let flow = crate::sim::sim_toolpaths(
+            &mut im,
+            &mut toolpaths,
+            None,
+            Some(&mut on_progress),
+            crate::sim::SimOptions::default(),
+        );
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(progress_calls, vec![(1, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn make_stock_sizes_and_fills_the_surface() {
+        use crate::desc::DimDesc;
+        use crate::sim::{make_stock, make_stock_from_dim};
+
+        let stock = make_stock(7, 3, 1250);
+        assert_eq!((stock.w, stock.h), (7, 3));
+        assert!(stock.arr.iter().all(|&v| v == 1250));
+
+        // The frame goes on every side; the surface is the bulk depth in thou.
+        let dim = DimDesc {
+            bulk_d_inch: 0.75,
+            bulk_w_inch: 4.0,
+            bulk_h_inch: 2.5,
+            padding_inch: 0.0,
+            frame_inch: 0.5,
+        };
+        let stock = make_stock_from_dim(&dim, 10.0);
+        assert_eq!((stock.w, stock.h), (50, 35));
+        assert!(stock.arr.iter().all(|&v| v == 750));
+
+        // Depths past what a u16 heightmap holds are clamped.
+        let deep = DimDesc {
+            bulk_d_inch: 100.0,
+            ..dim
+        };
+        assert_eq!(make_stock_from_dim(&deep, 1.0).arr[0], u16::MAX);
+    }
+
+    #[test]
+    fn sim_sets_feed_scale_from_engagement() {
+        use crate::sim::{SimOptions, default_feed_scale, sim_toolpaths};
+
+        // No engagement runs at full feed, a full slot at half, and it is resolution free.
+        let cut = |pixels_changed: u64| CutPixels {
+            pixels_changed,
+            ..Default::default()
+        };
+        assert_eq!(default_feed_scale(&cut(0), 10.0, 4), 1.0);
+        let full = 10.0 * 4.0 + std::f64::consts::FRAC_PI_4 * 16.0;
+        assert_eq!(default_feed_scale(&cut(full.ceil() as u64), 10.0, 4), 0.5);
+        let half = default_feed_scale(&cut((full / 2.0) as u64), 10.0, 4);
+        assert!((half - 0.75).abs() < 0.01, "half engagement gave {half}");
+        assert_eq!(
+            default_feed_scale(&cut(4 * full as u64), 20.0, 8),
+            default_feed_scale(&cut(full as u64), 10.0, 4)
+        );
+
+        // The sim fills each cut segment's scale: a slot into stock is slowed, and the same
+        // pass again (cutting nothing) is not. Z-only moves keep 1.0.
+        let slot = || ToolPath {
+            points: vec![
+                IV3 {
+                    x: 4,
+                    y: 8,
+                    z: 1000,
+                },
+                IV3 { x: 4, y: 8, z: 500 },
+                IV3 {
+                    x: 20,
+                    y: 8,
+                    z: 500,
+                },
+            ],
+            closed: false,
+            tool_dia_pix: 5,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![CutPixels::default(); 3],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+        let mut im = crate::sim::make_stock(24, 16, 1000);
+        let mut toolpaths = vec![slot(), slot()];
+        let _ = sim_toolpaths(&mut im, &mut toolpaths, None, None, SimOptions::default());
+        let (first, second) = (&toolpaths[0].cuts, &toolpaths[1].cuts);
+        assert_eq!(first[0].feed_scale, 1.0);
+        assert!(first[1].pixels_changed > 0);
+        assert!(
+            first[1].feed_scale < 0.75,
+            "slot scale {}",
+            first[1].feed_scale
+        );
+        assert_eq!(second[1].pixels_changed, 0);
+        assert_eq!(second[1].feed_scale, 1.0);
+    }
+
+    #[test]
+    fn sim_options_accumulate_heights_and_reset_cuts_per_pass() {
+        use crate::sim::{SimOptions, sim_toolpaths};
+
+        let line = |z: i32, tool_dia_pix: usize| ToolPath {
+            points: vec![IV3 { x: 3, y: 6, z }, IV3 { x: 12, y: 6, z }],
+            closed: false,
+            tool_dia_pix,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![CutPixels::default(); 2],
+            is_traverse: false,
+            is_raster: true,
+            is_plunge_run: false,
+        };
+        let sim = |im: &mut Lum16Im, toolpaths: &mut [ToolPath], options: SimOptions| {
+            let _ = sim_toolpaths(im, toolpaths, None, None, options);
+        };
+        let stock = crate::sim::make_stock(16, 12, 1000);
+
+        // Rough then refine in two calls: same heights as one call with both passes.
+        let mut rough = vec![line(800, 5)];
+        let mut refine = vec![line(600, 3)];
+        let mut im = stock.clone();
+        sim(&mut im, &mut rough, SimOptions::default());
+        let after_rough = im.clone();
+        sim(&mut im, &mut refine, SimOptions::default());
+        let mut both = vec![line(800, 5), line(600, 3)];
+        let mut im_both = stock.clone();
+        sim(&mut im_both, &mut both, SimOptions::default());
+        assert_eq!(im.arr, im_both.arr);
+
+        // Refine's cuts only count what the rough pass left: the 200 thou below it.
+        let refine_cut = refine[0].cuts[0];
+        assert_eq!(refine_cut, both[1].cuts[0]);
+        assert_eq!(refine_cut.depth_sum_thou, 200 * refine_cut.pixels_changed);
+
+        // Without resetting cuts, a second run merges in nothing new: the map is already cut.
+        let mut again = refine.clone();
+        sim(
+            &mut im,
+            &mut again,
+            SimOptions {
+                reset_cuts: false,
+                ..SimOptions::default()
+            },
+        );
+        assert_eq!(again[0].cuts[0].pixels_changed, refine_cut.pixels_changed);
+        assert_eq!(again[0].cuts[0].depth_sum_thou, refine_cut.depth_sum_thou);
+
+        // Resetting the heights starts refine from flat stock again.
+        let mut im = after_rough;
+        let mut fresh = vec![line(600, 3)];
+        sim(
+            &mut im,
+            &mut fresh,
+            SimOptions {
+                reset_heights: true,
+                ..SimOptions::default()
+            },
+        );
+        let fresh_cut = fresh[0].cuts[0];
+        assert_eq!(fresh_cut.depth_sum_thou, 400 * fresh_cut.pixels_changed);
+        assert!(im.arr.iter().all(|&v| v == 1000 || v == 600));
+    }
+
+    #[test]
+    fn toolpaths_covering_uses_the_tool_radius() {
+        let v = |x: i32, y: i32, z: i32| IV3 { x, y, z };
+        let tp = |points: Vec<IV3>, is_traverse: bool| ToolPath {
+            cuts: vec![CutPixels::default(); points.len()],
+            points,
+            closed: false,
+            tool_dia_pix: 6,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            is_traverse,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+        let toolpaths = vec![
+            tp(vec![v(0, 0, 100), v(20, 0, 100)], false),
+            tp(vec![v(0, 10, 100), v(20, 10, 100)], false),
+            tp(vec![v(0, 3, 900), v(20, 3, 900)], true),
+            tp(vec![v(10, 5, 900), v(10, 5, 100)], false),
+        ];
+
+        // (10, 3) is 3 px from the first path and 7 px from the second.
+        assert_eq!(toolpaths_covering(&toolpaths, 10, 3, 6), vec![0]);
+        assert!(toolpaths_covering(&toolpaths, 10, 3, 4).is_empty());
+        assert_eq!(toolpaths_covering(&toolpaths, 10, 5, 10), vec![0, 1]);
+        // Past the end cap.
+        assert!(toolpaths_covering(&toolpaths, 24, 0, 6).is_empty());
+        assert_eq!(toolpaths_covering(&toolpaths, 23, 0, 6), vec![0]);
+    }
+
+    #[test]
+    fn insert_retracts_bridges_gaps_per_policy() {
+        let v = |x: i32, y: i32, z: i32| IV3 { x, y, z };
+        let tp = |points: Vec<IV3>| ToolPath {
+            cuts: vec![CutPixels::default(); points.len()],
+            points,
+            closed: false,
+            tool_dia_pix: 4,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+        let cuts = vec![
+            tp(vec![v(2, 2, 100), v(10, 2, 100)]),
+            // Contiguous with the first: no retract.
+            tp(vec![v(10, 2, 100), v(10, 10, 100)]),
+            tp(vec![v(20, 10, 200), v(30, 10, 200)]),
+        ];
+
+        let mut none = cuts.clone();
+        insert_retracts(&mut none, None, RetractPolicy::None);
+        assert_eq!(none, cuts);
+
+        let mut global = cuts.clone();
+        insert_retracts(&mut global, None, RetractPolicy::GlobalSafeZ(Thou(900)));
+        let kinds: Vec<bool> = global.iter().map(|tp| tp.is_traverse).collect();
+        assert_eq!(kinds, vec![false, false, true, false]);
+        assert_eq!(
+            global[2].points,
+            vec![
+                v(10, 10, 100),
+                v(10, 10, 900),
+                v(20, 10, 900),
+                v(20, 10, 200)
+            ]
+        );
+        // A safe Z below an endpoint is raised to it, so the move never dips.
+        let mut low = cuts.clone();
+        insert_retracts(&mut low, None, RetractPolicy::GlobalSafeZ(Thou(150)));
+        let zs: Vec<i32> = low[2].points.iter().map(|p| p.z).collect();
+        assert_eq!(zs, vec![100, 200, 200, 200]);
+
+        // A 500-high wall between the two paths; the rest of the surface is at 150.
+        let mut heights = Lum16Im::new(40, 20);
+        heights.arr.fill(150);
+        for y in 0..20 {
+            heights.arr[y * heights.s + 15] = 500;
+        }
+        let mut local = cuts.clone();
+        insert_retracts(
+            &mut local,
+            Some(&heights),
+            RetractPolicy::LocalScan(Thou(50)),
+        );
+        assert_eq!(local.len(), 4);
+        let zs: Vec<i32> = local[2].points.iter().map(|p| p.z).collect();
+        assert_eq!(zs, vec![100, 550, 550, 200]);
+
+        // The retract and plunge are Z-only, so the sim doesn't cut with them.
+        let mut im = heights.clone();
+        let _ = crate::sim::sim_toolpaths(
+            &mut im,
+            &mut local[2..3],
+            None,
+            None,
+            crate::sim::SimOptions::default(),
+        );
+        assert_eq!(im.arr, heights.arr);
+    }
+
+    #[test]
+    fn total_cut_sums_segments_and_averages_depth() {
+        let v = |x: i32| IV3 { x, y: 0, z: 100 };
+        let tp = ToolPath {
+            points: vec![v(0), v(5), v(9)],
+            closed: false,
+            tool_dia_pix: 4,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![
+                CutPixels {
+                    pixels_changed: 10,
+                    depth_sum_thou: 200,
+                    feed_scale: 0.8,
+                    max_engagement_frac: 0.5,
+                    is_tab: false,
+                },
+                CutPixels {
+                    pixels_changed: 30,
+                    depth_sum_thou: 1000,
+                    feed_scale: 0.6,
+                    max_engagement_frac: 0.9,
+                    is_tab: true,
+                },
+                CutPixels::default(),
+            ],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+
+        let total = tp.total_cut();
+        assert_eq!((total.pixels_changed, total.depth_sum_thou), (40, 1200));
+        assert_eq!(total.feed_scale, 0.6);
+        assert_eq!(total.max_engagement_frac, 0.9);
+        assert!(total.is_tab);
+        assert_eq!(total.avg_depth_thou(), Some(30.0));
+        assert_eq!(CutPixels::default().avg_depth_thou(), None);
+    }
+
+    #[test]
+    fn enforce_max_doc_stacks_passes_down_a_deep_band() {
+        let v = |x: i32, y: i32, z: i32| IV3 { x, y, z };
+        let tp = |points: Vec<IV3>, is_traverse: bool| ToolPath {
+            cuts: vec![CutPixels::default(); points.len()],
+            points,
+            closed: false,
+            tool_dia_pix: 4,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            is_traverse,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+
+        // A 300-thou band (top 400, floor at 100) with a 100-thou max DOC.
+        let mut toolpaths = vec![
+            tp(vec![v(0, 0, 100), v(10, 0, 100)], false),
+            tp(vec![v(10, 0, 100), v(20, 0, 100)], true),
+            tp(vec![v(20, 0, 350), v(30, 0, 150)], false),
+        ];
+        enforce_max_doc(&mut toolpaths, Thou(100), Thou(400));
+
+        let zs: Vec<Vec<i32>> = toolpaths
+            .iter()
+            .map(|tp| tp.points.iter().map(|p| p.z).collect())
+            .collect();
+        assert_eq!(
+            zs,
+            vec![
+                // Every path at each level before the next; a sloped path is clamped per point,
+                // never below the original.
+                vec![300, 300],
+                vec![350, 300],
+                vec![200, 200],
+                vec![350, 200],
+                // Then the originals, with the traverse untouched.
+                vec![100, 100],
+                vec![100, 100],
+                vec![350, 150],
+            ]
+        );
+
+        // A band no deeper than the max DOC is left as-is.
+        let mut shallow = vec![tp(vec![v(0, 0, 300), v(10, 0, 300)], false)];
+        enforce_max_doc(&mut shallow, Thou(100), Thou(400));
+        assert_eq!(shallow.len(), 1);
+    }
+
+    #[test]
+    fn break_long_toolpaths_distributes_cuts_over_pieces() {
+        let cut = CutPixels {
+            pixels_changed: 101,
+            depth_sum_thou: 5003,
+            feed_scale: 0.5,
+            max_engagement_frac: 0.25,
+            is_tab: true,
+        };
+        let mut toolpaths = vec![ToolPath {
+            points: vec![IV3 { x: 0, y: 0, z: 0 }, IV3 { x: 95, y: 0, z: 0 }],
+            closed: false,
+            tool_dia_pix: 1,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![cut, CutPixels::default()],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        }];
+
+        break_long_toolpaths(&mut toolpaths, 10);
+        assert_eq!(toolpaths.len(), 10);
+
+        let total_pixels: u64 = toolpaths.iter().map(|tp| tp.cuts[0].pixels_changed).sum();
+        let total_depth: u64 = toolpaths.iter().map(|tp| tp.cuts[0].depth_sum_thou).sum();
+        assert_eq!(total_pixels, cut.pixels_changed);
+        assert_eq!(total_depth, cut.depth_sum_thou);
+        assert!(toolpaths.iter().all(|tp| tp.cuts[0].pixels_changed > 0));
+        assert!(
+            toolpaths
+                .iter()
+                .all(|tp| tp.cuts[0].feed_scale == 0.5 && tp.cuts[0].is_tab)
+        );
+
+        // Nothing was lost, so culling keeps every piece.
+        cull_empty_toolpaths(&mut toolpaths);
+        assert_eq!(toolpaths.len(), 10);
+    }
+
+    #[test]
+    fn break_long_toolpaths_splits_on_long_mid_segment() {
+        let mut toolpaths = vec![ToolPath {
+            points: vec![
+                IV3 { x: 0, y: 0, z: 0 },
+                IV3 { x: 1, y: 0, z: 0 },
+                // Big jump in XY from previous point => should trigger a split.
+                IV3 { x: 100, y: 0, z: 0 },
+            ],
+            closed: false,
+            tool_dia_pix: 1,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![CutPixels::default(); 3],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        }];
+
+        break_long_toolpaths(&mut toolpaths, 10);
+
+        // We should get more segments back (the 1->100 segment subdivides).
+        assert!(toolpaths.len() > 2);
+        assert!(toolpaths.iter().all(|tp| tp.points.len() == 2));
+
+        // Every segment should now be <= max length in XY.
+        for tp in &toolpaths {
+            let a = tp.points[0];
+            let b = tp.points[1];
+            let dx = (a.x - b.x) as i64;
+            let dy = (a.y - b.y) as i64;
+            let d2 = dx * dx + dy * dy;
+            assert!(d2 <= 100, "segment too long: d2={d2}");
+        }
+    }
+
+    #[test]
+    fn break_long_toolpaths_keeps_short_runs_whole() {
+        let p = |x: i32, y: i32| IV3 { x, y, z: 0 };
+        let mut toolpaths = vec![ToolPath {
+            points: vec![
+                p(0, 0),
+                p(2, 1),
+                p(4, 1),
+                p(6, 0),
+                p(36, 0),
+                p(38, 1),
+                p(40, 3),
+            ],
+            closed: false,
+            tool_dia_pix: 1,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![CutPixels::default(); 7],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        }];
+
+        break_long_toolpaths(&mut toolpaths, 10);
+
+        // The short runs either side of the long edge keep their points; only the long edge
+        // is cut into 2-point pieces.
+        let shapes: Vec<usize> = toolpaths.iter().map(|tp| tp.points.len()).collect();
+        assert_eq!(shapes, vec![4, 2, 2, 2, 3]);
+        assert_eq!(
+            toolpaths[0].points,
+            vec![p(0, 0), p(2, 1), p(4, 1), p(6, 0)]
+        );
+        assert_eq!(toolpaths[4].points, vec![p(36, 0), p(38, 1), p(40, 3)]);
+        assert!(toolpaths.iter().all(|tp| tp.cuts.len() == tp.points.len()));
+        for w in toolpaths.windows(2) {
+            assert_eq!(w[0].points.last(), w[1].points.first());
+        }
+
+        // On a loop, the run that wraps past the seam comes out as one path.
+        let mut toolpaths = vec![ToolPath {
+            points: vec![p(0, 3), p(0, 0), p(30, 0), p(30, 5), p(0, 5), p(0, 3)],
+            closed: true,
+            tool_dia_pix: 1,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![CutPixels::default(); 6],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        }];
+        break_long_toolpaths(&mut toolpaths, 10);
+        let shapes: Vec<usize> = toolpaths.iter().map(|tp| tp.points.len()).collect();
+        assert_eq!(shapes, vec![2, 2, 2, 2, 3, 2, 2, 2]);
+        assert_eq!(toolpaths[4].points, vec![p(0, 5), p(0, 3), p(0, 0)]);
+    }
+
+    #[test]
+    fn spiral_clearing_never_links_across_a_wall() {
+        // A U-shaped pocket: a wide left arm and a narrow, taller right arm split by a thin
+        // wall. The right arm erodes away first, leaving its spiral ending right by the wall.
+        let (w, h) = (28_usize, 30_usize);
+        let mut mask = MaskIm::new(w, h);
+        for y in 2..h - 2 {
+            for x in 2..24 {
+                let left_arm = x < 17 && y >= 4;
+                let right_arm = x >= 20;
+                let bottom = y >= 20;
+                if left_arm || right_arm || bottom {
+                    mask.arr[y * mask.s + x] = 255;
+                }
+            }
+        }
+        let roi = ROI {
+            l: 0,
+            t: 0,
+            r: w,
+            b: h,
+        };
+
+        for step in 1..=4 {
+            let paths = create_spiral_clearing(&mask, &roi, 0, 2, step, Thou(100), 7);
+            println!("step={step}\n{}", toolpaths_to_ascii(&paths, w, h));
+            assert!(!paths.is_empty());
+            // Rings are RDP-simplified by a pixel, so allow that much; a link across the
+            // 3-pixel wall still hits its middle column.
+            let near_mask = |x: i32, y: i32| {
+                (y - 1..=y + 1).any(|ny| {
+                    (x - 1..=x + 1).any(|nx| mask.arr[ny as usize * mask.s + nx as usize] != 0)
+                })
+            };
+            for tp in &paths {
+                for seg in tp.points.windows(2) {
+                    let (a, b) = (seg[0], seg[1]);
+                    let n = (a.x - b.x).abs().max((a.y - b.y).abs()).max(1);
+                    for i in 0..=n {
+                        let t = i as f64 / n as f64;
+                        let x = (a.x as f64 + (b.x - a.x) as f64 * t).round() as i32;
+                        let y = (a.y as f64 + (b.y - a.y) as f64 * t).round() as i32;
+                        assert!(
+                            near_mask(x, y),
+                            "step={step}: {a:?} -> {b:?} leaves the pocket at ({x}, {y})"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn spiral_clearing_rings_a_disc_into_one_spiral() {
+        let (w, h) = (31_usize, 31_usize);
+        let mut mask = MaskIm::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let dx = x as i32 - 15;
+                let dy = y as i32 - 15;
+                if dx * dx + dy * dy <= 11 * 11 {
+                    mask.arr[y * mask.s + x] = 255;
+                }
+            }
+        }
+        let roi = ROI {
+            l: 0,
+            t: 0,
+            r: w,
+            b: h,
+        };
+
+        let paths = create_spiral_clearing(&mask, &roi, 0, 2, 3, Thou(100), 7);
+        println!("{}", toolpaths_to_ascii(&paths, w, h));
+
+        // Concentric rings of a disc never split, so they chain into a single spiral.
+        assert_eq!(paths.len(), 1);
+        let tp = &paths[0];
+        assert_eq!(tp.tree_node_id, 7);
+        assert_eq!(tp.cuts.len(), tp.points.len());
+        assert!(tp.points.len() > 8, "expected several rings");
+        for p in &tp.points {
+            assert_eq!(p.z, 100);
+            assert!(
+                mask.arr[p.y as usize * mask.s + p.x as usize] != 0,
+                "spiral point outside the mask: {p:?}"
+            );
+        }
+
+        // The first ring is the region boundary.
+        let first = tp.points[0];
+        let (dx, dy) = (first.x - 15, first.y - 15);
+        assert!(dx * dx + dy * dy >= 10 * 10);
+
+        // An empty mask produces nothing.
+        let empty = MaskIm::new(w, h);
+        assert!(create_spiral_clearing(&empty, &roi, 0, 2, 3, Thou(100), 7).is_empty());
+    }
+
+    #[test]
+    fn mask_needs_refine_marks_only_leftover_in_roi() {
+        let mut target = Lum16Im::new(4, 3);
+        let mut roughed = Lum16Im::new(4, 3);
+        target.arr.fill(100);
+        roughed.arr.fill(100);
+
+        roughed.arr[1 * roughed.s + 1] = 110; // Leftover, inside the ROI
+        roughed.arr[1 * roughed.s + 2] = 102; // Within tolerance
+        roughed.arr[1 * roughed.s + 3] = 150; // Leftover, but outside the ROI
+
+        let roi = ROI {
+            l: 0,
+            t: 0,
+            r: 3,
+            b: 3,
+        };
+        let mask_im = mask_needs_refine(&target, &roughed, &roi, 2);
+
+        let marked: Vec<(usize, usize)> = (0..3)
+            .flat_map(|y| (0..4).map(move |x| (x, y)))
+            .filter(|&(x, y)| mask_im.arr[y * mask_im.s + x] != 0)
+            .collect();
+        assert_eq!(marked, vec![(1, 1)]);
+    }
+
+    #[test]
+    fn above_mask_and_subtract_stay_inside_roi() {
+        let (w, h) = (9, 7);
+        let mut ply_im = PlyIm::new(w, h);
+        for (i, v) in ply_im.arr.iter_mut().enumerate() {
+            *v = (i % 5) as u16;
+        }
+        let roi = ROI {
+            l: 2,
+            t: 1,
+            r: 7,
+            b: 5,
+        };
+        let in_roi =
+            |i: usize| (roi.t..roi.b).contains(&(i / w)) && (roi.l..roi.r).contains(&(i % w));
+
+        let mut above_mask_im = MaskIm::new(w, h);
+        mark_above_ply(&ply_im, &mut above_mask_im, &roi, 2);
+        for i in 0..w * h {
+            let want = in_roi(i) && ply_im.arr[i] > 2;
+            assert_eq!(above_mask_im.arr[i] == 255, want, "i={i}");
+        }
+
+        let mut cut_mask_im = MaskIm::new(w, h);
+        cut_mask_im.arr.fill(255);
+        subtract_mask(&mut cut_mask_im, &above_mask_im, &roi);
+        for i in 0..w * h {
+            assert_eq!(cut_mask_im.arr[i] == 0, above_mask_im.arr[i] != 0, "i={i}");
+        }
+    }
+
+    #[test]
+    fn compute_above_mask_pads_roi_and_clamps_border() {
+        let (w, h) = (10, 8);
+        let mut ply_im = PlyIm::new(w, h);
+        ply_im.arr.fill(3);
+        let roi = ROI {
+            l: 1,
+            t: 5,
+            r: 3,
+            b: 6,
+        };
+        let mut out = MaskIm::new(w, h);
+        out.arr.fill(7); // Stale data from a previous node is cleared.
+
+        // Nothing is above ply 3, so only the whole image border is set, including the
+        // edges far from the padded ROI (l=0 t=3 r=5 b=8 after clamping the pad of 2).
+        compute_above_mask(&ply_im, &roi, 3, 2, &mut out);
+        let marked: Vec<(usize, usize)> = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .filter(|&(x, y)| out.arr[y * out.s + x] != 0)
+            .collect();
+        let mut want: Vec<(usize, usize)> = Vec::new();
+        for y in 0..h {
+            want.push((0, y));
+            want.push((w - 1, y));
+        }
+        for x in 0..w {
+            want.push((x, 0));
+            want.push((x, h - 1));
+        }
+        want.sort_by_key(|&(x, y)| (y, x));
+        want.dedup();
+        assert_eq!(marked, want);
+        assert!(out.arr.iter().all(|&v| v == 0 || v == 255));
+
+        // A ply above the threshold inside the padded ROI is marked; one outside is not.
+        ply_im.arr[4 * ply_im.s + 4] = 4;
+        ply_im.arr[1 * ply_im.s + 4] = 4;
+        compute_above_mask(&ply_im, &roi, 3, 2, &mut out);
+        assert_eq!(out.arr[4 * out.s + 4], 255);
+        assert_eq!(out.arr[1 * out.s + 4], 0);
+    }
+
+    #[test]
+    fn exclude_image_border_keeps_tool_off_a_flush_edge() {
+        let mut mask = MaskIm::new(5, 4);
+        exclude_image_border(&mut mask);
+        assert_eq!(
+            mask_to_ascii(&mask, None),
+            ["#####", "#...#", "#...#", "#####", ""].join("\n")
+        );
+
+        // A ply-1 pocket runs off the left edge of a ply-2 top.
+        let fixture = plan_fixture(
+            r#"
+                222222222222
+                222222222222
+                111111112222
+                111111112222
+                111111112222
+                111111112222
+                111111112222
+                111111112222
+                222222222222
+                222222222222
+            "#,
+            &[100, 200],
+            &[stub_band_desc(300, 0, "rough")],
+        );
+
+        let toolpaths = plan_toolpaths(
+            &fixture,
+            &RegionToolpathOpts {
+                tool_dia_pix: 2,
+                rdp_tolerance_pix: 0.0,
+                ..Default::default()
+            },
+        );
+
+        // The pocket is cut, but no tool center lands on the image edge.
+        assert!(toolpaths.iter().any(|tp| tp.points.iter().any(|p| p.x < 4)));
+        for tp in &toolpaths {
+            for p in &tp.points {
+                assert!(p.x >= 2, "tool center on the edge at x={} y={}", p.x, p.y);
+            }
+        }
+    }
+
+    #[test]
+    fn reachable_mask_keeps_the_tool_radius_off_walls_and_edges() {
+        let ply_im = ply_im_from_ascii(
+            r#"
+                222222222222
+                211111111112
+                211111111112
+                211111111112
+                211111111112
+                211111111112
+                211112111112
+                211111111112
+                211111111112
+                222222222222
+            "#,
+        );
+
+        // A 4px tool centers more than its 2px radius from the ply-2 wall and post.
+        let reachable = reachable_mask(&ply_im, 1, 4);
+        assert_eq!(
+            mask_to_ascii(&reachable, None),
+            [
+                "............",
+                "............",
+                "............",
+                "...######...",
+                "...##.###...",
+                "...#...##...",
+                "........#...",
+                "............",
+                "............",
+                "............",
+                "",
+            ]
+            .join("\n")
+        );
+
+        // At the top ply nothing is above, so only the edge keeps the tool away.
+        let reachable = reachable_mask(&ply_im, 2, 4);
+        for y in 0..ply_im.h {
+            for x in 0..ply_im.w {
+                let off_edge = (3..ply_im.w - 3).contains(&x) && (3..ply_im.h - 3).contains(&y);
+                assert_eq!(
+                    reachable.arr[y * reachable.s + x] != 0,
+                    off_edge,
+                    "x={x} y={y}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn find_unreachable_regions_flags_pockets_narrower_than_the_tool() {
+        // A 3px slot and a 12px pocket sunk into a ply-2 top.
+        let (w, h) = (40, 20);
+        let mut ply_im = PlyIm::new(w, h);
+        ply_im.arr.fill(2);
+        for y in 5..15 {
+            for x in (5..8).chain(20..32) {
+                ply_im.arr[y * ply_im.s + x] = 1;
+            }
+        }
+        let (_region_im, region_infos): (Im<u16, 1>, _) = label_im(&ply_im).unwrap();
+        let label_at = |x: usize, y: usize| {
+            (1..region_infos.len())
+                .find(|&i| region_infos[i].pixel_iz.contains(&(y * ply_im.s + x)))
+                .unwrap()
+        };
+        let slot = RegionI(label_at(6, 10) as u16);
+
+        // A 2px tool fits both pockets; the top is reachable everywhere off the border.
+        assert!(find_unreachable_regions(&ply_im, &region_infos, 2).is_empty());
+        // An 8px tool is within its radius of the slot walls everywhere but fits the pocket.
+        assert_eq!(
+            find_unreachable_regions(&ply_im, &region_infos, 8),
+            vec![slot]
+        );
+        // A 14px tool fits neither pocket.
+        let unreachable = find_unreachable_regions(&ply_im, &region_infos, 14);
+        assert_eq!(unreachable.len(), 2);
+        assert!(unreachable.contains(&slot));
+        assert!(unreachable.contains(&RegionI(label_at(25, 10) as u16)));
+    }
+
+    #[test]
+    fn validate_checks_cuts_and_closed_loops() {
+        let v = |x: i32, y: i32| IV3 { x, y, z: 100 };
+        let tp = |points: Vec<IV3>, closed: bool| ToolPath {
+            cuts: vec![CutPixels::default(); points.len()],
+            points,
+            closed,
+            tool_dia_pix: 4,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+
+        let square = tp(vec![v(0, 0), v(4, 0), v(4, 4), v(0, 4), v(0, 0)], true);
+        assert_eq!(square.validate(), Ok(()));
+        assert_eq!(tp(vec![v(0, 0), v(4, 0)], false).validate(), Ok(()));
+
+        let mut short_cuts = square.clone();
+        short_cuts.cuts.pop();
+        assert_eq!(
+            short_cuts.validate(),
+            Err(ToolPathError::CutsLenMismatch {
+                n_points: 5,
+                n_cuts: 4
+            })
+        );
+        let mut nan_feed = square.clone();
+        nan_feed.cuts[2].feed_scale = f32::NAN;
+        assert_eq!(
+            nan_feed.validate(),
+            Err(ToolPathError::NonFiniteFeedScale { seg_i: 2 })
+        );
 
-            // Never traverse below either endpoint's commanded Z.
-            safe_z_i32 = safe_z_i32.max(from_vert.z).max(to_vert.z);
+        let unclosed = tp(vec![v(0, 0), v(4, 0), v(4, 4), v(0, 4)], true);
+        assert_eq!(
+            unclosed.validate(),
+            Err(ToolPathError::ClosedWithoutClosingVertex)
+        );
+        let sliver = tp(vec![v(0, 0), v(4, 0), v(0, 0), v(4, 0), v(0, 0)], true);
+        let e = sliver.validate().unwrap_err();
+        assert_eq!(e, ToolPathError::ClosedTooFewVertices { n_distinct: 2 });
+        assert!(e.is_degenerate());
+        let e = tp(vec![v(0, 0)], false).validate().unwrap_err();
+        assert_eq!(e, ToolPathError::TooFewPoints { n_points: 1 });
+        assert!(e.is_degenerate());
+    }
 
-            Some(vec![
-                IV3 {
-                    x: from_vert.x,
-                    y: from_vert.y,
-                    z: from_vert.z,
-                },
-                IV3 {
-                    x: from_vert.x,
-                    y: from_vert.y,
-                    z: safe_z_i32,
-                },
-                IV3 {
-                    x: to_vert.x,
-                    y: to_vert.y,
-                    z: to_vert.z,
-                },
-            ])
+    #[test]
+    fn toolpaths_json_round_trip() {
+        let v = |x: i32, y: i32, z: i32| IV3 { x, y, z };
+        let tps = vec![
+            ToolPath {
+                points: vec![v(1, 2, 300), v(5, 2, 300), v(5, -4, 250)],
+                closed: true,
+                tool_dia_pix: 7,
+                tool_i: 1,
+                tile_i: 2,
+                tree_node_id: 9,
+                cuts: vec![
+                    CutPixels {
+                        pixels_changed: 12,
+                        depth_sum_thou: 340,
+                        feed_scale: 0.5,
+                        max_engagement_frac: 0.75,
+                        is_tab: true,
+                    },
+                    CutPixels::default(),
+                    CutPixels::default(),
+                ],
+                is_traverse: false,
+                is_raster: true,
+                is_plunge_run: false,
+            },
+            ToolPath {
+                points: vec![v(5, -4, 250), v(0, 0, 250)],
+                closed: false,
+                tool_dia_pix: 7,
+                tool_i: 1,
+                tile_i: 2,
+                tree_node_id: 0,
+                cuts: vec![CutPixels::default(); 2],
+                is_traverse: true,
+                is_raster: false,
+                is_plunge_run: false,
+            },
+        ];
 
-        });
+        let json = toolpaths_to_json(&tps);
+        assert_eq!(toolpaths_from_json(&json).unwrap(), tps);
+        assert!(toolpaths_from_json("[{\"points\": []}]").is_err());
+    }
 
-        let traverse_verts = traverse_verts_opt.unwrap_or_default();
+    #[test]
+    fn perimeters_cut_each_exterior_then_its_holes_with_opposite_winding() {
+        // Blob A (with a hole) is traced first, then blob B, then A's hole.
+        let mut im = Im::<i32, 1>::new(40, 30);
+        let mut fill = |l: usize, t: usize, r: usize, b: usize, v: i32| {
+            for y in t..b {
+                for x in l..r {
+                    im.arr[y * im.s + x] = v;
+                }
+            }
+        };
+        fill(2, 2, 20, 20, 1);
+        fill(8, 10, 14, 16, 0);
+        fill(25, 4, 35, 12, 1);
+        let contours = contours_by_suzuki_abe(&mut im);
+        let kinds: Vec<bool> = contours.iter().map(|c| c.is_hole).collect();
+        assert_eq!(kinds, vec![false, false, true]);
+        assert_eq!(contours[2].parent, Some(0));
+
+        let first_xy = |tp: &ToolPath| (tp.points[0].x, tp.points[0].y);
+        let area2 = |tp: &ToolPath| {
+            let ring: Vec<Iv2> = tp.points.iter().map(|p| Iv2 { x: p.x, y: p.y }).collect();
+            ring_signed_area2(&ring)
+        };
 
-        let n_verts = traverse_verts.len();
+        let tps = create_perimeter_tool_paths(&contours, Thou(100), 0, 4, 3);
+        assert_eq!(tps.len(), 3);
+        let firsts: Vec<(i32, i32)> = tps.iter().map(first_xy).collect();
+        let contour_firsts: Vec<(i32, i32)> = contours
+            .iter()
+            .map(|c| (c.points[0].x, c.points[0].y))
+            .collect();
+        assert!(firsts[0] == contour_firsts[0] && firsts[2] == contour_firsts[1]);
+        let is_closed_ring = |tp: &ToolPath| tp.closed && tp.points.first() == tp.points.last();
+        assert!(tps.iter().all(is_closed_ring));
+        assert!(area2(&tps[0]) < 0 && area2(&tps[1]) > 0 && area2(&tps[2]) < 0);
+
+        // The inward offset keeps A's hole attached to A's offset piece.
+        let offset = offset_contours_inward(&contours, 1.0);
+        assert_eq!(offset.len(), 3);
+        let hole_i = offset.iter().position(|c| c.is_hole).unwrap();
+        let parent_i = offset[hole_i].parent.unwrap();
+        assert!(!offset[parent_i].is_hole && offset[parent_i].id == contours[0].id);
+    }
 
-        traverse_paths.push(ToolPath {
-            points: traverse_verts,
+    #[test]
+    fn detail_toolpaths_trace_wall_bottoms_with_leftover() {
+        // A floor at 100 with a 10x10 block up to 500 in the middle.
+        let (w, h) = (40, 30);
+        let mut target = Lum16Im::new(w, h);
+        target.arr.fill(100);
+        for y in 10..20 {
+            for x in 10..20 {
+                target.arr[y * target.s + x] = 500;
+            }
+        }
+
+        // Nothing left over => no detail pass.
+        assert!(create_detail_toolpaths(&target, &target, 2, 3, 50).is_empty());
+
+        // Leftover material only along the block's left wall.
+        let mut heights = target.clone();
+        for y in 10..20 {
+            for x in 6..10 {
+                heights.arr[y * heights.s + x] = 300;
+            }
+        }
+        for tool_dia_pix in [3, 5] {
+            let mut toolpaths = create_detail_toolpaths(&heights, &target, 2, tool_dia_pix, 50);
+            assert!(!toolpaths.is_empty());
+            for tp in &toolpaths {
+                assert!(!tp.closed, "only part of the loop needs work");
+                assert_eq!((tp.tool_i, tp.tool_dia_pix), (2, tool_dia_pix));
+                for p in &tp.points {
+                    assert_eq!(p.z, 100);
+                    assert!(p.x < 10, "stays by the left wall: {p:?}");
+                }
+            }
+
+            // Simulating the pass never gouges and clears the leftover next to the wall.
+            let mut sim_im = heights.clone();
+            let _ = crate::sim::sim_toolpaths(
+                &mut sim_im,
+                &mut toolpaths,
+                None,
+                None,
+                crate::sim::SimOptions::default(),
+            );
+            for (i, (&v, &t)) in sim_im.arr.iter().zip(target.arr.iter()).enumerate() {
+                assert!(v >= t, "gouged at {:?}", target.idx_to_xyc(i));
+            }
+            for y in 10..20 {
+                assert_eq!(sim_im.arr[y * sim_im.s + 9], 100, "wall bottom at y={y}");
+            }
+        }
+    }
+
+    #[test]
+    fn recenter_to_origin_moves_min_corner_to_zero() {
+        let mut toolpaths = vec![
+            ToolPath {
+                points: vec![IV3 { x: 12, y: 7, z: 50 }, IV3 { x: 20, y: 7, z: 50 }],
+                closed: false,
+                tool_dia_pix: 1,
+                tool_i: 0,
+                tile_i: 0,
+                tree_node_id: 0,
+                cuts: vec![CutPixels::default(); 2],
+                is_traverse: false,
+                is_raster: false,
+                is_plunge_run: false,
+            },
+            ToolPath {
+                points: vec![IV3 { x: 15, y: 3, z: 40 }, IV3 { x: 15, y: 9, z: 40 }],
+                closed: false,
+                tool_dia_pix: 1,
+                tool_i: 0,
+                tile_i: 0,
+                tree_node_id: 0,
+                cuts: vec![CutPixels::default(); 2],
+                is_traverse: false,
+                is_raster: false,
+                is_plunge_run: false,
+            },
+        ];
+
+        let offset = recenter_to_origin(&mut toolpaths);
+        assert_eq!(
+            offset,
+            IV3 {
+                x: -12,
+                y: -3,
+                z: 0
+            }
+        );
+
+        let min_x = toolpaths
+            .iter()
+            .flat_map(|tp| tp.points.iter())
+            .map(|p| p.x)
+            .min();
+        let min_y = toolpaths
+            .iter()
+            .flat_map(|tp| tp.points.iter())
+            .map(|p| p.y)
+            .min();
+        assert_eq!(min_x, Some(0));
+        assert_eq!(min_y, Some(0));
+        assert_eq!(toolpaths[0].points[1], IV3 { x: 8, y: 4, z: 50 });
+        assert_eq!(toolpaths[1].points[0], IV3 { x: 3, y: 0, z: 40 });
+
+        // Already at the origin => no-op.
+        assert_eq!(recenter_to_origin(&mut toolpaths), IV3 { x: 0, y: 0, z: 0 });
+    }
+
+    #[test]
+    fn toolpaths_bbox_spans_all_points_and_filters_by_tool() {
+        let tp = |tool_i: usize, points: Vec<IV3>| ToolPath {
+            cuts: vec![CutPixels::default(); points.len()],
+            points,
             closed: false,
-            tool_dia_pix,
+            tool_dia_pix: 1,
             tool_i,
-            tile_i: tp_tile_i,
-            tree_node_id: toolpath_i, // Encode the source toolpath index
-            cuts: vec![CutPixels::default(); n_verts],
-            is_traverse: true,
+            tile_i: 0,
+            tree_node_id: 0,
+            is_traverse: false,
             is_raster: false,
-        });
-    };
+            is_plunge_run: false,
+        };
+        let v = |x: i32, y: i32, z: i32| IV3 { x, y, z };
+        let toolpaths = vec![
+            tp(0, vec![v(12, 7, 50), v(20, 2, 90)]),
+            tp(1, vec![v(-3, 30, 10)]),
+            tp(0, vec![]),
+        ];
 
-    crate::sim::sim_toolpaths(before_sim_im, &mut toolpaths[..], Some(&mut callback));
+        assert_eq!(
+            toolpaths_bbox(&toolpaths),
+            Some((v(-3, 2, 10), v(20, 30, 90)))
+        );
+        assert_eq!(
+            toolpaths_bbox_for_tool(&toolpaths, 0),
+            Some((v(12, 2, 50), v(20, 7, 90)))
+        );
+        assert_eq!(toolpaths_bbox_for_tool(&toolpaths, 2), None);
+        assert_eq!(toolpaths_bbox(&[]), None);
+    }
 
-    traverse_paths
-}
+    fn cut(pixels_changed: u64) -> CutPixels {
+        CutPixels {
+            pixels_changed,
+            ..Default::default()
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::desc::{CompDesc, Guid, parse_comp_json};
-    use crate::im::label::label_im;
-    use crate::region_tree::{create_cut_bands, create_region_tree};
-    use crate::test_helpers::{
-        im_u16_to_ascii, mask_to_ascii, ply_im_from_ascii, stub_band_desc, stub_ply_desc,
-        toolpaths_to_ascii,
-    };
+    #[test]
+    fn cull_splits_open_toolpath_on_empty_segments() {
+        let mut toolpaths = vec![ToolPath {
+            points: vec![
+                IV3 { x: 0, y: 0, z: 0 },
+                IV3 { x: 1, y: 0, z: 0 },
+                IV3 { x: 2, y: 0, z: 0 },
+                IV3 { x: 3, y: 0, z: 0 },
+            ],
+            closed: false,
+            tool_dia_pix: 1,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![cut(5), cut(0), cut(7), CutPixels::default()],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        }];
+
+        cull_empty_toolpaths(&mut toolpaths);
+        assert_eq!(toolpaths.len(), 2);
+        assert_eq!(toolpaths[0].points.len(), 2);
+        assert_eq!(toolpaths[1].points.len(), 2);
+        assert_eq!(toolpaths[0].points[0].x, 0);
+        assert_eq!(toolpaths[0].points[1].x, 1);
+        assert_eq!(toolpaths[1].points[0].x, 2);
+        assert_eq!(toolpaths[1].points[1].x, 3);
+        assert_eq!(toolpaths[0].cuts[0].pixels_changed, 5);
+        assert_eq!(toolpaths[1].cuts[0].pixels_changed, 7);
+    }
+
+    #[test]
+    fn cull_breaks_closed_toolpath_into_open_runs() {
+        // Triangle loop 0->1->2->0, but the middle edge cuts nothing.
+        let p0 = IV3 { x: 0, y: 0, z: 0 };
+        let p1 = IV3 { x: 1, y: 0, z: 0 };
+        let p2 = IV3 { x: 2, y: 0, z: 0 };
+
+        let mut toolpaths = vec![ToolPath {
+            points: vec![p0, p1, p2, p0],
+            closed: true,
+            tool_dia_pix: 1,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            cuts: vec![cut(3), cut(0), cut(4), CutPixels::default()],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        }];
+
+        cull_empty_toolpaths(&mut toolpaths);
+        assert_eq!(toolpaths.len(), 1);
+        assert!(!toolpaths[0].closed);
+        // Remaining segments: 2->0 and 0->1, i.e. points [2,0,1].
+        assert_eq!(toolpaths[0].points, vec![p2, p0, p1]);
+        assert_eq!(toolpaths[0].cuts[0].pixels_changed, 4);
+        assert_eq!(toolpaths[0].cuts[1].pixels_changed, 3);
+    }
+
+    #[test]
+    fn clear_mode_perimeter_only_skips_rasters_of_a_ring() {
+        // A ring of ply 1 cut down around a ply 2 island, inside a ply 2 frame.
+        let toolpaths_for = |clear_mode: ClearMode| {
+            let band_descs = [BandDesc {
+                clear_mode,
+                ..stub_band_desc(300, 0, "rough")
+            }];
+            let fixture = plan_fixture(
+                r#"
+                    2222222222222222
+                    2222222222222222
+                    2211111111111122
+                    2211111111111122
+                    2211111111111122
+                    2211122222211122
+                    2211122222211122
+                    2211122222211122
+                    2211122222211122
+                    2211122222211122
+                    2211122222211122
+                    2211111111111122
+                    2211111111111122
+                    2211111111111122
+                    2222222222222222
+                    2222222222222222
+                "#,
+                &[100, 200],
+                &band_descs,
+            );
+            plan_toolpaths(
+                &fixture,
+                &RegionToolpathOpts {
+                    tool_dia_pix: 2,
+                    rdp_tolerance_pix: 0.0,
+                    ..Default::default()
+                },
+            )
+        };
+        let n_raster = |paths: &[ToolPath]| paths.iter().filter(|tp| tp.is_raster).count();
+        let n_perimeter = |paths: &[ToolPath]| {
+            paths
+                .iter()
+                .filter(|tp| !tp.is_raster && !tp.is_traverse)
+                .count()
+        };
+
+        let full = toolpaths_for(ClearMode::Full);
+        assert!(n_raster(&full) > 0);
+        assert!(n_perimeter(&full) > 0);
+
+        let perimeter_only = toolpaths_for(ClearMode::PerimeterOnly);
+        assert_eq!(n_raster(&perimeter_only), 0);
+        // The ring has an outer and an inner wall.
+        assert!(n_perimeter(&perimeter_only) >= 2);
+        assert_eq!(n_perimeter(&perimeter_only), n_perimeter(&full));
+
+        let raster_only = toolpaths_for(ClearMode::RasterOnly);
+        assert_eq!(n_raster(&raster_only), n_raster(&full));
+        assert_eq!(n_perimeter(&raster_only), 0);
+    }
+
+    #[test]
+    fn mark_reversals_flags_row_ends_but_not_gentle_bends() {
+        let v = |x: i32, y: i32| IV3 { x, y, z: 100 };
+        let tp = |points: Vec<IV3>| ToolPath {
+            cuts: vec![CutPixels::default(); points.len()],
+            points,
+            closed: false,
+            tool_dia_pix: 6,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 0,
+            is_traverse: false,
+            is_raster: true,
+            is_plunge_run: false,
+        };
+        // A snake over three rows with a 2px stepover, and a line bending by about 10°.
+        let snake = tp(vec![
+            v(0, 0),
+            v(20, 0),
+            v(20, 2),
+            v(0, 2),
+            v(0, 4),
+            v(20, 4),
+        ]);
+        let bend = tp(vec![v(0, 0), v(20, 0), v(40, 4)]);
+
+        let flags = mark_reversals(&[snake, bend.clone()], 45.0);
+        // Each row end is one reversal; the far end of its stepover belongs to the same one.
+        assert_eq!(flags[0], [false, true, false, true, false, false]);
+        assert_eq!(flags[1], [false, false, false]);
+        // A threshold under the bend's angle catches it.
+        assert_eq!(mark_reversals(&[bend], 5.0)[0], [false, true, false]);
+    }
+
+    #[test]
+    fn trochoidalize_loops_along_the_slot() {
+        let v = |x: i32, y: i32| IV3 { x, y, z: 300 };
+        let tp = ToolPath {
+            points: vec![v(2, 10), v(42, 10)],
+            cuts: vec![CutPixels::default(); 2],
+            closed: false,
+            tool_dia_pix: 4,
+            tool_i: 1,
+            tile_i: 0,
+            tree_node_id: 7,
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+
+        // The radius is capped by the slot: (12 - 4) / 2 = 4.
+        let out = trochoidalize(&tp, 12, 10, 2, None);
+        assert_eq!(out.len(), 1);
+        let troch = &out[0];
+        assert_eq!(troch.tree_node_id, 7);
+        assert_eq!(troch.tool_i, 1);
+        assert_eq!(troch.cuts.len(), troch.points.len());
+        assert_eq!(troch.points.first(), tp.points.first());
+        assert_eq!(troch.points.last(), tp.points.last());
+        assert!(
+            troch
+                .points
+                .iter()
+                .all(|p| (p.y - 10).abs() <= 4 && p.z == 300)
+        );
+        assert!(troch.points.iter().all(|p| p.x >= 2 && p.x <= 42));
+        // Both sides of the slot are reached, by many loops.
+        assert!(troch.points.iter().any(|p| p.y == 6));
+        assert!(troch.points.iter().any(|p| p.y == 14));
+        assert!(troch.points.len() > 100);
+
+        // A mask as wide as the loops keeps them; a narrower one drops every loop.
+        let mask_rows = |t: usize, b: usize| {
+            let mut mask = MaskIm::new(50, 20);
+            for y in t..b {
+                mask.arr[y * mask.s..y * mask.s + mask.w].fill(255);
+            }
+            mask
+        };
+        let wide = mask_rows(6, 15);
+        assert_eq!(
+            trochoidalize(&tp, 12, 10, 2, Some(&wide))[0].points,
+            troch.points
+        );
+        let narrow = mask_rows(8, 13);
+        assert_eq!(
+            trochoidalize(&tp, 12, 10, 2, Some(&narrow))[0].points,
+            tp.points
+        );
+
+        // No room in the slot, or a traverse: unchanged.
+        assert_eq!(trochoidalize(&tp, 5, 10, 2, None)[0].points, tp.points);
+        let traverse = ToolPath {
+            is_traverse: true,
+            ..tp.clone()
+        };
+        assert_eq!(
+            trochoidalize(&traverse, 12, 10, 2, None)[0].points,
+            tp.points
+        );
+    }
+
+    #[test]
+    fn round_corners_fillets_gentle_corners_only() {
+        let v = |x: i32, y: i32| IV3 { x, y, z: 300 };
+        let tp = |points: Vec<IV3>, closed: bool| ToolPath {
+            cuts: vec![CutPixels::default(); points.len()],
+            points,
+            closed,
+            tool_dia_pix: 4,
+            tool_i: 1,
+            tile_i: 0,
+            tree_node_id: 7,
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+        let dist = |p: &IV3, q: &IV3| ((p.x - q.x) as f64).hypot((p.y - q.y) as f64);
+
+        // A 45° corner: the fillet replaces the vertex and comes within max_dev of it.
+        let corner = v(20, 0);
+        let out = round_corners(&tp(vec![v(0, 0), corner, v(40, 20)], false), 40.0, 1.5);
+        assert_eq!(out.tree_node_id, 7);
+        assert_eq!(out.cuts.len(), out.points.len());
+        assert_eq!(out.points[0], v(0, 0));
+        assert_eq!(*out.points.last().unwrap(), v(40, 20));
+        assert!(!out.points.contains(&corner));
+        assert!(out.points.len() > 5);
+        let closest = out
+            .points
+            .iter()
+            .map(|p| dist(p, &corner))
+            .fold(f64::MAX, f64::min);
+        assert!((closest - 1.5).abs() <= 1.0, "closest={closest}");
+        // The arc stays on the inside of the corner.
+        assert!(out.points.iter().all(|p| p.y >= 0 && p.y >= p.x - 21));
+
+        // A right angle, a turn of over 90° (here ~153°) and a Z change are left sharp.
+        let right = tp(vec![v(0, 0), v(20, 0), v(20, 20)], false);
+        assert_eq!(round_corners(&right, 10.0, 3.0).points, right.points);
+        let sharp = tp(vec![v(0, 0), v(20, 0), v(0, 10)], false);
+        assert_eq!(round_corners(&sharp, 10.0, 3.0).points, sharp.points);
+        let mut plunge = tp(vec![v(0, 0), v(20, 0), v(40, 20)], false);
+        plunge.points[2].z = 200;
+        assert_eq!(round_corners(&plunge, 10.0, 3.0).points, plunge.points);
+
+        // A closed square perimeter keeps its corners.
+        let square = tp(vec![v(0, 0), v(20, 0), v(20, 20), v(0, 20), v(0, 0)], true);
+        assert_eq!(round_corners(&square, 4.0, 3.0).points, square.points);
+
+        // A closed octagon has all eight corners rounded and stays closed.
+        let corners = [
+            v(10, 0),
+            v(30, 0),
+            v(40, 10),
+            v(40, 30),
+            v(30, 40),
+            v(10, 40),
+            v(0, 30),
+            v(0, 10),
+        ];
+        let mut ring = corners.to_vec();
+        ring.push(ring[0]);
+        let out = round_corners(&tp(ring, true), 20.0, 3.0);
+        assert!(out.closed);
+        assert_eq!(out.points[0], *out.points.last().unwrap());
+        for c in corners {
+            assert!(!out.points.contains(&c));
+        }
+        assert!(out.validate().is_ok());
+    }
+
+    #[test]
+    fn add_holding_tabs_leaves_tabs_on_straight_stretches() {
+        let v = |x: i32, y: i32| IV3 { x, y, z: 100 };
+        let up = |x: i32, y: i32| IV3 { x, y, z: 150 };
+        let corners = [v(0, 0), v(40, 0), v(40, 20), v(0, 20)];
+        let mut points = corners.to_vec();
+        points.push(points[0]);
+        let tp = ToolPath {
+            cuts: vec![CutPixels::default(); points.len()],
+            points,
+            closed: true,
+            tool_dia_pix: 4,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 3,
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+
+        // Runs of raised points, one per tab.
+        let tab_runs = |out: &ToolPath| {
+            let mut runs: Vec<Vec<IV3>> = Vec::new();
+            let mut prev_raised = false;
+            for &p in &out.points {
+                let is_raised = p.z == 150;
+                if is_raised && !prev_raised {
+                    runs.push(Vec::new());
+                }
+                if is_raised {
+                    runs.last_mut().unwrap().push(p);
+                }
+                prev_raised = is_raised;
+            }
+            runs
+        };
 
-    fn count_cut_leaves(node: &crate::region_tree::RegionNode) -> usize {
-        match node {
-            crate::region_tree::RegionNode::Cut { .. } => 1,
-            crate::region_tree::RegionNode::Floor { children, .. } => {
-                children.iter().map(count_cut_leaves).sum()
+        // Perimeter 120, so two 6 px tabs centered at 30 and 90: mid-way along the long sides.
+        let out = add_holding_tabs(&tp, 50, 6, 2);
+        assert_eq!(out.validate(), Ok(()));
+        assert_eq!(out.tree_node_id, 3);
+        assert!(out.points.iter().all(|p| p.z == 100 || p.z == 150));
+        let runs = tab_runs(&out);
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0], vec![up(27, 0), up(33, 0)]);
+        assert_eq!(runs[1], vec![up(13, 20), up(7, 20)]);
+        // Each tab steps straight up and straight back down.
+        let up_i = out.points.iter().position(|p| p.z == 150).unwrap();
+        assert_eq!(out.points[up_i - 1], v(27, 0));
+        assert_eq!(out.points[up_i + 2], v(33, 0));
+        // The moves up and down and the run on top are the tab's segments; nothing else is.
+        let is_tab = |tp: &ToolPath| -> Vec<bool> { tp.cuts.iter().map(|c| c.is_tab).collect() };
+        let tab_segs: Vec<usize> = (0..out.cuts.len())
+            .filter(|&i| out.cuts[i].is_tab)
+            .collect();
+        assert_eq!(tab_segs.len(), 6);
+        assert_eq!(tab_segs[..3], [up_i - 1, up_i, up_i + 1]);
+        // The sim keeps the marks when it resets the cut counts.
+        let mut simmed = vec![out.clone()];
+        let mut im = Lum16Im::new(48, 28);
+        im.arr.fill(200);
+        let _ = crate::sim::sim_toolpaths(
+            &mut im,
+            &mut simmed,
+            None,
+            None,
+            crate::sim::SimOptions::default(),
+        );
+        assert!(simmed[0].cuts.iter().any(|c| c.pixels_changed > 0));
+        assert_eq!(is_tab(&simmed[0]), is_tab(&out));
+
+        // With four tabs the second share's middle (45) is near the corner at 40, so that tab
+        // slides along until it and the tool radius clear the corner.
+        let out = add_holding_tabs(&tp, 50, 6, 4);
+        assert_eq!(out.validate(), Ok(()));
+        let runs = tab_runs(&out);
+        assert_eq!(runs.len(), 4);
+        for p in runs.iter().flatten() {
+            for c in &corners {
+                assert!(
+                    ((p.x - c.x) as f64).hypot((p.y - c.y) as f64) > 2.0,
+                    "{p:?}"
+                );
             }
         }
+
+        // Tabs that can't fit in their share, and open paths, are left alone.
+        assert_eq!(add_holding_tabs(&tp, 50, 40, 4), tp);
+        let open = ToolPath {
+            closed: false,
+            ..tp.clone()
+        };
+        assert_eq!(add_holding_tabs(&open, 50, 6, 2), open);
     }
 
     #[test]
-    fn surface_tool_path_generation_smoke_test() {
-        // Build a non-trivial region tree (must contain Cut leaves) and ensure
-        // toolpath generation runs without panicking.
+    fn heightmap_diff_counts_and_colors_changes() {
+        use crate::sim::{heightmap_diff, heightmap_diff_rgba, make_stock};
+
+        let a = make_stock(4, 3, 500);
+        let same = heightmap_diff(&a, &a.clone());
+        assert_eq!(same.n_changed, 0);
+        assert_eq!(same.max_abs_delta, 0);
+        assert!(same.changed_mask.arr.iter().all(|&v| v == 0));
+
+        let mut b = a.clone();
+        b.arr[b.s + 1] = 400; // b deeper
+        b.arr[2 * b.s + 3] = 550; // a deeper
+        let diff = heightmap_diff(&a, &b);
+        assert_eq!(diff.n_changed, 2);
+        assert_eq!(diff.max_abs_delta, 100);
+        let set: Vec<usize> = (0..diff.changed_mask.arr.len())
+            .filter(|&i| diff.changed_mask.arr[i] != 0)
+            .collect();
+        assert_eq!(set, vec![b.s + 1, 2 * b.s + 3]);
+
+        let rgba = heightmap_diff_rgba(&a, &b);
+        let px = |x: usize, y: usize| &rgba.arr[y * rgba.s + x * 4..y * rgba.s + x * 4 + 4];
+        assert_eq!(px(0, 0), [0, 0, 0, 255]);
+        assert_eq!(px(1, 1), [0, 0, 255, 255]);
+        assert_eq!(px(3, 2), [64 + 191 / 2, 0, 0, 255]);
+    }
 
-        let ply_im = ply_im_from_ascii(
-            r#"
-                11111
-                12221
-                12321
-                12221
-                11111
-            "#,
-        );
+    #[test]
+    fn raster_surface_is_empty_for_rois_off_the_centerable_area() {
+        let mut mask = MaskIm::new(10, 10);
+        mask.arr.fill(255);
+        let raster = |roi: ROI| {
+            create_raster_surface_tool_paths_from_cut_mask(&mask, &roi, 0, 4, 1, Thou(0), 0)
+        };
+        let roi = |l: usize, t: usize, r: usize, b: usize| ROI { l, t, r, b };
 
-        // Dummy + 3 real plies (values 1,2,3). We only need enough info to build cut bands/tree.
-        let ply_descs = vec![
-            stub_ply_desc("dummy", 0, true),
-            stub_ply_desc("ply100", 100, false),
-            stub_ply_desc("ply200", 200, false),
-            stub_ply_desc("ply300", 300, false),
-        ];
+        assert!(!raster(roi(0, 0, 10, 10)).is_empty());
+        // Past the image, and within the tool radius of its edge.
+        assert!(raster(roi(12, 0, 20, 10)).is_empty());
+        assert!(raster(roi(0, 0, 2, 10)).is_empty());
+        assert!(raster(roi(0, 8, 10, 10)).is_empty());
+    }
 
-        let band_descs = vec![stub_band_desc(400, 0, "rough")];
+    #[test]
+    fn stepover_frac_scales_with_the_tool() {
+        assert_eq!(stepover_pix(20, 0.5), 10);
+        assert_eq!(stepover_pix(10, 0.4), 4);
+        assert_eq!(stepover_pix(7, 0.8), 6);
+        // Never below one pixel.
+        assert_eq!(stepover_pix(1, 0.4), 1);
+        assert_eq!(stepover_pix(20, 0.0), 1);
+
+        assert_eq!(Stepover::Frac(0.5).to_pix(20), 10);
+        assert_eq!(Stepover::Frac(0.5).to_pix(40), 20);
+        assert_eq!(Stepover::Pix(3).to_pix(40), 3);
+        assert_eq!(Stepover::Pix(0).to_pix(40), 1);
+    }
 
-        let (region_im_raw, region_infos) = label_im(&ply_im);
-        let region_im: RegionIm = region_im_raw.retag::<crate::region_tree::RegionI>();
+    #[test]
+    fn scallop_height_and_stepover_invert() {
+        // A 1/4in ball (R = 125 thou) at a 50 thou stepover:
+        // h = 125 - sqrt(125² - 25²) = 125 - sqrt(15000) = 2.5255 thou.
+        assert!((scallop_height_thou(125.0, 50.0) - 2.5255).abs() < 1e-4);
+        // A 2 thou scallop: s = 2 sqrt(2 * (250 - 2)) = 2 sqrt(496) = 44.542 thou.
+        assert!((stepover_for_scallop(125.0, 2.0) - 44.542).abs() < 1e-3);
+        for s in [0.0, 10.0, 50.0, 200.0] {
+            let h = scallop_height_thou(125.0, s);
+            assert!((stepover_for_scallop(125.0, h) - s).abs() < 1e-6);
+        }
+        // Past the full diameter the cusp is the whole radius.
+        assert_eq!(scallop_height_thou(125.0, 300.0), 125.0);
+        assert_eq!(stepover_for_scallop(125.0, 500.0), 250.0);
 
-        let cut_bands = create_cut_bands(
-            "rough",
-            &ply_im,
-            &band_descs,
-            &region_im,
-            &region_infos,
-            &ply_descs,
-        );
+        // At 1000 ppi the tool is 250 px across, so a 2 thou scallop steps 45 px.
+        assert_eq!(Stepover::for_scallop(125.0, 2.0).to_pix(250), 45);
+    }
 
-        let region_root = create_region_tree(&cut_bands, &region_infos);
-        let total_cut_leaves: usize = region_root.children().iter().map(count_cut_leaves).sum();
-        assert!(total_cut_leaves > 0, "test setup must produce cut leaves");
+    #[test]
+    fn split_toolpaths_at_keep_out_splits_straddling_paths() {
+        let mut keep_out = MaskIm::new(20, 20);
+        for y in 8..12 {
+            for x in 8..12 {
+                keep_out.arr[y * keep_out.s + x] = 255;
+            }
+        }
+        let v = |x: i32, y: i32| IV3 { x, y, z: 100 };
+        let tp = |points: Vec<IV3>, closed: bool| ToolPath {
+            cuts: vec![CutPixels::default(); points.len()],
+            points,
+            closed,
+            tool_dia_pix: 2,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id: 3,
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
 
-        let tool_dia_pix = 2_usize;
-        let tool_step_pix = 1_usize;
-        let paths = create_toolpaths_from_region_tree(
-            "test",
-            &region_root,
-            &cut_bands,
-            0,
-            tool_dia_pix,
-            tool_step_pix,
-            0,
-            Thou(0),
-            &ply_im,
-            &region_im,
-            None,
-            &region_infos,
-            0,
-            1,
-            true,
-            None,
-        );
+        let mut toolpaths = vec![
+            // Both endpoints are clear but the line crosses the keep-out.
+            tp(vec![v(2, 10), v(6, 10), v(14, 10), v(18, 10)], false),
+            // A loop with one side through the keep-out opens there.
+            tp(vec![v(2, 2), v(10, 2), v(10, 18), v(2, 18), v(2, 2)], true),
+            // Clear of it entirely.
+            tp(vec![v(2, 4), v(18, 4)], false),
+        ];
+        split_toolpaths_at_keep_out(&mut toolpaths, &keep_out);
 
-        assert!(!paths.is_empty(), "expected non-empty raster toolpaths");
-        assert!(
-            paths.iter().all(|p| p.points.len() >= 2),
-            "each toolpath should have at least a start and end point"
-        );
-        assert!(
-            paths
-                .iter()
-                .all(|p| p.points.iter().all(|pt| matches!(pt.z, 100 | 200 | 300))),
-            "surface raster z should come from cut plane top_thou"
+        let points: Vec<Vec<IV3>> = toolpaths.iter().map(|tp| tp.points.clone()).collect();
+        assert_eq!(
+            points,
+            vec![
+                vec![v(2, 10), v(6, 10)],
+                vec![v(14, 10), v(18, 10)],
+                vec![v(10, 18), v(2, 18), v(2, 2), v(10, 2)],
+                vec![v(2, 4), v(18, 4)],
+            ]
         );
+        assert!(toolpaths.iter().all(|tp| !tp.closed));
+        assert!(toolpaths.iter().all(|tp| tp.tree_node_id == 3));
+        assert!(toolpaths.iter().all(|tp| tp.cuts.len() == tp.points.len()));
     }
 
     #[test]
-    fn raster_surface_toolpaths_basic_runs() {
-        let mut m = MaskIm::new(6, 3);
+    fn keep_out_is_never_cut() {
+        let fixture = plan_fixture(
+            r#"
+                22222222222222222222
+                22222222222222222222
+                22111111111111111122
+                22111111111111111122
+                22111111111111111122
+                22111111111111111122
+                22111111111111111122
+                22111111111111111122
+                22111111111111111122
+                22111111111111111122
+                22111111111111111122
+                22111111111111111122
+                22111111111111111122
+                22111111111111111122
+                22222222222222222222
+                22222222222222222222
+            "#,
+            &[100, 200],
+            &[stub_band_desc(300, 0, "rough")],
+        );
+        let (ply_im, ..) = &fixture;
 
-        // y=0: ..###.
-        for x in 2..5 {
-            m.arr[0 * m.s + x] = 255;
+        // A clamp in the middle of the pocket.
+        let mut keep_out = MaskIm::new(ply_im.w, ply_im.h);
+        for y in 6..10 {
+            for x in 8..12 {
+                keep_out.arr[y * keep_out.s + x] = 255;
+            }
         }
 
-        // y=1: #.#..#
-        m.arr[1 * m.s + 0] = 255;
-        m.arr[1 * m.s + 2] = 255;
-        m.arr[1 * m.s + 5] = 255;
-
-        // y=2: (empty)
-
-        let roi = ROI {
-            l: 0,
-            t: 0,
-            r: 6,
-            b: 3,
-        };
-        let paths = create_raster_surface_tool_paths_from_cut_mask(&m, &roi, 0, 1, 1, Thou(123), 0);
-
-        // Expect 1 run on y=0 and 3 runs on y=1.
-        assert_eq!(paths.len(), 4);
-
-        assert_eq!(paths[0].points[0], IV3 { x: 2, y: 0, z: 123 });
-        assert_eq!(paths[0].points[1], IV3 { x: 4, y: 0, z: 123 });
-
-        assert_eq!(paths[1].points[0], IV3 { x: 0, y: 1, z: 123 });
-        assert_eq!(paths[1].points[1], IV3 { x: 0, y: 1, z: 123 });
-
-        assert_eq!(paths[2].points[0], IV3 { x: 2, y: 1, z: 123 });
-        assert_eq!(paths[2].points[1], IV3 { x: 2, y: 1, z: 123 });
+        let toolpaths = plan_toolpaths(
+            &fixture,
+            &RegionToolpathOpts {
+                tool_dia_pix: 2,
+                rdp_tolerance_pix: 0.0,
+                keep_out: Some(&keep_out),
+                ..Default::default()
+            },
+        );
 
-        assert_eq!(paths[3].points[0], IV3 { x: 5, y: 1, z: 123 });
-        assert_eq!(paths[3].points[1], IV3 { x: 5, y: 1, z: 123 });
+        assert!(!toolpaths.is_empty());
+        // No part of the tool (radius 1) over the keep-out at any toolpath point.
+        for tp in toolpaths.iter().filter(|tp| !tp.is_traverse) {
+            for p in &tp.points {
+                let nearest_x = p.x.clamp(8, 11);
+                let nearest_y = p.y.clamp(6, 9);
+                let d2 = (p.x - nearest_x).pow(2) + (p.y - nearest_y).pow(2);
+                assert!(d2 > 1, "toolpath point {p:?} touches the keep-out");
+            }
+        }
+        // Rasters pass on both sides of it.
+        let row_7: Vec<&ToolPath> = toolpaths
+            .iter()
+            .filter(|tp| tp.is_raster && tp.points.iter().all(|p| p.y == 7))
+            .collect();
+        assert!(row_7.iter().any(|tp| tp.points.iter().all(|p| p.x < 8)));
+        assert!(row_7.iter().any(|tp| tp.points.iter().all(|p| p.x >= 12)));
     }
 
     #[test]
-    fn surface_tool_path_generation_dump_better_image() {
+    fn sort_toolpaths_respects_region_tree_order() {
         let ply_im = ply_im_from_ascii(
             r#"
-                111111111111111111111111111111111111111111
-                111111111111111111111111111111111111111111
-                111111111111111111111111111111111111111111
-                111111144444411111111111111111111111111111
-                111111144444433333333333333333331111111111
-                111111144444433333333333333333331111111111
-                111111111333333333333333333333331111111111
-                111111111333222222222222222233331111111111
-                111111111333222211111112222233331111111111
-                111111111333222211111112222233331111111111
-                111111111333222222222222222233331111111111
-                111111111333333333333333333333331111111111
-                111111111333333333333333333333331111111111
-                111111111111111111111111111111111111111111
-                111111111111111111111111111111111111111111
-                111111111111111111111111111111111111111111
+                11111
+                12221
+                12321
+                12221
+                11111
             "#,
         );
 
-        // Dummy + 4 real plies (values 1..4).
         let ply_descs = vec![
             stub_ply_desc("dummy", 0, true),
             stub_ply_desc("ply100", 100, false),
             stub_ply_desc("ply200", 200, false),
             stub_ply_desc("ply300", 300, false),
-            stub_ply_desc("ply400", 400, false),
-        ];
-        let band_descs = vec![
-            stub_band_desc(500, 350, "rough"),
-            stub_band_desc(350, 0, "rough"),
         ];
+        let band_descs = vec![stub_band_desc(400, 0, "rough")];
 
-        let (region_im_raw, region_infos) = label_im(&ply_im);
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
         let region_im: RegionIm = region_im_raw.retag::<crate::region_tree::RegionI>();
-
         let cut_bands = create_cut_bands(
             "rough",
             &ply_im,
@@ -1645,331 +6327,845 @@ mod tests {
             &region_infos,
             &ply_descs,
         );
-        let region_root = create_region_tree(&cut_bands, &region_infos);
-
-        // print the z ranges of the cut bands
-        for (i, band) in cut_bands.iter().enumerate() {
-            let top = band.top_thou.0;
-            let bot = band.bot_thou.0;
-            println!("Cut band {i}: top_thou={}, bot_thou={}", top, bot);
-        }
-
-        let tool_dia_pix = 2_usize;
-        let tool_step_pix = 2_usize;
-
-        let mut node_results: Vec<(RegionNode, ROI, MaskIm, MaskIm, MaskIm)> = Vec::new();
-        let snapshot = |src: &MaskIm| {
-            let mut dst = MaskIm::new(src.w, src.h);
-            dst.arr.copy_from_slice(&src.arr);
-            dst
-        };
-        let mut on_region_masks = |node: &RegionNode,
-                                   roi_pad: &ROI,
-                                   cut_mask_im: &MaskIm,
-                                   above_mask_im: &MaskIm,
-                                   dil_abv_mask_im: &MaskIm| {
-            if matches!(node, RegionNode::Cut { .. }) {
-                node_results.push((
-                    node.clone(),
-                    *roi_pad,
-                    snapshot(cut_mask_im),
-                    snapshot(above_mask_im),
-                    snapshot(dil_abv_mask_im),
-                ));
-            }
-        };
+        let region_root = create_region_tree(&cut_bands, &region_infos, 0);
 
-        // Primary call under test (should not panic).
-        let _paths = create_toolpaths_from_region_tree(
-            "test",
+        let mut toolpaths = create_toolpaths_from_region_tree(
             &region_root,
-            &cut_bands,
-            0,
-            tool_dia_pix,
-            tool_step_pix,
-            0,
-            Thou(0),
-            &ply_im,
-            &region_im,
-            None,
-            &region_infos,
-            0,
-            1,
-            true,
-            Some(&mut on_region_masks),
-        );
-
-        // Dump ascii maps for visual inspection.
-        println!("ply_im:\n{}", im_u16_to_ascii(&ply_im));
-        println!("region_im:\n{}", im_u16_to_ascii(&region_im));
-
-        assert_eq!(
-            node_results.len(),
-            region_root
-                .children()
-                .iter()
-                .map(count_cut_leaves)
-                .sum::<usize>(),
-            "expected one callback per cut leaf"
-        );
-
-        for (i, (region_node, roi_pad, cut_m, above_m, dil_abv_m)) in
-            node_results.iter().enumerate()
-        {
-            // Derive the ply value for this cut leaf via the region label's start_x/start_y.
-            let (label_i, curr_ply_i) = match region_node {
-                RegionNode::Cut { region_i, .. } => {
-                    let label_i = region_i.0 as usize;
-                    if label_i == 0 || label_i >= region_infos.len() {
-                        (label_i, None)
-                    } else {
-                        let label_info = &region_infos[label_i];
-                        let start_i = label_info.start_y * ply_im.s + label_info.start_x;
-                        let curr_ply_v = ply_im.arr.get(start_i).copied();
-                        (label_i, curr_ply_v)
-                    }
-                }
-                _ => (0, None),
-            };
-
-            let mut at_mask = MaskIm::new(ply_im.w, ply_im.h);
-            if label_i != 0 && label_i < region_infos.len() {
-                let label_info = &region_infos[label_i];
-                for &pix_i in &label_info.pixel_iz {
-                    if pix_i < at_mask.arr.len() {
-                        at_mask.arr[pix_i] = 255;
-                    }
-                }
-            }
-
-            println!(
-                "--- Cut leaf {i} masks --------------------------------------------------------"
-            );
-            println!("cut[{i}] region_node: {:?}", region_node);
-            println!("cut[{i}] label_i: {label_i}, curr_ply_i: {:?}", curr_ply_i);
-            // Outline the padded ROI actually used for above-mask extraction.
-            let roi_opt = Some(roi_pad);
+            &cut_bands,
+            &ply_im,
+            &region_im,
+            &region_infos,
+            &RegionToolpathOpts {
+                tool_dia_pix: 2,
+                n_perimeters: 0,
+                ..Default::default()
+            },
+            None,
+        );
 
-            println!(
-                "cut[{i}] at_mask (label pixels):\n{}",
-                mask_to_ascii(&at_mask, None)
-            );
-            println!("cut[{i}] above_mask:\n{}", mask_to_ascii(above_m, roi_opt));
-            println!("cut[{i}] dil_abv_mask:\n{}", mask_to_ascii(dil_abv_m, None));
-            println!("cut[{i}] cut_mask:\n{}", mask_to_ascii(cut_m, None));
+        // Deliberately scramble the toolpaths a bit.
+        toolpaths.reverse();
+        if toolpaths.len() >= 3 {
+            toolpaths.swap(0, 2);
         }
 
-        // Print the paths grouped by their exact Z value.
-        let mut paths_by_z: std::collections::BTreeMap<i32, Vec<ToolPath>> =
-            std::collections::BTreeMap::new();
+        sort_toolpaths(&mut toolpaths, &region_root, &DefaultOrderCost);
 
-        for path in &_paths {
-            let z = path.points.first().map(|p| p.z).unwrap_or(0);
-            paths_by_z.entry(z).or_default().push(path.clone());
+        let node_order: Vec<usize> = region_root.iter_dfs().map(|n| n.get_id()).collect();
+        let mut id_to_rank: Vec<usize> = vec![usize::MAX; region_root.get_n_nodes()];
+        for (rank, &id) in node_order.iter().enumerate() {
+            id_to_rank[id] = rank;
         }
 
-        println!("--- Raster toolpaths by Z ---------------------------------------------------");
+        let mut last_rank = 0usize;
+        for (i, tp) in toolpaths.iter().enumerate() {
+            let r = id_to_rank
+                .get(tp.tree_node_id)
+                .copied()
+                .unwrap_or(usize::MAX);
+            if i == 0 {
+                last_rank = r;
+            } else {
+                assert!(r >= last_rank, "node rank should be nondecreasing");
+                last_rank = r;
+            }
+        }
+    }
 
-        for (&z, z_paths) in paths_by_z.iter().rev() {
-            println!(
-                "paths (rasterized) z={}:\n{}",
-                z,
-                toolpaths_to_ascii(z_paths, ply_im.w, ply_im.h)
-            );
+    #[test]
+    fn sort_toolpaths_keeps_the_raster_plunge_run_first() {
+        let fixture = plan_fixture(
+            r#"
+                0000000000000000
+                0111111111111110
+                0111111111111110
+                0111111111111110
+                0111111111111110
+                0111111111111110
+                0111111111111110
+                0111111111111110
+                0111111111111110
+                0000000000000000
+            "#,
+            &[100],
+            &[stub_band_desc(200, 0, "rough")],
+        );
+        let (.., region_root) = &fixture;
+
+        let mut toolpaths = plan_toolpaths(
+            &fixture,
+            &RegionToolpathOpts {
+                tool_dia_pix: 2,
+                n_perimeters: 0,
+                ..Default::default()
+            },
+        );
+        // The plunge run starts inside the pocket, away from the walls, and heads for its
+        // nearer end first.
+        let plunge_run = toolpaths[0].clone();
+        assert!(plunge_run.is_raster && plunge_run.is_plunge_run);
+        assert_eq!(toolpaths.iter().filter(|tp| tp.is_plunge_run).count(), 1);
+        let [start, near, far] = plunge_run.points[..] else {
+            panic!("plunge run {:?}", plunge_run.points);
+        };
+        assert!(near.x.min(far.x) < start.x && start.x < near.x.max(far.x));
+        assert!((near.x - start.x).abs() <= (far.x - start.x).abs());
+
+        // The origin is nearer other runs' ends, but the pocket still starts at the plunge run,
+        // wherever the generator put it.
+        toolpaths.rotate_left(1);
+        let mut sorted = toolpaths.clone();
+        sort_toolpaths(&mut sorted, region_root, &DefaultOrderCost);
+        assert_eq!(sorted[0], plunge_run);
+
+        // Without a marked plunge run no raster run is pinned: the nearest one goes first.
+        for tp in toolpaths.iter_mut() {
+            tp.is_plunge_run = false;
         }
+        sort_toolpaths(&mut toolpaths, region_root, &DefaultOrderCost);
+        assert_ne!(toolpaths[0].points[0], start);
     }
 
     #[test]
-    fn break_long_toolpaths_does_not_drop_paths() {
-        let mut toolpaths = vec![
-            ToolPath {
-                points: vec![IV3 { x: 0, y: 0, z: 0 }, IV3 { x: 10, y: 0, z: 0 }],
-                closed: false,
-                tool_dia_pix: 1,
-                tool_i: 0,
-                tile_i: 0,
-                tree_node_id: 0,
-                cuts: vec![CutPixels::default(); 2],
-                is_traverse: false,
-                is_raster: false,
+    fn group_by_tool_then_order_follows_the_pass_order_and_each_tree() {
+        let ascii = r#"
+                111111111111
+                111111111111
+                112222222211
+                112222222211
+                112233332211
+                112233332211
+                112233332211
+                112233332211
+                112222222211
+                112222222211
+                111111111111
+                111111111111
+            "#;
+        // Rough and detail come from separate trees: one band vs. a band per ply.
+        let rough_fixture =
+            plan_fixture(ascii, &[100, 200, 300], &[stub_band_desc(400, 0, "rough")]);
+        let detail_fixture = plan_fixture(
+            ascii,
+            &[100, 200, 300],
+            &[
+                stub_band_desc(400, 250, "rough"),
+                stub_band_desc(250, 150, "rough"),
+                stub_band_desc(150, 0, "rough"),
+            ],
+        );
+        let (.., rough_root) = &rough_fixture;
+        let (.., detail_root) = &detail_fixture;
+        assert_ne!(rough_root.get_n_nodes(), detail_root.get_n_nodes());
+
+        // Rough is tool 1; detail is tool 0 with a bit no smaller than the rough one, so
+        // neither the tool index nor the diameter puts rough first.
+        let rough = plan_toolpaths(
+            &rough_fixture,
+            &RegionToolpathOpts {
+                tool_i: 1,
+                tool_dia_pix: 4,
+                ..Default::default()
             },
-            ToolPath {
-                points: vec![IV3 { x: 5, y: 5, z: 0 }, IV3 { x: 6, y: 6, z: 0 }],
-                closed: false,
-                tool_dia_pix: 1,
+        );
+        let detail = plan_toolpaths(
+            &detail_fixture,
+            &RegionToolpathOpts {
                 tool_i: 0,
-                tile_i: 0,
-                tree_node_id: 0,
-                cuts: vec![CutPixels::default(); 2],
-                is_traverse: false,
-                is_raster: false,
+                tool_dia_pix: 4,
+                ..Default::default()
             },
-        ];
+        );
+        let (n_rough, n_detail) = (rough.len(), detail.len());
+        assert!(n_rough > 1 && n_detail > 1);
 
-        break_long_toolpaths(&mut toolpaths, 1000);
-        assert_eq!(toolpaths.len(), 2);
-        assert!(toolpaths.iter().all(|tp| tp.points.len() >= 2));
+        // Detail listed before rough, and scrambled.
+        let mut toolpaths = detail.clone();
+        toolpaths.extend(rough.clone());
+        toolpaths.reverse();
+        toolpaths.rotate_left(n_detail / 2);
+
+        let ordered = group_by_tool_then_order(
+            toolpaths,
+            &[(1, rough_root), (0, detail_root)],
+            &DefaultOrderCost,
+        );
+        let tool_iz: Vec<usize> = ordered.iter().map(|tp| tp.tool_i).collect();
+        let mut want = vec![1; n_rough];
+        want.extend(vec![0; n_detail]);
+        assert_eq!(tool_iz, want);
+
+        // Each group is ordered against its own tree, like `sort_toolpaths` alone.
+        for (group, root) in [
+            (&ordered[..n_rough], rough_root),
+            (&ordered[n_rough..], detail_root),
+        ] {
+            let dfs_rank: HashMap<usize, usize> = root
+                .iter_dfs()
+                .enumerate()
+                .map(|(rank, n)| (n.get_id(), rank))
+                .collect();
+            assert!(
+                group
+                    .iter()
+                    .map(|tp| dfs_rank[&tp.tree_node_id])
+                    .is_sorted()
+            );
+            let mut resorted = group.to_vec();
+            sort_toolpaths(&mut resorted, root, &DefaultOrderCost);
+            assert_eq!(resorted, group);
+        }
     }
 
     #[test]
-    fn break_long_toolpaths_ignores_z_only_jumps() {
-        let mut toolpaths = vec![ToolPath {
-            points: vec![
-                IV3 { x: 0, y: 0, z: 0 },
-                IV3 {
-                    x: 0,
-                    y: 0,
-                    z: 10_000,
+    fn tools_by_pass_assigns_tools_per_band_and_skips_missing() {
+        let ply_im = ply_im_from_ascii(
+            r#"
+                1111111
+                1111111
+                1122211
+                1123211
+                1122211
+                1111111
+                1111111
+            "#,
+        );
+
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+            stub_ply_desc("ply200", 200, false),
+            stub_ply_desc("ply300", 300, false),
+        ];
+        let band_descs = vec![
+            stub_band_desc(400, 150, "rough"),
+            stub_band_desc(150, 0, "rough"),
+        ];
+
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+        let region_im: RegionIm = region_im_raw.retag::<crate::region_tree::RegionI>();
+        let mut cut_bands = create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+        // Make the lower band a different pass so the tree mixes passes.
+        cut_bands[1].band_desc.cut_pass = "refine".to_string();
+        let region_root = create_region_tree(&cut_bands, &region_infos, 0);
+
+        let gen_paths = |tools_by_pass: &HashMap<String, ToolDef>| {
+            create_toolpaths_from_region_tree(
+                &region_root,
+                &cut_bands,
+                &ply_im,
+                &region_im,
+                &region_infos,
+                &RegionToolpathOpts {
+                    tool_dia_pix: 2,
+                    tools_by_pass: Some(tools_by_pass),
+                    n_perimeters: 0,
+                    ..Default::default()
                 },
+                None,
+            )
+        };
+
+        let mut tools_by_pass: HashMap<String, ToolDef> = HashMap::new();
+        tools_by_pass.insert(
+            "rough".to_string(),
+            ToolDef {
+                tool_i: 3,
+                tool_dia_pix: 2,
+                max_doc_thou: None,
+            },
+        );
+
+        // No refine tool => only the rough band's nodes produce toolpaths.
+        let paths = gen_paths(&tools_by_pass);
+        assert!(!paths.is_empty());
+        assert!(paths.iter().all(|tp| tp.tool_i == 3));
+
+        tools_by_pass.insert(
+            "refine".to_string(),
+            ToolDef {
+                tool_i: 5,
+                tool_dia_pix: 1,
+                max_doc_thou: None,
+            },
+        );
+        let paths = gen_paths(&tools_by_pass);
+        assert!(
+            paths
+                .iter()
+                .any(|tp| tp.tool_i == 3 && tp.tool_dia_pix == 2)
+        );
+        assert!(
+            paths
+                .iter()
+                .any(|tp| tp.tool_i == 5 && tp.tool_dia_pix == 1)
+        );
+        for tp in &paths {
+            let node = region_root.node_by_id(tp.tree_node_id).unwrap();
+            assert_eq!(tp.tool_i, if node.band_i() == 0 { 3 } else { 5 });
+        }
+    }
+
+    #[test]
+    fn band_range_limits_toolpaths_to_those_bands() {
+        let fixture = plan_fixture(
+            r#"
+                1111111
+                1111111
+                1122211
+                1123211
+                1122211
+                1111111
+                1111111
+            "#,
+            &[100, 200, 300],
+            &[
+                stub_band_desc(400, 150, "rough"),
+                stub_band_desc(150, 0, "rough"),
             ],
-            closed: false,
-            tool_dia_pix: 1,
-            tool_i: 0,
-            tile_i: 0,
-            tree_node_id: 0,
-            cuts: vec![CutPixels::default(); 2],
-            is_traverse: false,
-            is_raster: false,
-        }];
+        );
+        let (.., region_root) = &fixture;
+
+        let gen_paths = |band_range: Option<Range<usize>>| {
+            plan_toolpaths(
+                &fixture,
+                &RegionToolpathOpts {
+                    n_perimeters: 0,
+                    band_range,
+                    ..Default::default()
+                },
+            )
+        };
+        let band_of = |tp: &ToolPath| region_root.node_by_id(tp.tree_node_id).unwrap().band_i();
+
+        let all_paths = gen_paths(None);
+        assert!(all_paths.iter().any(|tp| band_of(tp) == 1));
+
+        // Only band 0 is emitted, and its toolpaths are the same as in the full run.
+        let band_0_paths = gen_paths(Some(0..1));
+        assert!(!band_0_paths.is_empty());
+        assert!(band_0_paths.iter().all(|tp| band_of(tp) == 0));
+        let expected: Vec<&ToolPath> = all_paths.iter().filter(|tp| band_of(tp) == 0).collect();
+        assert_eq!(band_0_paths.len(), expected.len());
+        for (got, want) in band_0_paths.iter().zip(expected) {
+            assert_eq!(got.tree_node_id, want.tree_node_id);
+            assert_eq!(got.points, want.points);
+        }
 
-        // Even though z jumps, XY distance is 0 so it should not be broken.
-        break_long_toolpaths(&mut toolpaths, 1);
-        assert_eq!(toolpaths.len(), 1);
-        assert_eq!(toolpaths[0].points.len(), 2);
+        assert!(gen_paths(Some(2..3)).is_empty());
     }
 
     #[test]
-    fn break_long_toolpaths_splits_on_long_mid_segment() {
-        let mut toolpaths = vec![ToolPath {
-            points: vec![
-                IV3 { x: 0, y: 0, z: 0 },
-                IV3 { x: 1, y: 0, z: 0 },
-                // Big jump in XY from previous point => should trigger a split.
-                IV3 { x: 100, y: 0, z: 0 },
+    fn create_toolpaths_streaming_emits_in_vec_order() {
+        let fixture = plan_fixture(
+            r#"
+                1111111
+                1111111
+                1122211
+                1123211
+                1122211
+                1111111
+                1111111
+            "#,
+            &[100, 200, 300],
+            &[
+                stub_band_desc(400, 150, "rough"),
+                stub_band_desc(150, 0, "rough"),
             ],
-            closed: false,
-            tool_dia_pix: 1,
-            tool_i: 0,
-            tile_i: 0,
-            tree_node_id: 0,
-            cuts: vec![CutPixels::default(); 3],
-            is_traverse: false,
-            is_raster: false,
-        }];
+        );
+        let (ply_im, region_im, region_infos, cut_bands, region_root) = &fixture;
 
-        break_long_toolpaths(&mut toolpaths, 10);
+        let opts = RegionToolpathOpts {
+            n_perimeters: 0,
+            ..Default::default()
+        };
+        let mut streamed: Vec<ToolPath> = Vec::new();
+        create_toolpaths_streaming(
+            region_root,
+            cut_bands,
+            ply_im,
+            region_im,
+            region_infos,
+            &opts,
+            None,
+            &mut |toolpath| streamed.push(toolpath),
+        );
+        assert!(!streamed.is_empty());
+
+        // Nodes are emitted one after another in DFS order.
+        let dfs_rank: HashMap<usize, usize> = region_root
+            .iter_dfs()
+            .enumerate()
+            .map(|(rank, node)| (node.get_id(), rank))
+            .collect();
+        assert!(
+            streamed
+                .iter()
+                .map(|tp| dfs_rank[&tp.tree_node_id])
+                .is_sorted()
+        );
 
-        // We should get more segments back (the 1->100 segment subdivides).
-        assert!(toolpaths.len() > 2);
-        assert!(toolpaths.iter().all(|tp| tp.points.len() == 2));
+        let collected = plan_toolpaths(&fixture, &opts);
+        assert_eq!(streamed, collected);
+    }
 
-        // Every segment should now be <= max length in XY.
-        for tp in &toolpaths {
-            let a = tp.points[0];
-            let b = tp.points[1];
-            let dx = (a.x - b.x) as i64;
-            let dy = (a.y - b.y) as i64;
-            let d2 = dx * dx + dy * dy;
-            assert!(d2 <= 100, "segment too long: d2={d2}");
+    #[test]
+    fn compute_node_cut_mask_matches_the_tree_pass() {
+        let fixture = plan_fixture(
+            r#"
+                22222222222
+                22222222222
+                22111111122
+                22111111122
+                22111111122
+                22111111122
+                22111111122
+                22111111122
+                22111111122
+                22222222222
+                22222222222
+            "#,
+            &[100, 200],
+            &[
+                stub_band_desc(300, 150, "rough"),
+                stub_band_desc(150, 0, "rough"),
+            ],
+        );
+        let (ply_im, region_im, region_infos, cut_bands, region_root) = &fixture;
+
+        let pocket_region = region_im.arr[5 * region_im.s + 5];
+        let node = region_root
+            .iter_cut_leaves()
+            .find(|n| matches!(n, RegionNode::Cut { region_i, .. } if region_i.0 == pocket_region))
+            .expect("the pocket has a cut leaf");
+
+        // A keep-out pixel inside the pocket, so every input the tree uses shows up.
+        let mut keep_out = MaskIm::new(ply_im.w, ply_im.h);
+        keep_out.arr[7 * keep_out.s + 7] = 255;
+        let base_opts = RegionToolpathOpts {
+            tool_dia_pix: 2,
+            wall_stock_pix: 1,
+            keep_out: Some(&keep_out),
+            ..Default::default()
+        };
+        let dilate_opts = RegionToolpathOpts {
+            n_perimeters: 2,
+            perimeter_strategy: PerimeterStrategy::Dilate,
+            ..base_opts.clone()
+        };
+
+        // The mask the tree cut the pocket with on its last pass, via the hook.
+        let tree_mask = |opts: &RegionToolpathOpts| {
+            let mut found: Option<(MaskIm, ROI)> = None;
+            let mut on_region_masks =
+                |n: &RegionNode,
+                 padded_roi: &ROI,
+                 _: &MaskIm,
+                 _: &MaskIm,
+                 _: &MaskIm,
+                 dil_cut_mask_im: &MaskIm| {
+                    if n.get_id() == node.get_id() {
+                        found = Some((dil_cut_mask_im.clone(), *padded_roi));
+                    }
+                };
+            create_toolpaths_from_region_tree(
+                region_root,
+                cut_bands,
+                ply_im,
+                region_im,
+                region_infos,
+                opts,
+                Some(&mut on_region_masks),
+            );
+            found.expect("the tree visits the pocket")
+        };
+
+        let (mask, roi) =
+            compute_node_cut_mask(node, cut_bands, ply_im, region_infos, &base_opts, 0).unwrap();
+        let (want_mask, want_roi) = tree_mask(&base_opts);
+        assert_eq!(mask.arr, want_mask.arr);
+        assert_eq!(roi, want_roi);
+
+        // The tool center stays inside the pocket and a radius plus the wall stock off its walls.
+        let is_set = |m: &MaskIm, x: usize, y: usize| m.arr[y * m.s + x] != 0;
+        println!("{}", mask_to_ascii(&mask, Some(&roi)));
+        assert!(is_set(&mask, 5, 5));
+        assert!(!is_set(&mask, 3, 5) && !is_set(&mask, 5, 7) && !is_set(&mask, 7, 7));
+        for y in 0..mask.h {
+            for x in 0..mask.w {
+                if is_set(&mask, x, y) {
+                    assert_eq!(
+                        ply_im.arr[y * ply_im.s + x],
+                        1,
+                        "({x}, {y}) left the pocket"
+                    );
+                }
+            }
         }
+
+        // With dilated perimeters the later pass sits inside the first and matches the tree's.
+        let (outer, _) =
+            compute_node_cut_mask(node, cut_bands, ply_im, region_infos, &dilate_opts, 0).unwrap();
+        let (inner, inner_roi) =
+            compute_node_cut_mask(node, cut_bands, ply_im, region_infos, &dilate_opts, 1).unwrap();
+        let (want_inner, want_inner_roi) = tree_mask(&dilate_opts);
+        assert_eq!(inner.arr, want_inner.arr);
+        assert_eq!(inner_roi, want_inner_roi);
+        let n_set = |m: &MaskIm| m.arr.iter().filter(|&&v| v != 0).count();
+        assert!(
+            outer
+                .arr
+                .iter()
+                .zip(&inner.arr)
+                .all(|(&a, &b)| b == 0 || a != 0)
+        );
+        assert!(n_set(&inner) < n_set(&outer));
     }
 
-    fn cut(pixels_changed: u64) -> CutPixels {
-        CutPixels {
-            pixels_changed,
-            depth_sum_thou: 0,
+    #[test]
+    fn metrics_count_the_work_without_changing_the_output() {
+        let fixture = plan_fixture(
+            r#"
+                1111111
+                1111111
+                1122211
+                1123211
+                1122211
+                1111111
+                1111111
+            "#,
+            &[100, 200, 300],
+            &[
+                stub_band_desc(400, 150, "rough"),
+                stub_band_desc(150, 0, "rough"),
+            ],
+        );
+        let (ply_im, .., region_root) = &fixture;
+
+        let plan = |metrics: Option<&RefCell<Metrics>>| {
+            plan_toolpaths(
+                &fixture,
+                &RegionToolpathOpts {
+                    metrics,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let metrics = RefCell::new(Metrics::default());
+        let with_metrics = plan(Some(&metrics));
+        assert_eq!(with_metrics, plan(None));
+        let metrics = metrics.into_inner();
+
+        let n_nodes = region_root.iter_dfs().count();
+        assert_eq!(metrics.nodes_visited, n_nodes);
+        // Every node here has pixels, so each runs one perimeter pass (two dilations).
+        assert_eq!(metrics.dilations_run, 2 * n_nodes);
+        assert!(metrics.contours_traced >= n_nodes);
+        // At least the three full-image clears per node.
+        assert!(metrics.pixels_touched >= (3 * n_nodes * ply_im.w * ply_im.h) as u64);
+        let phases: Vec<&str> = metrics.phases.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            phases,
+            [
+                "clear",
+                "splat",
+                "above_mask",
+                "dilate",
+                "surfaces",
+                "perimeters"
+            ]
+        );
+
+        // A second call accumulates into the same metrics.
+        let metrics = RefCell::new(metrics);
+        let _ = plan(Some(&metrics));
+        assert_eq!(metrics.borrow().nodes_visited, 2 * n_nodes);
+    }
+
+    #[test]
+    fn rdp_tolerance_thins_perimeters_on_a_curve() {
+        // A disc of ply 2 inside ply 1, so the perimeter is a stair-stepped circle.
+        let mut ply_im = PlyIm::new(24, 24);
+        for y in 0..24 {
+            for x in 0..24 {
+                let (dx, dy) = (x as i32 - 12, y as i32 - 12);
+                ply_im.arr[y * ply_im.s + x] = if dx * dx + dy * dy <= 64 { 2 } else { 1 };
+            }
         }
+
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+            stub_ply_desc("ply200", 200, false),
+        ];
+        let band_descs = vec![stub_band_desc(300, 0, "rough")];
+
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+        let region_im: RegionIm = region_im_raw.retag::<crate::region_tree::RegionI>();
+        let cut_bands = create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+        let region_root = create_region_tree(&cut_bands, &region_infos, 0);
+
+        let n_perimeter_points = |rdp_tolerance_pix: f64| -> usize {
+            create_toolpaths_from_region_tree(
+                &region_root,
+                &cut_bands,
+                &ply_im,
+                &region_im,
+                &region_infos,
+                &RegionToolpathOpts {
+                    tool_dia_pix: 2,
+                    rdp_tolerance_pix,
+                    gen_surfaces: false,
+                    ..Default::default()
+                },
+                None,
+            )
+            .iter()
+            .map(|tp| tp.points.len())
+            .sum()
+        };
+
+        let n_raw = n_perimeter_points(0.0);
+        let n_fine = n_perimeter_points(0.5);
+        let n_coarse = n_perimeter_points(2.0);
+        assert!(n_coarse > 0);
+        assert!(n_fine <= n_raw);
+        assert!(n_coarse < n_fine, "n_coarse={n_coarse} n_fine={n_fine}");
     }
 
     #[test]
-    fn cull_splits_open_toolpath_on_empty_segments() {
-        let mut toolpaths = vec![ToolPath {
-            points: vec![
-                IV3 { x: 0, y: 0, z: 0 },
-                IV3 { x: 1, y: 0, z: 0 },
-                IV3 { x: 2, y: 0, z: 0 },
-                IV3 { x: 3, y: 0, z: 0 },
-            ],
-            closed: false,
-            tool_dia_pix: 1,
-            tool_i: 0,
-            tile_i: 0,
-            tree_node_id: 0,
-            cuts: vec![cut(5), cut(0), cut(7), CutPixels::default()],
-            is_traverse: false,
-            is_raster: false,
-        }];
+    fn offset_and_dilate_perimeters_agree_on_a_square() {
+        // A square pocket (ply 1) in a raised field (ply 2).
+        let mut ply_im = PlyIm::new(24, 24);
+        for y in 0..24 {
+            for x in 0..24 {
+                let in_square = (6..18).contains(&x) && (6..18).contains(&y);
+                ply_im.arr[y * ply_im.s + x] = if in_square { 1 } else { 2 };
+            }
+        }
 
-        cull_empty_toolpaths(&mut toolpaths);
-        assert_eq!(toolpaths.len(), 2);
-        assert_eq!(toolpaths[0].points.len(), 2);
-        assert_eq!(toolpaths[1].points.len(), 2);
-        assert_eq!(toolpaths[0].points[0].x, 0);
-        assert_eq!(toolpaths[0].points[1].x, 1);
-        assert_eq!(toolpaths[1].points[0].x, 2);
-        assert_eq!(toolpaths[1].points[1].x, 3);
-        assert_eq!(toolpaths[0].cuts[0].pixels_changed, 5);
-        assert_eq!(toolpaths[1].cuts[0].pixels_changed, 7);
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+            stub_ply_desc("ply200", 200, false),
+        ];
+        let band_descs = vec![stub_band_desc(300, 0, "rough")];
+
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+        let region_im: RegionIm = region_im_raw.retag::<crate::region_tree::RegionI>();
+        let cut_bands = create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+        let region_root = create_region_tree(&cut_bands, &region_infos, 0);
+
+        let n_loops = |perimeter_strategy: PerimeterStrategy| -> usize {
+            create_toolpaths_from_region_tree(
+                &region_root,
+                &cut_bands,
+                &ply_im,
+                &region_im,
+                &region_infos,
+                &RegionToolpathOpts {
+                    tool_dia_pix: 2,
+                    n_perimeters: 3,
+                    gen_surfaces: false,
+                    perimeter_strategy,
+                    ..Default::default()
+                },
+                None,
+            )
+            .iter()
+            .filter(|tp| tp.closed)
+            .count()
+        };
+
+        let n_offset = n_loops(PerimeterStrategy::Offset);
+        let n_dilate = n_loops(PerimeterStrategy::Dilate);
+        assert!(n_offset >= 3);
+        assert_eq!(n_offset, n_dilate);
     }
 
     #[test]
-    fn cull_breaks_closed_toolpath_into_open_runs() {
-        // Triangle loop 0->1->2->0, but the middle edge cuts nothing.
-        let p0 = IV3 { x: 0, y: 0, z: 0 };
-        let p1 = IV3 { x: 1, y: 0, z: 0 };
-        let p2 = IV3 { x: 2, y: 0, z: 0 };
+    fn wall_stock_keeps_clearing_off_walls_only() {
+        // A raised wall (ply 3) on the left, a shelf (ply 2), and a step down (ply 1) on the
+        // right: the shelf has a wall on one side and is open on the other.
+        let row = format!("{}{}{}", "3".repeat(8), "2".repeat(12), "1".repeat(12));
+        let fixture = plan_fixture(
+            &vec![row; 16].join("\n"),
+            &[100, 200, 300],
+            &[stub_band_desc(400, 0, "rough")],
+        );
 
-        let mut toolpaths = vec![ToolPath {
-            points: vec![p0, p1, p2, p0],
-            closed: true,
-            tool_dia_pix: 1,
-            tool_i: 0,
-            tile_i: 0,
-            tree_node_id: 0,
-            cuts: vec![cut(3), cut(0), cut(4), CutPixels::default()],
-            is_traverse: false,
-            is_raster: false,
-        }];
+        // Returns the x-extent of the shelf's raster clearing on row y.
+        let raster_x_range = |wall_stock_pix: usize, y: i32| -> (i32, i32) {
+            let toolpaths = plan_toolpaths(
+                &fixture,
+                &RegionToolpathOpts {
+                    tool_dia_pix: 2,
+                    wall_stock_pix,
+                    n_perimeters: 0,
+                    rdp_tolerance_pix: 0.0,
+                    ..Default::default()
+                },
+            );
+            let xs: Vec<i32> = toolpaths
+                .iter()
+                .filter(|tp| tp.is_raster && tp.points.iter().all(|p| p.z == 200))
+                .flat_map(|tp| tp.points.iter())
+                .filter(|p| p.y == y)
+                .map(|p| p.x)
+                .collect();
+            (*xs.iter().min().unwrap(), *xs.iter().max().unwrap())
+        };
 
-        cull_empty_toolpaths(&mut toolpaths);
-        assert_eq!(toolpaths.len(), 1);
-        assert!(!toolpaths[0].closed);
-        // Remaining segments: 2->0 and 0->1, i.e. points [2,0,1].
-        assert_eq!(toolpaths[0].points, vec![p2, p0, p1]);
-        assert_eq!(toolpaths[0].cuts[0].pixels_changed, 4);
-        assert_eq!(toolpaths[0].cuts[1].pixels_changed, 3);
+        let (l0, r0) = raster_x_range(0, 8);
+        let (l3, r3) = raster_x_range(3, 8);
+        assert!(l0 < 14 && 14 < r0);
+        // The wall side backs off by exactly the stock...
+        assert_eq!(l3, l0 + 3);
+        // ...but the open side is cut just as far.
+        assert_eq!(r3, r0);
     }
 
-    fn build_node_visit_order_for_test(region_root: &RegionRoot) -> Vec<usize> {
-        // Keep in sync with the implementation in sort_tool_paths.
-        fn band_i(node: &RegionNode) -> usize {
-            match node {
-                RegionNode::Floor { band_i, .. } => *band_i,
-                RegionNode::Cut { band_i, .. } => *band_i,
-            }
-        }
-        fn recurse(nodes: &[RegionNode], out: &mut Vec<usize>) {
-            if nodes.is_empty() {
-                return;
-            }
-            let b0 = band_i(&nodes[0]);
-            assert!(nodes.iter().all(|n| band_i(n) == b0));
+    #[test]
+    fn roughed_im_limits_clearing_to_a_radius_of_leftover() {
+        // An open floor (ply 1) roughed flat except for one leftover bump.
+        let mut ply_im = PlyIm::new(32, 16);
+        ply_im.arr.fill(1);
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+        ];
+        let band_descs = vec![stub_band_desc(300, 0, "refine")];
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+        let region_im: RegionIm = region_im_raw.retag::<crate::region_tree::RegionI>();
+        let cut_bands = create_cut_bands(
+            "refine",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+        let region_root = create_region_tree(&cut_bands, &region_infos, 0);
+
+        let gen_paths = |roughed_im: &Lum16Im| {
+            create_toolpaths_from_region_tree(
+                &region_root,
+                &cut_bands,
+                &ply_im,
+                &region_im,
+                &region_infos,
+                &RegionToolpathOpts {
+                    tool_dia_pix: 4,
+                    n_perimeters: 0,
+                    rdp_tolerance_pix: 0.0,
+                    roughed_im: Some(roughed_im),
+                    ..Default::default()
+                },
+                None,
+            )
+        };
 
-            for n in nodes {
-                out.push(n.get_id());
-                if let RegionNode::Floor { children, .. } = n {
-                    recurse(children, out);
-                }
-            }
+        let mut roughed_im = Lum16Im::new(32, 16);
+        roughed_im.arr.fill(100);
+        assert!(gen_paths(&roughed_im).is_empty());
+
+        roughed_im.arr[8 * roughed_im.s + 20] = 150;
+        let paths = gen_paths(&roughed_im);
+        assert!(!paths.is_empty());
+        // Every tool center that would still touch the bump is visited, and no others.
+        let pts: Vec<&IV3> = paths.iter().flat_map(|tp| tp.points.iter()).collect();
+        for p in &pts {
+            assert_eq!(p.z, 100);
+            let (dx, dy) = (p.x - 20, p.y - 8);
+            assert!(dx * dx + dy * dy <= 4, "{p:?} is off the bump");
         }
+        let row_8: Vec<i32> = pts.iter().filter(|p| p.y == 8).map(|p| p.x).collect();
+        assert!(row_8.contains(&18) && row_8.contains(&22), "{row_8:?}");
+    }
 
-        let mut out = Vec::new();
-        recurse(region_root.children(), &mut out);
-        out
+    #[test]
+    fn signed_pride_offsets_cut_z_with_clamping() {
+        let ply_im = ply_im_from_ascii(
+            r#"
+                2222222
+                2111112
+                2111112
+                2111112
+                2222222
+            "#,
+        );
+        let ply_descs = vec![
+            stub_ply_desc("dummy", 0, true),
+            stub_ply_desc("ply100", 100, false),
+            stub_ply_desc("ply200", 200, false),
+        ];
+        let band_descs = vec![stub_band_desc(300, 0, "rough")];
+
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
+        let region_im: RegionIm = region_im_raw.retag::<crate::region_tree::RegionI>();
+        let cut_bands = create_cut_bands(
+            "rough",
+            &ply_im,
+            &band_descs,
+            &region_im,
+            &region_infos,
+            &ply_descs,
+        );
+        let region_root = create_region_tree(&cut_bands, &region_infos, 0);
+
+        // The lowest Z of any cutting toolpath.
+        let min_cut_z = |pride_thou: i32| -> i32 {
+            create_toolpaths_from_region_tree(
+                &region_root,
+                &cut_bands,
+                &ply_im,
+                &region_im,
+                &region_infos,
+                &RegionToolpathOpts {
+                    pride_thou: Thou(pride_thou),
+                    rdp_tolerance_pix: 0.0,
+                    ..Default::default()
+                },
+                None,
+            )
+            .iter()
+            .flat_map(|tp| tp.points.iter())
+            .map(|p| p.z)
+            .min()
+            .unwrap()
+        };
+
+        assert_eq!(min_cut_z(0), 100);
+        assert_eq!(min_cut_z(30), 130);
+        assert_eq!(min_cut_z(-30), 70);
+        // Cutting below zero is clamped to the bottom of the stock.
+        assert_eq!(min_cut_z(-500), 0);
     }
 
     #[test]
-    fn sort_toolpaths_respects_region_tree_order() {
+    fn band_report_joins_toolpaths_to_bands() {
         let ply_im = ply_im_from_ascii(
             r#"
                 11111
@@ -1986,9 +7182,12 @@ mod tests {
             stub_ply_desc("ply200", 200, false),
             stub_ply_desc("ply300", 300, false),
         ];
-        let band_descs = vec![stub_band_desc(400, 0, "rough")];
+        let band_descs = vec![
+            stub_band_desc(400, 150, "rough"),
+            stub_band_desc(150, 0, "rough"),
+        ];
 
-        let (region_im_raw, region_infos) = label_im(&ply_im);
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
         let region_im: RegionIm = region_im_raw.retag::<crate::region_tree::RegionI>();
         let cut_bands = create_cut_bands(
             "rough",
@@ -1998,54 +7197,49 @@ mod tests {
             &region_infos,
             &ply_descs,
         );
-        let region_root = create_region_tree(&cut_bands, &region_infos);
+        let region_root = create_region_tree(&cut_bands, &region_infos, 0);
 
-        let mut toolpaths = create_toolpaths_from_region_tree(
-            "test",
-            &region_root,
-            &cut_bands,
-            0,
-            2,
-            1,
-            0,
-            Thou(0),
-            &ply_im,
-            &region_im,
-            None,
-            &region_infos,
-            0,
-            1,
-            true,
-            None,
-        );
+        let node_in_band = |want_band_i: usize| {
+            region_root
+                .iter_cut_leaves()
+                .find(|n| matches!(n, RegionNode::Cut { band_i, .. } if *band_i == want_band_i))
+                .map(|n| n.get_id())
+                .unwrap()
+        };
 
-        // Deliberately scramble the toolpaths a bit.
-        toolpaths.reverse();
-        if toolpaths.len() >= 3 {
-            toolpaths.swap(0, 2);
-        }
+        let mk = |tree_node_id: usize, x1: i32, is_traverse: bool| ToolPath {
+            points: vec![IV3 { x: 0, y: 0, z: 0 }, IV3 { x: x1, y: 0, z: 0 }],
+            closed: false,
+            tool_dia_pix: 1,
+            tool_i: 0,
+            tile_i: 0,
+            tree_node_id,
+            cuts: vec![CutPixels::default(); 2],
+            is_traverse,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+        let toolpaths = vec![
+            mk(node_in_band(0), 3, false),
+            mk(node_in_band(0), 4, false),
+            mk(node_in_band(1), 5, false),
+            mk(node_in_band(1), 100, true), // Traverses are ignored
+        ];
 
-        sort_toolpaths(&mut toolpaths, &region_root);
+        let report = band_report(&region_root, &cut_bands, &region_infos, &toolpaths);
+        assert_eq!(report.len(), 2);
 
-        let node_order = build_node_visit_order_for_test(&region_root);
-        let mut id_to_rank: Vec<usize> = vec![usize::MAX; region_root.get_n_nodes()];
-        for (rank, &id) in node_order.iter().enumerate() {
-            id_to_rank[id] = rank;
-        }
+        // Band 0 holds ply 200 (the ring, 8 px) and ply 300 (the center, 1 px).
+        assert_eq!(report[0].n_cut_leaves, 2);
+        assert_eq!(report[0].region_area_pix, 9);
+        assert_eq!(report[0].n_toolpaths, 2);
+        assert_eq!(report[0].cut_len_pix, 7.0);
 
-        let mut last_rank = 0usize;
-        for (i, tp) in toolpaths.iter().enumerate() {
-            let r = id_to_rank
-                .get(tp.tree_node_id)
-                .copied()
-                .unwrap_or(usize::MAX);
-            if i == 0 {
-                last_rank = r;
-            } else {
-                assert!(r >= last_rank, "node rank should be nondecreasing");
-                last_rank = r;
-            }
-        }
+        // Band 1 holds ply 100 (the border, 16 px).
+        assert_eq!(report[1].n_cut_leaves, 1);
+        assert_eq!(report[1].region_area_pix, 16);
+        assert_eq!(report[1].n_toolpaths, 1);
+        assert_eq!(report[1].cut_len_pix, 5.0);
     }
 
     #[test]
@@ -2063,7 +7257,7 @@ mod tests {
         ];
         let band_descs = vec![stub_band_desc(200, 0, "rough")];
 
-        let (region_im_raw, region_infos) = label_im(&ply_im);
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
         let region_im: RegionIm = region_im_raw.retag::<crate::region_tree::RegionI>();
         let cut_bands = create_cut_bands(
             "rough",
@@ -2073,7 +7267,7 @@ mod tests {
             &region_infos,
             &ply_descs,
         );
-        let region_root = create_region_tree(&cut_bands, &region_infos);
+        let region_root = create_region_tree(&cut_bands, &region_infos, 0);
         let some_node_id = region_root
             .children()
             .first()
@@ -2092,6 +7286,7 @@ mod tests {
                 cuts: vec![CutPixels::default(); 2],
                 is_traverse: false,
                 is_raster: false,
+                is_plunge_run: false,
             },
             // Closed path intentionally not rotated.
             ToolPath {
@@ -2109,10 +7304,11 @@ mod tests {
                 cuts: vec![CutPixels::default(); 4],
                 is_traverse: false,
                 is_raster: false,
+                is_plunge_run: false,
             },
         ];
 
-        sort_toolpaths(&mut toolpaths, &region_root);
+        sort_toolpaths(&mut toolpaths, &region_root, &DefaultOrderCost);
 
         // Find our two toolpaths again by their closed flag.
         let open = toolpaths.iter().find(|tp| !tp.closed).unwrap();
@@ -2125,6 +7321,154 @@ mod tests {
         assert_eq!(closed.points[0].x, 4);
     }
 
+    #[test]
+    fn sort_toolpaths_breaks_exact_distance_ties_deterministically() {
+        let fixture = plan_fixture(
+            r#"
+                11
+                11
+            "#,
+            &[100],
+            &[stub_band_desc(200, 0, "rough")],
+        );
+        let (.., region_root) = &fixture;
+        let node_id = region_root.children()[0].get_id();
+
+        let tp = |tool_i: usize, closed: bool, xys: &[(i32, i32)]| ToolPath {
+            points: xys.iter().map(|&(x, y)| IV3 { x, y, z: 100 }).collect(),
+            closed,
+            tool_dia_pix: 1,
+            tool_i,
+            tile_i: 0,
+            tree_node_id: node_id,
+            cuts: vec![CutPixels::default(); xys.len()],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+        let xys =
+            |tp: &ToolPath| -> Vec<(i32, i32)> { tp.points.iter().map(|p| (p.x, p.y)).collect() };
+
+        // Every vertex of the diamond is equidistant from the origin: keep the first one, and
+        // keep the winding too.
+        let mut toolpaths = vec![tp(0, true, &[(0, 4), (4, 0), (0, -4), (-4, 0)])];
+        sort_toolpaths(&mut toolpaths, region_root, &DefaultOrderCost);
+        assert_eq!(
+            xys(&toolpaths[0]),
+            vec![(0, 4), (4, 0), (0, -4), (-4, 0), (0, 4)]
+        );
+
+        // An open path whose ends are equidistant keeps its direction.
+        let mut toolpaths = vec![tp(0, false, &[(3, 0), (-3, 0)])];
+        sort_toolpaths(&mut toolpaths, region_root, &DefaultOrderCost);
+        assert_eq!(xys(&toolpaths[0]), vec![(3, 0), (-3, 0)]);
+
+        // Equidistant candidates go by start (y, x); identical candidates keep input order.
+        let mut toolpaths = vec![
+            tp(0, false, &[(0, 5), (0, 9)]),
+            tp(1, false, &[(5, 0), (9, 0)]),
+            tp(2, false, &[(9, 4), (9, 6)]),
+            tp(3, false, &[(9, 4), (9, 6)]),
+        ];
+        sort_toolpaths(&mut toolpaths, region_root, &DefaultOrderCost);
+        let order: Vec<usize> = toolpaths.iter().map(|tp| tp.tool_i).collect();
+        assert_eq!(order, vec![1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn sort_toolpaths_keeps_closed_winding() {
+        let fixture = plan_fixture(
+            r#"
+                11
+                11
+            "#,
+            &[100],
+            &[stub_band_desc(200, 0, "rough")],
+        );
+        let (.., region_root) = &fixture;
+        let node_id = region_root.children()[0].get_id();
+
+        let signed_area2 = |pts: &[IV3]| -> i64 {
+            pts.windows(2)
+                .map(|w| w[0].x as i64 * w[1].y as i64 - w[1].x as i64 * w[0].y as i64)
+                .sum()
+        };
+
+        // The nearest corner to the origin is (2, 2); its nearer neighbor (2, 12) is behind it in
+        // one of the two windings, and rolling must not flip that one either.
+        let square = [(2, 2), (2, 12), (12, 12), (12, 3)];
+        for ccw in [true, false] {
+            let mut xys: Vec<(i32, i32)> = square.to_vec();
+            if !ccw {
+                xys.reverse();
+            }
+            xys.rotate_left(2);
+            xys.push(xys[0]);
+            let mut toolpaths = vec![ToolPath {
+                points: xys.iter().map(|&(x, y)| IV3 { x, y, z: 100 }).collect(),
+                closed: true,
+                tool_dia_pix: 1,
+                tool_i: 0,
+                tile_i: 0,
+                tree_node_id: node_id,
+                cuts: vec![CutPixels::default(); xys.len()],
+                is_traverse: false,
+                is_raster: false,
+                is_plunge_run: false,
+            }];
+            let area_before = signed_area2(&toolpaths[0].points);
+            sort_toolpaths(&mut toolpaths, region_root, &DefaultOrderCost);
+            let pts = &toolpaths[0].points;
+            assert_eq!((pts[0].x, pts[0].y), (2, 2));
+            assert_eq!(pts.first(), pts.last());
+            assert_eq!(signed_area2(pts), area_before);
+        }
+    }
+
+    #[test]
+    fn sort_toolpaths_uses_the_given_order_cost() {
+        let fixture = plan_fixture(
+            r#"
+                11
+                11
+            "#,
+            &[100],
+            &[stub_band_desc(200, 0, "rough")],
+        );
+        let (.., region_root) = &fixture;
+        let node_id = region_root.children()[0].get_id();
+
+        let tp = |tool_i: usize, xys: &[(i32, i32)]| ToolPath {
+            points: xys.iter().map(|&(x, y)| IV3 { x, y, z: 100 }).collect(),
+            closed: false,
+            tool_dia_pix: 1,
+            tool_i,
+            tile_i: 0,
+            tree_node_id: node_id,
+            cuts: vec![CutPixels::default(); xys.len()],
+            is_traverse: false,
+            is_raster: false,
+            is_plunge_run: false,
+        };
+
+        // A machine whose X axis is slow, so only X travel counts.
+        struct XTravelCost;
+        impl OrderCost for XTravelCost {
+            fn cost(&self, curr: &IV3, tp: &ToolPath) -> f64 {
+                (tp.points[0].x - curr.x).abs() as f64
+            }
+        }
+
+        let toolpaths = vec![tp(0, &[(0, 10), (0, 20)]), tp(1, &[(5, 0), (6, 0)])];
+        let order = |cost: &dyn OrderCost| -> Vec<usize> {
+            let mut toolpaths = toolpaths.clone();
+            sort_toolpaths(&mut toolpaths, region_root, cost);
+            toolpaths.iter().map(|tp| tp.tool_i).collect()
+        };
+        assert_eq!(order(&DefaultOrderCost), vec![1, 0]);
+        assert_eq!(order(&XTravelCost), vec![0, 1]);
+    }
+
     #[test]
     fn toolpath_movie_replay_matches_cut_only_after_scaled_compdesc() {
         // This test mirrors the debug_ui "toolpath movie" behavior:
@@ -2220,7 +7564,7 @@ mod tests {
             }
         }
 
-        let (region_im_raw, region_infos) = label_im(&ply_im);
+        let (region_im_raw, region_infos) = label_im(&ply_im).unwrap();
         let region_im: crate::region_tree::RegionIm =
             region_im_raw.retag::<crate::region_tree::RegionI>();
 
@@ -2233,27 +7577,24 @@ mod tests {
             &region_infos,
             &sorted_ply_descs,
         );
-        let region_root = create_region_tree(&cut_bands, &region_infos);
+        let region_root = create_region_tree(&cut_bands, &region_infos, 0);
 
         // Generate toolpaths.
         let tool_dia_pix = 5_usize;
         let tool_step_pix = 3_usize;
         let toolpaths = create_toolpaths_from_region_tree(
-            "rough",
             &region_root,
             &cut_bands,
-            0,
-            tool_dia_pix,
-            tool_step_pix,
-            0,
-            crate::desc::Thou(0),
             &ply_im,
             &region_im,
-            None,
             &region_infos,
-            0,
-            tool_step_pix,
-            true,
+            &RegionToolpathOpts {
+                tool_dia_pix,
+                stepover: Stepover::Pix(tool_step_pix),
+                n_perimeters: 0,
+                perimeter_step_size_pix: tool_step_pix,
+                ..Default::default()
+            },
             None,
         );
 
@@ -2285,7 +7626,13 @@ mod tests {
         // Expected: replay only cutting toolpaths.
         let mut expected = base.clone();
         let mut cut_only = toolpaths.clone();
-        crate::sim::sim_toolpaths(&mut expected, &mut cut_only, None);
+        let _ = crate::sim::sim_toolpaths(
+            &mut expected,
+            &mut cut_only,
+            None,
+            None,
+            crate::sim::SimOptions::default(),
+        );
 
         // Movie behavior: splice traverse toolpaths, then replay *all* toolpaths.
         let mut movie_toolpaths = toolpaths;
@@ -2305,7 +7652,13 @@ mod tests {
         }
         let mut movie_toolpaths = interleaved;
         let mut movie = base;
-        crate::sim::sim_toolpaths(&mut movie, &mut movie_toolpaths, None);
+        let _ = crate::sim::sim_toolpaths(
+            &mut movie,
+            &mut movie_toolpaths,
+            None,
+            None,
+            crate::sim::SimOptions::default(),
+        );
 
         assert_eq!(
             expected.arr, movie.arr,
@@ -1,7 +1,9 @@
 #[allow(dead_code)]
 use std::collections::HashMap;
 
-use crate::im::Im;
+use crate::im::{Im, MaskIm, ROI};
+use crate::mpoly::{IntPath, IntPoint, MPoly};
+use clipper2::{EndType, JoinType};
 
 pub const CONTOUR_ID_MAX: i32 = i32::MAX;
 pub const CONTOUR_ID_MIN: i32 = i32::MIN;
@@ -12,7 +14,7 @@ pub struct Iv2 {
     pub y: i32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Contour {
     pub id: i32,
     pub is_hole: bool,
@@ -100,41 +102,51 @@ impl Contour {
         }
     }
 
+    /// Remove consecutive duplicate points (zero-length segments).
+    /// A closed contour (last point equals first) keeps its closing duplicate.
+    pub fn dedup_points(&mut self) {
+        let is_closed =
+            self.points.len() >= 2 && self.points[0] == self.points[self.points.len() - 1];
+        self.points.dedup();
+        if is_closed && self.points.len() == 1 {
+            self.points.push(self.points[0]);
+        }
+    }
+
     /// Simplify this contour using the Ramer–Douglas–Peucker algorithm.
     ///
     /// `tolerance` is in the same units as the contour coordinates (pixels).
     ///
+    /// Consecutive duplicate points are removed first (see `dedup_points`).
     /// If the contour is "closed" (last point equals first point), this treats it as a ring by
     /// simplifying the path without the duplicated final point, then re-closing it.
     pub fn simplify_by_rdp(&self, tolerance: f64) -> Contour {
-        if self.points.len() <= 2 {
-            return Contour {
-                id: self.id,
-                is_hole: self.is_hole,
-                parent: self.parent,
-                points: self.points.clone(),
-            };
+        let mut deduped = Contour {
+            id: self.id,
+            is_hole: self.is_hole,
+            parent: self.parent,
+            points: self.points.clone(),
+        };
+        deduped.dedup_points();
+
+        if deduped.points.len() <= 2 {
+            return deduped;
         }
 
         let tolerance_sq = tolerance.max(0.0) * tolerance.max(0.0);
 
-        let is_closed =
-            self.points.len() >= 2 && self.points[0] == self.points[self.points.len() - 1];
+        let points = &deduped.points;
+        let is_closed = points.len() >= 2 && points[0] == points[points.len() - 1];
 
         let source: &[Iv2] = if is_closed {
             // Drop the repeated closing point for simplification.
-            &self.points[..self.points.len() - 1]
+            &points[..points.len() - 1]
         } else {
-            &self.points
+            points
         };
 
         if source.len() <= 2 {
-            return Contour {
-                id: self.id,
-                is_hole: self.is_hole,
-                parent: self.parent,
-                points: self.points.clone(),
-            };
+            return deduped;
         }
 
         let mut simplified: Vec<Iv2> = Vec::with_capacity(source.len());
@@ -155,6 +167,76 @@ impl Contour {
             points: simplified,
         }
     }
+
+    /// Offset this (closed) contour by `dist_pix` with round joins, via clipper2.
+    ///
+    /// Positive grows the material side: an exterior moves outward and a hole shrinks;
+    /// negative does the opposite. An inward offset can pinch a thin neck into several
+    /// pieces or consume the contour entirely, hence the Vec (possibly empty).
+    /// Results are closed (last point == first) and keep `id`, `is_hole` and `parent`.
+    pub fn offset(&self, dist_pix: f64) -> Vec<Contour> {
+        let mut ring: &[Iv2] = &self.points;
+        if ring.len() >= 2 && ring[0] == ring[ring.len() - 1] {
+            ring = &ring[..ring.len() - 1];
+        }
+        if ring.len() < 3 {
+            return Vec::new();
+        }
+
+        let path: IntPath = ring
+            .iter()
+            .map(|p| IntPoint::from_scaled(p.x as i64, p.y as i64))
+            .collect::<Vec<_>>()
+            .into();
+
+        // clipper2 grows the enclosed area for a positive delta whatever the winding.
+        let delta = if self.is_hole { -dist_pix } else { dist_pix };
+        let offset = MPoly::new(vec![path]).inflate(delta, JoinType::Round, EndType::Polygon, 2.0);
+
+        offset
+            .iter()
+            .filter(|path| path.len() >= 3)
+            .map(|path| {
+                let mut points: Vec<Iv2> = path
+                    .iter()
+                    .map(|pt| Iv2 {
+                        x: pt.x_scaled() as i32,
+                        y: pt.y_scaled() as i32,
+                    })
+                    .collect();
+                points.push(points[0]);
+                Contour {
+                    id: self.id,
+                    is_hole: self.is_hole,
+                    parent: self.parent,
+                    points,
+                }
+            })
+            .collect()
+    }
+
+    /// Sum of the segment lengths between consecutive points, as an open polyline.
+    /// For a loop stored with its closing duplicate this is the full perimeter.
+    pub fn length(&self) -> f64 {
+        self.points
+            .windows(2)
+            .map(|seg| {
+                let dx = (seg[1].x - seg[0].x) as f64;
+                let dy = (seg[1].y - seg[0].y) as f64;
+                dx.hypot(dy)
+            })
+            .sum()
+    }
+
+    /// Length around the closed loop, whether or not the last point repeats the first:
+    /// the closing segment is added only when it isn't already stored.
+    pub fn perimeter_px(&self) -> f64 {
+        let (Some(&first), Some(&last)) = (self.points.first(), self.points.last()) else {
+            return 0.0;
+        };
+        let closing = ((first.x - last.x) as f64).hypot((first.y - last.y) as f64);
+        self.length() + closing
+    }
 }
 
 /// Port of your Suzuki–Abe contour tracing.
@@ -166,6 +248,20 @@ impl Contour {
 ///
 /// Returns a Vec of contours; parent references are indices into that Vec.
 pub fn contours_by_suzuki_abe(im: &mut Im<i32, 1>) -> Vec<Contour> {
+    contours_by_suzuki_abe_min_area(im, 0)
+}
+
+/// Like `contours_by_suzuki_abe` but drops contours whose bounding box covers fewer than
+/// `min_contour_area` pixels, e.g. noise specks on a full-resolution mask.
+///
+/// Small contours are still traced, since the IDs written into `im` drive the rest of the
+/// raster scan, but their points go into a reused scratch buffer instead of a new `Vec`. A
+/// contour's children lie inside its bounding box, so they are dropped along with it and the
+/// parents of the contours that remain are unchanged (re-indexed into the returned Vec).
+pub fn contours_by_suzuki_abe_min_area(
+    im: &mut Im<i32, 1>,
+    min_contour_area: usize,
+) -> Vec<Contour> {
     let w = im.w;
     let h = im.h;
     assert!(w >= 2 && h >= 2, "need at least a 1-pixel border");
@@ -250,6 +346,8 @@ pub fn contours_by_suzuki_abe(im: &mut Im<i32, 1>) -> Vec<Contour> {
 
     let mut contours: Vec<Contour> = Vec::new();
     let mut id_to_index: HashMap<i32, usize> = HashMap::new();
+    let mut keep: Vec<bool> = Vec::new();
+    let mut points: Vec<Iv2> = Vec::new();
 
     let mut curr_id: i32 = 1;
 
@@ -296,6 +394,8 @@ pub fn contours_by_suzuki_abe(im: &mut Im<i32, 1>) -> Vec<Contour> {
                 let new_index = contours.len();
                 contours.push(Contour::new(curr_id, is_hole));
                 id_to_index.insert(curr_id, new_index);
+                // A singleton pixel has a 1-pixel bounding box.
+                keep.push(min_contour_area <= 1);
 
                 let last_idx_opt = id_to_index.get(&last_id).copied();
 
@@ -364,10 +464,11 @@ pub fn contours_by_suzuki_abe(im: &mut Im<i32, 1>) -> Vec<Contour> {
                     let mut y3: i32 = y0 as i32;
                     let mut x3: i32 = x0 as i32;
                     let start = Iv2 { x: x3, y: y3 };
+                    points.clear();
 
                     loop {
                         // record point ((3))
-                        contours[new_index].points.push(Iv2 { x: x3, y: y3 });
+                        points.push(Iv2 { x: x3, y: y3 });
 
                         // (3.3) counter-clockwise search for ((4)), starting from next element after ((2))
                         let dy = y2 - y3;
@@ -433,7 +534,21 @@ pub fn contours_by_suzuki_abe(im: &mut Im<i32, 1>) -> Vec<Contour> {
                     }
 
                     // Repeat the initial pixel
-                    contours[new_index].points.push(start);
+                    points.push(start);
+
+                    let (mut min_x, mut min_y) = (start.x, start.y);
+                    let (mut max_x, mut max_y) = (start.x, start.y);
+                    for p in &points {
+                        min_x = min_x.min(p.x);
+                        min_y = min_y.min(p.y);
+                        max_x = max_x.max(p.x);
+                        max_y = max_y.max(p.y);
+                    }
+                    let bbox_area = (max_x - min_x + 1) as usize * (max_y - min_y + 1) as usize;
+                    keep[new_index] = bbox_area >= min_contour_area;
+                    if keep[new_index] {
+                        contours[new_index].points = std::mem::take(&mut points);
+                    }
                 }
             }
 
@@ -444,7 +559,322 @@ pub fn contours_by_suzuki_abe(im: &mut Im<i32, 1>) -> Vec<Contour> {
         }
     }
 
+    if keep.iter().all(|&k| k) {
+        return contours;
+    }
+
+    // Compact, re-indexing the parents; a dropped contour only has dropped descendants.
+    let mut new_index_of: Vec<Option<usize>> = vec![None; contours.len()];
+    let mut n_kept = 0;
+    for (i, &k) in keep.iter().enumerate() {
+        if k {
+            new_index_of[i] = Some(n_kept);
+            n_kept += 1;
+        }
+    }
     contours
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, k)| *k)
+        .map(|(mut contour, _)| {
+            contour.parent = contour.parent.and_then(|p| new_index_of[p]);
+            contour
+        })
+        .collect()
+}
+
+/// Like `contours_by_suzuki_abe` but only traces the pixels of `mask_im` inside `roi`, on
+/// a scratch image just big enough for the ROI plus its zero margin. `scratch` is resized
+/// in place so a caller tracing many regions can reuse one buffer.
+///
+/// `mask_im` is left untouched and output points are in full-image coordinates. When every
+/// nonzero pixel lies within `roi` the result matches tracing the whole image, including
+/// the forced zero border on the image edges.
+pub fn contours_by_suzuki_abe_in_roi<T: Copy + Default + PartialEq, S>(
+    mask_im: &Im<T, 1, S>,
+    roi: &ROI,
+    scratch: &mut Im<i32, 1>,
+) -> Vec<Contour> {
+    let l = roi.l.min(mask_im.w);
+    let t = roi.t.min(mask_im.h);
+    let r = roi.r.clamp(l, mask_im.w);
+    let b = roi.b.clamp(t, mask_im.h);
+
+    // One pixel of zero margin on every side of the ROI.
+    let sw = (r - l) + 2;
+    let sh = (b - t) + 2;
+    scratch.w = sw;
+    scratch.h = sh;
+    scratch.s = sw;
+    scratch.arr.clear();
+    scratch.arr.resize(sw * sh, 0);
+
+    let zero = T::default();
+    for y in t..b {
+        // The full-image trace zeroes the image edges, so do the same here.
+        if y == 0 || y + 1 == mask_im.h {
+            continue;
+        }
+        let src_row = y * mask_im.s;
+        let dst_row = (y - t + 1) * sw;
+        for x in l.max(1)..r.min(mask_im.w.saturating_sub(1)) {
+            if mask_im.arr[src_row + x] != zero {
+                scratch.arr[dst_row + x - l + 1] = 1;
+            }
+        }
+    }
+
+    let mut contours = contours_by_suzuki_abe(scratch);
+    let dx = l as i32 - 1;
+    let dy = t as i32 - 1;
+    for contour in &mut contours {
+        for p in &mut contour.points {
+            p.x += dx;
+            p.y += dy;
+        }
+    }
+    contours
+}
+
+/// Rasterize traced contours back into a mask (255 inside): the inverse of
+/// `contours_by_suzuki_abe`, e.g. to re-derive a region after editing its outline.
+///
+/// Contour points are pixel centers on the region's border, so the interior is an even-odd
+/// scanline fill at pixel centers and the outlines themselves are drawn on top. Even-odd
+/// follows the nesting from the geometry rather than `parent`, so holes subtract and islands
+/// inside holes add back however the hierarchy was flattened. A hole's outline runs over the
+/// foreground pixels around it, so it stays set. Points outside `w` x `h` are clipped.
+pub fn fill_contours(contours: &[Contour], w: usize, h: usize) -> MaskIm {
+    let mut mask_im = MaskIm::new(w, h);
+    let mut put_px = |x: i32, y: i32| {
+        if x >= 0 && y >= 0 && (x as usize) < w && (y as usize) < h {
+            mask_im.arr[y as usize * w + x as usize] = 255;
+        }
+    };
+
+    // Every edge of every ring, including the closing edge; horizontal edges never cross a
+    // scanline and a repeated closing point makes a zero-length one.
+    let edges: Vec<(Iv2, Iv2)> = contours
+        .iter()
+        .flat_map(|c| {
+            let n = c.points.len();
+            (0..n).map(move |i| (c.points[i], c.points[(i + 1) % n]))
+        })
+        .filter(|(a, b)| a.y != b.y)
+        .collect();
+
+    let mut crossings: Vec<f64> = Vec::new();
+    for y in 0..h as i32 {
+        // Half-open in y so a vertex shared by two edges is counted once.
+        crossings.clear();
+        for &(a, b) in &edges {
+            if (a.y <= y) != (b.y <= y) {
+                let t = (y - a.y) as f64 / (b.y - a.y) as f64;
+                crossings.push(a.x as f64 + t * (b.x - a.x) as f64);
+            }
+        }
+        crossings.sort_by(f64::total_cmp);
+        for span in crossings.chunks_exact(2) {
+            for x in span[0].ceil() as i32..=span[1].floor() as i32 {
+                put_px(x, y);
+            }
+        }
+    }
+
+    for c in contours {
+        let n = c.points.len();
+        if n == 1 {
+            put_px(c.points[0].x, c.points[0].y);
+        }
+        for i in 0..n {
+            // Bresenham, for outlines that have been simplified or edited.
+            let (a, b) = (c.points[i], c.points[(i + 1) % n]);
+            let (mut x, mut y) = (a.x, a.y);
+            let dx = (b.x - a.x).abs();
+            let dy = -(b.y - a.y).abs();
+            let sx = if a.x < b.x { 1 } else { -1 };
+            let sy = if a.y < b.y { 1 } else { -1 };
+            let mut err = dx + dy;
+            loop {
+                put_px(x, y);
+                if x == b.x && y == b.y {
+                    break;
+                }
+                let e2 = 2 * err;
+                if e2 >= dy {
+                    err += dy;
+                    x += sx;
+                }
+                if e2 <= dx {
+                    err += dx;
+                    y += sy;
+                }
+            }
+        }
+    }
+    mask_im
+}
+
+/// Twice the signed (shoelace) area of a ring; positive is counterclockwise with y up
+/// (which looks clockwise in an image, where y points down). A repeated closing point is fine.
+pub fn ring_signed_area2(points: &[Iv2]) -> i64 {
+    let n = points.len();
+    (0..n)
+        .map(|i| {
+            let a = points[i];
+            let b = points[(i + 1) % n];
+            a.x as i64 * b.y as i64 - b.x as i64 * a.y as i64
+        })
+        .sum()
+}
+
+/// True if (x, y) is inside the ring (even-odd, half-open in y like `fill_contours`).
+/// Points on the ring itself may go either way.
+fn ring_contains(points: &[Iv2], x: i32, y: i32) -> bool {
+    let n = points.len();
+    let mut inside = false;
+    for i in 0..n {
+        let (a, b) = (points[i], points[(i + 1) % n]);
+        if (a.y <= y) != (b.y <= y) {
+            let t = (y - a.y) as f64 / (b.y - a.y) as f64;
+            if (x as f64) < a.x as f64 + t * (b.x - a.x) as f64 {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+/// The nesting of a contour set: exteriors, the holes in them, the islands in those holes, and
+/// so on. Nodes are indices into the contour slice it was built from. See `build_contour_tree`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContourTree {
+    roots: Vec<usize>,
+    parents: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+}
+
+impl ContourTree {
+    /// The outermost contours, in index order.
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+
+    /// The contours directly inside `contour_i`, in index order.
+    pub fn children(&self, contour_i: usize) -> &[usize] {
+        &self.children[contour_i]
+    }
+
+    pub fn parent(&self, contour_i: usize) -> Option<usize> {
+        self.parents[contour_i]
+    }
+
+    /// `contour_i` and everything inside it, depth first with parents before children.
+    pub fn subtree(&self, contour_i: usize) -> Vec<usize> {
+        let mut out: Vec<usize> = Vec::new();
+        let mut stack: Vec<usize> = vec![contour_i];
+        while let Some(i) = stack.pop() {
+            out.push(i);
+            stack.extend(self.children[i].iter().rev());
+        }
+        out
+    }
+}
+
+/// Build the nesting tree of a Suzuki–Abe contour set. Each contour's `parent` is used where
+/// it is set (a hole under its exterior). The tracer leaves islands inside holes top-level, so
+/// an exterior without a parent is placed under the smallest hole around its first point.
+/// Anything else without a valid parent is a root.
+pub fn build_contour_tree(contours: &[Contour]) -> ContourTree {
+    let n = contours.len();
+    let hole_area2: Vec<i64> = contours
+        .iter()
+        .map(|c| ring_signed_area2(&c.points).abs())
+        .collect();
+
+    let parents: Vec<Option<usize>> = contours
+        .iter()
+        .enumerate()
+        .map(|(i, c)| match c.parent {
+            Some(p) if p < n && p != i => Some(p),
+            _ if c.is_hole => None,
+            _ => {
+                let first = c.points.first()?;
+                (0..n)
+                    .filter(|&h| {
+                        contours[h].is_hole && ring_contains(&contours[h].points, first.x, first.y)
+                    })
+                    .min_by_key(|&h| hole_area2[h])
+            }
+        })
+        .collect();
+
+    let mut tree = ContourTree {
+        roots: Vec::new(),
+        parents,
+        children: vec![Vec::new(); n],
+    };
+    for i in 0..n {
+        match tree.parents[i] {
+            Some(p) => tree.children[p].push(i),
+            None => tree.roots.push(i),
+        }
+    }
+    tree
+}
+
+/// Which way `normalize_contours` winds the loops (CCW/CW are with y up, see `ring_signed_area2`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContourWinding {
+    /// Leave the trace direction alone.
+    #[default]
+    Keep,
+    /// Exteriors counterclockwise, holes clockwise.
+    ExteriorCcw,
+    /// Exteriors clockwise, holes counterclockwise.
+    ExteriorCw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ContourNormOpts {
+    pub winding: ContourWinding,
+    /// Rotate each loop to start at its smallest point by (x, y).
+    pub start_at_min_point: bool,
+}
+
+/// Post-trace normalization of closed contours for stable output: set the winding per
+/// `is_hole` and/or rotate each loop to a canonical start. Loops that repeat their first
+/// point at the end still do so afterwards. Ids, hole flags and parents are untouched.
+pub fn normalize_contours(contours: &mut [Contour], opts: ContourNormOpts) {
+    for contour in contours.iter_mut() {
+        let points = &mut contour.points;
+        let is_closed = points.len() >= 2 && points.first() == points.last();
+        if is_closed {
+            points.pop();
+        }
+
+        let want_ccw = match opts.winding {
+            ContourWinding::Keep => None,
+            ContourWinding::ExteriorCcw => Some(!contour.is_hole),
+            ContourWinding::ExteriorCw => Some(contour.is_hole),
+        };
+        if let Some(want_ccw) = want_ccw {
+            let area2 = ring_signed_area2(points);
+            if area2 != 0 && (area2 > 0) != want_ccw {
+                points.reverse();
+            }
+        }
+
+        if opts.start_at_min_point
+            && let Some(min_i) = (0..points.len()).min_by_key(|&i| (points[i].x, points[i].y))
+        {
+            points.rotate_left(min_i);
+        }
+
+        if is_closed {
+            points.push(points[0]);
+        }
+    }
 }
 
 fn fmt_verts(points: &[Iv2]) -> String {
@@ -600,41 +1030,33 @@ pub trait ContoursDebug {
     );
 }
 
+/// Extension trait for metrics over a whole contour set (e.g. a `Vec<Contour>`).
+pub trait ContoursMetrics {
+    /// Sum of `Contour::perimeter_px` over every contour.
+    fn total_length(&self) -> f64;
+}
+
+impl ContoursMetrics for [Contour] {
+    fn total_length(&self) -> f64 {
+        self.iter().map(Contour::perimeter_px).sum()
+    }
+}
+
 impl ContoursDebug for [Contour] {
     fn dump(&self) {
         println!("Contours");
 
-        let mut holes_by_parent: Vec<Vec<usize>> = vec![Vec::new(); self.len()];
-        let mut orphan_holes: Vec<usize> = Vec::new();
-
-        for (i, c) in self.iter().enumerate() {
-            if !c.is_hole {
-                continue;
-            }
-
-            match c.parent {
-                Some(p) if p < self.len() => holes_by_parent[p].push(i),
-                _ => orphan_holes.push(i),
+        // Each level of nesting indents by 4.
+        fn dump_subtree(contours: &[Contour], tree: &ContourTree, i: usize, indent: usize) {
+            dump_contour_line(&contours[i], indent);
+            for &child_i in tree.children(i) {
+                dump_subtree(contours, tree, child_i, indent + 4);
             }
         }
 
-        for (i, c) in self.iter().enumerate() {
-            if c.is_hole {
-                continue;
-            }
-
-            dump_contour_line(c, 2);
-
-            let holes = &holes_by_parent[i];
-            for &hi in holes {
-                let h = &self[hi];
-                dump_contour_line(h, 6);
-            }
-        }
-
-        for hi in orphan_holes {
-            let h = &self[hi];
-            dump_contour_line(h, 2);
+        let tree = build_contour_tree(self);
+        for &root_i in tree.roots() {
+            dump_subtree(self, &tree, root_i, 2);
         }
     }
 
@@ -823,6 +1245,208 @@ mod tests {
 
     }
 
+    #[test]
+    fn normalize_contours_sets_winding_and_start() {
+        // The square-with-holes fixture: an outer square, two holes, an island in each hole.
+        let mut im: Im<i32, 1> = Im::new(100, 100);
+        fill_rect(&mut im, 10, 10, 75, 75, 1);
+        fill_rect(&mut im, 20, 20, 20, 50, 0);
+        fill_rect(&mut im, 55, 20, 20, 50, 0);
+        fill_rect(&mut im, 25, 30, 10, 10, 1);
+        fill_rect(&mut im, 60, 30, 10, 10, 1);
+        let traced = contours_by_suzuki_abe(&mut im);
+        assert_eq!(traced.iter().filter(|c| c.is_hole).count(), 2);
+
+        for (winding, ext_ccw) in [
+            (ContourWinding::ExteriorCcw, true),
+            (ContourWinding::ExteriorCw, false),
+        ] {
+            let mut contours = traced.clone();
+            let opts = ContourNormOpts {
+                winding,
+                start_at_min_point: true,
+            };
+            normalize_contours(&mut contours, opts);
+
+            for (c, orig) in contours.iter().zip(traced.iter()) {
+                assert_eq!(c.points.len(), orig.points.len());
+                assert_eq!(c.points.first(), c.points.last());
+                let is_ccw = ring_signed_area2(&c.points) > 0;
+                assert_eq!(is_ccw, ext_ccw != c.is_hole, "id={}", c.id);
+                let min = c.points.iter().map(|p| (p.x, p.y)).min().unwrap();
+                assert_eq!((c.points[0].x, c.points[0].y), min);
+            }
+
+            // Normalizing is idempotent.
+            let before: Vec<Vec<Iv2>> = contours.iter().map(|c| c.points.clone()).collect();
+            normalize_contours(&mut contours, opts);
+            let after: Vec<Vec<Iv2>> = contours.iter().map(|c| c.points.clone()).collect();
+            assert_eq!(before, after);
+        }
+    }
+
+    #[test]
+    fn contour_tree_nests_islands_under_their_holes() {
+        // An outer square with two holes, an island in the first hole, and a separate blob.
+        let mut im: Im<i32, 1> = Im::new(60, 40);
+        fill_rect(&mut im, 2, 2, 40, 30, 1);
+        fill_rect(&mut im, 6, 6, 14, 20, 0);
+        fill_rect(&mut im, 24, 6, 14, 20, 0);
+        fill_rect(&mut im, 10, 12, 5, 5, 1);
+        fill_rect(&mut im, 48, 10, 6, 6, 1);
+
+        let contours = contours_by_suzuki_abe(&mut im);
+        assert_eq!(contours.len(), 5);
+        let find = |x: i32, y: i32, is_hole: bool| {
+            contours
+                .iter()
+                .position(|c| {
+                    let (l, t, _, _) = bbox(&c.points);
+                    c.is_hole == is_hole && (l, t) == (x, y)
+                })
+                .unwrap()
+        };
+        let outer = find(2, 2, false);
+        let hole1 = find(5, 5, true);
+        let hole2 = find(23, 5, true);
+        let island = find(10, 12, false);
+        let blob = find(48, 10, false);
+
+        let tree = build_contour_tree(&contours);
+        let mut roots = vec![outer, blob];
+        roots.sort();
+        assert_eq!(tree.roots(), roots);
+        let mut holes = vec![hole1, hole2];
+        holes.sort();
+        assert_eq!(tree.children(outer), holes);
+        assert_eq!(tree.children(hole1), [island]);
+        assert!(tree.children(hole2).is_empty());
+        assert_eq!(tree.parent(island), Some(hole1));
+        assert_eq!(tree.subtree(hole1), vec![hole1, island]);
+        assert_eq!(tree.subtree(outer).len(), 4);
+    }
+
+    #[test]
+    fn contours_in_roi_match_full_image_trace() {
+        // A ring with a hole, plus a blob touching the image's left edge (which the full
+        // trace zeroes), all inside the ROI.
+        let mut mask_im: Im<u8, 1> = Im::new(40, 30);
+        for y in 5..20 {
+            for x in 0..25 {
+                mask_im.arr[y * mask_im.s + x] = 255;
+            }
+        }
+        for y in 9..14 {
+            for x in 10..16 {
+                mask_im.arr[y * mask_im.s + x] = 0;
+            }
+        }
+        let roi = ROI {
+            l: 0,
+            t: 4,
+            r: 27,
+            b: 21,
+        };
+
+        let mut full_im: Im<i32, 1> = mask_im.map_values(|v| i32::from(v != 0));
+        let full = contours_by_suzuki_abe(&mut full_im);
+
+        let mut scratch: Im<i32, 1> = Im::new(1, 1);
+        let in_roi = contours_by_suzuki_abe_in_roi(&mask_im, &roi, &mut scratch);
+        assert_eq!(scratch.w, 29);
+        assert_eq!(scratch.h, 19);
+
+        assert_eq!(full.len(), 2);
+        assert_eq!(in_roi.len(), full.len());
+        for (a, b) in in_roi.iter().zip(full.iter()) {
+            assert_eq!((a.id, a.is_hole, a.parent), (b.id, b.is_hole, b.parent));
+            assert_eq!(a.points, b.points);
+        }
+    }
+
+    #[test]
+    fn contours_by_suzuki_abe_min_area_drops_specks() {
+        // A 40x40 square with a 10x10 hole, plus specks: a lone pixel and a 2x2 blob outside,
+        // a 1-pixel pinhole in the square, and a 2x2 blob inside the hole.
+        let mut im: Im<i32, 1> = Im::new(60, 60);
+        fill_rect(&mut im, 10, 10, 40, 40, 1);
+        fill_rect(&mut im, 20, 20, 10, 10, 0);
+        fill_rect(&mut im, 3, 3, 1, 1, 1);
+        fill_rect(&mut im, 53, 5, 2, 2, 1);
+        fill_rect(&mut im, 40, 40, 1, 1, 0);
+        fill_rect(&mut im, 24, 24, 2, 2, 1);
+
+        let all = contours_by_suzuki_abe(&mut im.clone());
+        assert_eq!(all.len(), 6);
+
+        let big = contours_by_suzuki_abe_min_area(&mut im, 16);
+        assert_eq!(big.len(), 2);
+        let outer = big.iter().position(|c| !c.is_hole).unwrap();
+        let hole = big.iter().position(|c| c.is_hole).unwrap();
+        assert_eq!(bbox(&big[outer].points), (10, 10, 49, 49));
+        assert_eq!(bbox(&big[hole].points), (19, 19, 30, 30));
+        assert_eq!(big[outer].parent, None);
+        assert_eq!(big[hole].parent, Some(outer));
+
+        // The surviving contours are traced exactly as without the filter.
+        for c in &big {
+            let same = all.iter().find(|a| a.id == c.id).unwrap();
+            assert_eq!(same.points, c.points);
+        }
+    }
+
+    #[test]
+    fn fill_contours_reproduces_traced_masks() {
+        // A solid square, a ring with an island in its hole, and a diagonal staircase.
+        let mut im: Im<i32, 1> = Im::new(40, 30);
+        fill_rect(&mut im, 3, 3, 8, 8, 1);
+        fill_rect(&mut im, 14, 2, 20, 20, 1);
+        fill_rect(&mut im, 17, 5, 12, 12, 0);
+        fill_rect(&mut im, 21, 9, 3, 3, 1);
+        for i in 0..6 {
+            fill_rect(&mut im, 3 + i, 14 + i, 2, 1, 1);
+        }
+        let want = im.to_mask_im().arr;
+
+        let contours = contours_by_suzuki_abe(&mut im.clone());
+        let filled = fill_contours(&contours, im.w, im.h);
+        assert_eq!(filled.arr, want);
+
+        // The square alone, traced and filled, is exactly the square.
+        let mut square: Im<i32, 1> = Im::new(12, 12);
+        fill_rect(&mut square, 2, 2, 8, 8, 1);
+        let want = square.to_mask_im().arr;
+        let contours = contours_by_suzuki_abe(&mut square.clone());
+        assert_eq!(contours.len(), 1);
+        assert_eq!(fill_contours(&contours, 12, 12).arr, want);
+    }
+
+    #[test]
+    fn contour_length_and_perimeter_of_a_unit_square() {
+        let v = |x: i32, y: i32| Iv2 { x, y };
+        let square = |points: Vec<Iv2>| Contour {
+            id: 1,
+            is_hole: false,
+            parent: None,
+            points,
+        };
+        let open = square(vec![v(0, 0), v(1, 0), v(1, 1), v(0, 1)]);
+        let closed = square(vec![v(0, 0), v(1, 0), v(1, 1), v(0, 1), v(0, 0)]);
+
+        assert_eq!(open.length(), 3.0);
+        assert_eq!(closed.length(), 4.0);
+        assert_eq!(open.perimeter_px(), 4.0);
+        assert_eq!(closed.perimeter_px(), 4.0);
+        assert_eq!(square(Vec::new()).perimeter_px(), 0.0);
+
+        let diag = square(vec![v(0, 0), v(3, 4)]);
+        assert_eq!(diag.length(), 5.0);
+        assert_eq!(diag.perimeter_px(), 10.0);
+
+        let contours = vec![open, closed, diag];
+        assert_eq!(contours.total_length(), 18.0);
+    }
+
     #[test]
     fn simplify_by_rdp_open_line_keeps_endpoints() {
         let c = Contour {
@@ -855,6 +1479,73 @@ mod tests {
         assert_eq!(s2.points, c.points);
     }
 
+    fn rect_contour(l: i32, t: i32, r: i32, b: i32) -> Contour {
+        Contour {
+            id: 1,
+            is_hole: false,
+            parent: None,
+            points: vec![
+                Iv2 { x: l, y: t },
+                Iv2 { x: r, y: t },
+                Iv2 { x: r, y: b },
+                Iv2 { x: l, y: b },
+                Iv2 { x: l, y: t },
+            ],
+        }
+    }
+
+    #[test]
+    fn offset_moves_square_in_and_out() {
+        let c = rect_contour(10, 10, 30, 30);
+
+        let inner = c.offset(-3.0);
+        assert_eq!(inner.len(), 1);
+        assert_eq!(bbox(&inner[0].points), (13, 13, 27, 27));
+        assert_eq!(inner[0].points.first(), inner[0].points.last());
+
+        let outer = c.offset(3.0);
+        assert_eq!(outer.len(), 1);
+        assert_eq!(bbox(&outer[0].points), (7, 7, 33, 33));
+
+        // The same ring as a hole goes the other way.
+        let mut hole = rect_contour(10, 10, 30, 30);
+        hole.is_hole = true;
+        let shrunk = hole.offset(3.0);
+        assert_eq!(shrunk.len(), 1);
+        assert_eq!(bbox(&shrunk[0].points), (13, 13, 27, 27));
+        assert!(shrunk[0].is_hole);
+
+        // Offsetting past the half-width consumes it.
+        assert!(c.offset(-11.0).is_empty());
+    }
+
+    #[test]
+    fn offset_inward_splits_at_a_thin_neck() {
+        // Two 20x20 squares joined by a 4-pixel-tall neck.
+        let c = Contour {
+            id: 1,
+            is_hole: false,
+            parent: None,
+            points: vec![
+                Iv2 { x: 0, y: 0 },
+                Iv2 { x: 20, y: 0 },
+                Iv2 { x: 20, y: 8 },
+                Iv2 { x: 30, y: 8 },
+                Iv2 { x: 30, y: 0 },
+                Iv2 { x: 50, y: 0 },
+                Iv2 { x: 50, y: 20 },
+                Iv2 { x: 30, y: 20 },
+                Iv2 { x: 30, y: 12 },
+                Iv2 { x: 20, y: 12 },
+                Iv2 { x: 20, y: 20 },
+                Iv2 { x: 0, y: 20 },
+                Iv2 { x: 0, y: 0 },
+            ],
+        };
+        assert_eq!(c.offset(-1.0).len(), 1);
+        assert_eq!(c.offset(-4.0).len(), 2);
+    }
+
     #[test]
     fn simplify_by_rdp_closed_contour_stays_closed() {
         let c = Contour {
@@ -877,4 +1568,51 @@ mod tests {
 
         s.dump();
     }
+
+    #[test]
+    fn dedup_points_drops_repeats_but_keeps_closure() {
+        let p = |x: i32, y: i32| Iv2 { x, y };
+        let mut c = Contour {
+            id: 1,
+            is_hole: false,
+            parent: None,
+            points: vec![
+                p(0, 0),
+                p(0, 0),
+                p(10, 0),
+                p(10, 0),
+                p(10, 0),
+                p(10, 10),
+                p(0, 10),
+                p(0, 0),
+                p(0, 0),
+            ],
+        };
+        c.dedup_points();
+        assert_eq!(
+            c.points,
+            vec![p(0, 0), p(10, 0), p(10, 10), p(0, 10), p(0, 0)]
+        );
+
+        // A degenerate closed contour stays closed.
+        let mut dot = Contour {
+            id: 2,
+            is_hole: false,
+            parent: None,
+            points: vec![p(3, 3), p(3, 3), p(3, 3)],
+        };
+        dot.dedup_points();
+        assert_eq!(dot.points, vec![p(3, 3), p(3, 3)]);
+
+        // RDP output has no zero-length segments even with a zero tolerance.
+        let open = Contour {
+            id: 3,
+            is_hole: false,
+            parent: None,
+            points: vec![p(0, 0), p(5, 1), p(5, 1), p(9, 0), p(9, 0)],
+        };
+        let s = open.simplify_by_rdp(0.0);
+        assert_eq!(s.points, vec![p(0, 0), p(5, 1), p(9, 0)]);
+        assert!(s.points.windows(2).all(|w| w[0] != w[1]));
+    }
 }
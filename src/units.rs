@@ -0,0 +1,200 @@
+use crate::desc::Thou;
+
+/// A length in pixels (X/Y). Thou (`desc::Thou`) is used for Z.
+/// `IV3` still stores both as raw `i32`; use these on new APIs so the two can't be swapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pix(pub i32);
+
+/// The one place pixels, thou and inches are converted, given the image resolution.
+/// Pixels may be non-square (e.g. from a scanner), so X and Y each have their own resolution;
+/// the axis-free conversions are only meaningful for square pixels.
+///
+/// Images are top-left origin, Y down, while G-code (and DXF) expect bottom-left origin, Y up.
+/// Exporters place points with `px_to_export_x_inch`/`px_to_export_y_inch`, which flip Y when
+/// `with_flip_y` gave the image height, so every emitter agrees on the orientation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PixScale {
+    px_per_inch_x: f64,
+    px_per_inch_y: f64,
+    /// The image height in pixels when exported Y is flipped.
+    flip_y_image_h: Option<usize>,
+}
+
+impl PixScale {
+    /// Square pixels.
+    pub fn new(px_per_inch: f64) -> Self {
+        Self::new_anisotropic(px_per_inch, px_per_inch)
+    }
+
+    pub fn new_anisotropic(px_per_inch_x: f64, px_per_inch_y: f64) -> Self {
+        for px_per_inch in [px_per_inch_x, px_per_inch_y] {
+            assert!(
+                px_per_inch.is_finite() && px_per_inch > 0.0,
+                "px_per_inch must be positive, got {px_per_inch}"
+            );
+        }
+        Self {
+            px_per_inch_x,
+            px_per_inch_y,
+            flip_y_image_h: None,
+        }
+    }
+
+    /// Export Y up from the bottom of an `image_h`-tall image: pixel row `y` exports at row
+    /// `image_h - 1 - y`, so row 0 is the top of the part rather than the bottom. This mirrors
+    /// the path, so loops run the other way around (climb vs. conventional) in the output.
+    pub fn with_flip_y(mut self, image_h: usize) -> Self {
+        self.flip_y_image_h = Some(image_h);
+        self
+    }
+
+    pub fn flip_y(&self) -> bool {
+        self.flip_y_image_h.is_some()
+    }
+
+    pub fn is_square(&self) -> bool {
+        self.px_per_inch_x == self.px_per_inch_y
+    }
+
+    /// The resolution of square pixels.
+    pub fn px_per_inch(&self) -> f64 {
+        debug_assert!(self.is_square(), "px_per_inch() on non-square pixels");
+        self.px_per_inch_x
+    }
+
+    pub fn px_per_inch_x(&self) -> f64 {
+        self.px_per_inch_x
+    }
+
+    pub fn px_per_inch_y(&self) -> f64 {
+        self.px_per_inch_y
+    }
+
+    /// Nearest pixel to a thou length (square pixels).
+    pub fn thou_to_px(&self, thou: Thou) -> Pix {
+        self.inch_to_px(thou.0 as f64 / 1000.0)
+    }
+
+    /// Nearest thou to a pixel length (square pixels).
+    pub fn px_to_thou(&self, px: Pix) -> Thou {
+        let thou = (self.px_to_inch(px) * 1000.0).round();
+        debug_assert!(thou.abs() <= i32::MAX as f64, "thou out of range: {thou}");
+        Thou(thou as i32)
+    }
+
+    /// Pixel length to inches (square pixels).
+    pub fn px_to_inch(&self, px: Pix) -> f64 {
+        px.0 as f64 / self.px_per_inch()
+    }
+
+    /// Nearest pixel to an inch length (square pixels).
+    pub fn inch_to_px(&self, inch: f64) -> Pix {
+        Self::round_px(inch * self.px_per_inch())
+    }
+
+    pub fn px_to_inch_x(&self, px: Pix) -> f64 {
+        px.0 as f64 / self.px_per_inch_x
+    }
+
+    pub fn px_to_inch_y(&self, px: Pix) -> f64 {
+        px.0 as f64 / self.px_per_inch_y
+    }
+
+    pub fn inch_to_px_x(&self, inch: f64) -> Pix {
+        Self::round_px(inch * self.px_per_inch_x)
+    }
+
+    pub fn inch_to_px_y(&self, inch: f64) -> Pix {
+        Self::round_px(inch * self.px_per_inch_y)
+    }
+
+    /// Exported X position in inches of pixel column `x` (X is never flipped).
+    pub fn px_to_export_x_inch(&self, x: Pix) -> f64 {
+        self.px_to_inch_x(x)
+    }
+
+    /// Exported Y position in inches of pixel row `y`, flipped if `with_flip_y` was set.
+    pub fn px_to_export_y_inch(&self, y: Pix) -> f64 {
+        match self.flip_y_image_h {
+            Some(image_h) => self.px_to_inch_y(Pix(image_h as i32 - 1 - y.0)),
+            None => self.px_to_inch_y(y),
+        }
+    }
+
+    /// Real-world length in inches of a pixel displacement, scaling each axis separately.
+    /// Use this (not `dx*dx + dy*dy` in pixels) wherever lengths are compared or reported.
+    pub fn px_dist_inch(&self, dx: Pix, dy: Pix) -> f64 {
+        self.px_to_inch_x(dx).hypot(self.px_to_inch_y(dy))
+    }
+
+    /// Thou to inches doesn't depend on the resolution; here so exporters only need `PixScale`.
+    pub fn thou_to_inch(&self, thou: Thou) -> f64 {
+        thou.0 as f64 / 1000.0
+    }
+
+    fn round_px(px: f64) -> Pix {
+        let px = px.round();
+        debug_assert!(px.abs() <= i32::MAX as f64, "pixels out of range: {px}");
+        Pix(px as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pix_scale_converts_between_pixels_thou_and_inches() {
+        let scale = PixScale::new(100.0);
+        assert_eq!(scale.px_to_inch(Pix(250)), 2.5);
+        assert_eq!(scale.inch_to_px(0.125), Pix(13));
+        assert_eq!(scale.thou_to_px(Thou(1000)), Pix(100));
+        assert_eq!(scale.thou_to_px(Thou(-15)), Pix(-2));
+        assert_eq!(scale.px_to_thou(Pix(3)), Thou(30));
+        assert_eq!(scale.thou_to_inch(Thou(125)), 0.125);
+
+        // Round trips are exact wherever the finer unit divides the coarser one.
+        for px in -50..50 {
+            assert_eq!(scale.thou_to_px(scale.px_to_thou(Pix(px))), Pix(px));
+        }
+        let scale = PixScale::new(1000.0);
+        for thou in -50..50 {
+            assert_eq!(scale.px_to_thou(scale.thou_to_px(Thou(thou))), Thou(thou));
+        }
+    }
+
+    #[test]
+    fn anisotropic_pix_scale_scales_each_axis() {
+        let scale = PixScale::new_anisotropic(100.0, 50.0);
+        assert!(!scale.is_square());
+        assert_eq!(scale.px_to_inch_x(Pix(100)), 1.0);
+        assert_eq!(scale.px_to_inch_y(Pix(100)), 2.0);
+        assert_eq!(scale.inch_to_px_x(0.5), Pix(50));
+        assert_eq!(scale.inch_to_px_y(0.5), Pix(25));
+        // 30 px in X is 0.3", 20 px in Y is 0.4" => a 0.5" move.
+        assert!((scale.px_dist_inch(Pix(30), Pix(20)) - 0.5).abs() < 1e-12);
+        assert!(PixScale::new(100.0).is_square());
+    }
+
+    #[test]
+    fn flip_y_exports_the_top_row_at_the_top() {
+        let scale = PixScale::new_anisotropic(100.0, 50.0);
+        assert!(!scale.flip_y());
+        assert_eq!(scale.px_to_export_y_inch(Pix(0)), 0.0);
+
+        // Pixel (0, 0) of a 201-tall image is (H - 1) = 200 px = 4" up; X is unchanged.
+        let flipped = scale.with_flip_y(201);
+        assert!(flipped.flip_y());
+        assert_eq!(flipped.px_to_export_x_inch(Pix(0)), 0.0);
+        assert_eq!(flipped.px_to_export_y_inch(Pix(0)), 4.0);
+        assert_eq!(flipped.px_to_export_y_inch(Pix(200)), 0.0);
+        // Lengths don't depend on the flip.
+        assert_eq!(flipped.px_to_inch_y(Pix(50)), scale.px_to_inch_y(Pix(50)));
+    }
+
+    #[test]
+    #[should_panic(expected = "px_per_inch must be positive")]
+    fn pix_scale_rejects_a_zero_resolution() {
+        let _ = PixScale::new(0.0);
+    }
+}